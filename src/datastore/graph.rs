@@ -1,17 +1,19 @@
 
 use {
   bimap::BiBTreeMap,
-  bitvec::vec::BitVec,
   serde::{
     Serialize,
     Deserialize,
-    ser::SerializeStruct,
     de::{self, Visitor, MapAccess}
   },
   crate::{
-    errors::GraphError as Error,
+    errors::{GraphError as Error, K2TreeError},
     RdfNode, RdfTriple,
-    datastore::k2_tree::{self, K2Tree},
+    datastore::{
+      k2_tree::{self, K2Tree, K2TreeView, K2TreeIndex, K2TreeStats},
+      ik2_tree::{self, IK2Tree},
+      k3_tree::{self, K3Tree},
+    },
     rdf::{
       query::{Sparql, QueryUnit},
       builder::RdfBuilder,
@@ -32,201 +34,178 @@ pub struct Graph {
   //Store dict_max because the max R-value in a dict is expensive to calulate on-the-fly
   //Store tombstone-indices for dict and predicates to be reused in later inserts
   //Use BiMap instead of HashMap because we want to be able to find the strings rows/columns represent
+  //Store k so that slices created after construction match the existing ones
+  k: usize,
   dict_max: usize,
   dict_tombstones: Vec<usize>,
   dict: BiBTreeMap<RdfNode, usize>,
   pred_tombstones: Vec<usize>,
   predicates: BiBTreeMap<RdfNode, usize>,
-  slices: Vec<Option<Box<K2Tree>>>,
+  storage: Storage,
   persist_location: Option<String>,
+  reorder_on_persist: Option<IdOrder>, //Not part of a backup
+}
+/* How a Graph stores its triples. Slices keeps a K2Tree per predicate,
+Interleaved keeps every predicate in a single IK2Tree, so queries with
+the predicate unbound descend once instead of once per slice and rare
+predicates don't each pay for a tree of their own. Ternary keeps the
+whole (subject, predicate, object) cube in a K3Tree, where a predicate
+costs nothing beyond the bits on its triples' paths */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GraphStorage {
+  Slices,
+  Interleaved,
+  Ternary,
+}
+impl Default for GraphStorage {
+  fn default() -> Self {
+    GraphStorage::Slices
+  }
+}
+/* The trees themselves, a variant for each GraphStorage */
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Storage {
+  Slices(Vec<Option<Box<K2Tree>>>), //None where a predicate's been removed
+  Interleaved(IK2Tree),
+  Ternary(K3Tree),
+}
+/* How reorder_ids gives out node ids. Nodes with nearby ids share
+subtrees, so the closer together the 1s of each slice end up the
+fewer stems it takes to hold them */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdOrder {
+  Bfs, //breadth-first along triples either way, from the nodes in most triples
+  Namespace, //by IRI, so nodes sharing a namespace are together, then blanks and literals
+  Degree, //nodes in the most triples first
+}
+/* What reorder_ids did, sizes are GraphStats::total_bits */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorderReport {
+  pub bits_before: usize,
+  pub bits_after: usize,
+  pub ids_changed: usize,
+}
+/* What vacuum did, bytes are those of the trees, slices and tombstones */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VacuumReport {
+  pub nodes_remapped: usize,
+  pub predicates_remapped: usize,
+  pub bytes_reclaimed: usize,
 }
 
 /* Public */
 impl Graph {
   /* Constructors */
   pub fn new() -> Self {
+    Graph::with_k(2)
+  }
+  pub fn with_k(k: usize) -> Self {
+    Graph::with_storage(k, GraphStorage::Slices)
+  }
+  pub fn with_storage(k: usize, storage: GraphStorage) -> Self {
+    assert_valid_k(k);
     Graph {
+      k: k,
       dict_max: 0,
       dict_tombstones: Vec::new(),
       dict: BiBTreeMap::new(),
       pred_tombstones: Vec::new(),
       predicates: BiBTreeMap::new(),
-      slices: Vec::new(),
+      storage: match storage {
+        GraphStorage::Slices => Storage::Slices(Vec::new()),
+        GraphStorage::Interleaved => Storage::Interleaved(IK2Tree::with_extents(k, 0, 0)),
+        GraphStorage::Ternary => Storage::Ternary(K3Tree::with_extents(k, 0, 0, 0)),
+      },
       persist_location: None,
+      reorder_on_persist: None,
     }
   }
   pub fn from_backup(path: &str) -> Result<Self> {
-    /* Private trait impl */
-    impl<'de> Deserialize<'de> for Graph {
-      fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
-        #[derive(Deserialize)]
-        #[serde(field_identifier, rename_all = "camelCase")]
-        enum Field {
-          DictMax,
-          DictTombstones,
-          Dict,
-          PredTombstones,
-          Predicates,
-          PersistLocation
-        }
-        struct GraphVisitor;
-        impl<'de> Visitor<'de> for GraphVisitor {
-          type Value = Graph;
-          fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-            formatter.write_str("struct Graph")
-          }
-          fn visit_map<V: MapAccess<'de>>(self, mut map: V) -> std::result::Result<Graph, V::Error> {
-            let mut dict_max = None;
-            let mut dict_tombstones = None;
-            let mut dict = None;
-            let mut pred_tombstones = None;
-            let mut predicates = None;
-            let mut persist_location = None;
-            while let Some(key) = map.next_key()? {
-              match key {
-                Field::DictMax => {
-                  if dict_max.is_some() {
-                      return Err(de::Error::duplicate_field("dictMax"));
-                  }
-                  dict_max = Some(map.next_value()?);
-                }
-                Field::DictTombstones => {
-                  if dict_tombstones.is_some() {
-                    return Err(de::Error::duplicate_field("dictTombstones"));
-                  }
-                  dict_tombstones = Some(map.next_value()?);
-                }
-                Field::Dict => {
-                  if dict.is_some() {
-                    return Err(de::Error::duplicate_field("dict"));
-                  }
-                  dict = Some(map.next_value::<Vec<(RdfNode, usize)>>()?);
-                }
-                Field::PredTombstones => {
-                  if pred_tombstones.is_some() {
-                      return Err(de::Error::duplicate_field("predTombstones"));
-                  }
-                  pred_tombstones = Some(map.next_value()?);
-                }
-                Field::Predicates => {
-                  if predicates.is_some() {
-                    return Err(de::Error::duplicate_field("predicates"));
-                  }
-                  predicates = Some(map.next_value::<Vec<(RdfNode, usize)>>()?);
-                }
-                Field::PersistLocation => {
-                  if persist_location.is_some() {
-                    return Err(de::Error::duplicate_field("persistLocation"));
-                  }
-                  persist_location = Some(map.next_value()?);
-                }
-              }
-            }
-            let dict_max = dict_max.ok_or_else(|| de::Error::missing_field("dictMax"))?;
-            let dict_tombstones = dict_tombstones.ok_or_else(|| de::Error::missing_field("dictTombstones"))?;
-            let dict = dict.ok_or_else(|| de::Error::missing_field("dict"))?;
-            let pred_tombstones = pred_tombstones.ok_or_else(|| de::Error::missing_field("predTombstones"))?;
-            let predicates = predicates.ok_or_else(|| de::Error::missing_field("predicates"))?;
-            let persist_location = persist_location.ok_or_else(|| de::Error::missing_field("persistLocation"))?;
-            
-            let mut final_dict: BiBTreeMap<RdfNode, usize> = BiBTreeMap::new();
-            for (key, val) in dict.into_iter() {
-              final_dict.insert(key, val);
-            }
-            let mut final_preds: BiBTreeMap<RdfNode, usize> = BiBTreeMap::new();
-            for (key, val) in predicates.into_iter() {
-              final_preds.insert(key, val);
-            }
-
-            Ok(Graph {
-              dict_max: dict_max,
-              dict_tombstones: dict_tombstones,
-              dict: final_dict,
-              pred_tombstones: pred_tombstones,
-              predicates: final_preds,
-              slices: Vec::new(),
-              persist_location: persist_location
-            })
-          }
-        }
-        const FIELDS: &'static [&'static str] = &[
-          "dict_max",
-          "dict_tombstones",
-          "dict",
-          "pred_tombstones",
-          "predicates",
-          "persist_location"
-        ];
-        deserializer.deserialize_struct("Graph", FIELDS, GraphVisitor)
-      }
-    }
-    /* Closure definitions */
-    let read_json = |path_to_file: &std::path::Path| -> Result<String> {
-      use std::io::Read;
-      let mut buf = String::new();
-      std::fs::File::open(path_to_file)?.read_to_string(&mut buf)?;
-      Ok(buf)
-    };
-    /* Function start */
-    /* Define key filesystem locations */
-    let root_dir = std::path::Path::new(path);
-    let trees_dir = root_dir.join("trees");
-    let head_file = root_dir.join("head.json");
-    let dot_file = root_dir.join(".ripplebackup");
-    /* Check that all files and dirs actually exist */
-    if !root_dir.is_dir() {
-      return Err(Error::MissingBackup(std::path::PathBuf::from(root_dir)))
-    }
-    else if !trees_dir.is_dir() {
-      return Err(Error::InvalidBackup("root/trees/".into(), std::path::PathBuf::from(root_dir)))
-    }
-    else if !head_file.is_file() {
-      return Err(Error::InvalidBackup("root/head.json".into(), std::path::PathBuf::from(root_dir)))
-    }
-    else if !dot_file.is_file() {
-      return Err(Error::InvalidBackup("root/.ripplebackup".into(), std::path::PathBuf::from(root_dir)))
+    let head = read_backup_head(path)?;
+    let trees_dir = std::path::Path::new(path).join("trees");
+    match head.storage() {
+      GraphStorage::Interleaved => return Ok(Graph {
+        storage: Storage::Interleaved(read_interleaved(&trees_dir)?),
+        ..head
+      }),
+      GraphStorage::Ternary => return Ok(Graph {
+        storage: Storage::Ternary(read_ternary(&trees_dir)?),
+        ..head
+      }),
+      GraphStorage::Slices => {},
     }
-    /* Build surface level of the Graph from root/head.json */
-    let Graph {
-      dict_max,
-      dict_tombstones,
-      dict,
-      pred_tombstones,
-      predicates,
-      slices: _,
-      persist_location: _
-    } = match serde_json::from_str::<Graph>(&read_json(&head_file)?) {
-      Ok(g) => g,
-      Err(e) => return Err(Error::FromBadJson(String::from("Graph"), head_file, Box::new(e))),
-    };
-
-    /* Build K2Trees from json files in root/trees/ */
+    /* Build K2Trees from binary files in root/trees/,
+    backups made before the binary format used json files */
     let mut slices: Vec<Option<Box<K2Tree>>> = Vec::new();
     for i in 0.. {
-      if let Some(_) = predicates.get_by_right(&i) {
-        let tree_json = read_json(&trees_dir.join(format!("{}.json", i)))?;
-        slices.push(Some(Box::new(K2Tree::from_json(&tree_json)?)));
+      if let Some(_) = head.predicates.get_by_right(&i) {
+        let tree_file = trees_dir.join(format!("{}.k2t", i));
+        let tree = if tree_file.is_file() {
+          K2Tree::read_from(std::io::BufReader::new(std::fs::File::open(&tree_file)?))?
+        }
+        else {
+          K2Tree::from_json(&read_json(&trees_dir.join(format!("{}.json", i)))?)?
+        };
+        slices.push(Some(Box::new(tree)));
       }
-      else if pred_tombstones.contains(&i) {
+      else if head.pred_tombstones.contains(&i) {
         slices.push(None);
       }
       else {
         break
       }
     }
-
     Ok(Graph {
+      storage: Storage::Slices(slices),
+      ..head
+    })
+  }
+  pub fn from_rdf(path: &str) -> Result<Self> {
+    Graph::from_rdf_with_k(path, 2)
+  }
+  pub fn from_rdf_with_storage(path: &str, k: usize, storage: GraphStorage) -> Result<Self> {
+    use crate::rdf::parser::ParsedTriples;
+    check_k(k)?;
+    if let GraphStorage::Slices = storage {
+      return Graph::from_rdf_with_k(path, k)
+    }
+    let ParsedTriples {
+      dict_max,
+      dict,
+      pred_max: _,
+      predicates,
+      partitioned_triples,
+    } = ParsedTriples::from_rdf(path)?;
+    let num_predicates = partitioned_triples.len();
+    let triples: Vec<[usize; 3]> = partitioned_triples.into_iter()
+      .enumerate()
+      .flat_map(|(predicate, doubles)| doubles.into_iter().map(move |[x, y]| [x, y, predicate]))
+      .collect();
+    let width = triples.iter().map(|&[x, _, _]| x+1).max().unwrap_or(0);
+    let height = triples.iter().map(|&[_, y, _]| y+1).max().unwrap_or(0);
+    let storage = if let GraphStorage::Ternary = storage {
+      let cells = triples.into_iter().map(|[x, y, predicate]| [x, predicate, y]);
+      Storage::Ternary(K3Tree::from_cells(k, width, num_predicates, height, cells)?)
+    }
+    else {
+      Storage::Interleaved(IK2Tree::from_triples(k, width, height, num_predicates, triples)?)
+    };
+    Ok(Graph {
+      k: k,
       dict_max: dict_max,
-      dict_tombstones: dict_tombstones,
+      dict_tombstones: Vec::new(),
       dict: dict,
-      pred_tombstones: pred_tombstones,
+      pred_tombstones: Vec::new(),
       predicates: predicates,
-      slices: slices,
-      persist_location: Some(path.to_string()),
+      storage: storage,
+      persist_location: None,
+      reorder_on_persist: None,
     })
   }
-  pub fn from_rdf(path: &str) -> Result<Self> {
+  pub fn from_rdf_with_k(path: &str, k: usize) -> Result<Self> {
     use crate::rdf::parser::ParsedTriples;
+    check_k(k)?;
     /* Parse the RDF file at path */
     let ParsedTriples {
       dict_max,
@@ -291,8 +270,7 @@ impl Graph {
         else {
           sorted_trips[(median_tripleset + (thread_num * num_uppers_per_thread))..].to_vec()
       };
-      let dict_max = dict_max;
-      handles.push(std::thread::spawn(move || build_slices(triplesets, dict_max)));
+      handles.push(std::thread::spawn(move || build_slices(triplesets, k)));
     }
     /* Spawn lower threads */
     for thread_num in 0..num_lower_threads {
@@ -305,8 +283,7 @@ impl Graph {
         else {
           sorted_trips[(thread_num * num_lowers_per_thread)..median_tripleset].to_vec()
       };
-      let dict_max = dict_max;
-      handles.push(std::thread::spawn(move || build_slices(triplesets, dict_max)));
+      handles.push(std::thread::spawn(move || build_slices(triplesets, k)));
     }
     let mut slice_sets: Vec<Vec<Slice>> = Vec::new();
     for handle in handles { slice_sets.push(handle.join().unwrap()); }
@@ -324,198 +301,115 @@ impl Graph {
       return Err(Error::DeadK2Tree("it could not be built".into()))
     }
     Ok(Graph {
+      k: k,
       dict_max: dict_max,
       dict_tombstones: Vec::new(),
       dict: dict,
       pred_tombstones: Vec::new(),
       predicates: predicates,
-      slices: slices,
+      storage: Storage::Slices(slices),
       persist_location: None,
+      reorder_on_persist: None,
     })
   }
   /*For even greater building performance get it to build the trees in the background and saved to files
     If the predicate isn't built yet on query, go build it, otherwise finish building the rest. */
   pub fn get(&self, query: &Sparql) -> Vec<RdfNode> {
-    /* Assume only one variable */
-    use std::collections::HashSet;
-    use QueryUnit::{Var, Val};
-    /* Util closures for later */
-    let cond_to_qt = |cond: &[QueryUnit; 3]| {
-      let mut qt: [Option<String>; 3] = [None, None, None];
-      if let Val(s) = &cond[0] { qt[0] = Some(s.to_string()); }
-      if let Val(p) = &cond[1] { qt[1] = Some(p.to_string()); }
-      if let Val(o) = &cond[2] { qt[2] = Some(o.to_string()); }
-      qt
+    self.query(query)
+  }
+  pub fn compose(&self, first: &RdfNode, second: &RdfNode) -> Result<Vec<[RdfNode; 2]>> {
+    /* Answers ?a first ?b . ?b second ?c as the [?a, ?c] pairs,
+    multiplying the two slices instead of matching each condition */
+    let slice = |predicate: &RdfNode| -> Result<Option<std::borrow::Cow<K2Tree>>> {
+      let slice_index = match self.predicates.get_by_left(predicate) {
+        Some(&slice_index) => slice_index,
+        None => return Ok(None),
+      };
+      Ok(match &self.storage {
+        Storage::Interleaved(tree) => Some(std::borrow::Cow::Owned(tree.predicate_slice(slice_index)?)),
+        Storage::Ternary(tree) => Some(std::borrow::Cow::Owned(tree.plane(slice_index)?)),
+        Storage::Slices(slices) => slices[slice_index].as_deref().map(std::borrow::Cow::Borrowed),
+      })
     };
-    let var_pos = |cond: &[QueryUnit; 3]| {
-      match cond {
-        [Var(_), _, _] => 0,
-        [_, Var(_), _] => 1,
-        [_, _, Var(_)] => 2,
-        _ => 3
-      }
+    let (first_slice, second_slice) = match (slice(first)?, slice(second)?) {
+      (Some(first_slice), Some(second_slice)) => (first_slice, second_slice),
+      _ => return Ok(Vec::new()),
     };
-    /* Gather raw results from each condition to filter later */
-    let mut results: Vec<(&[QueryUnit; 3], Vec<[usize; 3]>)> = Vec::new();
-    for cond in query.conds.iter() {
-      let qt = cond_to_qt(cond);
-      let res = self.get_from_triple(qt);
-      results.push((cond, res));
+    let composed = first_slice.multiply(&second_slice)?;
+    let node = |id: usize| self.dict.get_by_right(&id).cloned()
+      .ok_or_else(|| Error::DeadK2Tree(format!("it holds node {} which is not in the dictionary", id)));
+    composed.iter_range(0..composed.matrix_width(), 0..composed.matrix_height())
+      .map(|[a, c]| Ok([node(a)?, node(c)?]))
+      .collect()
+  }
+  pub fn insert_triple(&mut self, val: RdfTriple) -> Result<()> {
+    let (col, row, slice_pos) = self.allocate_triple(&val);
+    let [width, height, depth] = extents_holding([col, slice_pos, row])?;
+    match &mut self.storage {
+      Storage::Interleaved(tree) => {
+        fit_interleaved(tree, width, depth)?;
+        tree.set(col, row, slice_pos, true)?;
+      },
+      Storage::Ternary(tree) => {
+        fit_ternary(tree, width, height, depth)?;
+        tree.set(col, slice_pos, row, true)?;
+      },
+      Storage::Slices(slices) => if let Some(slice) = &mut slices[slice_pos] {
+        fit_slice(slice, width, depth)?;
+        slice.set(col, row, true)?;
+      },
     }
-    /* Filter results */
-    let mut final_results: Vec<usize> = results[0].1.iter().map(|[s, p, o]|
-      match var_pos(results[0].0) {
-        0 => s.clone(),
-        1 => p.clone(),
-        2 => o.clone(),
-        _ => std::usize::MAX,
-      }
-    ).collect();
-    for (query_triple, qt_results) in &results[1..] {
-      let qt_var_pos = var_pos(query_triple);
-      let mut used_vars_vals: HashSet<usize> = HashSet::new();
-      let mut vars_vals_to_remove: Vec<usize> = Vec::new();
-      for (i, final_result) in final_results.iter().enumerate() {
-        if !used_vars_vals.contains(final_result) {
-          used_vars_vals.insert(final_result.clone());
-          let filter_t = match qt_var_pos {
-            0 => [Some(final_result.clone()), None, None],
-            1 => [None, Some(final_result.clone()), None],
-            2 => [None, None, Some(final_result.clone())],
-            _ => [None, None, None],
-          };
-          if self.filter_triples(qt_results.clone(), filter_t).is_empty() {
-            /* There was no match for this value of the variable from final_results in
-            the query triple, so mark it to be removed at the end of this cycle */
-            vars_vals_to_remove.push(i);
-          }
+    Ok(())
+  }
+  pub fn insert_triples<I: IntoIterator<Item=RdfTriple>>(&mut self, triples: I) -> Result<()> {
+    /* Like insert_triple for each triple, but every slice
+    is rebuilt once with all of its changes */
+    let mut batches: std::collections::BTreeMap<usize, Vec<(usize, usize, bool)>> = std::collections::BTreeMap::new();
+    for triple in triples {
+      let (col, row, slice_pos) = self.allocate_triple(&triple);
+      batches.entry(slice_pos).or_insert_with(Vec::new).push((col, row, true));
+    }
+    let mut extents = [0; 3];
+    for (&slice_pos, changes) in batches.iter() {
+      for &(col, row, _) in changes {
+        let cell_extents = extents_holding([col, slice_pos, row])?;
+        for (extent, cell_extent) in extents.iter_mut().zip(cell_extents.iter()) {
+          *extent = std::cmp::max(*extent, *cell_extent);
         }
       }
-      final_results = final_results.into_iter().enumerate().filter_map(|(i, res)| {
-        if !vars_vals_to_remove.is_empty() {
-          if i == vars_vals_to_remove[0] {
-            vars_vals_to_remove.remove(0);
-            None
-          }
-          else {
-            Some(res)
-          }
-        }
-        else {
-          Some(res)
-        }
-      }).collect();
     }
-    let ret: Vec<RdfNode> = final_results.into_iter().map(|n| {
-      if var_pos(results[0].0) == 1 {
-        self.predicates.get_by_right(&n).unwrap().clone()
-      }
-      else {
-        self.dict.get_by_right(&n).unwrap().clone()
-      }
-    }).collect();
-    ret
-  }
-  pub fn insert_triple(&mut self, val: RdfTriple) -> Result<()> {
-
-    let col = match self.dict.get_by_left(&val[0]) {
-      Some(&col) => col,
-      None => {
-        if !self.dict_tombstones.is_empty() {
-          let col = self.dict_tombstones[0];
-          self.dict.insert(val[0].clone(), col);
-          col
-        }
-        else {
-          if self.dict_max != 0 { self.dict_max += 1; }
-          self.dict.insert(val[0].clone(), self.dict_max);
-          if !self.slices.is_empty() {
-            for slice in &mut self.slices {
-              match slice {
-                Some(slice) if self.dict_max >= slice.matrix_width() => {
-                  slice.grow();
-                },
-                _ => {}
-              };
-            }
+    let [width, height, depth] = extents;
+    match &mut self.storage {
+      Storage::Interleaved(tree) => {
+        /* Set in place, there's only the one tree to grow */
+        fit_interleaved(tree, width, depth)?;
+        for (slice_pos, changes) in batches.into_iter() {
+          for (col, row, state) in changes {
+            tree.set(col, row, slice_pos, state)?;
           }
-          self.dict_max
         }
       },
-    };
-    let row = match self.dict.get_by_left(&val[2]) {
-      Some(&row) => row,
-      None => {
-        if !self.dict_tombstones.is_empty() {
-          let row = self.dict_tombstones[0];
-          self.dict.insert(val[2].clone(), row);
-          row
-        }
-        else {
-          self.dict_max += 1;
-          self.dict.insert(val[2].clone(), self.dict_max);
-          if !self.slices.is_empty() {
-            for slice in &mut self.slices {
-              match slice {
-                Some(slice) if self.dict_max >= slice.matrix_width() => {
-                  slice.grow();
-                },
-                _ => {}
-              };
-            }
+      Storage::Ternary(tree) => {
+        fit_ternary(tree, width, height, depth)?;
+        for (slice_pos, changes) in batches.into_iter() {
+          for (col, row, state) in changes {
+            tree.set(col, slice_pos, row, state)?;
           }
-          self.dict_max
         }
       },
-    };
-    let slice = match self.predicates.get_by_left(&val[1]) {
-      Some(&slice_index) => &mut self.slices[slice_index],
-      None => {
-        let desired_size =
-          if !self.slices.is_empty() {
-            let mut desired_size = 0;
-            for slice in &self.slices {
-              match slice {
-                Some(slice) => {
-                  desired_size = slice.matrix_width();
-                  break
-                },
-                None => {},
-              }
-            }
-            desired_size
-          }
-          else {
-            8 //Min size assuming k=2
-          };
-        let new_slice =
-          if !self.pred_tombstones.is_empty() {
-            let new_slice_pos = self.pred_tombstones[0];
-            self.predicates.insert(val[1].clone(), new_slice_pos);
-            &mut self.slices[new_slice_pos]
-          }
-          else {
-            self.slices.push(Some(Box::new(K2Tree::new())));
-            let slice_len = self.slices.len();
-            self.predicates.insert(val[1].clone(), slice_len-1);
-            &mut self.slices[slice_len-1]
-          };
-        if let Some(new_slice) = new_slice {
-          while new_slice.matrix_width() < desired_size {
-            new_slice.grow();
-          }
+      Storage::Slices(slices) => for (slice_pos, changes) in batches.into_iter() {
+        if let Some(slice) = &mut slices[slice_pos] {
+          let width = changes.iter().map(|&(col, _, _)| col+1).max().unwrap_or(0);
+          let height = changes.iter().map(|&(_, row, _)| row+1).max().unwrap_or(0);
+          fit_slice(slice, width, height)?;
+          slice.apply_batch(changes)?;
         }
-        new_slice
       },
-    };
-    if let Some(slice) = slice {
-      slice.set(col, row, true)?;
     }
     Ok(())
   }
   pub fn remove_triple(&mut self, [subject, predicate, object]: &RdfTriple) -> Result<()> {
-    /* TODO: Add ability to shrink matrix_width for all slices if
-    needed */
+    /* Extents are left as they are, vacuum shrinks them */
     let (subject_pos, object_pos, slice_pos) = match [
       self.dict.get_by_left(subject),
       self.dict.get_by_left(object),
@@ -523,115 +417,83 @@ impl Graph {
         [Some(&c), Some(&r), Some(&s)] => (c, r, s),
         _ => return Ok(())
     };
-    let slice = match &mut self.slices[slice_pos] {
-      Some(slice) => slice,
-      None => return Ok(()),
-    };
-    slice.set(subject_pos, object_pos, false)?;
-    /* Check if we've removed all instances of a word.
-    If we have: Remove from dictionaries and do other stuff */
-    if slice.is_empty() {
-      self.predicates.remove_by_left(&predicate);
-      if slice_pos == self.slices.len()-1 {
-        self.slices.pop();
-        while self.slices[self.slices.len()-1] == None {
-          self.slices.pop();
+    match &mut self.storage {
+      Storage::Interleaved(tree) => {
+        if subject_pos >= tree.matrix_width()
+        || object_pos >= tree.matrix_height() {
+          return Ok(())
         }
-        let newly_invalid_tombstones: Vec<usize> = self.pred_tombstones
-          .iter()
-          .filter_map(|&tombstone|
-            if tombstone > self.slices.len()-1 { Some(tombstone) }
-            else { None }
-          )
-          .collect();
-        self.pred_tombstones = self.pred_tombstones
-          .iter()
-          .filter_map(|tombstone|
-            if newly_invalid_tombstones.contains(tombstone) { None }
-            else { Some(*tombstone) }
-          )
-          .collect();
-        self.slices = self.slices
-          .iter()
-          .enumerate()
-          .filter_map(|(i, slice)|
-            match slice {
-              None if newly_invalid_tombstones.contains(&i) => None,
-              _ => Some(slice.clone()),
-            }
-          )
-          .collect();
-      }
-      else {
-        self.pred_tombstones.push(slice_pos);
-        self.slices[slice_pos] = None;
-      }
-    }
-    let mut subject_exists = false;
-    let mut object_exists = false;
-    for slice in
-      self.slices.iter().filter(|slice|
-        match slice {
-          Some(slice) => !slice.is_empty(),
-          None => false,
-        }) {
-      if let Some(slice) = slice {
-        if !subject_exists
-        && (ones_in_bitvec(&slice.get_row(subject_pos)?) > 0
-        || ones_in_bitvec(&slice.get_column(subject_pos)?) > 0) {
-          subject_exists = true;
+        tree.set(subject_pos, object_pos, slice_pos, false)?;
+      },
+      Storage::Ternary(tree) => {
+        if subject_pos >= tree.width()
+        || slice_pos >= tree.height()
+        || object_pos >= tree.depth() {
+          return Ok(())
         }
-        if !object_exists
-        && (ones_in_bitvec(&slice.get_row(object_pos)?) > 0
-        || ones_in_bitvec(&slice.get_column(object_pos)?) > 0) {
-          object_exists = true;
+        tree.set(subject_pos, slice_pos, object_pos, false)?;
+      },
+      Storage::Slices(slices) => {
+        let slice = match &mut slices[slice_pos] {
+          Some(slice) => slice,
+          None => return Ok(()),
+        };
+        if subject_pos >= slice.matrix_width()
+        || object_pos >= slice.matrix_height() {
+          /* Outside the slice's extents so the triple isn't there */
+          return Ok(())
         }
-        if subject_exists && object_exists { break }
-      }
+        slice.set(subject_pos, object_pos, false)?;
+      },
     }
-    if !subject_exists {
-      self.dict.remove_by_left(subject);
-      if subject_pos == self.dict_max {
-        /* Find next highest valid dict_max,
-        remove all newly-invalid tombstones greater than new dict_max */
-        while !self.dict.contains_right(&self.dict_max) {
-          self.dict_max -= 1;
-        }
-        self.dict_tombstones = self.dict_tombstones
-          .iter()
-          .filter_map(|&tombstone|
-            if tombstone <= self.dict_max { Some(tombstone) }
-            else { None }
-          )
-          .collect();
-      }
-      else {
-        self.dict_tombstones.push(subject_pos);
+    /* Check if we've removed all instances of a word.
+    If we have: Remove from dictionaries and do other stuff */
+    self.drop_slice_if_empty(slice_pos);
+    self.drop_node_if_unused(subject);
+    /* In the case of "gabe likes gabe" making "gabe" dead, the
+    second call finds "gabe" already gone and does nothing */
+    self.drop_node_if_unused(object);
+    Ok(())
+  }
+  pub fn remove_triples(&mut self, triples: &[RdfTriple]) -> Result<()> {
+    /* Like remove_triple for each triple, but every slice
+    is rebuilt once with all of its changes */
+    let mut batches: std::collections::BTreeMap<usize, Vec<(usize, usize, bool)>> = std::collections::BTreeMap::new();
+    for [subject, predicate, object] in triples {
+      if let [Some(&col), Some(&row), Some(&slice_pos)] = [
+        self.dict.get_by_left(subject),
+        self.dict.get_by_left(object),
+        self.predicates.get_by_left(predicate)] {
+        batches.entry(slice_pos).or_insert_with(Vec::new).push((col, row, false));
       }
     }
-    /* In the case of "gabe likes gabe" making "gabe" dead,
-    we don't want to try to remove or declare "gabe" dead twice. */
-    if !object_exists
-    && subject != object {
-      self.dict.remove_by_left(object);
-      if object_pos == self.dict_max {
-        /* Find next highest valid dict_max,
-        remove all newly-invalid tombstones greater than new dict_max */
-        while !self.dict.contains_right(&self.dict_max) {
-          self.dict_max -= 1;
-        }
-        self.dict_tombstones = self.dict_tombstones
-          .iter()
-          .filter_map(|&tombstone|
-            if tombstone < self.dict_max { Some(tombstone) }
-            else { None }
-          )
-          .collect();
-      }
-      else {
-        self.dict_tombstones.push(object_pos);
+    for (&slice_pos, changes) in batches.iter_mut() {
+      match &mut self.storage {
+        Storage::Interleaved(tree) => for &(col, row, state) in changes.iter() {
+          if col < tree.matrix_width() && row < tree.matrix_height() {
+            tree.set(col, row, slice_pos, state)?;
+          }
+        },
+        Storage::Ternary(tree) => for &(col, row, state) in changes.iter() {
+          if col < tree.width() && slice_pos < tree.height() && row < tree.depth() {
+            tree.set(col, slice_pos, row, state)?;
+          }
+        },
+        Storage::Slices(slices) => if let Some(slice) = &mut slices[slice_pos] {
+          /* Anything outside the slice's extents isn't there */
+          let (width, height) = (slice.matrix_width(), slice.matrix_height());
+          changes.retain(|&(col, row, _)| col < width && row < height);
+          slice.apply_batch(changes.iter().cloned())?;
+        },
       }
     }
+    for &slice_pos in batches.keys().rev() {
+      self.drop_slice_if_empty(slice_pos);
+    }
+    for [subject, _, object] in triples {
+      self.drop_node_if_unused(subject);
+      self.drop_node_if_unused(object);
+    }
     Ok(())
   }
   pub fn persist_to(&mut self, path: &str) -> Result<()> {
@@ -645,24 +507,160 @@ impl Graph {
   pub fn persist_location(&self) -> &Option<String> {
     &self.persist_location
   }
+  pub fn set_reorder_on_persist(&mut self, order: Option<IdOrder>) {
+    /* With Some, persist writes the Graph with its ids reordered,
+    this Graph's own ids are left as they are */
+    self.reorder_on_persist = order;
+  }
+  pub fn reorder_on_persist(&self) -> Option<IdOrder> {
+    self.reorder_on_persist
+  }
+  pub fn reorder_ids(&mut self, order: IdOrder) -> Result<ReorderReport> {
+    /* Gives the nodes new ids in the given order, from 0 with no
+    tombstones, and rebuilds every tree under them */
+    let bits_before = self.stats().total_bits;
+    let (new_ids, triples) = self.reordered_triples(order);
+    let ids_changed = new_ids.iter().filter(|(old_id, new_id)| old_id != new_id).count();
+    self.storage = self.rebuilt_storage(self.num_predicate_ids(), triples)?;
+    self.dict = self.dict.iter().map(|(node, old_id)| (node.clone(), new_ids[old_id])).collect();
+    self.dict_max = new_ids.len().saturating_sub(1);
+    self.dict_tombstones.clear();
+    Ok(ReorderReport {
+      bits_before: bits_before,
+      bits_after: self.stats().total_bits,
+      ids_changed: ids_changed,
+    })
+  }
+  pub fn vacuum(&mut self) -> Result<VacuumReport> {
+    /* Purges the tombstones, giving nodes and predicates dense ids in
+    their old order, drops empty slices and shrinks every tree to the
+    smallest extents that hold its triples */
+    let bytes_before = self.heapsize();
+    let triples = self.get_from_triple([None, None, None]);
+    let dense_ids = |old_ids: Vec<usize>| -> std::collections::BTreeMap<usize, usize> {
+      old_ids.into_iter().enumerate().map(|(new_id, old_id)| (old_id, new_id)).collect()
+    };
+    let new_ids = dense_ids(self.dict.right_values().copied().collect());
+    let new_preds = dense_ids(self.predicates.right_values().copied().collect());
+    let remapped = |ids: &std::collections::BTreeMap<usize, usize>| ids.iter().filter(|(old_id, new_id)| old_id != new_id).count();
+    let (nodes_remapped, predicates_remapped) = (remapped(&new_ids), remapped(&new_preds));
+    let triples: Vec<[usize; 3]> = triples.into_iter()
+      .map(|[x, predicate, y]| [new_ids[&x], new_preds[&predicate], new_ids[&y]])
+      .collect();
+    if let Storage::Slices(slices) = &mut self.storage {
+      let mut old_slices = std::mem::take(slices);
+      *slices = new_preds.keys().map(|&old_pred| old_slices[old_pred].take()).collect();
+    }
+    self.storage = self.rebuilt_storage(new_preds.len(), triples)?;
+    self.dict = self.dict.iter().map(|(node, old_id)| (node.clone(), new_ids[old_id])).collect();
+    self.predicates = self.predicates.iter().map(|(predicate, old_pred)| (predicate.clone(), new_preds[old_pred])).collect();
+    self.dict_max = new_ids.len().saturating_sub(1);
+    self.dict_tombstones = Vec::new();
+    self.pred_tombstones = Vec::new();
+    Ok(VacuumReport {
+      nodes_remapped: nodes_remapped,
+      predicates_remapped: predicates_remapped,
+      bytes_reclaimed: bytes_before.saturating_sub(self.heapsize()),
+    })
+  }
+  pub fn k(&self) -> usize {
+    self.k
+  }
+  pub fn storage(&self) -> GraphStorage {
+    match &self.storage {
+      Storage::Slices(_) => GraphStorage::Slices,
+      Storage::Interleaved(_) => GraphStorage::Interleaved,
+      Storage::Ternary(_) => GraphStorage::Ternary,
+    }
+  }
+  pub fn stats(&self) -> GraphStats {
+    match &self.storage {
+      Storage::Interleaved(tree) => {
+        /* Each predicate as it would be in a slice of its own,
+        the totals are those of the interleaved tree */
+        let predicates: Vec<(RdfNode, K2TreeStats)> = (0..tree.num_predicates())
+          .filter_map(|i| match (tree.predicate_slice(i), self.predicates.get_by_right(&i)) {
+            (Ok(slice), Some(predicate)) => Some((predicate.clone(), slice.stats())),
+            _ => None,
+          })
+          .collect();
+        GraphStats::new(predicates).stored_in(tree.storage_bits())
+      },
+      Storage::Ternary(tree) => {
+        let predicates: Vec<(RdfNode, K2TreeStats)> = (0..tree.height())
+          .filter_map(|i| match (tree.plane(i), self.predicates.get_by_right(&i)) {
+            (Ok(slice), Some(predicate)) => Some((predicate.clone(), slice.stats())),
+            _ => None,
+          })
+          .collect();
+        GraphStats::new(predicates).stored_in(tree.storage_bits())
+      },
+      Storage::Slices(slices) => {
+        let predicates: Vec<(RdfNode, K2TreeStats)> = slices
+          .iter()
+          .enumerate()
+          .filter_map(|(i, slice)| match (slice, self.predicates.get_by_right(&i)) {
+            (Some(slice), Some(predicate)) => Some((predicate.clone(), slice.stats())),
+            _ => None,
+          })
+          .collect();
+        GraphStats::new(predicates)
+      },
+    }
+  }
   pub fn persist(&self) -> Result<()> {
-    /* Only want to use this trait in this func, not public as it's not really
-    "serializing" the Graph and would be confusing to users if the trait was
-    publicly implemented */
-    impl Serialize for Graph {
-      fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
-        let mut state = serializer.serialize_struct("Graph", 6)?;
-        state.serialize_field("dictMax", &self.dict_max)?;
-        state.serialize_field("dictTombstones", &self.dict_tombstones)?;
-        state.serialize_field("dict", &self.dict.iter().collect() as &Vec<(&RdfNode, &usize)>)?;
-        state.serialize_field("predTombstones", &self.pred_tombstones)?;
-        state.serialize_field("predicates", &self.predicates.iter().collect() as &Vec<(&RdfNode, &usize)>)?;
-        state.serialize_field("persistLocation", &self.persist_location)?;
-        state.end()
-      }
+    /* Only want to use this struct in this func, it's the surface level of
+    the Graph as root/head.json holds it, borrowed so that a reordered
+    backup only needs its own dict rather than a copy of the whole Graph */
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct BackupHead<'a> {
+      k: usize,
+      storage: GraphStorage,
+      dict_max: usize,
+      dict_tombstones: &'a [usize],
+      dict: Vec<(&'a RdfNode, usize)>,
+      pred_tombstones: &'a [usize],
+      predicates: Vec<(&'a RdfNode, usize)>,
+      persist_location: &'a Option<String>,
     }
-    if let None = self.persist_location { return Err(Error::NoPersistLocation) }
-    let path = &self.persist_location.clone().unwrap();
+    let path = match &self.persist_location {
+      Some(path) => path,
+      None => return Err(Error::NoPersistLocation),
+    };
+    /* With reorder_on_persist the backup gets the ids reorder_ids would give
+    out, only its trees are built again, this Graph is left as it is */
+    let reordered = match self.reorder_on_persist {
+      Some(order) => {
+        let (new_ids, triples) = self.reordered_triples(order);
+        Some((self.rebuilt_storage(self.num_predicate_ids(), triples)?, new_ids))
+      },
+      None => None,
+    };
+    let (storage, dict_max, dict_tombstones, dict): (&Storage, usize, &[usize], Vec<(&RdfNode, usize)>) = match &reordered {
+      Some((storage, new_ids)) => (
+        storage,
+        new_ids.len().saturating_sub(1),
+        &[],
+        self.dict.iter().map(|(node, old_id)| (node, new_ids[old_id])).collect()
+      ),
+      None => (
+        &self.storage,
+        self.dict_max,
+        &self.dict_tombstones,
+        self.dict.iter().map(|(node, &id)| (node, id)).collect()
+      ),
+    };
+    let head = BackupHead {
+      k: self.k,
+      storage: self.storage(),
+      dict_max: dict_max,
+      dict_tombstones: dict_tombstones,
+      dict: dict,
+      pred_tombstones: &self.pred_tombstones,
+      predicates: self.predicates.iter().map(|(predicate, &id)| (predicate, id)).collect(),
+      persist_location: &self.persist_location,
+    };
     /* Define locations to persist to */
     let root_dir = std::path::Path::new(path);
     let trees_dir = root_dir.join("trees");
@@ -687,21 +685,34 @@ impl Graph {
     }
     /* Create an serialise Graph to root/head.json */
     std::fs::File::create(&head_file)?;
-    std::fs::write(head_file, serde_json::to_string(self)?)?;
-    /* Serialise each K2Tree and save to a json file in root/trees/,
+    std::fs::write(head_file, serde_json::to_string(&head)?)?;
+    /* Serialise each K2Tree and save to a binary file in root/trees/,
     Name each K2Tree's file after it's corresponding's predicate's
     rhs value in self.predicates to aid reconstruction in future */
-    for (i, slice) in self.slices.iter().enumerate() {
-      if let Some(k2_tree) = slice {
-        let tree_file = trees_dir.join(format!("{}.json", i));
-        std::fs::File::create(&tree_file)?;
-        std::fs::write(tree_file, k2_tree.to_json()?)?;
-      }
+    match storage {
+      Storage::Interleaved(tree) => {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(trees_dir.join(INTERLEAVED_FILE))?);
+        tree.write_to(&mut writer)?;
+        std::io::Write::flush(&mut writer)?;
+      },
+      Storage::Ternary(tree) => {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(trees_dir.join(TERNARY_FILE))?);
+        tree.write_to(&mut writer)?;
+        std::io::Write::flush(&mut writer)?;
+      },
+      Storage::Slices(slices) => for (i, slice) in slices.iter().enumerate() {
+        if let Some(k2_tree) = slice {
+          let tree_file = trees_dir.join(format!("{}.k2t", i));
+          let mut writer = std::io::BufWriter::new(std::fs::File::create(&tree_file)?);
+          k2_tree.write_to(&mut writer)?;
+          std::io::Write::flush(&mut writer)?;
+        }
+      },
     }
     Ok(())
   }
   pub fn iter(&self) -> Iter {
-    let iter = match &self.slices.get(0) {
+    let iter = match &self.slices().get(0) {
       Some(Some(slice)) => Some(slice.leaves()),
       _ => None,
     };
@@ -709,17 +720,36 @@ impl Graph {
       graph: self,
       slice: 0,
       slice_iter: iter,
+      interleaved: self.interleaved().map(|tree| tree.iter()),
+      ternary: self.ternary().map(|tree| tree.iter()),
     }
   }
   pub fn into_iter(self) -> IntoIter {
-    let iter = match &self.slices.get(0) {
+    let iter = match &self.slices().get(0) {
       Some(Some(slice)) => Some(slice.clone().into_leaves()),
       _ => None,
     };
+    let interleaved = self.interleaved().map(|tree| tree.iter().collect::<Vec<_>>().into_iter());
+    let ternary = self.ternary().map(|tree| tree.iter().collect::<Vec<_>>().into_iter());
     IntoIter {
       graph: self,
       slice: 0,
       slice_iter: iter,
+      interleaved: interleaved,
+      ternary: ternary,
+    }
+  }
+  pub fn par_iter(&self) -> ParIter {
+    let parts = self.slices()
+      .iter()
+      .enumerate()
+      .filter_map(|(i, slice)| slice.as_ref().map(|slice| (i, slice.par_cells())))
+      .collect();
+    ParIter {
+      graph: self,
+      parts: parts,
+      interleaved: self.interleaved().map(|tree| tree.iter()),
+      ternary: self.ternary().map(|tree| tree.iter()),
     }
   }
   pub fn to_rdf(&self) -> Result<Vec<u8>> {
@@ -730,17 +760,159 @@ impl Graph {
   }
 }
 
-/* Iterators */
+/* Statistics */
+/* K2TreeStats for every predicate's slice, in slice order,
+and the same figures across all of them */
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphStats {
+  pub predicates: Vec<(RdfNode, K2TreeStats)>,
+  pub total_bits: usize,
+  pub cells_set: usize,
+  pub bits_per_cell: f64,
+  pub dense_ratio: f64,
+  pub coordinate_ratio: f64,
+}
+impl GraphStats {
+  fn new(predicates: Vec<(RdfNode, K2TreeStats)>) -> Self {
+    let total_bits = predicates.iter().fold(0, |bits, (_, stats)| bits + stats.total_bits);
+    let cells_set = predicates.iter().fold(0, |cells, (_, stats)| cells + stats.cells_set);
+    let dense_bits = predicates.iter().fold(0, |bits, (_, stats)| bits + stats.matrix_cells);
+    let ratio = |bits: f64| if total_bits == 0 { 0.0 } else { bits / total_bits as f64 };
+    GraphStats {
+      predicates: predicates,
+      total_bits: total_bits,
+      cells_set: cells_set,
+      bits_per_cell: if cells_set == 0 { 0.0 } else { total_bits as f64 / cells_set as f64 },
+      dense_ratio: ratio(dense_bits as f64),
+      coordinate_ratio: ratio((cells_set * k2_tree::COORDINATE_BITS) as f64),
+    }
+  }
+  fn stored_in(self, total_bits: usize) -> Self {
+    /* The same figures for the same cells taking total_bits */
+    let ratio = |bits: f64| if total_bits == 0 { 0.0 } else { bits / total_bits as f64 };
+    GraphStats {
+      total_bits: total_bits,
+      bits_per_cell: if self.cells_set == 0 { 0.0 } else { total_bits as f64 / self.cells_set as f64 },
+      dense_ratio: ratio(self.dense_ratio * self.total_bits as f64),
+      coordinate_ratio: ratio((self.cells_set * k2_tree::COORDINATE_BITS) as f64),
+      ..self
+    }
+  }
+}
+
+/* Read-only */
+/* A backup whose trees are memory mapped rather than read into memory,
+queries run through a GraphView over the mapped trees.
+An interleaved or ternary backup's single tree is read into memory instead.
+The backup must not be persisted to while it's mapped */
+#[derive(Debug)]
+pub struct MappedGraph {
+  head: Graph,
+  maps: Vec<Option<(memmap::Mmap, K2TreeIndex)>>, //checked and indexed once, on open
+}
+impl MappedGraph {
+  pub fn open(path: &str) -> Result<Self> {
+    let head = read_backup_head(path)?;
+    let trees_dir = std::path::Path::new(path).join("trees");
+    let tree = match head.storage() {
+      GraphStorage::Interleaved => Some(Storage::Interleaved(read_interleaved(&trees_dir)?)),
+      GraphStorage::Ternary => Some(Storage::Ternary(read_ternary(&trees_dir)?)),
+      GraphStorage::Slices => None,
+    };
+    if let Some(storage) = tree {
+      return Ok(MappedGraph {
+        head: Graph {
+          storage: storage,
+          ..head
+        },
+        maps: Vec::new(),
+      })
+    }
+    let mut maps: Vec<Option<(memmap::Mmap, K2TreeIndex)>> = Vec::new();
+    for i in 0.. {
+      if let Some(_) = head.predicates.get_by_right(&i) {
+        /* Only trees in the binary format can be read in place */
+        let tree_file = trees_dir.join(format!("{}.k2t", i));
+        if !tree_file.is_file() {
+          return Err(Error::InvalidBackup(format!("root/trees/{}.k2t", i), std::path::PathBuf::from(path)))
+        }
+        let file = std::fs::File::open(&tree_file)?;
+        let map = unsafe { memmap::Mmap::map(&file)? };
+        let index = K2TreeIndex::new(&map[..])?;
+        maps.push(Some((map, index)));
+      }
+      else if head.pred_tombstones.contains(&i) {
+        maps.push(None);
+      }
+      else {
+        break
+      }
+    }
+    Ok(MappedGraph {
+      head: head,
+      maps: maps,
+    })
+  }
+  pub fn view(&self) -> Result<GraphView> {
+    /* Only borrows the maps and their indexes, so views are cheap */
+    let mut slices: Vec<Option<K2TreeView>> = Vec::new();
+    for map in self.maps.iter() {
+      match map {
+        Some((map, index)) => slices.push(Some(K2TreeView::with_index(&map[..], index)?)),
+        None => slices.push(None),
+      }
+    }
+    Ok(GraphView {
+      head: &self.head,
+      slices: slices,
+    })
+  }
+}
+#[derive(Debug, Clone)]
+pub struct GraphView<'a> {
+  head: &'a Graph, //Everything but the slices
+  slices: Vec<Option<K2TreeView<'a>>>,
+}
+impl<'a> GraphView<'a> {
+  pub fn get(&self, query: &Sparql) -> Vec<RdfNode> {
+    self.query(query)
+  }
+  pub fn k(&self) -> usize {
+    self.head.k
+  }
+  pub fn to_graph(&self) -> Graph {
+    /* The same Graph as Graph::from_backup would build */
+    if self.head.storage() != GraphStorage::Slices {
+      return self.head.clone()
+    }
+    Graph {
+      storage: Storage::Slices(self.slices.iter().map(|slice|
+        slice.as_ref().map(|slice| Box::new(slice.to_k2tree()))
+      ).collect()),
+      ..self.head.clone()
+    }
+  }
+}
+
+/* Iterators */
 pub struct Iter<'a> {
   graph: &'a Graph,
   slice: usize,
   slice_iter: Option<k2_tree::Leaves<'a>>,
+  interleaved: Option<ik2_tree::Cells<'a>>,
+  ternary: Option<k3_tree::Cells<'a>>,
 }
 impl<'a> Iterator for Iter<'a> {
   type Item = RdfTriple;
   fn next(&mut self) -> Option<Self::Item> {
+    if let Some(cells) = &mut self.interleaved {
+      return cells.next().map(|cell| self.graph.cell_to_triple(cell))
+    }
+    if let Some(cells) = &mut self.ternary {
+      return cells.next().map(|[x, predicate, y]| self.graph.cell_to_triple([x, y, predicate]))
+    }
 
-    if self.slice == self.graph.slices.len() { return None }
+    if self.slice == self.graph.slices().len() { return None }
 
     loop {
       let leaf = match &mut self.slice_iter {
@@ -764,11 +936,11 @@ impl<'a> Iterator for Iter<'a> {
         },
         None => {
           self.slice += 1;
-          while let Some(None) = self.graph.slices.get(self.slice) {
+          while let Some(None) = self.graph.slices().get(self.slice) {
             self.slice += 1;
           }
-          if self.slice == self.graph.slices.len() { return None }
-          if let Some(slice) = &self.graph.slices[self.slice] {
+          if self.slice == self.graph.slices().len() { return None }
+          if let Some(slice) = &self.graph.slices()[self.slice] {
             self.slice_iter = Some(slice.leaves());
           }
         },
@@ -779,13 +951,21 @@ impl<'a> Iterator for Iter<'a> {
 pub struct IntoIter {
   graph: Graph,
   slice: usize,
-  slice_iter: Option<k2_tree::IntoLeaves>
+  slice_iter: Option<k2_tree::IntoLeaves>,
+  interleaved: Option<std::vec::IntoIter<[usize; 3]>>,
+  ternary: Option<std::vec::IntoIter<[usize; 3]>>,
 }
 impl Iterator for IntoIter {
   type Item = RdfTriple;
   fn next(&mut self) -> Option<Self::Item> {
+    if let Some(cells) = &mut self.interleaved {
+      return cells.next().map(|cell| self.graph.cell_to_triple(cell))
+    }
+    if let Some(cells) = &mut self.ternary {
+      return cells.next().map(|[x, predicate, y]| self.graph.cell_to_triple([x, y, predicate]))
+    }
     
-    if self.slice == self.graph.slices.len() { return None }
+    if self.slice == self.graph.slices().len() { return None }
 
     loop {
       let leaf = match &mut self.slice_iter {
@@ -809,12 +989,12 @@ impl Iterator for IntoIter {
         },
         None => {
           self.slice += 1;
-          while let Some(None) = self.graph.slices.get(self.slice) {
+          while let Some(None) = self.graph.slices().get(self.slice) {
             self.slice += 1;
           }
-          if self.slice == self.graph.slices.len() { return None }
+          if self.slice == self.graph.slices().len() { return None }
           let slice_num  = self.slice.clone();
-          if let Some(slice) = &self.graph.slices[slice_num] {
+          if let Some(slice) = &self.graph.slices()[slice_num] {
             self.slice_iter = Some(slice.clone().into_leaves());
           }
         },
@@ -822,11 +1002,434 @@ impl Iterator for IntoIter {
     }
   }
 }
+/* Splittable iterator over every triple for whole-store scans on
+several threads, split by slice first and then within slices */
+pub struct ParIter<'a> {
+  graph: &'a Graph,
+  parts: Vec<(usize, k2_tree::ParCells<'a>)>, //slice index, cells
+  interleaved: Option<ik2_tree::Cells<'a>>,
+  ternary: Option<k3_tree::Cells<'a>>,
+}
+impl<'a> ParIter<'a> {
+  pub fn split(&mut self) -> Option<Self> {
+    if let Some(cells) = &mut self.interleaved {
+      return Some(ParIter {
+        graph: self.graph,
+        parts: Vec::new(),
+        interleaved: Some(cells.split()?),
+        ternary: None,
+      })
+    }
+    if let Some(cells) = &mut self.ternary {
+      return Some(ParIter {
+        graph: self.graph,
+        parts: Vec::new(),
+        interleaved: None,
+        ternary: Some(cells.split()?),
+      })
+    }
+    let later = match self.parts.len() {
+      0 => return None,
+      1 => {
+        let (slice, cells) = &mut self.parts[0];
+        vec![(*slice, cells.split()?)]
+      },
+      num_parts => self.parts.split_off(num_parts/2),
+    };
+    Some(ParIter {
+      graph: self.graph,
+      parts: later,
+      interleaved: None,
+      ternary: None,
+    })
+  }
+  pub fn into_parts(self, max_parts: usize) -> Vec<Self> {
+    k2_tree::split_parts(self, max_parts, ParIter::split)
+  }
+  pub fn for_each<F: Fn(RdfTriple) + Sync>(self, f: F) {
+    k2_tree::run_parts(self.into_parts(num_cpus::get() * 4), |part| part.triples().for_each(&f));
+  }
+  pub fn map_collect<T: Send, F: Fn(RdfTriple) -> T + Sync>(self, f: F) -> Vec<T> {
+    /* Results are in slice order */
+    k2_tree::run_parts(self.into_parts(num_cpus::get() * 4), |part| part.triples().map(&f).collect::<Vec<T>>())
+      .into_iter()
+      .flatten()
+      .collect()
+  }
+  pub fn count(self) -> usize {
+    /* Doesn't need to look anything up in the dictionaries */
+    k2_tree::run_parts(self.into_parts(num_cpus::get() * 4), |part| {
+      part.parts.into_iter().fold(0, |count, (_, cells)| count + cells.into_iter().count())
+      + part.interleaved.map_or(0, |cells| cells.count())
+      + part.ternary.map_or(0, |cells| cells.count())
+    })
+      .into_iter()
+      .sum()
+  }
+  fn triples(self) -> impl Iterator<Item=RdfTriple> + 'a {
+    let graph = self.graph;
+    self.parts.into_iter().flat_map(move |(slice, cells)| {
+      let predicate = graph.predicates.get_by_right(&slice).unwrap();
+      cells.into_iter().map(move |[x, y]| [
+        graph.dict.get_by_right(&x).unwrap().clone(),
+        predicate.clone(),
+        graph.dict.get_by_right(&y).unwrap().clone()
+      ])
+    })
+      .chain(self.interleaved.into_iter().flatten().map(move |cell| graph.cell_to_triple(cell)))
+      .chain(self.ternary.into_iter().flatten().map(move |[x, predicate, y]| graph.cell_to_triple([x, y, predicate])))
+  }
+}
 
 /* Std Traits */
 
 /* Private */
 impl Graph {
+  fn allocate_triple(&mut self, [subject, predicate, object]: &RdfTriple) -> (usize, usize, usize) {
+    /* The column, row and slice of a triple about to be inserted,
+    giving its nodes and predicate ids (and a slice) if they're new */
+    let col = self.node_id(subject);
+    let row = self.node_id(object);
+    let slice_pos = match self.predicates.get_by_left(predicate) {
+      Some(&slice_pos) => slice_pos,
+      None => {
+        let slice = Some(Box::new(K2Tree::with_no_extents(self.k)));
+        let slice_pos = match (&mut self.storage, self.pred_tombstones.pop()) {
+          (Storage::Interleaved(_), Some(slice_pos)) => slice_pos,
+          (Storage::Interleaved(tree), None) => tree.add_predicate(),
+          /* Without tombstones the predicates are 0 to len-1, the cube
+          grows to fit the next when its triple is inserted */
+          (Storage::Ternary(_), Some(slice_pos)) => slice_pos,
+          (Storage::Ternary(_), None) => self.predicates.len(),
+          (Storage::Slices(slices), Some(slice_pos)) => {
+            slices[slice_pos] = slice;
+            slice_pos
+          },
+          (Storage::Slices(slices), None) => {
+            slices.push(slice);
+            slices.len()-1
+          },
+        };
+        self.predicates.insert(predicate.clone(), slice_pos);
+        slice_pos
+      },
+    };
+    (col, row, slice_pos)
+  }
+  fn node_id(&mut self, node: &RdfNode) -> usize {
+    if let Some(&id) = self.dict.get_by_left(node) {
+      return id
+    }
+    let id = match self.dict_tombstones.pop() {
+      Some(id) => id,
+      None if self.dict.is_empty() => 0,
+      None => self.dict_max + 1,
+    };
+    if id > self.dict_max || self.dict.is_empty() { self.dict_max = id; }
+    self.dict.insert(node.clone(), id);
+    id
+  }
+  fn drop_slice_if_empty(&mut self, slice_pos: usize) {
+    let slices = match &mut self.storage {
+      Storage::Interleaved(tree) => {
+        if !self.predicates.contains_right(&slice_pos)
+        || !tree.predicate_is_empty(slice_pos) {
+          return
+        }
+        self.predicates.remove_by_right(&slice_pos);
+        self.pred_tombstones.push(slice_pos);
+        /* Trailing predicates are dropped rather than left as tombstones */
+        while tree.num_predicates() > 0 && self.pred_tombstones.contains(&(tree.num_predicates()-1)) {
+          let last = tree.num_predicates()-1;
+          if tree.pop_predicate().is_err() { break }
+          self.pred_tombstones.retain(|&tombstone| tombstone != last);
+        }
+        return
+      },
+      Storage::Ternary(tree) => {
+        if !self.predicates.contains_right(&slice_pos)
+        || tree.iter_range(0..tree.width(), slice_pos..slice_pos+1, 0..tree.depth()).next().is_some() {
+          return
+        }
+        self.predicates.remove_by_right(&slice_pos);
+        self.pred_tombstones.push(slice_pos);
+        /* Trailing predicates are dropped by shrinking the cube's height */
+        while tree.height() > 0 && self.pred_tombstones.contains(&(tree.height()-1)) {
+          let last = tree.height()-1;
+          if tree.resize(tree.width(), last, tree.depth()).is_err() { break }
+          self.pred_tombstones.retain(|&tombstone| tombstone != last);
+        }
+        return
+      },
+      Storage::Slices(slices) => slices,
+    };
+    match slices.get(slice_pos) {
+      Some(Some(slice)) if slice.is_empty() => {},
+      _ => return,
+    }
+    self.predicates.remove_by_right(&slice_pos);
+    if slice_pos == slices.len()-1 {
+      /* Trailing slices are dropped rather than left as tombstones,
+      along with any tombstones they uncover */
+      slices.pop();
+      while let Some(None) = slices.last() {
+        slices.pop();
+      }
+      let num_slices = slices.len();
+      self.pred_tombstones.retain(|&tombstone| tombstone < num_slices);
+    }
+    else {
+      self.pred_tombstones.push(slice_pos);
+      slices[slice_pos] = None;
+    }
+  }
+  fn drop_node_if_unused(&mut self, node: &RdfNode) {
+    let node_pos = match self.dict.get_by_left(node) {
+      Some(&node_pos) => node_pos,
+      None => return,
+    };
+    let used = match &self.storage {
+      Storage::Interleaved(tree) => tree.successors(node_pos).next().is_some()
+        || tree.predecessors(node_pos).next().is_some(),
+      Storage::Ternary(tree) => tree.iter_range(node_pos..node_pos+1, 0..tree.height(), 0..tree.depth()).next().is_some()
+        || tree.iter_range(0..tree.width(), 0..tree.height(), node_pos..node_pos+1).next().is_some(),
+      Storage::Slices(slices) => slices.iter().any(|slice| match slice {
+        Some(slice) => slice.predecessors(node_pos).next().is_some()
+          || slice.successors(node_pos).next().is_some(),
+        None => false,
+      }),
+    };
+    if used { return }
+    self.dict.remove_by_left(node);
+    if node_pos == self.dict_max {
+      /* Find next highest valid dict_max,
+      remove all newly-invalid tombstones greater than new dict_max */
+      while self.dict_max > 0 && !self.dict.contains_right(&self.dict_max) {
+        self.dict_max -= 1;
+      }
+      let dict_max = self.dict_max;
+      self.dict_tombstones.retain(|&tombstone| tombstone < dict_max);
+    }
+    else {
+      self.dict_tombstones.push(node_pos);
+    }
+  }
+  fn ordered_ids(&self, order: IdOrder, triples: &[[usize; 3]]) -> Vec<usize> {
+    /* Every node's id, in the order they're to be renumbered */
+    let mut degrees: std::collections::BTreeMap<usize, usize> = self.dict.right_values().map(|&id| (id, 0)).collect();
+    for &[x, _, y] in triples {
+      *degrees.entry(x).or_insert(0) += 1;
+      *degrees.entry(y).or_insert(0) += 1;
+    }
+    let mut by_degree: Vec<usize> = degrees.keys().cloned().collect();
+    by_degree.sort_by_key(|id| std::cmp::Reverse(degrees[id]));
+    match order {
+      IdOrder::Degree => by_degree,
+      IdOrder::Namespace => self.dict.iter().map(|(_, &id)| id).collect(),
+      IdOrder::Bfs => {
+        let mut neighbours: std::collections::BTreeMap<usize, Vec<usize>> = std::collections::BTreeMap::new();
+        for &[x, _, y] in triples {
+          neighbours.entry(x).or_insert_with(Vec::new).push(y);
+          neighbours.entry(y).or_insert_with(Vec::new).push(x);
+        }
+        for ids in neighbours.values_mut() {
+          ids.sort();
+          ids.dedup();
+        }
+        let mut visited: std::collections::BTreeSet<usize> = std::collections::BTreeSet::new();
+        let mut ids = Vec::with_capacity(by_degree.len());
+        for &start in by_degree.iter() {
+          if !visited.insert(start) { continue }
+          let mut queue = std::collections::VecDeque::new();
+          queue.push_back(start);
+          while let Some(id) = queue.pop_front() {
+            ids.push(id);
+            for &neighbour in neighbours.get(&id).into_iter().flatten() {
+              if visited.insert(neighbour) {
+                queue.push_back(neighbour);
+              }
+            }
+          }
+        }
+        ids
+      },
+    }
+  }
+  fn reordered_triples(&self, order: IdOrder) -> (std::collections::BTreeMap<usize, usize>, Vec<[usize; 3]>) {
+    /* Every node's new id by its old one, in the given order from 0
+    with no tombstones, and every triple under the new ids */
+    let triples = self.get_from_triple([None, None, None]);
+    let new_ids: std::collections::BTreeMap<usize, usize> = self.ordered_ids(order, &triples)
+      .into_iter()
+      .enumerate()
+      .map(|(new_id, old_id)| (old_id, new_id))
+      .collect();
+    let triples = triples.into_iter()
+      .map(|[x, predicate, y]| [new_ids[&x], predicate, new_ids[&y]])
+      .collect();
+    (new_ids, triples)
+  }
+  fn rebuilt_storage(&self, num_predicates: usize, triples: Vec<[usize; 3]>) -> Result<Storage> {
+    /* Every tree built again from triples of [x, predicate, y], each
+    with the smallest extents that hold its triples. Slices keep their
+    leaf encodings, and removed predicates stay None */
+    let k = self.k;
+    let extents = |triples: &[[usize; 3]]| triples.iter()
+      .fold((0, 0), |(width, height), &[x, _, y]| (std::cmp::max(width, x+1), std::cmp::max(height, y+1)));
+    let slices = match &self.storage {
+      Storage::Interleaved(_) => {
+        let (width, height) = extents(&triples);
+        let triples = triples.into_iter().map(|[x, predicate, y]| [x, y, predicate]);
+        return Ok(Storage::Interleaved(IK2Tree::from_triples(k, width, height, num_predicates, triples)?))
+      },
+      Storage::Ternary(_) => {
+        let (width, depth) = extents(&triples);
+        return Ok(Storage::Ternary(K3Tree::from_cells(k, width, num_predicates, depth, triples)?))
+      },
+      Storage::Slices(slices) => slices,
+    };
+    let mut slice_triples: Vec<Vec<[usize; 3]>> = vec![Vec::new(); slices.len()];
+    for triple in triples {
+      slice_triples[triple[1]].push(triple);
+    }
+    let mut rebuilt: Vec<Option<Box<K2Tree>>> = Vec::with_capacity(slices.len());
+    for (slice, triples) in slices.iter().zip(slice_triples.into_iter()) {
+      rebuilt.push(match slice {
+        Some(slice) => {
+          let (width, height) = extents(&triples);
+          let coords = triples.into_iter().map(|[x, _, y]| (x, y));
+          Some(Box::new(K2Tree::from_coordinates_with_extents(k, width, height, coords)?.with_leaf_encoding(slice.leaf_encoding())))
+        },
+        None => None,
+      });
+    }
+    Ok(Storage::Slices(rebuilt))
+  }
+  fn num_predicate_ids(&self) -> usize {
+    /* Live predicates and tombstones */
+    match &self.storage {
+      Storage::Interleaved(tree) => tree.num_predicates(),
+      Storage::Ternary(tree) => tree.height(),
+      Storage::Slices(slices) => slices.len(),
+    }
+  }
+  fn heapsize(&self) -> usize {
+    /* Bytes held by the trees, slices and tombstones, not the dictionaries */
+    let trees = match &self.storage {
+      Storage::Interleaved(tree) => tree.heapsize(),
+      Storage::Ternary(tree) => tree.heapsize(),
+      Storage::Slices(slices) => slices.iter().fold(0, |size, slice| match slice {
+        Some(slice) => size + slice.heapsize(),
+        None => size,
+      }),
+    };
+    trees
+    + self.slices().len() * std::mem::size_of::<Option<Box<K2Tree>>>()
+    + (self.dict_tombstones.len() + self.pred_tombstones.len()) * std::mem::size_of::<usize>()
+  }
+  fn slices(&self) -> &[Option<Box<K2Tree>>] {
+    /* Empty unless the triples are stored in slices */
+    match &self.storage {
+      Storage::Slices(slices) => slices,
+      _ => &[],
+    }
+  }
+  fn cell_to_triple(&self, [x, y, predicate]: [usize; 3]) -> RdfTriple {
+    [self.dict.get_by_right(&x).unwrap().clone(),
+    self.predicates.get_by_right(&predicate).unwrap().clone(),
+    self.dict.get_by_right(&y).unwrap().clone()]
+  }
+}
+/* Queries shared by Graph and GraphView, which differ only
+in whether their slices are owned or read in place */
+trait TripleStore {
+  fn dict(&self) -> &BiBTreeMap<RdfNode, usize>;
+  fn predicates(&self) -> &BiBTreeMap<RdfNode, usize>;
+  fn slice(&self, index: usize) -> Option<K2TreeView>;
+  fn slice_views(&self) -> Vec<Option<K2TreeView>>;
+  fn interleaved(&self) -> Option<&IK2Tree>;
+  fn ternary(&self) -> Option<&K3Tree>;
+  fn query(&self, query: &Sparql) -> Vec<RdfNode> {
+    /* Assume only one variable */
+    use std::collections::HashSet;
+    use QueryUnit::{Var, Val};
+    /* Util closures for later */
+    let cond_to_qt = |cond: &[QueryUnit; 3]| {
+      let mut qt: [Option<String>; 3] = [None, None, None];
+      if let Val(s) = &cond[0] { qt[0] = Some(s.to_string()); }
+      if let Val(p) = &cond[1] { qt[1] = Some(p.to_string()); }
+      if let Val(o) = &cond[2] { qt[2] = Some(o.to_string()); }
+      qt
+    };
+    let var_pos = |cond: &[QueryUnit; 3]| {
+      match cond {
+        [Var(_), _, _] => 0,
+        [_, Var(_), _] => 1,
+        [_, _, Var(_)] => 2,
+        _ => 3
+      }
+    };
+    /* Gather raw results from each condition to filter later */
+    let mut results: Vec<(&[QueryUnit; 3], Vec<[usize; 3]>)> = Vec::new();
+    for cond in query.conds.iter() {
+      let qt = cond_to_qt(cond);
+      let res = self.get_from_triple(qt);
+      results.push((cond, res));
+    }
+    /* Filter results */
+    let mut final_results: Vec<usize> = results[0].1.iter().map(|[s, p, o]|
+      match var_pos(results[0].0) {
+        0 => s.clone(),
+        1 => p.clone(),
+        2 => o.clone(),
+        _ => std::usize::MAX,
+      }
+    ).collect();
+    for (query_triple, qt_results) in &results[1..] {
+      let qt_var_pos = var_pos(query_triple);
+      let mut used_vars_vals: HashSet<usize> = HashSet::new();
+      let mut vars_vals_to_remove: Vec<usize> = Vec::new();
+      for (i, final_result) in final_results.iter().enumerate() {
+        if !used_vars_vals.contains(final_result) {
+          used_vars_vals.insert(final_result.clone());
+          let filter_t = match qt_var_pos {
+            0 => [Some(final_result.clone()), None, None],
+            1 => [None, Some(final_result.clone()), None],
+            2 => [None, None, Some(final_result.clone())],
+            _ => [None, None, None],
+          };
+          if self.filter_triples(qt_results.clone(), filter_t).is_empty() {
+            /* There was no match for this value of the variable from final_results in
+            the query triple, so mark it to be removed at the end of this cycle */
+            vars_vals_to_remove.push(i);
+          }
+        }
+      }
+      final_results = final_results.into_iter().enumerate().filter_map(|(i, res)| {
+        if !vars_vals_to_remove.is_empty() {
+          if i == vars_vals_to_remove[0] {
+            vars_vals_to_remove.remove(0);
+            None
+          }
+          else {
+            Some(res)
+          }
+        }
+        else {
+          Some(res)
+        }
+      }).collect();
+    }
+    let ret: Vec<RdfNode> = final_results.into_iter().map(|n| {
+      if var_pos(results[0].0) == 1 {
+        self.predicates().get_by_right(&n).unwrap().clone()
+      }
+      else {
+        self.dict().get_by_right(&n).unwrap().clone()
+      }
+    }).collect();
+    ret
+  }
   fn filter_triples(&self, triples: Vec<[usize; 3]>, pattern: [Option<usize>; 3]) -> Vec<[usize; 3]> {
     triples.into_iter().filter(|[s, p, o]| {
       match &pattern {
@@ -843,6 +1446,9 @@ impl Graph {
   }
   /* Return the triples in the compact form of their dict index */
   fn get_from_triple(&self, triple: [Option<String>; 3]) -> Vec<[usize; 3]> {
+    if let Some(tree) = self.ternary() {
+      return self.ternary_triples(tree, triple)
+    }
     match triple {
       [Some(s), Some(p), Some(o)] => self.spo(&s, &p, &o),
       [None, Some(p), Some(o)]    => self._po(&p, &o),
@@ -854,12 +1460,33 @@ impl Graph {
       [None, None, None]          => self.___(),
     }
   }
+  fn ternary_triples(&self, tree: &K3Tree, [s, p, o]: [Option<String>; 3]) -> Vec<[usize; 3]> {
+    /* Every pattern is a range of the cube, a whole axis where it's unbound */
+    let axis = |id: Option<Option<&usize>>, len: usize| match id {
+      Some(Some(&id)) => Some(id..id+1),
+      Some(None) => None,
+      None => Some(0..len),
+    };
+    let x_range = axis(s.map(|s| self.dict().get_by_left(&to_named_node(&s))), tree.width());
+    let y_range = axis(p.map(|p| self.predicates().get_by_left(&to_named_node(&p))), tree.height());
+    let z_range = axis(o.map(|o| self.dict().get_by_left(&to_named_node(&o))), tree.depth());
+    match (x_range, y_range, z_range) {
+      (Some(x_range), Some(y_range), Some(z_range)) => tree.iter_range(x_range, y_range, z_range).collect(),
+      _ => Vec::new(),
+    }
+  }
   fn spo(&self, s: &str, p: &str, o: &str) -> Vec<[usize; 3]> {
-    match [self.dict.get_by_left(&RdfNode::Named{iri:s.to_string()}),
-      self.dict.get_by_left(&to_named_node(o)),
-      self.predicates.get_by_left(&to_named_node(p))] {
+    match [self.dict().get_by_left(&RdfNode::Named{iri:s.to_string()}),
+      self.dict().get_by_left(&to_named_node(o)),
+      self.predicates().get_by_left(&to_named_node(p))] {
         [Some(&x), Some(&y), Some(&slice_index)] => {
-          if let Some(slice) = &self.slices[slice_index] {
+          if let Some(tree) = self.interleaved() {
+            return match tree.get(x, y, slice_index) {
+              Ok(b) if b => vec![[x, slice_index, y]],
+              _ => Vec::new(),
+            }
+          }
+          if let Some(slice) = self.slice(slice_index) {
             match slice.get(x, y) {
               Ok(b) if b => vec![[x, slice_index, y]],
               _ => Vec::new(),
@@ -873,17 +1500,18 @@ impl Graph {
     }
   }
   fn _po(&self, p: &str, o: &str) -> Vec<[usize; 3]> {
-    match [self.dict.get_by_left(&to_named_node(o)),
-      self.predicates.get_by_left(&to_named_node(p))] {
+    match [self.dict().get_by_left(&to_named_node(o)),
+      self.predicates().get_by_left(&to_named_node(p))] {
         [Some(&y), Some(&slice_index)] => {
-          if let Some(slice) = &self.slices[slice_index] {
-            match slice.get_row(y) {
-              Ok(bitvec) => one_positions(&bitvec)
-                .into_iter()
-                .map(|pos| [pos, slice_index, y])
-                .collect(),
-              _ => Vec::new(),
-            }
+          if let Some(tree) = self.interleaved() {
+            return tree.iter_range(0..tree.matrix_width(), y..y+1, Some(slice_index))
+              .map(|[x, y, p]| [x, p, y])
+              .collect()
+          }
+          if let Some(slice) = self.slice(slice_index) {
+            slice.predecessors(y)
+              .map(|pos| [pos, slice_index, y])
+              .collect()
           }
           else {
             Vec::new()
@@ -893,11 +1521,14 @@ impl Graph {
     }
   }
   fn s_o(&self, s: &str, o: &str) -> Vec<[usize; 3]> {
-    match [self.dict.get_by_left(&to_named_node(s)),
-      self.dict.get_by_left(&to_named_node(o))] {
+    match [self.dict().get_by_left(&to_named_node(s)),
+      self.dict().get_by_left(&to_named_node(o))] {
         [Some(&x), Some(&y)] => {
+          if let Some(tree) = self.interleaved() {
+            return tree.predicates_at(x, y).map(|p| [x, p, y]).collect()
+          }
           let mut triples: Vec<[usize; 3]> = Vec::new();
-          for (i, slice) in self.slices.iter().enumerate() {
+          for (i, slice) in self.slice_views().into_iter().enumerate() {
             if let Some(slice) = slice {
               match slice.get(x, y) {
                 Ok(b) if b => triples.push([x, i, y]),
@@ -911,17 +1542,18 @@ impl Graph {
     }
   }
   fn sp_(&self, s: &str, p: &str) -> Vec<[usize; 3]> {
-    match [self.dict.get_by_left(&to_named_node(s)),
-      self.predicates.get_by_left(&to_named_node(p))] {
+    match [self.dict().get_by_left(&to_named_node(s)),
+      self.predicates().get_by_left(&to_named_node(p))] {
         [Some(&x), Some(&slice_index)] => {
-          if let Some(slice) = &self.slices[slice_index] {
-            match slice.get_column(x) {
-              Ok(bitvec) => one_positions(&bitvec)
-                .into_iter()
-                .map(|pos| [x, slice_index, pos])
-                .collect(),
-              _ => Vec::new(),
-            }
+          if let Some(tree) = self.interleaved() {
+            return tree.iter_range(x..x+1, 0..tree.matrix_height(), Some(slice_index))
+              .map(|[x, y, p]| [x, p, y])
+              .collect()
+          }
+          if let Some(slice) = self.slice(slice_index) {
+            slice.successors(x)
+              .map(|pos| [x, slice_index, pos])
+              .collect()
           }
           else {
             Vec::new()
@@ -931,18 +1563,15 @@ impl Graph {
     }
   }
   fn __o(&self, o: &str) -> Vec<[usize; 3]> {
-    match self.dict.get_by_left(&to_named_node(o)) {
+    match self.dict().get_by_left(&to_named_node(o)) {
         Some(&y) => {
+          if let Some(tree) = self.interleaved() {
+            return tree.predecessors(y).map(|[x, y, p]| [x, p, y]).collect()
+          }
           let mut ret_v = Vec::new();
-          for (index, slice) in self.slices.iter().enumerate() {
+          for (index, slice) in self.slice_views().into_iter().enumerate() {
             if let Some(slice) = slice {
-              ret_v.append(&mut match slice.get_row(y) {
-                Ok(bitvec) => one_positions(&bitvec)
-                  .into_iter()
-                  .map(|pos| [pos, index, y])
-                  .collect(),
-                _ => Vec::new(),
-              });
+              ret_v.extend(slice.predecessors(y).map(|pos| [pos, index, y]));
             }
           }
           ret_v
@@ -951,20 +1580,17 @@ impl Graph {
     }
   }
   fn _p_(&self, p: &str) -> Vec<[usize; 3]> {
-    match self.predicates.get_by_left(&to_named_node(p)) {
+    match self.predicates().get_by_left(&to_named_node(p)) {
       Some(&slice_index) => {
-        if let Some(slice) = &self.slices[slice_index] {
-          let mut ret_v = Vec::new();
-          for x in 0..slice.matrix_width() {
-            ret_v.append(&mut match slice.get_column(x) {
-              Ok(bitvec) => one_positions(&bitvec)
-                .into_iter()
-                .map(|pos| [x, slice_index, pos])
-                .collect(),
-              _ => Vec::new(),
-            });
-          }
-          ret_v
+        if let Some(tree) = self.interleaved() {
+          return tree.iter_range(0..tree.matrix_width(), 0..tree.matrix_height(), Some(slice_index))
+            .map(|[x, y, p]| [x, p, y])
+            .collect()
+        }
+        if let Some(slice) = self.slice(slice_index) {
+          slice.iter_range(0..slice.matrix_width(), 0..slice.matrix_height())
+            .map(|[x, y]| [x, slice_index, y])
+            .collect()
         }
         else {
           Vec::new()
@@ -974,18 +1600,15 @@ impl Graph {
     }
   }
   fn s__(&self, s: &str) -> Vec<[usize; 3]> {
-    match self.dict.get_by_left(&to_named_node(s)) {
+    match self.dict().get_by_left(&to_named_node(s)) {
       Some(&x) => {
+        if let Some(tree) = self.interleaved() {
+          return tree.successors(x).map(|[x, y, p]| [x, p, y]).collect()
+        }
         let mut ret_v = Vec::new();
-        for (index, slice) in self.slices.iter().enumerate() {
+        for (index, slice) in self.slice_views().into_iter().enumerate() {
           if let Some(slice) = slice {
-            ret_v.append(&mut match slice.get_column(x) {
-              Ok(bitvec) => one_positions(&bitvec)
-                .into_iter()
-                .map(|pos| [x, index, pos])
-                .collect(),
-              _ => Vec::new(),
-            });
+            ret_v.extend(slice.successors(x).map(|pos| [x, index, pos]));
           }
         }
         ret_v
@@ -994,40 +1617,289 @@ impl Graph {
     }
   }
   fn ___(&self) -> Vec<[usize; 3]> {
+    if let Some(tree) = self.interleaved() {
+      return tree.iter().map(|[x, y, p]| [x, p, y]).collect()
+    }
     let mut ret_v = Vec::new();
-    for (index, slice) in self.slices.iter().enumerate() {
+    for (index, slice) in self.slice_views().into_iter().enumerate() {
       if let Some(slice) = slice {
-        for x in 0..slice.matrix_width() {
-          ret_v.append(&mut match slice.get_column(x) {
-            Ok(bitvec) => one_positions(&bitvec)
-              .into_iter()
-              .map(|pos| [x, index, pos])
-              .collect(),
-            _ => Vec::new(),
-          });
-        }
+        ret_v.extend(slice.iter_range(0..slice.matrix_width(), 0..slice.matrix_height())
+          .map(|[x, y]| [x, index, y]));
       }
     };
     ret_v
+  }}
+impl<'a> TripleStore for GraphView<'a> {
+  fn dict(&self) -> &BiBTreeMap<RdfNode, usize> {
+    &self.head.dict
+  }
+  fn predicates(&self) -> &BiBTreeMap<RdfNode, usize> {
+    &self.head.predicates
+  }
+  fn slice(&self, index: usize) -> Option<K2TreeView> {
+    self.slices[index].as_ref().map(|slice| slice.as_view())
+  }
+  fn slice_views(&self) -> Vec<Option<K2TreeView>> {
+    self.slices.iter().map(|slice| slice.as_ref().map(|slice| slice.as_view())).collect()
+  }
+  fn interleaved(&self) -> Option<&IK2Tree> {
+    self.head.interleaved()
+  }
+  fn ternary(&self) -> Option<&K3Tree> {
+    self.head.ternary()
+  }
+}
+impl TripleStore for Graph {
+  fn dict(&self) -> &BiBTreeMap<RdfNode, usize> {
+    &self.dict
+  }
+  fn predicates(&self) -> &BiBTreeMap<RdfNode, usize> {
+    &self.predicates
+  }
+  fn slice(&self, index: usize) -> Option<K2TreeView> {
+    self.slices()[index].as_ref().map(|slice| slice.as_view())
+  }
+  fn slice_views(&self) -> Vec<Option<K2TreeView>> {
+    self.slices().iter().map(|slice| slice.as_ref().map(|slice| slice.as_view())).collect()
+  }
+  fn interleaved(&self) -> Option<&IK2Tree> {
+    match &self.storage {
+      Storage::Interleaved(tree) => Some(tree),
+      _ => None,
+    }
+  }
+  fn ternary(&self) -> Option<&K3Tree> {
+    match &self.storage {
+      Storage::Ternary(tree) => Some(tree),
+      _ => None,
+    }
   }
 }
 
 /* Utils */
-fn to_named_node(s: &str) -> RdfNode {
-  RdfNode::Named{ iri: s.to_string() }
+fn extents_holding(cell: [usize; 3]) -> Result<[usize; 3]> {
+  /* The smallest [width, height, depth] with cell inside, ids at
+  usize::MAX would need an extent one past what a usize can hold */
+  match [cell[0].checked_add(1), cell[1].checked_add(1), cell[2].checked_add(1)] {
+    [Some(width), Some(height), Some(depth)] => Ok([width, height, depth]),
+    _ => Err(K2TreeError::CellOutOfBounds(cell, [usize::MAX-1; 3]).into()),
+  }
+}
+fn fit_slice(slice: &mut K2Tree, width: usize, height: usize) -> Result<()> {
+  /* Each slice only spans the subjects and objects it holds,
+  rather than the whole dict, so grow it to hold new ones */
+  if width > slice.matrix_width() || height > slice.matrix_height() {
+    let width = std::cmp::max(slice.matrix_width(), width);
+    let height = std::cmp::max(slice.matrix_height(), height);
+    slice.resize(width, height)?;
+  }
+  Ok(())
+}
+fn fit_interleaved(tree: &mut IK2Tree, width: usize, height: usize) -> Result<()> {
+  if width > tree.matrix_width() || height > tree.matrix_height() {
+    let width = std::cmp::max(tree.matrix_width(), width);
+    let height = std::cmp::max(tree.matrix_height(), height);
+    tree.resize(width, height)?;
+  }
+  Ok(())
+}
+fn fit_ternary(tree: &mut K3Tree, width: usize, height: usize, depth: usize) -> Result<()> {
+  if width > tree.width() || height > tree.height() || depth > tree.depth() {
+    let width = std::cmp::max(tree.width(), width);
+    let height = std::cmp::max(tree.height(), height);
+    let depth = std::cmp::max(tree.depth(), depth);
+    tree.resize(width, height, depth)?;
+  }
+  Ok(())
+}
+/* An interleaved or ternary Graph's backup holds
+its one tree in root/trees/ under these names */
+const INTERLEAVED_FILE: &str = "interleaved.ik2t";
+const TERNARY_FILE: &str = "ternary.k3t";
+fn read_interleaved(trees_dir: &std::path::Path) -> Result<IK2Tree> {
+  let tree_file = trees_dir.join(INTERLEAVED_FILE);
+  if !tree_file.is_file() {
+    return Err(Error::InvalidBackup(format!("root/trees/{}", INTERLEAVED_FILE), trees_dir.parent().unwrap_or(trees_dir).to_path_buf()))
+  }
+  Ok(IK2Tree::read_from(std::io::BufReader::new(std::fs::File::open(&tree_file)?))?)
+}
+fn read_ternary(trees_dir: &std::path::Path) -> Result<K3Tree> {
+  let tree_file = trees_dir.join(TERNARY_FILE);
+  if !tree_file.is_file() {
+    return Err(Error::InvalidBackup(format!("root/trees/{}", TERNARY_FILE), trees_dir.parent().unwrap_or(trees_dir).to_path_buf()))
+  }
+  Ok(K3Tree::read_from(std::io::BufReader::new(std::fs::File::open(&tree_file)?))?)
+}
+fn read_backup_head(path: &str) -> Result<Graph> {
+  /* Checks the backup's layout and builds the surface level of its Graph
+  from root/head.json, leaving the slices to be loaded by the caller */
+  /* Private trait impl */
+  impl<'de> Deserialize<'de> for Graph {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+      #[derive(Deserialize)]
+      #[serde(field_identifier, rename_all = "camelCase")]
+      enum Field {
+        K,
+        Storage,
+        DictMax,
+        DictTombstones,
+        Dict,
+        PredTombstones,
+        Predicates,
+        PersistLocation
+      }
+      struct GraphVisitor;
+      impl<'de> Visitor<'de> for GraphVisitor {
+        type Value = Graph;
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+          formatter.write_str("struct Graph")
+        }
+        fn visit_map<V: MapAccess<'de>>(self, mut map: V) -> std::result::Result<Graph, V::Error> {
+          let mut k = None;
+          let mut storage = None;
+          let mut dict_max = None;
+          let mut dict_tombstones = None;
+          let mut dict = None;
+          let mut pred_tombstones = None;
+          let mut predicates = None;
+          let mut persist_location = None;
+          while let Some(key) = map.next_key()? {
+            match key {
+              Field::K => {
+                if k.is_some() {
+                    return Err(de::Error::duplicate_field("k"));
+                }
+                k = Some(map.next_value()?);
+              }
+              Field::Storage => {
+                if storage.is_some() {
+                  return Err(de::Error::duplicate_field("storage"));
+                }
+                storage = Some(map.next_value::<GraphStorage>()?);
+              }
+              Field::DictMax => {
+                if dict_max.is_some() {
+                    return Err(de::Error::duplicate_field("dictMax"));
+                }
+                dict_max = Some(map.next_value()?);
+              }
+              Field::DictTombstones => {
+                if dict_tombstones.is_some() {
+                  return Err(de::Error::duplicate_field("dictTombstones"));
+                }
+                dict_tombstones = Some(map.next_value()?);
+              }
+              Field::Dict => {
+                if dict.is_some() {
+                  return Err(de::Error::duplicate_field("dict"));
+                }
+                dict = Some(map.next_value::<Vec<(RdfNode, usize)>>()?);
+              }
+              Field::PredTombstones => {
+                if pred_tombstones.is_some() {
+                    return Err(de::Error::duplicate_field("predTombstones"));
+                }
+                pred_tombstones = Some(map.next_value()?);
+              }
+              Field::Predicates => {
+                if predicates.is_some() {
+                  return Err(de::Error::duplicate_field("predicates"));
+                }
+                predicates = Some(map.next_value::<Vec<(RdfNode, usize)>>()?);
+              }
+              Field::PersistLocation => {
+                if persist_location.is_some() {
+                  return Err(de::Error::duplicate_field("persistLocation"));
+                }
+                persist_location = Some(map.next_value()?);
+              }
+            }
+          }
+          /* Backups made before k was configurable always used k=2 */
+          let k = k.unwrap_or(2);
+          /* and always stored their triples in slices */
+          let storage = storage.unwrap_or_default();
+          let dict_max = dict_max.ok_or_else(|| de::Error::missing_field("dictMax"))?;
+          let dict_tombstones = dict_tombstones.ok_or_else(|| de::Error::missing_field("dictTombstones"))?;
+          let dict = dict.ok_or_else(|| de::Error::missing_field("dict"))?;
+          let pred_tombstones = pred_tombstones.ok_or_else(|| de::Error::missing_field("predTombstones"))?;
+          let predicates = predicates.ok_or_else(|| de::Error::missing_field("predicates"))?;
+          let persist_location = persist_location.ok_or_else(|| de::Error::missing_field("persistLocation"))?;
+          
+          let mut final_dict: BiBTreeMap<RdfNode, usize> = BiBTreeMap::new();
+          for (key, val) in dict.into_iter() {
+            final_dict.insert(key, val);
+          }
+          let mut final_preds: BiBTreeMap<RdfNode, usize> = BiBTreeMap::new();
+          for (key, val) in predicates.into_iter() {
+            final_preds.insert(key, val);
+          }
+
+          Ok(Graph {
+            k: k,
+            dict_max: dict_max,
+            dict_tombstones: dict_tombstones,
+            dict: final_dict,
+            pred_tombstones: pred_tombstones,
+            predicates: final_preds,
+            /* Left for the caller to fill in from root/trees/,
+            a k under 2 is rejected before any tree is built with it */
+            storage: match storage {
+              _ if k < 2 => Storage::Slices(Vec::new()),
+              GraphStorage::Slices => Storage::Slices(Vec::new()),
+              GraphStorage::Interleaved => Storage::Interleaved(IK2Tree::with_k(k)),
+              GraphStorage::Ternary => Storage::Ternary(K3Tree::with_k(k)),
+            },
+            persist_location: persist_location,
+            reorder_on_persist: None,
+          })
+        }
+      }
+      const FIELDS: &'static [&'static str] = &[
+        "k",
+        "storage",
+        "dict_max",
+        "dict_tombstones",
+        "dict",
+        "pred_tombstones",
+        "predicates",
+        "persist_location"
+      ];
+      deserializer.deserialize_struct("Graph", FIELDS, GraphVisitor)
+    }
+  }
+  /* Define key filesystem locations */
+  let root_dir = std::path::Path::new(path);
+  let trees_dir = root_dir.join("trees");
+  let head_file = root_dir.join("head.json");
+  let dot_file = root_dir.join(".ripplebackup");
+  /* Check that all files and dirs actually exist */
+  if !root_dir.is_dir() {
+    return Err(Error::MissingBackup(std::path::PathBuf::from(root_dir)))
+  }
+  else if !trees_dir.is_dir() {
+    return Err(Error::InvalidBackup("root/trees/".into(), std::path::PathBuf::from(root_dir)))
+  }
+  else if !head_file.is_file() {
+    return Err(Error::InvalidBackup("root/head.json".into(), std::path::PathBuf::from(root_dir)))
+  }
+  else if !dot_file.is_file() {
+    return Err(Error::InvalidBackup("root/.ripplebackup".into(), std::path::PathBuf::from(root_dir)))
+  }
+  match serde_json::from_str::<Graph>(&read_json(&head_file)?) {
+    Ok(g) if g.k < 2 => Err(Error::InvalidBackup(format!("a k of at least 2 in root/head.json, found {}", g.k), std::path::PathBuf::from(root_dir))),
+    Ok(g) => Ok(Graph { persist_location: Some(path.to_string()), ..g }),
+    Err(e) => Err(Error::FromBadJson(String::from("Graph"), head_file, Box::new(e))),
+  }
 }
-fn ones_in_bitvec(bits: &BitVec) -> usize {
-  bits.iter().fold(0, |total, bit| total + bit as usize)
+fn read_json(path_to_file: &std::path::Path) -> Result<String> {
+  use std::io::Read;
+  let mut buf = String::new();
+  std::fs::File::open(path_to_file)?.read_to_string(&mut buf)?;
+  Ok(buf)
 }
-fn one_positions(bit_vec: &BitVec) -> Vec<usize> {
-  bit_vec
-  .iter()
-  .enumerate()
-  .filter_map(
-    |(pos, bit)|
-    if bit { Some(pos) }
-    else   { None })
-  .collect()
+fn to_named_node(s: &str) -> RdfNode {
+  RdfNode::Named{ iri: s.to_string() }
 }
 struct Slice {
   pub predicate_index: usize,
@@ -1040,22 +1912,20 @@ struct TripleSet {
   pub doubles: Vec<[usize; 2]>,
 }
 type PartitionedTriples = Vec<Vec<[usize; 2]>>;
-async fn build_tree(pred_index: usize, doubles: &[[usize; 2]], dict_max: usize) -> Option<Slice> {
-  let mut tree = K2Tree::new();
-  while tree.matrix_width() < dict_max {
-    tree.grow();
+async fn build_tree(pred_index: usize, doubles: &[[usize; 2]], k: usize) -> Option<Slice> {
+  /* Each slice only spans the subjects and objects it holds */
+  let width = doubles.iter().map(|&[x, _]| x+1).max().unwrap_or(0);
+  let height = doubles.iter().map(|&[_, y]| y+1).max().unwrap_or(0);
+  let coords = doubles.iter().map(|&[x, y]| (x, y));
+  match K2Tree::from_coordinates_with_extents(k, width, height, coords) {
+    Ok(tree) => Some(Slice{
+      predicate_index: pred_index,
+      tree: Box::new(tree),
+    }),
+    Err(_) => None,
   }
-  for &[x, y] in doubles {
-    if tree.set(x, y, true).is_err() {
-      return None
-    }
-  }
-  Some(Slice{
-    predicate_index: pred_index,
-    tree: Box::new(tree),
-  })
 }
-fn build_slices(triple_sets: Vec<TripleSet>, dict_max: usize) -> Vec<Slice> {
+fn build_slices(triple_sets: Vec<TripleSet>, k: usize) -> Vec<Slice> {
   use futures::{executor, StreamExt, stream::FuturesUnordered};
   executor::block_on(async {
     let mut futs = FuturesUnordered::new();
@@ -1064,7 +1934,7 @@ fn build_slices(triple_sets: Vec<TripleSet>, dict_max: usize) -> Vec<Slice> {
       predicate_index: pi,
       doubles: ds
     } in &triple_sets {
-      futs.push(build_tree(*pi, ds, dict_max));
+      futs.push(build_tree(*pi, ds, k));
     }
     let mut ret_vals = Vec::new();
     while let Some(Some(tree)) = futs.next().await {
@@ -1084,4 +1954,440 @@ fn sort_by_size(triples: PartitionedTriples) -> Vec<TripleSet> {
     .collect::<Vec<TripleSet>>();
   sorted_triples.sort_by(|a, b| a.size.cmp(&b.size));
   sorted_triples
-}
\ No newline at end of file
+}
+fn assert_valid_k(k: usize) {
+  /* Same rule as K2Tree::with_k, checked up front rather than on the first slice */
+  assert!(k >= 2, "Graph k must be at least 2, got {}", k);
+}
+fn check_k(k: usize) -> Result<()> {
+  /* assert_valid_k for constructors that can return an error instead */
+  if k < 2 { Err(Error::InvalidK(k)) } else { Ok(()) }
+}
+
+/* Unit Tests */
+#[cfg(test)]
+mod unit_tests {
+  use super::*;
+  use rand::Rng;
+  fn triple(subject: &str, predicate: &str, object: &str) -> RdfTriple {
+    [subject.into(), predicate.into(), object.into()]
+  }
+  fn sorted_triples(graph: &Graph) -> Vec<RdfTriple> {
+    let mut triples: Vec<RdfTriple> = graph.iter().collect();
+    triples.sort();
+    triples
+  }
+  fn sample_graph(storage: GraphStorage) -> (Graph, Vec<RdfTriple>) {
+    /* The same triples inserted in the same order, so every storage gives out the same ids */
+    let triples: Vec<RdfTriple> = [
+      ("a", "p", "b"), ("a", "q", "c"), ("b", "p", "c"), ("c", "p", "a"),
+      ("b", "q", "b"), ("c", "r", "a"), ("d", "p", "c"),
+    ].iter().map(|&(subject, predicate, object)| triple(subject, predicate, object)).collect();
+    let mut graph = Graph::with_storage(2, storage);
+    graph.insert_triples(triples.clone()).unwrap();
+    (graph, triples)
+  }
+  fn check_patterns(graph: &impl TripleStore, triples: &[RdfTriple]) {
+    /* Every pattern of bound and unbound positions matches the triples it should,
+    a node or predicate that isn't in the graph matches nothing */
+    let id = |map: &BiBTreeMap<RdfNode, usize>, node: &RdfNode| *map.get_by_left(node).unwrap();
+    for &subject in [None, Some("a"), Some("c"), Some("z")].iter() {
+      for &predicate in [None, Some("p"), Some("r"), Some("z")].iter() {
+        for &object in [None, Some("c"), Some("a"), Some("z")].iter() {
+          let pattern = [subject, predicate, object];
+          let mut found = graph.get_from_triple([subject.map(String::from), predicate.map(String::from), object.map(String::from)]);
+          found.sort();
+          let mut expected: Vec<[usize; 3]> = triples.iter()
+            .filter(|&triple| triple.iter().zip(pattern.iter()).all(|(node, bound)| bound.map_or(true, |bound| *node == bound.into())))
+            .map(|[s, p, o]| [id(graph.dict(), s), id(graph.predicates(), p), id(graph.dict(), o)])
+            .collect();
+          expected.sort();
+          assert_eq!(found, expected, "pattern {:?}", pattern);
+        }
+      }
+    }
+  }
+  #[test]
+  #[should_panic(expected = "Graph k must be at least 2, got 1")]
+  fn with_k_0() {
+    Graph::with_k(1);
+  }
+  #[test]
+  fn with_k_1() {
+    /* Constructors returning a Result reject a k under 2 rather than panicking */
+    for &k in [0, 1].iter() {
+      assert!(matches!(Graph::from_rdf_with_k("missing.rdf", k), Err(Error::InvalidK(_))));
+      for &storage in [GraphStorage::Slices, GraphStorage::Interleaved, GraphStorage::Ternary].iter() {
+        assert!(matches!(Graph::from_rdf_with_storage("missing.rdf", k, storage), Err(Error::InvalidK(_))));
+      }
+    }
+    /* As does reading a backup whose head.json has one */
+    let path = std::env::temp_dir().join(format!("ripple_with_k_1_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    for &storage in [GraphStorage::Slices, GraphStorage::Interleaved, GraphStorage::Ternary].iter() {
+      let mut graph = Graph::with_storage(2, storage);
+      graph.insert_triple(triple("a", "p", "b")).unwrap();
+      graph.persist_to(path).unwrap();
+      let head_file = std::path::Path::new(path).join("head.json");
+      let head = std::fs::read_to_string(&head_file).unwrap();
+      assert!(head.contains("\"k\":2"));
+      for &k in [0, 1].iter() {
+        std::fs::write(&head_file, head.replace("\"k\":2", &format!("\"k\":{}", k))).unwrap();
+        assert!(matches!(Graph::from_backup(path), Err(Error::InvalidBackup(_, _))));
+        assert!(matches!(MappedGraph::open(path), Err(Error::InvalidBackup(_, _))));
+      }
+    }
+    std::fs::remove_dir_all(path).unwrap();
+  }
+  #[test]
+  fn extents_holding_0() {
+    assert_eq!(extents_holding([0, 4, 9]).unwrap(), [1, 5, 10]);
+    assert!(extents_holding([usize::MAX, 0, 0]).is_err());
+    assert!(extents_holding([0, 0, usize::MAX]).is_err());
+  }
+  #[test]
+  fn compose_0() {
+    let mut graph = Graph::new();
+    graph.insert_triple(triple("a", "knows", "b")).unwrap();
+    graph.insert_triple(triple("b", "likes", "c")).unwrap();
+    let composed = graph.compose(&"knows".into(), &"likes".into()).unwrap();
+    assert_eq!(composed, vec![["a".into(), "c".into()]]);
+    /* A node missing from the dictionary is an error, not a panic */
+    let c = *graph.dict.get_by_left(&"c".into()).unwrap();
+    graph.dict.remove_by_right(&c);
+    assert!(graph.compose(&"knows".into(), &"likes".into()).is_err());
+  }
+  #[test]
+  fn dict_tombstones_0() {
+    /* Every new node takes its own tombstone rather than sharing the first */
+    let mut graph = Graph::new();
+    graph.insert_triple(triple("a", "p", "b")).unwrap();
+    graph.insert_triple(triple("c", "p", "d")).unwrap();
+    graph.remove_triple(&triple("a", "p", "b")).unwrap();
+    assert_eq!(graph.dict_tombstones.len(), 2);
+    graph.insert_triple(triple("e", "p", "f")).unwrap();
+    assert!(graph.dict_tombstones.is_empty());
+    let mut ids: Vec<usize> = graph.dict.right_values().cloned().collect();
+    ids.sort();
+    assert_eq!(ids, vec![0, 1, 2, 3]);
+    assert_eq!(sorted_triples(&graph), vec![triple("c", "p", "d"), triple("e", "p", "f")]);
+  }
+  #[test]
+  fn first_node_0() {
+    /* A first triple from a node to itself gives out id 0 once, so the
+    next new node doesn't get 0 again and take the first one's place */
+    let mut graph = Graph::new();
+    graph.insert_triple(triple("a", "p", "a")).unwrap();
+    graph.insert_triple(triple("b", "p", "c")).unwrap();
+    assert_eq!(graph.dict.get_by_left(&"a".into()), Some(&0));
+    assert_eq!(graph.dict.get_by_left(&"b".into()), Some(&1));
+    assert_eq!(graph.dict.get_by_left(&"c".into()), Some(&2));
+    assert_eq!(sorted_triples(&graph), vec![triple("a", "p", "a"), triple("b", "p", "c")]);
+  }
+  #[test]
+  fn pred_tombstones_0() {
+    /* A new predicate given a tombstone gets a slice there to hold its triples */
+    let mut graph = Graph::new();
+    graph.insert_triple(triple("a", "p", "b")).unwrap();
+    graph.insert_triple(triple("a", "q", "b")).unwrap();
+    graph.remove_triple(&triple("a", "p", "b")).unwrap();
+    assert_eq!(graph.pred_tombstones, vec![0]);
+    assert!(graph.slices()[0].is_none());
+    graph.insert_triple(triple("a", "r", "b")).unwrap();
+    assert!(graph.pred_tombstones.is_empty());
+    assert!(graph.slices()[0].is_some());
+    assert_eq!(sorted_triples(&graph), vec![triple("a", "q", "b"), triple("a", "r", "b")]);
+  }
+  #[test]
+  fn remove_last_0() {
+    /* Emptying the last slice, with only a tombstone before it,
+    and then the whole dict, leaves an empty graph to start over with */
+    let mut graph = Graph::new();
+    graph.insert_triple(triple("a", "p", "b")).unwrap();
+    graph.insert_triple(triple("a", "q", "b")).unwrap();
+    graph.remove_triple(&triple("a", "p", "b")).unwrap();
+    graph.remove_triple(&triple("a", "q", "b")).unwrap();
+    assert!(graph.slices().is_empty());
+    assert!(graph.pred_tombstones.is_empty());
+    assert!(graph.dict.is_empty());
+    assert!(graph.dict_tombstones.is_empty());
+    assert_eq!(graph.dict_max, 0);
+    graph.insert_triple(triple("c", "p", "c")).unwrap();
+    assert_eq!(sorted_triples(&graph), vec![triple("c", "p", "c")]);
+  }
+  #[test]
+  fn insert_remove_triples_0() {
+    /* Batches leave the graph as the same calls one triple at a time do */
+    let mut rng = rand::thread_rng();
+    let random_triple = |rng: &mut rand::rngs::ThreadRng| triple(
+      &format!("n{}", rng.gen_range(0, 40)),
+      &format!("p{}", rng.gen_range(0, 5)),
+      &format!("n{}", rng.gen_range(0, 40))
+    );
+    let inserts: Vec<RdfTriple> = (0..300).map(|_| random_triple(&mut rng)).collect();
+    let mut removals: Vec<RdfTriple> = inserts.iter().filter(|_| rng.gen_range(0, 2) == 0).cloned().collect();
+    /* Triples that aren't there, some with nodes that aren't either */
+    removals.extend((0..20).map(|_| random_triple(&mut rng)));
+    removals.push(triple("nowhere", "p0", "n0"));
+    let mut batched = Graph::new();
+    let mut single = Graph::new();
+    batched.insert_triples(inserts.clone()).unwrap();
+    for triple in inserts.iter() {
+      single.insert_triple(triple.clone()).unwrap();
+    }
+    assert_eq!(batched, single);
+    let mut expected: Vec<RdfTriple> = inserts.clone();
+    expected.sort();
+    expected.dedup();
+    assert_eq!(sorted_triples(&batched), expected);
+    batched.remove_triples(&removals).unwrap();
+    for triple in removals.iter() {
+      single.remove_triple(triple).unwrap();
+    }
+    expected.retain(|triple| !removals.contains(triple));
+    assert_eq!(sorted_triples(&batched), expected);
+    assert_eq!(sorted_triples(&single), expected);
+    assert_eq!(batched.dict.len(), single.dict.len());
+    assert_eq!(batched.predicates.len(), single.predicates.len());
+    /* Removing everything empties it */
+    batched.remove_triples(&expected).unwrap();
+    assert!(batched.iter().next().is_none());
+    assert!(batched.dict.is_empty() && batched.slices().is_empty());
+  }
+  #[test]
+  fn reorder_ids_0() {
+    /* Ids 0 to 4 go to a, b, c, d and e as they're first seen, d is in
+    the most triples and then c, and by IRI the x/ nodes come first */
+    let [a, b, c, d, e] = ["http://y/a", "http://x/b", "http://y/c", "http://x/d", "http://z/e"];
+    let mut graph = Graph::new();
+    graph.insert_triple(triple(a, "p", b)).unwrap();
+    graph.insert_triple(triple(c, "p", d)).unwrap();
+    graph.insert_triple(triple(d, "q", c)).unwrap();
+    graph.insert_triple(triple(e, "p", d)).unwrap();
+    let ids = |graph: &Graph| -> Vec<usize> {
+      [a, b, c, d, e].iter().map(|&node| graph.dict.get_by_left(&node.into()).cloned().unwrap()).collect()
+    };
+    assert_eq!(ids(&graph), vec![0, 1, 2, 3, 4]);
+    let path = std::env::temp_dir().join(format!("ripple_reorder_ids_0_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    for &(order, expected_ids) in [
+      (IdOrder::Degree, [2, 3, 1, 0, 4]),
+      /* From d, its neighbours c and e, then from a */
+      (IdOrder::Bfs, [3, 4, 1, 0, 2]),
+      (IdOrder::Namespace, [2, 0, 3, 1, 4]),
+    ].iter() {
+      let mut reordered = graph.clone();
+      let report = reordered.reorder_ids(order).unwrap();
+      assert_eq!(ids(&reordered), expected_ids.to_vec());
+      assert_eq!(sorted_triples(&reordered), sorted_triples(&graph));
+      assert_eq!(report.ids_changed, expected_ids.iter().enumerate().filter(|&(old_id, &new_id)| old_id != new_id).count());
+      /* Persisting reordered writes the same Graph and leaves this one as it was */
+      let mut persisted = graph.clone();
+      persisted.set_reorder_on_persist(Some(order));
+      persisted.persist_to(path).unwrap();
+      assert_eq!(ids(&persisted), vec![0, 1, 2, 3, 4]);
+      assert_eq!(Graph::from_backup(path).unwrap(), Graph {
+        persist_location: Some(path.to_string()),
+        ..reordered
+      });
+    }
+    std::fs::remove_dir_all(path).unwrap();
+  }
+  #[test]
+  fn vacuum_0() {
+    for &storage in [GraphStorage::Slices, GraphStorage::Interleaved, GraphStorage::Ternary].iter() {
+      /* Removing c q d leaves tombstones at nodes 2 and 3 and predicate 1 */
+      let mut graph = Graph::with_storage(2, storage);
+      for &(subject, predicate, object) in [("a", "p", "b"), ("c", "q", "d"), ("e", "r", "f"), ("g", "s", "h")].iter() {
+        graph.insert_triple(triple(subject, predicate, object)).unwrap();
+      }
+      graph.remove_triple(&triple("c", "q", "d")).unwrap();
+      assert_eq!((graph.dict_tombstones.len(), graph.pred_tombstones.len()), (2, 1));
+      let triples = sorted_triples(&graph);
+      let report = graph.vacuum().unwrap();
+      assert_eq!(sorted_triples(&graph), triples);
+      assert!(graph.dict_tombstones.is_empty() && graph.pred_tombstones.is_empty());
+      assert!(graph.slices().iter().all(|slice| slice.is_some()));
+      assert_eq!(graph.slices().len(), if storage == GraphStorage::Slices { 3 } else { 0 });
+      let mut ids: Vec<usize> = graph.dict.right_values().cloned().collect();
+      ids.sort();
+      assert_eq!(ids, (0..6).collect::<Vec<usize>>());
+      assert_eq!(graph.dict_max, 5);
+      assert_eq!(graph.dict.get_by_left(&"e".into()), Some(&2));
+      let mut preds: Vec<usize> = graph.predicates.right_values().cloned().collect();
+      preds.sort();
+      assert_eq!(preds, vec![0, 1, 2]);
+      assert_eq!(graph.predicates.get_by_left(&"s".into()), Some(&2));
+      /* e, f, g and h move down two, r and s down one */
+      assert_eq!((report.nodes_remapped, report.predicates_remapped), (4, 2));
+      assert!(report.bytes_reclaimed > 0);
+      let report = graph.vacuum().unwrap();
+      assert_eq!((report.nodes_remapped, report.predicates_remapped), (0, 0));
+      assert_eq!(sorted_triples(&graph), triples);
+    }
+  }
+  #[test]
+  fn interleaved_0() {
+    let (mut graph, mut triples) = sample_graph(GraphStorage::Interleaved);
+    let (slices, _) = sample_graph(GraphStorage::Slices);
+    check_patterns(&graph, &triples);
+    let composed = graph.compose(&"p".into(), &"q".into()).unwrap();
+    assert!(!composed.is_empty());
+    assert_eq!(composed, slices.compose(&"p".into(), &"q".into()).unwrap());
+    /* A round trip through root/trees/interleaved.ik2t */
+    let path = std::env::temp_dir().join(format!("ripple_interleaved_0_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    graph.persist_to(path).unwrap();
+    assert!(std::path::Path::new(path).join("trees").join(INTERLEAVED_FILE).is_file());
+    assert_eq!(Graph::from_backup(path).unwrap(), graph);
+    std::fs::remove_dir_all(path).unwrap();
+    /* Emptying the last predicate pops it from the tree */
+    graph.remove_triple(&triple("c", "r", "a")).unwrap();
+    triples.retain(|triple| triple[1] != "r".into());
+    assert_eq!(graph.interleaved().unwrap().num_predicates(), 2);
+    assert!(graph.pred_tombstones.is_empty() && !graph.predicates.contains_left(&"r".into()));
+    check_patterns(&graph, &triples);
+    /* Emptying another leaves a tombstone, until the ones after it go too */
+    let p_triples: Vec<RdfTriple> = triples.iter().filter(|triple| triple[1] == "p".into()).cloned().collect();
+    for triple in p_triples.iter() {
+      graph.remove_triple(triple).unwrap();
+    }
+    triples.retain(|triple| triple[1] != "p".into());
+    assert_eq!(graph.interleaved().unwrap().num_predicates(), 2);
+    assert_eq!(graph.pred_tombstones, vec![0]);
+    assert_eq!(sorted_triples(&graph), triples);
+    graph.remove_triples(&triples).unwrap();
+    assert_eq!(graph.interleaved().unwrap().num_predicates(), 0);
+    assert!(graph.pred_tombstones.is_empty() && graph.predicates.is_empty());
+    assert!(graph.iter().next().is_none());
+  }
+  #[test]
+  fn ternary_0() {
+    let (mut graph, mut triples) = sample_graph(GraphStorage::Ternary);
+    check_patterns(&graph, &triples);
+    assert_eq!(graph.ternary().unwrap().height(), 3);
+    /* A round trip through root/trees/ternary.k3t */
+    let path = std::env::temp_dir().join(format!("ripple_ternary_0_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    graph.persist_to(path).unwrap();
+    assert!(std::path::Path::new(path).join("trees").join(TERNARY_FILE).is_file());
+    assert_eq!(Graph::from_backup(path).unwrap(), graph);
+    std::fs::remove_dir_all(path).unwrap();
+    /* Emptying the last predicate shrinks the cube's height */
+    graph.remove_triple(&triple("c", "r", "a")).unwrap();
+    triples.retain(|triple| triple[1] != "r".into());
+    assert_eq!(graph.ternary().unwrap().height(), 2);
+    assert!(graph.pred_tombstones.is_empty() && !graph.predicates.contains_left(&"r".into()));
+    check_patterns(&graph, &triples);
+    /* Emptying another leaves a tombstone, until the ones after it go too */
+    let p_triples: Vec<RdfTriple> = triples.iter().filter(|triple| triple[1] == "p".into()).cloned().collect();
+    for triple in p_triples.iter() {
+      graph.remove_triple(triple).unwrap();
+    }
+    triples.retain(|triple| triple[1] != "p".into());
+    assert_eq!(graph.ternary().unwrap().height(), 2);
+    assert_eq!(graph.pred_tombstones, vec![0]);
+    check_patterns(&graph, &triples);
+    for triple in triples.iter() {
+      graph.remove_triple(triple).unwrap();
+    }
+    assert_eq!(graph.ternary().unwrap().height(), 0);
+    assert!(graph.pred_tombstones.is_empty() && graph.predicates.is_empty());
+    assert!(graph.iter().next().is_none());
+  }
+  #[test]
+  fn mapped_graph_0() {
+    let path = std::env::temp_dir().join(format!("ripple_mapped_graph_0_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    for &storage in [GraphStorage::Slices, GraphStorage::Interleaved, GraphStorage::Ternary].iter() {
+      let (mut graph, triples) = sample_graph(storage);
+      graph.persist_to(path).unwrap();
+      let mapped = MappedGraph::open(path).unwrap();
+      let view = mapped.view().unwrap();
+      assert_eq!(view.k(), 2);
+      check_patterns(&view, &triples);
+      assert_eq!(view.to_graph(), Graph::from_backup(path).unwrap());
+      assert_eq!(view.to_graph(), graph);
+      std::fs::remove_dir_all(path).unwrap();
+    }
+    /* Slices can only be mapped from the binary format */
+    let (mut graph, _) = sample_graph(GraphStorage::Slices);
+    graph.persist_to(path).unwrap();
+    std::fs::remove_file(std::path::Path::new(path).join("trees").join("1.k2t")).unwrap();
+    assert!(matches!(MappedGraph::open(path), Err(Error::InvalidBackup(_, _))));
+    std::fs::remove_dir_all(path).unwrap();
+  }
+  #[test]
+  fn stats_0() {
+    let (slices_graph, _) = sample_graph(GraphStorage::Slices);
+    let slice_bits = slices_graph.slices().iter().fold(0, |bits, slice| bits + slice.as_ref().unwrap().stats().total_bits);
+    for &storage in [GraphStorage::Slices, GraphStorage::Interleaved, GraphStorage::Ternary].iter() {
+      let (graph, triples) = sample_graph(storage);
+      /* Interleaved and ternary slices span every node, so only their matrix sizes differ */
+      let (slices, total_bits): (Vec<K2Tree>, usize) = match &graph.storage {
+        Storage::Slices(slices) => (slices.iter().map(|slice| *slice.clone().unwrap()).collect(), slice_bits),
+        Storage::Interleaved(tree) => ((0..tree.num_predicates()).map(|i| tree.predicate_slice(i).unwrap()).collect(), tree.storage_bits()),
+        Storage::Ternary(tree) => ((0..tree.height()).map(|i| tree.plane(i).unwrap()).collect(), tree.storage_bits()),
+      };
+      let stats = graph.stats();
+      assert_eq!(stats.predicates.len(), 3);
+      for (i, ((predicate, predicate_stats), slice)) in stats.predicates.iter().zip(slices.iter()).enumerate() {
+        assert_eq!(Some(predicate), slices_graph.predicates.get_by_right(&i));
+        assert_eq!(predicate_stats, &slice.stats());
+        let slices_stats = slices_graph.slices()[i].as_ref().unwrap().stats();
+        assert_eq!(predicate_stats.bits_per_layer, slices_stats.bits_per_layer);
+        assert_eq!(predicate_stats.ones_per_layer, slices_stats.ones_per_layer);
+        assert_eq!(predicate_stats.cells_set, slices_stats.cells_set);
+      }
+      let matrix_cells = slices.iter().fold(0, |cells, slice| cells + slice.stats().matrix_cells);
+      assert_eq!(stats.total_bits, total_bits);
+      assert_eq!(stats.cells_set, triples.len());
+      assert_eq!(stats.bits_per_cell, total_bits as f64 / triples.len() as f64);
+      assert!((stats.dense_ratio - matrix_cells as f64 / total_bits as f64).abs() < 1e-9);
+      assert_eq!(stats.coordinate_ratio, (triples.len() * k2_tree::COORDINATE_BITS) as f64 / total_bits as f64);
+    }
+    /* Moving the same cells into other bits keeps the per-predicate figures */
+    let stats = slices_graph.stats();
+    let stored = stats.clone().stored_in(slice_bits * 2);
+    assert_eq!(stored.predicates, stats.predicates);
+    assert_eq!(stored.cells_set, stats.cells_set);
+    assert_eq!(stored.total_bits, slice_bits * 2);
+    assert_eq!(stored.bits_per_cell, stats.bits_per_cell * 2.0);
+    assert!((stored.dense_ratio - stats.dense_ratio / 2.0).abs() < 1e-9);
+    assert!((stored.coordinate_ratio - stats.coordinate_ratio / 2.0).abs() < 1e-9);
+    let empty = Graph::new().stats();
+    assert!(empty.predicates.is_empty());
+    assert_eq!((empty.total_bits, empty.cells_set), (0, 0));
+    assert_eq!((empty.bits_per_cell, empty.dense_ratio, empty.coordinate_ratio), (0.0, 0.0, 0.0));
+    let stored = empty.stored_in(0);
+    assert_eq!((stored.bits_per_cell, stored.dense_ratio, stored.coordinate_ratio), (0.0, 0.0, 0.0));
+  }
+  #[test]
+  fn par_iter_0() {
+    /* Enough cells across enough slices for the work to be split between parts */
+    let mut rng = rand::thread_rng();
+    let triples: Vec<RdfTriple> = (0..600).map(|_| triple(
+      &format!("s{}", rng.gen_range(0, 80)),
+      &format!("p{}", rng.gen_range(0, 6)),
+      &format!("o{}", rng.gen_range(0, 80)),
+    )).collect();
+    for &storage in [GraphStorage::Slices, GraphStorage::Interleaved, GraphStorage::Ternary].iter() {
+      let mut graph = Graph::with_storage(2, storage);
+      graph.insert_triples(triples.clone()).unwrap();
+      let expected = sorted_triples(&graph);
+      let mut found = graph.par_iter().map_collect(|triple| triple);
+      found.sort();
+      assert_eq!(found, expected);
+      assert_eq!(graph.par_iter().count(), expected.len());
+      let (graph, _) = sample_graph(storage);
+      let mut found = graph.par_iter().map_collect(|[subject, _, object]| [object, subject]);
+      found.sort();
+      let mut expected: Vec<[RdfNode; 2]> = graph.iter().map(|[subject, _, object]| [object, subject]).collect();
+      expected.sort();
+      assert_eq!(found, expected);
+      assert_eq!(graph.par_iter().count(), graph.iter().count());
+    }
+    assert!(Graph::new().par_iter().map_collect(|triple| triple).is_empty());
+    assert_eq!(Graph::new().par_iter().count(), 0);
+  }
+}
@@ -0,0 +1,804 @@
+use {
+  bitvec::{prelude::bitvec, vec::BitVec},
+  crate::{
+    errors::K2TreeError as Error,
+    datastore::k2_tree::{
+      K2Tree, BitStore, GrowableBits, Range,
+      invalid_binary, bytes_for_bits, bits_to_bytes, fnv1a, log_k, grown_extent, stems_cover,
+      to_subranges, within_range, ranges_overlap,
+    },
+  }
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/* An ik2-tree, the K2Trees of several predicates interleaved into one.
+Where a K2Tree has a bit per child in each stem, a node here has a bit per
+child for each predicate with 1s in the node's submatrix, so the bits of a
+node with predicates P are k*k groups of |P| bits, group i saying which of
+P have 1s in child i. The child's predicates are then those set in its group.
+Every 1 still has k*k bits below it, so children are found by rank as in a
+K2Tree, and a lookup with the predicate unbound visits each node once for
+all predicates rather than once per predicate. The root holds every
+predicate, one with no cells costs its k*k bits there and nothing else */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IK2Tree {
+  matrix_width: usize, //number of columns, x
+  matrix_height: usize, //number of rows, y
+  k: usize,
+  max_slayers: usize,
+  num_predicates: usize,
+  slayer_starts: Vec<usize>, //every stem layer, including empty ones
+  stems: GrowableBits,
+  leaves: GrowableBits,
+}
+
+/* Public */
+impl IK2Tree {
+  /* Creation */
+  pub fn new() -> Self {
+    IK2Tree::with_k(2)
+  }
+  pub fn with_k(k: usize) -> Self {
+    assert!(k >= 2, "IK2Tree k must be at least 2, got {}", k);
+    let mw = k.pow(3);
+    let max_slayers = log_k(mw, k) - 1;
+    IK2Tree {
+      matrix_width: mw,
+      matrix_height: mw,
+      k: k,
+      max_slayers: max_slayers,
+      num_predicates: 0,
+      slayer_starts: vec![0; max_slayers],
+      stems: BitVec::new().into(),
+      leaves: BitVec::new().into(),
+    }
+  }
+  pub fn with_extents(k: usize, width: usize, height: usize) -> Self {
+    let mut tree = IK2Tree::with_k(k);
+    while tree.tree_width() < std::cmp::max(width, height) {
+      tree.grow();
+    }
+    tree.matrix_width = width;
+    tree.matrix_height = height;
+    tree
+  }
+  pub fn from_triples<I: IntoIterator<Item=[usize; 3]>>(k: usize, width: usize, height: usize, num_predicates: usize, triples: I) -> Result<Self> {
+    /* Triples are [x, y, predicate]. Builds the same tree as calling
+    set(x, y, predicate, true) for each, a layer at a time */
+    let mut tree = IK2Tree::with_extents(k, width, height);
+    let mut cells: Vec<[usize; 3]> = Vec::new();
+    for [x, y, predicate] in triples {
+      if x >= tree.matrix_width || y >= tree.matrix_height {
+        return Err(Error::OutOfBounds([x, y], tree.max_coords()))
+      }
+      if predicate >= num_predicates {
+        return Err(Error::PredicateOutOfBounds(predicate, num_predicates))
+      }
+      cells.push([x, y, predicate]);
+    }
+    cells.sort();
+    cells.dedup();
+    let tree_width = tree.tree_width();
+    let stem_len = tree.stem_len();
+    let mut nodes = vec![([[0, tree_width-1], [0, tree_width-1]], (0..num_predicates).collect::<Vec<usize>>(), cells)];
+    let mut layers: Vec<BitVec> = Vec::new();
+    for layer in 0..=tree.max_slayers {
+      let mut bits = BitVec::new();
+      let mut next_nodes = Vec::new();
+      for (range, predicates, cells) in nodes.into_iter() {
+        let subranges = to_subranges(range, k);
+        let mut children: Vec<Vec<[usize; 3]>> = vec![Vec::new(); stem_len];
+        for cell in cells.into_iter() {
+          children[child_pos(range, k, cell[0], cell[1])].push(cell);
+        }
+        for (child_pos, child_cells) in children.into_iter().enumerate() {
+          let mut child_predicates: Vec<usize> = child_cells.iter().map(|&[_, _, predicate]| predicate).collect();
+          child_predicates.sort();
+          child_predicates.dedup();
+          for predicate in predicates.iter() {
+            bits.push(child_predicates.binary_search(predicate).is_ok());
+          }
+          if layer < tree.max_slayers && !child_cells.is_empty() {
+            next_nodes.push((subranges[child_pos], child_predicates, child_cells));
+          }
+        }
+      }
+      layers.push(bits);
+      nodes = next_nodes;
+    }
+    let leaves = layers.pop().unwrap();
+    let mut stems = BitVec::new();
+    for (layer, bits) in layers.into_iter().enumerate() {
+      tree.slayer_starts[layer] = stems.len();
+      stems.extend(bits.iter());
+    }
+    tree.num_predicates = num_predicates;
+    tree.stems = stems.into();
+    tree.leaves = leaves.into();
+    Ok(tree)
+  }
+  /* Information */
+  pub fn matrix_width(&self) -> usize {
+    self.matrix_width
+  }
+  pub fn matrix_height(&self) -> usize {
+    self.matrix_height
+  }
+  pub fn k(&self) -> usize {
+    self.k
+  }
+  pub fn num_predicates(&self) -> usize {
+    self.num_predicates
+  }
+  pub fn is_empty(&self) -> bool {
+    self.count_ones() == 0
+  }
+  pub fn count_ones(&self) -> usize {
+    /* Every cell set is a 1 in the leaves */
+    self.leaves.rank(self.leaves.len())
+  }
+  pub fn predicate_is_empty(&self, predicate: usize) -> bool {
+    /* Only needs the root */
+    predicate >= self.num_predicates
+    || (0..self.stem_len()).all(|child_pos| !self.stems.get(child_pos*self.num_predicates + predicate))
+  }
+  pub fn storage_bits(&self) -> usize {
+    self.stems.len() + self.leaves.len()
+  }
+  pub fn heapsize(&self) -> usize {
+    std::mem::size_of::<usize>() * self.slayer_starts.len()
+    + self.stems.heapsize()
+    + self.leaves.heapsize()
+  }
+  /* Predicates */
+  pub fn add_predicate(&mut self) -> usize {
+    /* A new predicate with no cells, its id is the one after the last */
+    let num_predicates = self.num_predicates;
+    for child_pos in 0..self.stem_len() {
+      let _ = self.stems.insert_block(child_pos*(num_predicates+1) + num_predicates, 1);
+    }
+    self.shift_layers(1, self.stem_len() as isize);
+    self.num_predicates += 1;
+    num_predicates
+  }
+  pub fn pop_predicate(&mut self) -> Result<()> {
+    /* Removes the last predicate, which has to have no cells */
+    if self.num_predicates == 0 {
+      return Err(Error::PredicateOutOfBounds(0, 0))
+    }
+    let predicate = self.num_predicates - 1;
+    if !self.predicate_is_empty(predicate) {
+      return Err(Error::PredicateInUse(predicate))
+    }
+    for child_pos in (0..self.stem_len()).rev() {
+      let _ = self.stems.remove_block(child_pos*self.num_predicates + predicate, 1);
+    }
+    self.shift_layers(1, -(self.stem_len() as isize));
+    self.num_predicates -= 1;
+    Ok(())
+  }
+  /* Operation */
+  pub fn get(&self, x: usize, y: usize, predicate: usize) -> Result<bool> {
+    self.check_bounds(x, y, predicate)?;
+    let mut node = self.root(predicate);
+    for layer in 0..=self.max_slayers {
+      let bit_pos = node.group_start(self.k, x, y) + node.predicate_pos;
+      if !self.layer_bits(layer).get(bit_pos) { return Ok(false) }
+      if layer < self.max_slayers {
+        node = self.child(layer, &node, x, y);
+      }
+    }
+    Ok(true)
+  }
+  pub fn set(&mut self, x: usize, y: usize, predicate: usize, state: bool) -> Result<()> {
+    self.check_bounds(x, y, predicate)?;
+    if state { self.insert(x, y, predicate) }
+    else { self.remove(x, y, predicate) }
+  }
+  /* Iteration */
+  pub fn iter(&self) -> Cells {
+    self.iter_range(0..self.matrix_width, 0..self.matrix_height, None)
+  }
+  pub fn iter_range(&self, x_range: std::ops::Range<usize>, y_range: std::ops::Range<usize>, predicate: Option<usize>) -> Cells {
+    /* Every [x, y, predicate] set within the ranges, of one predicate
+    or all of them, in the order of the cells and then the predicates */
+    let x_end = std::cmp::min(x_range.end, self.matrix_width);
+    let y_end = std::cmp::min(y_range.end, self.matrix_height);
+    let mut stack = Vec::new();
+    if x_range.start < x_end && y_range.start < y_end {
+      let tree_width = self.tree_width();
+      stack.push(CellNode::Stem(0, 0, (0..self.num_predicates).collect(), [[0, tree_width-1], [0, tree_width-1]]));
+    }
+    Cells {
+      tree: self,
+      query: [[x_range.start, x_end.saturating_sub(1)], [y_range.start, y_end.saturating_sub(1)]],
+      predicate: predicate,
+      stack: stack,
+    }
+  }
+  pub fn successors(&self, x: usize) -> Cells {
+    /* Every cell in column x, across all predicates */
+    self.iter_range(x..x+1, 0..self.matrix_height, None)
+  }
+  pub fn predecessors(&self, y: usize) -> Cells {
+    /* Every cell in row y, across all predicates */
+    self.iter_range(0..self.matrix_width, y..y+1, None)
+  }
+  pub fn predicates_at(&self, x: usize, y: usize) -> impl Iterator<Item=usize> + '_ {
+    self.iter_range(x..x+1, y..y+1, None).map(|[_, _, predicate]| predicate)
+  }
+  pub fn predicate_slice(&self, predicate: usize) -> Result<K2Tree> {
+    /* The K2Tree of a single predicate's cells */
+    if predicate >= self.num_predicates {
+      return Err(Error::PredicateOutOfBounds(predicate, self.num_predicates))
+    }
+    K2Tree::from_coordinates_with_extents(
+      self.k,
+      self.matrix_width,
+      self.matrix_height,
+      self.iter_range(0..self.matrix_width, 0..self.matrix_height, Some(predicate)).map(|[x, y, _]| (x, y))
+    )
+  }
+  /* Resizing */
+  pub fn grow(&mut self) {
+    /* Both extents grow by a factor of k (or to the new tree width
+    if they were 0), the old root becomes the new
+    root's first child, keeping only the predicates that have cells */
+    let num_predicates = self.num_predicates;
+    let stem_len = self.stem_len();
+    let used: Vec<bool> = (0..num_predicates).map(|predicate| !self.predicate_is_empty(predicate)).collect();
+    let root_len = stem_len * num_predicates;
+    let mut stems = bitvec![0; root_len];
+    for (predicate, &used) in used.iter().enumerate() {
+      stems.set(predicate, used);
+    }
+    for child_pos in 0..stem_len {
+      for predicate in (0..num_predicates).filter(|&predicate| used[predicate]) {
+        stems.push(self.stems.get(child_pos*num_predicates + predicate));
+      }
+    }
+    let first_child_len = stems.len() - root_len;
+    stems.extend(self.stems.as_bitslice()[root_len..].iter());
+    let mut slayer_starts = vec![0, root_len];
+    for &slayer_start in &self.slayer_starts[1..] {
+      slayer_starts.push(slayer_start + first_child_len);
+    }
+    self.max_slayers += 1;
+    self.matrix_width = grown_extent(self.matrix_width, self.k, self.tree_width());
+    self.matrix_height = grown_extent(self.matrix_height, self.k, self.tree_width());
+    self.slayer_starts = slayer_starts;
+    self.stems = stems.into();
+  }
+  pub fn resize(&mut self, width: usize, height: usize) -> Result<()> {
+    /* Grows the stems to cover the new extents if needed, but
+    never removes layers. Any cells outside the new extents is an error */
+    if self.iter_range(width..self.matrix_width, 0..self.matrix_height, None).next().is_some()
+    || self.iter_range(0..self.matrix_width, height..self.matrix_height, None).next().is_some() {
+      return Err(Error::CouldNotShrink("there are cells set outside of the new extents".into()))
+    }
+    while self.tree_width() < std::cmp::max(width, height) {
+      self.grow();
+    }
+    self.matrix_width = width;
+    self.matrix_height = height;
+    Ok(())
+  }
+  /* Serialization / Deserialization */
+  pub fn write_to(&self, writer: &mut impl std::io::Write) -> Result<()> {
+    /* See BINARY_MAGIC for the layout */
+    let stems = bits_to_bytes(self.stems.as_bitslice());
+    let leaves = bits_to_bytes(self.leaves.as_bitslice());
+    let mut buf: Vec<u8> = Vec::with_capacity(
+      BINARY_HEADER_LEN + (self.slayer_starts.len() * 8) + stems.len() + leaves.len() + 8
+    );
+    buf.extend_from_slice(&BINARY_MAGIC);
+    buf.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes());
+    for &field in [
+      self.k,
+      self.matrix_width,
+      self.matrix_height,
+      self.max_slayers,
+      self.num_predicates,
+      self.stems.len(),
+      self.leaves.len()
+    ].iter() {
+      buf.extend_from_slice(&(field as u64).to_le_bytes());
+    }
+    for &slayer_start in self.slayer_starts.iter() {
+      buf.extend_from_slice(&(slayer_start as u64).to_le_bytes());
+    }
+    buf.extend_from_slice(&stems);
+    buf.extend_from_slice(&leaves);
+    let checksum = fnv1a(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    writer.write_all(&buf)?;
+    Ok(())
+  }
+  pub fn read_from(mut reader: impl std::io::Read) -> Result<Self> {
+    let mut bytes: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    if bytes.len() < BINARY_HEADER_LEN + 8 {
+      return Err(invalid_binary("too short to hold a header"))
+    }
+    if bytes[0..4] != BINARY_MAGIC {
+      return Err(invalid_binary("missing magic number"))
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != BINARY_VERSION {
+      return Err(Error::InvalidBinary(format!("unsupported version {}", version)))
+    }
+    let end_offset = bytes.len() - 8;
+    let mut checksum = [0u8; 8];
+    checksum.copy_from_slice(&bytes[end_offset..]);
+    if u64::from_le_bytes(checksum) != fnv1a(&bytes[..end_offset]) {
+      return Err(invalid_binary("checksum mismatch"))
+    }
+    let read_u64 = |offset: usize| -> usize {
+      let mut field = [0u8; 8];
+      field.copy_from_slice(&bytes[offset..offset+8]);
+      u64::from_le_bytes(field) as usize
+    };
+    let k = read_u64(8);
+    let matrix_width = read_u64(16);
+    let matrix_height = read_u64(24);
+    let max_slayers = read_u64(32);
+    let num_predicates = read_u64(40);
+    let stems_len = read_u64(48);
+    let leaves_len = read_u64(56);
+    if k < 2 {
+      return Err(Error::InvalidBinary(format!("k must be at least 2, got {}", k)))
+    }
+    if max_slayers == 0 || !stems_cover(k, max_slayers, matrix_width, matrix_height) {
+      return Err(invalid_binary("the stems do not cover the matrix"))
+    }
+    /* Every field is untrusted, so an offset that overflows is as
+    invalid as one past the end of the input */
+    let section_lengths = || invalid_binary("section lengths do not match the size of the input");
+    let stems_offset = max_slayers.checked_mul(8)
+      .and_then(|len| len.checked_add(BINARY_HEADER_LEN))
+      .ok_or_else(section_lengths)?;
+    let leaves_offset = stems_offset.checked_add(bytes_for_bits(stems_len))
+      .ok_or_else(section_lengths)?;
+    if leaves_offset > end_offset
+    || leaves_offset.checked_add(bytes_for_bits(leaves_len)) != Some(end_offset) {
+      return Err(section_lengths())
+    }
+    let slayer_starts: Vec<usize> = (0..max_slayers).map(|i| read_u64(BINARY_HEADER_LEN + (i * 8))).collect();
+    if slayer_starts[0] != 0
+    || slayer_starts.windows(2).any(|starts| starts[0] > starts[1])
+    || slayer_starts[max_slayers-1] > stems_len {
+      return Err(invalid_binary("stem layer starts are not in ascending order within the stems"))
+    }
+    let stems = BitVec::from(&bitvec::slice::BitSlice::from_slice(&bytes[stems_offset..leaves_offset])[..stems_len]);
+    let leaves = BitVec::from(&bitvec::slice::BitSlice::from_slice(&bytes[leaves_offset..end_offset])[..leaves_len]);
+    /* The root has a group of bits per child for every predicate, and every
+    1 a child with k*k bits in the next layer, so each layer's length
+    follows from the one before it, the leaves' from the last stem layer */
+    let stem_len = k * k;
+    let mut layer_len = num_predicates.checked_mul(stem_len);
+    for layer in 0..max_slayers {
+      let layer_end = if layer+1 < max_slayers { slayer_starts[layer+1] } else { stems_len };
+      let layer_bits = &stems[slayer_starts[layer]..layer_end];
+      if layer_len != Some(layer_bits.len()) {
+        return Err(Error::InvalidBinary(format!("stem layer {} does not have a group for each predicate of its nodes", layer)))
+      }
+      layer_len = layer_bits.count_ones().checked_mul(stem_len);
+    }
+    if layer_len != Some(leaves_len) {
+      return Err(invalid_binary("the leaves do not match the 1s in the last stem layer"))
+    }
+    Ok(IK2Tree {
+      matrix_width: matrix_width,
+      matrix_height: matrix_height,
+      k: k,
+      max_slayers: max_slayers,
+      num_predicates: num_predicates,
+      slayer_starts: slayer_starts,
+      stems: stems.into(),
+      leaves: leaves.into(),
+    })
+  }
+}
+
+/* Iterators */
+enum CellNode {
+  Stem(usize, usize, Vec<usize>, Range), //layer, node_start, predicates, node_range
+  Cell([usize; 3]),
+}
+pub struct Cells<'a> {
+  tree: &'a IK2Tree,
+  query: Range,
+  predicate: Option<usize>,
+  stack: Vec<CellNode>,
+}
+impl<'a> Iterator for Cells<'a> {
+  type Item = [usize; 3];
+  fn next(&mut self) -> Option<Self::Item> {
+    while let Some(node) = self.stack.pop() {
+      match node {
+        CellNode::Stem(layer, node_start, predicates, range) => {
+          self.push_children(layer, node_start, &predicates, range);
+        },
+        CellNode::Cell(cell) => return Some(cell),
+      }
+    }
+    None
+  }
+}
+impl<'a> Cells<'a> {
+  pub fn split(&mut self) -> Option<Self> {
+    /* Hands the later half of the subtrees still to visit to a new
+    iterator, as K2Tree's cells are split */
+    while self.stack.len() == 1 {
+      match self.stack.pop() {
+        Some(CellNode::Stem(layer, node_start, predicates, range)) => {
+          self.push_children(layer, node_start, &predicates, range);
+        },
+        Some(cell) => {
+          self.stack.push(cell);
+          return None
+        },
+        None => return None,
+      }
+    }
+    if self.stack.len() < 2 { return None }
+    /* The stack is popped from the back, so later subtrees are at the front */
+    let later: Vec<CellNode> = self.stack.drain(..self.stack.len()/2).collect();
+    Some(Cells {
+      tree: self.tree,
+      query: self.query,
+      predicate: self.predicate,
+      stack: later,
+    })
+  }
+  fn push_children(&mut self, layer: usize, node_start: usize, predicates: &[usize], range: Range) {
+    let tree = self.tree;
+    let bits = tree.layer_bits(layer);
+    let subranges = to_subranges(range, tree.k);
+    /* Push children backwards so they're visited in order */
+    for child_pos in (0..tree.stem_len()).rev() {
+      if !ranges_overlap(&subranges[child_pos], &self.query) {
+        continue
+      }
+      let group_start = node_start + (child_pos * predicates.len());
+      if layer == tree.max_slayers {
+        let [x, y] = [subranges[child_pos][0][0], subranges[child_pos][1][0]];
+        if !within_range(&self.query, x, y) { continue }
+        for (predicate_pos, &predicate) in predicates.iter().enumerate().rev() {
+          if bits.get(group_start + predicate_pos)
+          && self.predicate.map_or(true, |wanted| wanted == predicate) {
+            self.stack.push(CellNode::Cell([x, y, predicate]));
+          }
+        }
+        continue
+      }
+      let child_predicates: Vec<usize> = predicates.iter()
+        .enumerate()
+        .filter(|&(predicate_pos, _)| bits.get(group_start + predicate_pos))
+        .map(|(_, &predicate)| predicate)
+        .collect();
+      if child_predicates.is_empty()
+      || self.predicate.map_or(false, |wanted| child_predicates.binary_search(&wanted).is_err()) {
+        continue
+      }
+      let child_start = tree.child_start(layer, group_start);
+      self.stack.push(CellNode::Stem(layer+1, child_start, child_predicates, subranges[child_pos]));
+    }
+  }
+}
+
+/* Binary layout written by write_to, all integers little-endian:
+   0..4      magic b"RIK2"
+   4..6      version (u16), currently 1
+   6..8      flags (u16), always 0
+   8..16     k (u64)
+   16..24    matrix_width (u64)
+   24..32    matrix_height (u64)
+   32..40    max_slayers, L (u64)
+   40..48    number of predicates (u64)
+   48..56    number of bits in stems (u64)
+   56..64    number of bits in leaves (u64)
+   64..      L stem layer starts (u64 each)
+   then      stems, leaves, each packed as in a K2Tree's binary form
+   last 8    FNV-1a 64 hash of every byte before it (u64) */
+const BINARY_MAGIC: [u8; 4] = *b"RIK2";
+const BINARY_VERSION: u16 = 1;
+const BINARY_HEADER_LEN: usize = 64;
+
+/* Private */
+/* Where a descent for a single predicate is in a node */
+struct Node {
+  start: usize, //first bit in the node's layer
+  num_predicates: usize,
+  predicate_pos: usize, //position of the predicate among the node's
+  range: Range,
+}
+impl Node {
+  fn group_start(&self, k: usize, x: usize, y: usize) -> usize {
+    self.start + (child_pos(self.range, k, x, y) * self.num_predicates)
+  }
+}
+impl IK2Tree {
+  fn stem_len(&self) -> usize {
+    self.k * self.k
+  }
+  fn tree_width(&self) -> usize {
+    self.k.pow(self.max_slayers as u32 + 1)
+  }
+  fn max_coords(&self) -> [usize; 2] {
+    /* An empty matrix has no valid coordinates, report 0s rather than underflow */
+    [self.matrix_width.saturating_sub(1), self.matrix_height.saturating_sub(1)]
+  }
+  fn check_bounds(&self, x: usize, y: usize, predicate: usize) -> Result<()> {
+    if x >= self.matrix_width || y >= self.matrix_height {
+      return Err(Error::OutOfBounds([x, y], self.max_coords()))
+    }
+    if predicate >= self.num_predicates {
+      return Err(Error::PredicateOutOfBounds(predicate, self.num_predicates))
+    }
+    Ok(())
+  }
+  fn layer_bits(&self, layer: usize) -> &GrowableBits {
+    if layer == self.max_slayers { &self.leaves } else { &self.stems }
+  }
+  fn layer_bits_mut(&mut self, layer: usize) -> &mut GrowableBits {
+    if layer == self.max_slayers { &mut self.leaves } else { &mut self.stems }
+  }
+  fn layer_start(&self, layer: usize) -> usize {
+    /* Leaves are in their own store, so start at 0 */
+    if layer == self.max_slayers { 0 } else { self.slayer_starts[layer] }
+  }
+  fn shift_layers(&mut self, first_layer: usize, by: isize) {
+    for slayer_start in self.slayer_starts.iter_mut().skip(first_layer) {
+      *slayer_start = (*slayer_start as isize + by) as usize;
+    }
+  }
+  fn child_start(&self, layer: usize, group_start: usize) -> usize {
+    /* Every 1 in a layer before the group has k*k bits
+    in the next, so its child starts after all of them */
+    let ones_before = self.stems.rank(group_start) - self.stems.rank(self.layer_start(layer));
+    self.layer_start(layer+1) + (ones_before * self.stem_len())
+  }
+  fn root(&self, predicate: usize) -> Node {
+    let tree_width = self.tree_width();
+    Node {
+      start: 0,
+      num_predicates: self.num_predicates,
+      predicate_pos: predicate,
+      range: [[0, tree_width-1], [0, tree_width-1]],
+    }
+  }
+  fn child(&self, layer: usize, node: &Node, x: usize, y: usize) -> Node {
+    /* The child of a stem node holding (x, y), the bit for
+    the predicate in the node must be set */
+    let group_start = node.group_start(self.k, x, y);
+    let bit_pos = group_start + node.predicate_pos;
+    Node {
+      start: self.child_start(layer, group_start),
+      num_predicates: self.stems.rank(group_start + node.num_predicates) - self.stems.rank(group_start),
+      predicate_pos: self.stems.rank(bit_pos) - self.stems.rank(group_start),
+      range: to_subranges(node.range, self.k)[child_pos(node.range, self.k, x, y)],
+    }
+  }
+  fn insert(&mut self, x: usize, y: usize, predicate: usize) -> Result<()> {
+    let stem_len = self.stem_len();
+    let mut node = self.root(predicate);
+    for layer in 0..self.max_slayers {
+      let bit_pos = node.group_start(self.k, x, y) + node.predicate_pos;
+      let bit_set = self.stems.get(bit_pos);
+      let child = self.child(layer, &node, x, y);
+      if bit_set {
+        node = child;
+        continue
+      }
+      /* The predicate is new to the child, give it a 0 in each of the
+      child's groups, which creates the child if it had no predicates */
+      if let Err(()) = self.stems.set(bit_pos, true) {
+        return Err(Error::TraverseError(x, y))
+      }
+      let num_predicates = child.num_predicates + 1;
+      for child_pos in 0..stem_len {
+        let new_bit = child.start + (child_pos * num_predicates) + child.predicate_pos;
+        if let Err(()) = self.layer_bits_mut(layer+1).insert_block(new_bit, 1) {
+          return Err(if layer+1 == self.max_slayers {
+            Error::LeafInsertionError(new_bit, 1)
+          }
+          else {
+            Error::StemInsertionError(new_bit, 1)
+          })
+        }
+      }
+      if layer+1 < self.max_slayers {
+        self.shift_layers(layer+2, stem_len as isize);
+      }
+      node = Node {
+        num_predicates: num_predicates,
+        ..child
+      };
+    }
+    let bit_pos = node.group_start(self.k, x, y) + node.predicate_pos;
+    if let Err(()) = self.leaves.set(bit_pos, true) {
+      return Err(Error::TraverseError(x, y))
+    }
+    Ok(())
+  }
+  fn remove(&mut self, x: usize, y: usize, predicate: usize) -> Result<()> {
+    let stem_len = self.stem_len();
+    /* Every node from the root to the leaf and the bit for the predicate in each */
+    let mut path: Vec<(Node, usize)> = Vec::with_capacity(self.max_slayers+1);
+    let mut node = self.root(predicate);
+    for layer in 0..=self.max_slayers {
+      let bit_pos = node.group_start(self.k, x, y) + node.predicate_pos;
+      if !self.layer_bits(layer).get(bit_pos) { return Ok(()) }
+      let child = if layer < self.max_slayers { Some(self.child(layer, &node, x, y)) } else { None };
+      path.push((node, bit_pos));
+      match child {
+        Some(child) => node = child,
+        None => break,
+      }
+    }
+    let _ = self.leaves.set(path[self.max_slayers].1, false);
+    /* Take the predicate out of each node left with no 1s for it,
+    clearing its bit in the parent, up to the root */
+    for layer in (1..=self.max_slayers).rev() {
+      let (node, _) = &path[layer];
+      let bits = self.layer_bits(layer);
+      let predicate_bits: Vec<usize> = (0..stem_len)
+        .map(|child_pos| node.start + (child_pos * node.num_predicates) + node.predicate_pos)
+        .collect();
+      if predicate_bits.iter().any(|&bit_pos| bits.get(bit_pos)) {
+        break
+      }
+      for &bit_pos in predicate_bits.iter().rev() {
+        if let Err(()) = self.layer_bits_mut(layer).remove_block(bit_pos, 1) {
+          return Err(if layer == self.max_slayers {
+            Error::LeafRemovalError(bit_pos, 1)
+          }
+          else {
+            Error::StemRemovalError(bit_pos, 1)
+          })
+        }
+      }
+      if layer < self.max_slayers {
+        self.shift_layers(layer+1, -(stem_len as isize));
+      }
+      let _ = self.stems.set(path[layer-1].1, false);
+    }
+    Ok(())
+  }
+}
+impl Default for IK2Tree {
+  fn default() -> Self {
+    IK2Tree::new()
+  }
+}
+
+/* Utils */
+fn child_pos(range: Range, k: usize, x: usize, y: usize) -> usize {
+  /* Which of range's k*k submatrices holds (x, y) */
+  let sub_width = (range[0][1] - range[0][0] + 1) / k;
+  let col = (x - range[0][0]) / sub_width;
+  let row = (y - range[1][0]) / sub_width;
+  (row * k) + col
+}
+
+/* Unit Tests */
+#[cfg(test)]
+mod unit_tests {
+  use super::*;
+  use rand::Rng;
+  fn sorted<I: Iterator<Item=[usize; 3]>>(cells: I) -> Vec<[usize; 3]> {
+    let mut cells: Vec<[usize; 3]> = cells.collect();
+    cells.sort();
+    cells
+  }
+  #[test]
+  fn ik2_tree_0() {
+    let mut tree = IK2Tree::with_extents(2, 8, 8);
+    let p0 = tree.add_predicate();
+    let p1 = tree.add_predicate();
+    tree.set(1, 0, p0, true).unwrap();
+    tree.set(1, 0, p1, true).unwrap();
+    tree.set(6, 7, p1, true).unwrap();
+    /* Root groups of 2, one node per child with 1s, its groups as wide
+    as the predicates set in the parent's group */
+    assert_eq!(tree.stems.as_bitslice()[..8], bitvec![1,1, 0,0, 0,0, 0,1][..]);
+    assert!(tree.get(1, 0, p0).unwrap() && tree.get(1, 0, p1).unwrap());
+    assert!(!tree.get(6, 7, p0).unwrap());
+    assert_eq!(tree.predicates_at(1, 0).collect::<Vec<_>>(), vec![p0, p1]);
+    assert_eq!(tree.iter().collect::<Vec<_>>(), vec![[1, 0, p0], [1, 0, p1], [6, 7, p1]]);
+    assert_eq!(tree.successors(1).collect::<Vec<_>>(), vec![[1, 0, p0], [1, 0, p1]]);
+    assert_eq!(tree.predecessors(7).collect::<Vec<_>>(), vec![[6, 7, p1]]);
+    assert_eq!(tree, IK2Tree::from_triples(2, 8, 8, 2, vec![[6, 7, p1], [1, 0, p0], [1, 0, p1]]).unwrap());
+    /* Removing every cell leaves just the root */
+    tree.set(1, 0, p0, false).unwrap();
+    tree.set(1, 0, p1, false).unwrap();
+    tree.set(6, 7, p1, false).unwrap();
+    assert!(tree.is_empty());
+    assert_eq!(tree.stems.len(), 8);
+    assert!(tree.leaves.is_empty());
+    tree.pop_predicate().unwrap();
+    assert_eq!(tree.num_predicates(), 1);
+    assert!(tree.get(0, 0, 1).is_err());
+    assert!(tree.set(8, 0, 0, true).is_err());
+  }
+  #[test]
+  fn ik2_tree_1() {
+    let mut rng = rand::thread_rng();
+    let mut tree = IK2Tree::with_extents(3, 40, 30);
+    for _ in 0..5 { tree.add_predicate(); }
+    let mut set = std::collections::BTreeSet::new();
+    for _ in 0..800 {
+      let cell = [rng.gen_range(0, 40), rng.gen_range(0, 30), rng.gen_range(0, 5)];
+      let state = rng.gen_range(0, 3) != 0;
+      tree.set(cell[0], cell[1], cell[2], state).unwrap();
+      if state { set.insert(cell); } else { set.remove(&cell); }
+    }
+    assert_eq!(sorted(tree.iter()), sorted(set.iter().cloned()));
+    assert_eq!(tree.count_ones(), set.len());
+    assert_eq!(tree, IK2Tree::from_triples(3, 40, 30, 5, set.iter().cloned()).unwrap());
+    for predicate in 0..5 {
+      let cells: Vec<(usize, usize)> = set.iter()
+        .filter(|cell| cell[2] == predicate)
+        .map(|&[x, y, _]| (x, y))
+        .collect();
+      assert_eq!(tree.predicate_slice(predicate).unwrap(), K2Tree::from_coordinates_with_extents(3, 40, 30, cells).unwrap());
+    }
+    assert_eq!(sorted(tree.predecessors(12)), sorted(set.iter().cloned().filter(|cell| cell[1] == 12)));
+    /* Growing keeps every cell */
+    tree.resize(100, 100).unwrap();
+    assert_eq!(sorted(tree.iter()), sorted(set.iter().cloned()));
+    tree.set(99, 99, 4, true).unwrap();
+    assert!(tree.resize(50, 50).is_err());
+    /* Binary round trip */
+    let mut bytes = Vec::new();
+    tree.write_to(&mut bytes).unwrap();
+    assert_eq!(IK2Tree::read_from(&bytes[..]).unwrap(), tree);
+    bytes[70] ^= 1;
+    assert!(IK2Tree::read_from(&bytes[..]).is_err());
+    /* Growing an empty matrix gives it the whole tree */
+    let mut tree = IK2Tree::with_extents(2, 0, 0);
+    tree.grow();
+    assert_eq!((tree.matrix_width(), tree.matrix_height()), (16, 16));
+  }
+  #[test]
+  fn ik2_tree_split_0() {
+    let mut rng = rand::thread_rng();
+    let triples: Vec<[usize; 3]> = (0..500).map(|_| [rng.gen_range(0, 64), rng.gen_range(0, 64), rng.gen_range(0, 3)]).collect();
+    let tree = IK2Tree::from_triples(2, 64, 64, 3, triples).unwrap();
+    let expected: Vec<[usize; 3]> = tree.iter().collect();
+    let mut cells = tree.iter();
+    let later = cells.split().unwrap();
+    assert_eq!(cells.chain(later).collect::<Vec<_>>(), expected);
+    assert!(IK2Tree::new().iter().split().is_none());
+  }
+  #[test]
+  fn ik2_tree_binary_0() {
+    let tree = IK2Tree::from_triples(2, 16, 16, 3, vec![[1, 2, 0], [1, 2, 2], [9, 14, 1]]).unwrap();
+    let mut bytes = Vec::new();
+    tree.write_to(&mut bytes).unwrap();
+    assert_eq!(IK2Tree::read_from(&bytes[..]).unwrap(), tree);
+    /* Rewrites a header field and the checksum, so only the field is wrong */
+    let with_field = |offset: usize, value: u64| -> Vec<u8> {
+      let mut bytes = bytes.clone();
+      bytes[offset..offset+8].copy_from_slice(&value.to_le_bytes());
+      let end_offset = bytes.len() - 8;
+      let checksum = fnv1a(&bytes[..end_offset]);
+      bytes[end_offset..].copy_from_slice(&checksum.to_le_bytes());
+      bytes
+    };
+    /* Overflowing lengths and offsets */
+    assert!(IK2Tree::read_from(&with_field(32, u64::MAX)[..]).is_err());
+    assert!(IK2Tree::read_from(&with_field(48, u64::MAX)[..]).is_err());
+    assert!(IK2Tree::read_from(&with_field(56, u64::MAX - 7)[..]).is_err());
+    /* More predicates than the root has groups for */
+    assert!(IK2Tree::read_from(&with_field(40, 4)[..]).is_err());
+    /* A stem layer starting out of order or at the wrong node */
+    assert!(IK2Tree::read_from(&with_field(64, 4)[..]).is_err());
+    assert!(IK2Tree::read_from(&with_field(72, 4)[..]).is_err());
+    /* An empty matrix reports its bounds rather than panicking */
+    assert!(IK2Tree::with_extents(2, 0, 0).get(0, 0, 0).is_err());
+  }
+}
@@ -0,0 +1,270 @@
+use crate::{
+  errors::K2TreeError as Error,
+  datastore::k2_tree::{self, K2Tree},
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/* A directed graph with unlabeled nodes 0 to num_nodes-1, stored as its
+adjacency matrix in a square K2Tree. The edge (from, to) is the 1 at
+column from, row to, so a node's out-neighbours are the successors of its
+column and its in-neighbours the predecessors of its row */
+#[derive(Debug, Clone, PartialEq)]
+pub struct K2Graph {
+  num_nodes: usize,
+  tree: K2Tree,
+}
+
+/* Public */
+impl K2Graph {
+  /* Creation */
+  pub fn new() -> Self {
+    K2Graph::with_k(2)
+  }
+  pub fn with_k(k: usize) -> Self {
+    K2Graph {
+      num_nodes: 0,
+      tree: K2Tree::with_no_extents(k),
+    }
+  }
+  pub fn with_nodes(k: usize, num_nodes: usize) -> Result<Self> {
+    Ok(K2Graph {
+      num_nodes: num_nodes,
+      tree: K2Tree::with_extents(k, num_nodes, num_nodes)?,
+    })
+  }
+  pub fn from_edges<I: IntoIterator<Item=(usize, usize)>>(k: usize, edges: I) -> Result<Self> {
+    /* Has as many nodes as it takes to hold every edge */
+    let edges: Vec<(usize, usize)> = edges.into_iter().collect();
+    let mut num_nodes = 0;
+    for &(from, to) in edges.iter() {
+      num_nodes = std::cmp::max(num_nodes, nodes_holding(k, from, to)?);
+    }
+    Ok(K2Graph {
+      num_nodes: num_nodes,
+      tree: K2Tree::from_coordinates_with_extents(k, num_nodes, num_nodes, edges)?,
+    })
+  }
+  /* Information */
+  pub fn num_nodes(&self) -> usize {
+    self.num_nodes
+  }
+  pub fn num_edges(&self) -> usize {
+    self.tree.count_ones()
+  }
+  pub fn k(&self) -> usize {
+    self.tree.k()
+  }
+  pub fn tree(&self) -> &K2Tree {
+    &self.tree
+  }
+  pub fn into_tree(self) -> K2Tree {
+    self.tree
+  }
+  /* Operation */
+  pub fn add_node(&mut self) -> Result<usize> {
+    /* A new node with no edges, its id is the one after the last */
+    self.fit_nodes(self.num_nodes + 1)?;
+    Ok(self.num_nodes - 1)
+  }
+  pub fn has_edge(&self, from: usize, to: usize) -> bool {
+    from < self.num_nodes
+    && to < self.num_nodes
+    && self.tree.get(from, to).unwrap_or(false)
+  }
+  pub fn add_edge(&mut self, from: usize, to: usize) -> Result<()> {
+    /* Adds any nodes up to from and to that aren't there yet */
+    self.fit_nodes(nodes_holding(self.k(), from, to)?)?;
+    self.tree.set(from, to, true)
+  }
+  pub fn remove_edge(&mut self, from: usize, to: usize) -> Result<()> {
+    /* Nodes are kept, even if they are left with no edges */
+    if from >= self.num_nodes || to >= self.num_nodes {
+      return Ok(())
+    }
+    self.tree.set(from, to, false)
+  }
+  pub fn out_neighbours(&self, node: usize) -> k2_tree::Successors {
+    /* Every to with (node, to) an edge, in ascending order */
+    self.tree.successors(node)
+  }
+  pub fn in_neighbours(&self, node: usize) -> k2_tree::Predecessors {
+    /* Every from with (from, node) an edge, in ascending order */
+    self.tree.predecessors(node)
+  }
+  pub fn out_degree(&self, node: usize) -> usize {
+    self.tree.count_in_column(node).unwrap_or(0)
+  }
+  pub fn in_degree(&self, node: usize) -> usize {
+    self.tree.count_in_row(node).unwrap_or(0)
+  }
+  pub fn degree(&self, node: usize) -> usize {
+    /* A loop counts once each way */
+    self.out_degree(node) + self.in_degree(node)
+  }
+  pub fn edges(&self) -> impl Iterator<Item=(usize, usize)> + '_ {
+    self.tree.iter_range(0..self.num_nodes, 0..self.num_nodes).map(|[from, to]| (from, to))
+  }
+  /* Edge lists */
+  pub fn read_edge_list(reader: impl std::io::BufRead, k: usize) -> Result<Self> {
+    /* One "from to" pair of node ids per line, separated by any whitespace.
+    Blank lines and lines starting with # or % are skipped, except a
+    "# nodes: n" line, which sets the number of nodes as written by
+    write_edge_list. Otherwise there are as many nodes as the edges need */
+    let mut num_nodes: Option<(usize, usize)> = None; //line, nodes
+    let mut edges: Vec<(usize, usize)> = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+      let line = line?;
+      let line = line.trim();
+      if line.is_empty() { continue }
+      if line.starts_with('#') || line.starts_with('%') {
+        if let Some(nodes) = line.trim_start_matches('#').trim().strip_prefix("nodes:") {
+          match nodes.trim().parse::<usize>() {
+            Ok(nodes) if !k2_tree::can_cover(k, nodes, nodes) => {
+              return Err(Error::InvalidEdgeList(i+1, format!("a K2Graph with k={} can't hold {} nodes", k, nodes)))
+            },
+            Ok(nodes) => num_nodes = Some((i+1, nodes)),
+            Err(_) => return Err(Error::InvalidEdgeList(i+1, format!("\"{}\" is not a number of nodes", nodes.trim()))),
+          }
+        }
+        continue
+      }
+      let ids: Vec<&str> = line.split_whitespace().collect();
+      if ids.len() != 2 {
+        return Err(Error::InvalidEdgeList(i+1, format!("expected 2 node ids, found {}", ids.len())))
+      }
+      let parse_id = |id: &str| id.parse::<usize>()
+        .map_err(|_| Error::InvalidEdgeList(i+1, format!("\"{}\" is not a node id", id)));
+      let (from, to) = (parse_id(ids[0])?, parse_id(ids[1])?);
+      nodes_holding(k, from, to).map_err(|e| Error::InvalidEdgeList(i+1, e.to_string()))?;
+      edges.push((from, to));
+    }
+    let mut graph = K2Graph::from_edges(k, edges)?;
+    if let Some((line, num_nodes)) = num_nodes {
+      if num_nodes < graph.num_nodes {
+        return Err(Error::InvalidEdgeList(line, format!("an edge has a node past the {} nodes declared", num_nodes)))
+      }
+      graph.fit_nodes(num_nodes)?;
+    }
+    Ok(graph)
+  }
+  pub fn write_edge_list(&self, writer: &mut impl std::io::Write) -> Result<()> {
+    /* Edges in order of from then to, after a "# nodes: n" line */
+    let mut edges: Vec<(usize, usize)> = self.edges().collect();
+    edges.sort();
+    let mut buf = format!("# nodes: {}\n", self.num_nodes);
+    for (from, to) in edges {
+      buf.push_str(&format!("{} {}\n", from, to));
+    }
+    writer.write_all(buf.as_bytes())?;
+    Ok(())
+  }
+}
+impl Default for K2Graph {
+  fn default() -> Self {
+    K2Graph::new()
+  }
+}
+
+/* Private */
+impl K2Graph {
+  fn fit_nodes(&mut self, num_nodes: usize) -> Result<()> {
+    if num_nodes > self.num_nodes {
+      self.tree.resize(num_nodes, num_nodes)?;
+      self.num_nodes = num_nodes;
+    }
+    Ok(())
+  }
+}
+
+/* Utils */
+fn nodes_holding(k: usize, from: usize, to: usize) -> Result<usize> {
+  /* The number of nodes it takes to hold the edge, as long as a usize can
+  count them and the width of a tree with k covering them */
+  let node = std::cmp::max(from, to);
+  match node.checked_add(1) {
+    Some(num_nodes) if k2_tree::can_cover(k, num_nodes, num_nodes) => Ok(num_nodes),
+    _ => Err(Error::NodeOutOfBounds(node, k)),
+  }
+}
+
+/* Unit Tests */
+#[cfg(test)]
+mod unit_tests {
+  use super::*;
+  #[test]
+  fn k2_graph_0() {
+    let mut graph = K2Graph::new();
+    graph.add_edge(0, 1).unwrap();
+    graph.add_edge(0, 2).unwrap();
+    graph.add_edge(2, 0).unwrap();
+    graph.add_edge(4, 4).unwrap();
+    assert_eq!(graph.num_nodes(), 5);
+    assert_eq!(graph.num_edges(), 4);
+    assert!(graph.has_edge(2, 0) && !graph.has_edge(1, 0) && !graph.has_edge(9, 0));
+    assert_eq!(graph.out_neighbours(0).collect::<Vec<_>>(), vec![1, 2]);
+    assert_eq!(graph.in_neighbours(0).collect::<Vec<_>>(), vec![2]);
+    assert_eq!((graph.out_degree(0), graph.in_degree(0), graph.degree(0)), (2, 1, 3));
+    assert_eq!(graph.degree(4), 2);
+    assert_eq!(graph.degree(3), 0);
+    assert_eq!(graph.add_node().unwrap(), 5);
+    graph.remove_edge(0, 2).unwrap();
+    graph.remove_edge(7, 0).unwrap();
+    assert_eq!(graph.num_nodes(), 6);
+    assert_eq!(graph.edges().collect::<Vec<_>>(), vec![(0, 1), (2, 0), (4, 4)]);
+    assert_eq!(graph, K2Graph::from_edges(2, vec![(4, 4), (0, 1), (2, 0), (5, 5)]).map(|mut graph| {
+      graph.remove_edge(5, 5).unwrap();
+      graph
+    }).unwrap());
+  }
+  #[test]
+  fn k2_graph_edge_list_0() {
+    let input = "# a comment\n% another\n0 3\n\n2\t1\n  3   0 \n";
+    let graph = K2Graph::read_edge_list(input.as_bytes(), 2).unwrap();
+    assert_eq!(graph.num_nodes(), 4);
+    assert_eq!(graph.out_neighbours(3).collect::<Vec<_>>(), vec![0]);
+    let mut bytes = Vec::new();
+    graph.write_edge_list(&mut bytes).unwrap();
+    assert_eq!(String::from_utf8(bytes.clone()).unwrap(), "# nodes: 4\n0 3\n2 1\n3 0\n");
+    assert_eq!(K2Graph::read_edge_list(&bytes[..], 2).unwrap(), graph);
+    /* Declared nodes past the last edge are kept */
+    let graph = K2Graph::read_edge_list("# nodes: 9\n0 1\n".as_bytes(), 3).unwrap();
+    assert_eq!(graph.num_nodes(), 9);
+    assert!(K2Graph::read_edge_list("0 1 2\n".as_bytes(), 2).is_err());
+    assert!(K2Graph::read_edge_list("0 x\n".as_bytes(), 2).is_err());
+    assert!(K2Graph::read_edge_list("# nodes: 1\n0 1\n".as_bytes(), 2).is_err());
+    /* Node ids with no room for one more node */
+    let max_id = format!("# a comment\n0 1\n{} 0\n", usize::MAX);
+    match K2Graph::read_edge_list(max_id.as_bytes(), 2) {
+      Err(Error::InvalidEdgeList(line, _)) => assert_eq!(line, 3),
+      result => panic!("expected an InvalidEdgeList error, got {:?}", result),
+    }
+    match K2Graph::read_edge_list("0 18446744073709551614\n".as_bytes(), 2) {
+      Err(Error::InvalidEdgeList(line, _)) => assert_eq!(line, 1),
+      result => panic!("expected an InvalidEdgeList error, got {:?}", result),
+    }
+    match K2Graph::read_edge_list(format!("# nodes: {}\n0 1\n", usize::MAX - 1).as_bytes(), 2) {
+      Err(Error::InvalidEdgeList(line, _)) => assert_eq!(line, 1),
+      result => panic!("expected an InvalidEdgeList error, got {:?}", result),
+    }
+    for &node in [usize::MAX, usize::MAX - 1, usize::MAX / 2 + 1].iter() {
+      match K2Graph::from_edges(2, vec![(0, 1), (2, node)]) {
+        Err(Error::NodeOutOfBounds(id, 2)) => assert_eq!(id, node),
+        result => panic!("expected a NodeOutOfBounds error, got {:?}", result),
+      }
+      let mut graph = K2Graph::new();
+      match graph.add_edge(node, 0) {
+        Err(Error::NodeOutOfBounds(id, 2)) => assert_eq!(id, node),
+        result => panic!("expected a NodeOutOfBounds error, got {:?}", result),
+      }
+      assert_eq!(graph, K2Graph::new());
+    }
+    /* The largest graph a k of 2 can hold still fits */
+    assert!(K2Graph::from_edges(2, vec![(usize::MAX / 2 - 1, 0)]).is_ok());
+    assert!(K2Graph::with_nodes(2, usize::MAX).is_err());
+    assert!(K2Tree::with_extents(2, usize::MAX, 0).is_err());
+    let mut tree = K2Tree::with_extents(2, 8, 8).unwrap();
+    assert!(tree.resize(usize::MAX, 8).is_err());
+    assert_eq!(tree, K2Tree::with_extents(2, 8, 8).unwrap());
+  }
+}
@@ -1,12 +1,13 @@
 
 use {
-  bitvec::{prelude::bitvec, vec::BitVec},
+  std::borrow::Cow,
+  bitvec::{prelude::bitvec, vec::BitVec, slice::BitSlice},
   serde::{
     Serialize,
     Deserialize,
     Serializer,
     Deserializer,
-    ser::SerializeStruct,
+    ser::{SerializeStruct, SerializeSeq},
     de::{self, Visitor, MapAccess}
   },
   crate::errors::K2TreeError as Error
@@ -17,106 +18,239 @@ pub type BitMatrix = Vec<BitVec>;
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Clone)]
-pub struct K2Tree {
-  matrix_width: usize,
+pub struct K2Tree<B: BitStore = GrowableBits> {
+  matrix_width: usize, //number of columns, x
+  matrix_height: usize, //number of rows, y
   k: usize, //k^2 == number of submatrices in each stem/leaf
   max_slayers: usize, //max stem layers
   slayer_starts: Vec<usize>, //stem layer starts
-  stems: BitVec,
+  stems: B, //answers rank/select over the stems itself
   stem_to_leaf: Vec<usize>,
-  leaves: BitVec,
+  leaves: LeafLayer<B>,
 }
 
 /* Public */
 impl K2Tree {
   /* Creation */
   pub fn new() -> Self {
-    /* For now fix k as 2, further work to make it user-defined */
-    let k: usize = 2;
+    K2Tree::with_k(2)
+  }
+  pub fn with_k(k: usize) -> Self {
+    /* A k of 0 or 1 can't subdivide the matrix, every descent would loop forever */
+    assert!(k >= 2, "K2Tree k must be at least 2, got {}", k);
     let mw = k.pow(3);
     K2Tree {
       matrix_width: mw,
+      matrix_height: mw,
       k: k,
-      max_slayers: (mw as f64).log(k as f64) as usize - 1,
+      max_slayers: log_k(mw, k) - 1,
       slayer_starts: vec![0],
-      stems: bitvec![0; k*k],
+      stems: GrowableBits::from_bitvec(bitvec![0; k*k]),
       stem_to_leaf: Vec::new(),
-      leaves: BitVec::new(),
+      leaves: BitVec::new().into(),
+    }
+  }
+  pub fn with_extents(k: usize, width: usize, height: usize) -> Result<Self> {
+    /* The stems still cover a square, the smallest one holding both extents */
+    let mut tree = K2Tree::with_k(k);
+    while tree.tree_width() < std::cmp::max(width, height) {
+      tree.grow()?;
+    }
+    tree.matrix_width = width;
+    tree.matrix_height = height;
+    Ok(tree)
+  }
+  pub(crate) fn with_no_extents(k: usize) -> Self {
+    /* What with_extents(k, 0, 0) builds, which never needs to grow */
+    K2Tree {
+      matrix_width: 0,
+      matrix_height: 0,
+      ..K2Tree::with_k(k)
     }
   }
   pub fn test_tree() -> Self {
+    let stems = bitvec![0,1,1,1, 1,1,0,1, 1,0,0,0, 1,0,0,0];
+    let leaves = bitvec![0,1,1,0, 0,1,0,1, 1,1,0,0, 1,0,0,0, 0,1,1,0];
     K2Tree {
       matrix_width: 8,
+      matrix_height: 8,
       k: 2,
       max_slayers: 2,
       slayer_starts: vec![0, 4],
-      stems:  bitvec![0,1,1,1, 1,1,0,1, 1,0,0,0, 1,0,0,0],
+      stems: stems.into(),
       stem_to_leaf: vec![0, 1, 3, 4, 8],
-      leaves: bitvec![0,1,1,0, 0,1,0,1, 1,1,0,0, 1,0,0,0, 0,1,1,0],
+      leaves: leaves.into(),
+    }
+  }
+  /* To / From */
+  pub fn from_matrix(m: BitMatrix) -> Result<Self> {
+    /* m[x] is the column at x, the height is that of the tallest column */
+    let height = m.iter().fold(0, |height, column| std::cmp::max(height, column.len()));
+    let mut coords: Vec<(usize, usize)> = Vec::new();
+    for (x, column) in m.iter().enumerate() {
+      for y in one_positions(column).into_iter() {
+        coords.push((x, y));
+      }
+    }
+    K2Tree::from_coordinates_with_extents(2, m.len(), height, coords)
+  }
+  pub fn from_coordinates<I: IntoIterator<Item=(usize, usize)>>(width: usize, coords: I) -> Result<Self> {
+    K2Tree::from_coordinates_with_k(width, 2, coords)
+  }
+  pub fn from_coordinates_with_k<I: IntoIterator<Item=(usize, usize)>>(width: usize, k: usize, coords: I) -> Result<Self> {
+    /* A square tree, the width is rounded up to the stems' */
+    let mut tree_width = k.pow(3);
+    while tree_width < width {
+      tree_width = tree_width.checked_mul(k).ok_or_else(|| too_wide(width, width))?;
+    }
+    K2Tree::from_coordinates_with_extents(k, tree_width, tree_width, coords)
+  }
+  pub fn from_coordinates_with_extents<I: IntoIterator<Item=(usize, usize)>>(k: usize, width: usize, height: usize, coords: I) -> Result<Self> {
+    /* Builds the same tree as calling set(x, y, true) for every pair,
+    but without shifting stems and leaves on every insert */
+    let mut tree = K2Tree::with_extents(k, width, height)?;
+    let mut keys: Vec<u128> = Vec::new();
+    for (x, y) in coords {
+      if x >= tree.matrix_width || y >= tree.matrix_height {
+        return Err(Error::OutOfBounds([x, y], tree.max_coords()))
+      }
+      keys.push(tree.z_order_key(x, y));
+    }
+    let (layers, leaves) = tree.layers_from_keys(keys);
+    tree.set_layers(layers, leaves);
+    Ok(tree)
+  }
+  /* Serialization / Deserialization */
+  pub fn from_json(json: &str) -> Result<Self> {
+    Ok(serde_json::from_str::<Self>(json)?)
+  }
+  pub fn read_from(mut reader: impl std::io::Read) -> Result<Self> {
+    let mut buf: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    Ok(K2TreeView::new(&buf)?.to_k2tree())
+  }
+}
+impl<'a> K2Tree<ByteBits<'a>> {
+  pub fn from_bytes(bytes: &'a [u8]) -> Result<Self> {
+    /* A tree over the bytes written by write_to, which its stores read in
+    place rather than copying, unless the leaves have a vocabulary */
+    let view = K2TreeView::new(bytes)?;
+    if view.full.is_some() {
+      return Err(invalid_binary("a K2Tree1's 1-regions can't be read in place, use K2Tree::read_from"))
     }
+    let final_layer_start = view.slayer_starts[view.slayer_starts.len()-1];
+    let stem_to_leaf = (final_layer_start..view.stems.len())
+      .filter(|&pos| view.stems[pos])
+      .map(|pos| pos - final_layer_start)
+      .collect();
+    let stem_ranks = match view.stem_ranks {
+      StemRanks::Index(index) => index.into_owned(),
+      StemRanks::Store(_) => RankIndex::new(view.stems),
+    };
+    Ok(K2Tree {
+      matrix_width: view.matrix_width,
+      matrix_height: view.matrix_height,
+      k: view.k,
+      max_slayers: view.max_slayers,
+      slayer_starts: view.slayer_starts.into_owned(),
+      stems: ByteBits {
+        bits: Cow::Borrowed(view.stems),
+        ranks: stem_ranks,
+      },
+      stem_to_leaf: stem_to_leaf,
+      leaves: match view.leaves {
+        LeafBits::Plain(bits) => LeafLayer::Plain(ByteBits::from_bitslice(bits)),
+        LeafBits::Vocabulary(vocabulary) => LeafLayer::Vocabulary(vocabulary.into_owned()),
+      },
+    })
   }
+}
+impl<B: BitStore> K2Tree<B> {
   /* Operation */
   pub fn is_empty(&self) -> bool {
-    ones_in_range(&self.leaves, 0, self.leaves.len()) == 0
+    match &self.leaves {
+      LeafLayer::Plain(leaves) | LeafLayer::Decoded(leaves) => leaves.rank(leaves.len()) == 0,
+      LeafLayer::Vocabulary(vocabulary) => !vocabulary.blocks.iter().any(|bit| bit),
+    }
   }
   pub fn get(&self, x: usize, y: usize) -> Result<bool> {
-    if x >= self.matrix_width || y >= self.matrix_width {
-      return Err(Error::OutOfBounds([x, y], [self.matrix_width-1; 2]))
+    if x >= self.matrix_width || y >= self.matrix_height {
+      return Err(Error::OutOfBounds([x, y], self.max_coords()))
     }
-    /* Assuming k=2 */
-    match self.matrix_bit(x, y, self.matrix_width)? {
+    match self.matrix_bit(x, y, self.tree_width())? {
       DescendResult::Leaf(leaf_start, leaf_range) => {
-        if leaf_range[0][1] - leaf_range[0][0] != 1
-        || leaf_range[1][1] - leaf_range[1][0] != 1 {
+        if leaf_range[0][1] - leaf_range[0][0] != self.k-1
+        || leaf_range[1][1] - leaf_range[1][0] != self.k-1 {
           return Err(Error::TraverseError(x, y))
         }
-        if x == leaf_range[0][0] {
-          if y == leaf_range[1][0] { Ok(self.leaves[leaf_start]) }
-          else { Ok(self.leaves[leaf_start+2]) }
-        }
-        else {
-          if y == leaf_range[1][0] { Ok(self.leaves[leaf_start+1]) }
-          else { Ok(self.leaves[leaf_start+3]) }
-        }
+        Ok(self.leaves[leaf_start + self.offset_in_block(&leaf_range, x, y)])
       },
       DescendResult::Stem(_, _) => Ok(false),
     }
   }
   pub fn get_row(&self, y: usize) -> Result<BitVec> {
-    let mut ret_v = BitVec::new();
-    for x in 0..self.matrix_width {
-      ret_v.push(self.get(x, y)?);
-    }
-    Ok(ret_v)
+    self.as_view().get_row(y)
   }
   pub fn get_column(&self, x: usize) -> Result<BitVec> {
-    let mut ret_v = BitVec::new();
-    for y in 0..self.matrix_width {
-      ret_v.push(self.get(x, y)?);
+    self.as_view().get_column(x)
+  }
+  pub fn count_ones(&self) -> usize {
+    self.leaf_ones(0..self.leaves.len())
+  }
+  pub fn count_in_row(&self, y: usize) -> Result<usize> {
+    if y >= self.matrix_height {
+      return Err(Error::OutOfBounds([0, y], self.max_coords()))
     }
-    Ok(ret_v)
+    Ok(self.count_in_range(0..self.matrix_width, y..y+1))
+  }
+  pub fn count_in_column(&self, x: usize) -> Result<usize> {
+    if x >= self.matrix_width {
+      return Err(Error::OutOfBounds([x, 0], self.max_coords()))
+    }
+    Ok(self.count_in_range(x..x+1, 0..self.matrix_height))
+  }
+  pub fn count_in_range(&self, x_range: std::ops::Range<usize>, y_range: std::ops::Range<usize>) -> usize {
+    /* Number of 1s in the range, subtrees wholly inside it are
+    counted from the leaf ranks without being descended into */
+    let x_end = std::cmp::min(x_range.end, self.matrix_width);
+    let y_end = std::cmp::min(y_range.end, self.matrix_height);
+    if x_range.start >= x_end
+    || y_range.start >= y_end
+    || self.leaves.is_empty() {
+      return 0
+    }
+    let query = [[x_range.start, x_end-1], [y_range.start, y_end-1]];
+    let tree_width = self.tree_width();
+    self.count_node(0, 0, [[0, tree_width-1], [0, tree_width-1]], &query)
+  }
+  pub fn successors(&self, x: usize) -> Successors<B> {
+    /* Every y with (x, y) set, in ascending order */
+    self.as_view().into_successors(x)
+  }
+  pub fn predecessors(&self, y: usize) -> Predecessors<B> {
+    /* Every x with (x, y) set, in ascending order */
+    self.as_view().into_predecessors(y)
   }
   pub fn set(&mut self, x: usize, y: usize, state: bool) -> Result<()> {
-    /* Assuming k=2 */
-    match self.matrix_bit(x, y, self.matrix_width)? {
+    if x >= self.matrix_width || y >= self.matrix_height {
+      return Err(Error::OutOfBounds([x, y], self.max_coords()))
+    }
+    let stem_len = self.stem_len();
+    match self.matrix_bit(x, y, self.tree_width())? {
       DescendResult::Leaf(leaf_start, leaf_range) => {
-        if leaf_range[0][1] - leaf_range[0][0] != 1
-        || leaf_range[1][1] - leaf_range[1][0] != 1 {
-          /* Final submatrix isn't a 2 by 2 so can't be a leaf */
+        if leaf_range[0][1] - leaf_range[0][0] != self.k-1
+        || leaf_range[1][1] - leaf_range[1][0] != self.k-1 {
+          /* Final submatrix isn't a k by k so can't be a leaf */
           return Err(Error::TraverseError(x, y))
         }
         /* Set the bit in the leaf to the new state */
-        if x == leaf_range[0][0] {
-          if y == leaf_range[1][0] { self.leaves.set(leaf_start, state); }
-          else { self.leaves.set(leaf_start+2, state); }
-        }
-        else {
-          if y == leaf_range[1][0] { self.leaves.set(leaf_start+1, state); }
-          else { self.leaves.set(leaf_start+3, state); }
+        let leaf_bit = leaf_start + self.offset_in_block(&leaf_range, x, y);
+        if let Err(()) = self.leaves.modify(|bits| bits.set(leaf_bit, state)) {
+          return Err(Error::ReadOnlyStore)
         }
         /* If leaf is now all 0's, remove leaf and alter rest of struct to reflect changes.
         Loop up the stems changing the parent bits to 0's and removing stems that become all 0's */
-        if ones_in_range(&self.leaves, leaf_start, leaf_start+3) == 0 {
+        if self.leaf_ones(leaf_start..leaf_start+stem_len) == 0 {
           /* - Remove the leaf
               - Use stem_to_leaf to find the dead leaf's parent bit
               - Remove the elem from stem_to_leaf that mapped to dead leaf
@@ -126,32 +260,41 @@ impl K2Tree {
               - - Alter layer_starts if needed
               - - Find parent bit and set to 0
               - - Repeat until reach stem that isn't all 0's or reach stem layer 0 */
-          if let Err(()) = remove_block(&mut self.leaves, leaf_start, 4) {
-            return Err(Error::LeafRemovalError(leaf_start, 4))
+          if let Err(()) = self.leaves.modify(|bits| bits.remove_block(leaf_start, stem_len)) {
+            return Err(Error::LeafRemovalError(leaf_start, stem_len))
           }
-          let stem_bit_pos = self.stem_to_leaf[leaf_start/4];
-          self.stem_to_leaf.remove(leaf_start/4);
+          let stem_bit_pos = self.stem_to_leaf[leaf_start/stem_len];
+          self.stem_to_leaf.remove(leaf_start/stem_len);
           if self.stem_to_leaf.is_empty() {
             /* If no more leaves, then remove all stems immediately
             and don't bother with complex stuff below */
-            self.stems = bitvec![0,0,0,0];
+            self.stems = B::from_bitvec(bitvec![0; stem_len]);
             self.slayer_starts = vec![0];
             return Ok(())
           }
           let layer_start = self.slayer_starts[self.slayer_starts.len()-1];
-          self.stems.set(layer_start + stem_bit_pos, false); //Dead leaf parent bit = 0
+          self.set_stem_bit(layer_start + stem_bit_pos, false)?; //Dead leaf parent bit = 0
           let mut curr_layer = self.slayer_starts.len()-1;
-          let mut stem_start = layer_start + block_start(stem_bit_pos, 4);
-          while curr_layer > 0 
-          && ones_in_range(&self.stems, stem_start, stem_start+3) == 0 {
+          let mut stem_start = layer_start + block_start(stem_bit_pos, stem_len);
+          while curr_layer > 0
+          && self.stem_ones(stem_start, stem_start+stem_len) == 0 {
+            /* Find the parent while the layer starts still describe the tree */
+            let (parent_stem_start, bit_offset) = self.parent(stem_start);
             for layer_start in &mut self.slayer_starts[curr_layer+1..] {
-              *layer_start -= 1; //Adjust lower layer start positions to reflect removal of stem
+              *layer_start -= stem_len; //Adjust lower layer start positions to reflect removal of stem
             }
-            let (parent_stem_start, bit_offset) = self.parent(stem_start);
-            if let Err(()) = remove_block(&mut self.stems, stem_start, 4) {
-              return Err(Error::StemRemovalError(stem_start, 4))
+            if curr_layer == self.slayer_starts.len()-1 {
+              /* Stems in the final layer after the removed one move back
+              by a stem, so the leaves' parent bits do too */
+              let removed_pos = stem_start - self.slayer_starts[curr_layer];
+              for parent_bit_pos in &mut self.stem_to_leaf {
+                if *parent_bit_pos > removed_pos { *parent_bit_pos -= stem_len; }
+              }
             }
-            self.stems.set(parent_stem_start + bit_offset, false);
+            if let Err(()) = self.stems.remove_block(stem_start, stem_len) {
+              return Err(Error::StemRemovalError(stem_start, stem_len))
+            }
+            self.set_stem_bit(parent_stem_start + bit_offset, false)?;
             stem_start = parent_stem_start;
             curr_layer -= 1;
           }
@@ -167,112 +310,135 @@ impl K2Tree {
           - Set bit at (x, y) to 1 */
         let mut layer_starts_len = self.slayer_starts.len();
         let mut layer = self.layer_from_range(stem_range);
-        let mut subranges: [Range; 4];
         /* Create correct stems in layers on the way down to the final layer,
         which points to the leaves */
         while layer < self.max_slayers-1 {
-          subranges = to_4_subranges(stem_range);
-          for child_pos in 0..4 {
-            if within_range(&subranges[child_pos], x, y) {
-              /* Change bit containing (x, y) to 1 */
-              self.stems.set(stem_start + child_pos, true);
-              /* If we're not at max possible layer, but at the lowest
-              but at the lowest existing layer: Create new layer before
-              adding new stem to it.
-              Otherwise: Find the correct position to add the new stem
-              in the child layer. */
-              if layer == layer_starts_len-1 {
-                stem_start = self.stems.len();
-                self.slayer_starts.push(stem_start);
-                layer_starts_len += 1;
-              }
-              else {
-                stem_start = match self.child_stem(layer, stem_start, child_pos) {
-                  Ok(ss) => ss,
-                  Err(()) => return Err(Error::TraverseError(x, y)),
-                };
-              }
-              /* We're now working on the child layer */
-              layer += 1;
-              stem_range = subranges[child_pos];
-              if let Err(()) = insert_block(&mut self.stems, stem_start, 4) {
-                return Err(Error::StemInsertionError(stem_start, 4))
-              }
-              /* If there are layers after the one we just insert a stem
-              into: Increase the layer_starts for them by 4 to account for
-              the extra stem */
-              if layer+1 <= layer_starts_len {
-                for layer_start in &mut self.slayer_starts[layer+1..layer_starts_len] {
-                  *layer_start += 4;
-                }
-              }
-              break //We've found the child, skip to next layer
+          let child_pos = self.offset_in_block(&stem_range, x, y);
+          /* Change bit containing (x, y) to 1 */
+          self.set_stem_bit(stem_start + child_pos, true)?;
+          /* If we're not at max possible layer, but at the lowest
+          but at the lowest existing layer: Create new layer before
+          adding new stem to it.
+          Otherwise: Find the correct position to add the new stem
+          in the child layer. */
+          if layer == layer_starts_len-1 {
+            stem_start = self.stems.len();
+            self.slayer_starts.push(stem_start);
+            layer_starts_len += 1;
+          }
+          else {
+            stem_start = match self.child_stem(layer, stem_start, child_pos) {
+              Ok(ss) => ss,
+              Err(()) => return Err(Error::TraverseError(x, y)),
+            };
+          }
+          /* We're now working on the child layer */
+          layer += 1;
+          stem_range = to_subranges(stem_range, self.k)[child_pos];
+          if let Err(()) = self.stems.insert_block(stem_start, stem_len) {
+            return Err(Error::StemInsertionError(stem_start, stem_len))
+          }
+          /* If there are layers after the one we just insert a stem
+          into: Increase the layer_starts for them by a stem's length
+          to account for the extra stem */
+          if layer+1 <= layer_starts_len {
+            for layer_start in &mut self.slayer_starts[layer+1..layer_starts_len] {
+              *layer_start += stem_len;
             }
           }
         }
         /* We're at the final stem layer */
-        subranges = to_4_subranges(stem_range);
-        for child_pos in 0..4 {
-          if within_range(&subranges[child_pos], x, y) {
-            /* Keep track of whether this stem is freshly created (all 0000s) */
-            let fresh_stem: bool = ones_in_range(&self.stems, stem_start, stem_start+4) == 0;
-            /* Set the correct stem bit to 1 */
-            self.stems.set(stem_start + child_pos, true);
-            /* Get the bit position within the final stem layer,
-            find the position in stem_to_leaf to insert the linking elem,
-            insert linking elem */
-            let layer_bit_pos = (stem_start + child_pos) - self.slayer_starts[layer_starts_len-1];
-            let mut stem_to_leaf_pos: usize = 0;
-            while stem_to_leaf_pos < self.stem_to_leaf.len()
-            && self.stem_to_leaf[stem_to_leaf_pos] < (layer_bit_pos/4)*4 {
-              //TODO: Clean up and make a note properly explaining why
-              //      we need to times then divide layer_bit_pos by 4
-              //      baso, we want to floor it to the nearest mult of 4
-              //      to get the beginning pos of the block we just
-              //      inserted into last layer of stems.
-              stem_to_leaf_pos += 1;
-            }
-            self.stem_to_leaf.insert(stem_to_leaf_pos, layer_bit_pos);
-            /* If stem is fresh, increase bit positions in stem_to_leaf
-            after the new elem by 4 to account for the new stem before them */
-            if fresh_stem {
-              let stem_to_leaf_len = self.stem_to_leaf.len();
-              for parent_bit_pos in &mut self.stem_to_leaf[stem_to_leaf_pos+1..stem_to_leaf_len] {
-                *parent_bit_pos += 4;
-              }
-            }
-            /* Create new leaf of all 0's */
-            let leaf_start = stem_to_leaf_pos * 4;
-            if let Err(()) = insert_block(&mut self.leaves, leaf_start, 4) {
-              return Err(Error::LeafInsertionError(leaf_start, 4))
-            }
-            /* Change bit at (x, y) to 1 */
-            let leaf_range = subranges[child_pos];
-            if x == leaf_range[0][0] {
-              if y == leaf_range[1][0] { self.leaves.set(leaf_start, true); }
-              else { self.leaves.set(leaf_start+2, true); }
-            }
-            else {
-              if y == leaf_range[1][0] { self.leaves.set(leaf_start+1, true); }
-              else { self.leaves.set(leaf_start+3, true); }
-            }
-            return Ok(())
+        let child_pos = self.offset_in_block(&stem_range, x, y);
+        /* Keep track of whether this stem is freshly created (all 0s) */
+        let fresh_stem: bool = self.stem_ones(stem_start, stem_start+stem_len) == 0;
+        /* Set the correct stem bit to 1 */
+        self.set_stem_bit(stem_start + child_pos, true)?;
+        /* Get the bit position within the final stem layer,
+        find the position in stem_to_leaf to insert the linking elem,
+        insert linking elem.
+        A fresh stem pushed every stem after it back, so every existing
+        elem from the start of the new stem onwards belongs after the new
+        elem. Otherwise only elems before our bit's position come first. */
+        let layer_bit_pos = (stem_start + child_pos) - self.slayer_starts[layer_starts_len-1];
+        let insert_before = if fresh_stem { block_start(layer_bit_pos, stem_len) } else { layer_bit_pos };
+        let mut stem_to_leaf_pos: usize = 0;
+        while stem_to_leaf_pos < self.stem_to_leaf.len()
+        && self.stem_to_leaf[stem_to_leaf_pos] < insert_before {
+          stem_to_leaf_pos += 1;
+        }
+        self.stem_to_leaf.insert(stem_to_leaf_pos, layer_bit_pos);
+        /* If stem is fresh, increase bit positions in stem_to_leaf
+        after the new elem by a stem's length to account for the new stem before them */
+        if fresh_stem {
+          let stem_to_leaf_len = self.stem_to_leaf.len();
+          for parent_bit_pos in &mut self.stem_to_leaf[stem_to_leaf_pos+1..stem_to_leaf_len] {
+            *parent_bit_pos += stem_len;
           }
         }
+        /* Create new leaf of all 0's */
+        let leaf_start = stem_to_leaf_pos * stem_len;
+        if let Err(()) = self.leaves.modify(|bits| bits.insert_block(leaf_start, stem_len)) {
+          return Err(Error::LeafInsertionError(leaf_start, stem_len))
+        }
+        /* Change bit at (x, y) to 1 */
+        let leaf_range = to_subranges(stem_range, self.k)[child_pos];
+        let leaf_bit = leaf_start + self.offset_in_block(&leaf_range, x, y);
+        if let Err(()) = self.leaves.modify(|bits| bits.set(leaf_bit, true)) {
+          return Err(Error::ReadOnlyStore)
+        }
       }
       _ => {},
     };
     Ok(())
   }
+  pub fn apply_batch<I: IntoIterator<Item=(usize, usize, bool)>>(&mut self, changes: I) -> Result<()> {
+    /* The same as calling set for each (x, y, state) in turn, but the
+    changes are merged with the tree's contents and it's rebuilt once.
+    Nothing changes if any of them are out of bounds */
+    let mut batch: Vec<(u128, bool)> = Vec::new();
+    for (x, y, state) in changes {
+      if x >= self.matrix_width || y >= self.matrix_height {
+        return Err(Error::OutOfBounds([x, y], self.max_coords()))
+      }
+      batch.push((self.z_order_key(x, y), state));
+    }
+    if batch.is_empty() { return Ok(()) }
+    /* The sort is stable so the last change to a cell comes last */
+    batch.sort_by_key(|&(key, _)| key);
+    let mut existing = self.iter_range(0..self.matrix_width, 0..self.matrix_height)
+      .map(|[x, y]| self.z_order_key(x, y))
+      .peekable();
+    let mut keys: Vec<u128> = Vec::new();
+    for (i, &(key, state)) in batch.iter().enumerate() {
+      if i+1 < batch.len() && batch[i+1].0 == key { continue }
+      while let Some(&existing_key) = existing.peek() {
+        if existing_key >= key { break }
+        keys.push(existing_key);
+        existing.next();
+      }
+      if existing.peek() == Some(&key) { existing.next(); }
+      if state { keys.push(key); }
+    }
+    keys.extend(existing);
+    let (layers, leaves) = self.layers_from_keys(keys);
+    self.set_layers(layers, leaves);
+    Ok(())
+  }
   /* Information */
   pub fn matrix_width(&self) -> usize {
     self.matrix_width
   }
+  pub fn matrix_height(&self) -> usize {
+    self.matrix_height
+  }
   pub fn k(&self) -> usize {
     self.k
   }
+  pub fn leaf_encoding(&self) -> LeafEncoding {
+    self.leaves.encoding()
+  }
   /* Iteration */
-  pub fn stems(&self) -> Stems {
+  pub fn stems(&self) -> Stems<B> {
     Stems {
       tree: &self,
       pos: 0,
@@ -283,43 +449,95 @@ impl K2Tree {
   }
   pub fn stems_raw(&self) -> StemsRaw {
     StemsRaw {
-      stems: &self.stems,
+      stems: self.stems.as_bitslice(),
       pos: 0,
     }
   }
-  pub fn leaves(&self) -> Leaves {
+  pub fn leaves(&self) -> Leaves<B> {
     Leaves {
       tree: &self,
       pos: 0,
     }
   }
-  pub fn into_leaves(self) -> IntoLeaves {
+  pub fn into_leaves(self) -> IntoLeaves<B> {
     IntoLeaves {
       tree: self,
       pos: 0,
     }
   }
-  pub fn leaves_raw(&self) -> LeavesRaw {
+  pub fn iter_range(&self, x_range: std::ops::Range<usize>, y_range: std::ops::Range<usize>) -> RangeIter<B> {
+    self.as_view().into_range_iter(x_range, y_range)
+  }
+  pub fn par_cells(&self) -> ParCells<B> {
+    self.as_view().into_par_cells()
+  }
+  pub fn cursor(&self) -> K2TreeCursor<B> {
+    self.as_view().into_cursor()
+  }
+  pub fn as_view(&self) -> K2TreeView<B> {
+    K2TreeView {
+      matrix_width: self.matrix_width,
+      matrix_height: self.matrix_height,
+      k: self.k,
+      max_slayers: self.max_slayers,
+      slayer_starts: Cow::Borrowed(&self.slayer_starts[..]),
+      stems: self.stems.as_bitslice(),
+      full: None,
+      leaves: self.leaves.as_bits(),
+      stem_ranks: StemRanks::Store(&self.stems),
+    }
+  }
+  pub fn leaves_raw(&self) -> LeavesRaw<B> {
     LeavesRaw {
       leaves: &self.leaves,
       pos: 0,
     }
   }
   /* Mutation */
-  pub fn grow(&mut self) {
-    self.matrix_width *= self.k;
+  pub fn set_leaf_encoding(&mut self, encoding: LeafEncoding) {
+    /* Changing an encoded tree decodes its leaves, which stay decoded until
+    written or until this encodes them again */
+    match (encoding, &self.leaves) {
+      (LeafEncoding::Plain, LeafLayer::Plain(_))
+      | (LeafEncoding::Vocabulary, LeafLayer::Vocabulary(_)) => {},
+      (LeafEncoding::Plain, _) => {
+        let bits = std::mem::replace(self.leaves.to_mut(), B::from_bitvec(BitVec::new()));
+        self.leaves = LeafLayer::Plain(bits);
+      },
+      (LeafEncoding::Vocabulary, LeafLayer::Plain(bits))
+      | (LeafEncoding::Vocabulary, LeafLayer::Decoded(bits)) => {
+        self.leaves = LeafLayer::Vocabulary(LeafVocabulary::new(bits.as_bitslice(), self.stem_len()));
+      },
+    }
+  }
+  pub fn with_leaf_encoding(mut self, encoding: LeafEncoding) -> Self {
+    self.set_leaf_encoding(encoding);
+    self
+  }
+  pub fn grow(&mut self) -> Result<()> {
+    /* Both extents grow by a factor of k, or to the new
+    tree width if they were 0. Fails if a usize can't count that width */
+    if !stems_cover(self.k, self.max_slayers + 1, 0, 0) {
+      return Err(too_wide(self.matrix_width, self.matrix_height))
+    }
     self.max_slayers += 1;
+    self.matrix_width = grown_extent(self.matrix_width, self.k, self.tree_width());
+    self.matrix_height = grown_extent(self.matrix_height, self.k, self.tree_width());
     if self.leaves.len() > 0  {
       /* Only insert the extra layers etc. if the
       tree isn't all 0s */
+      let stem_len = self.stem_len();
       for slayer_start in &mut self.slayer_starts {
-        *slayer_start += 4;
+        *slayer_start += stem_len;
       }
       self.slayer_starts.insert(0, 0);
-      /* Insert 1000 to beginning of stems */
-      for _ in 0..3 { self.stems.insert(0, false); }
-      self.stems.insert(0, true);
+      /* Insert 100..0 to beginning of stems */
+      let mut stems = bitvec![0; stem_len];
+      stems.set(0, true);
+      stems.extend(self.stems.as_bitslice().iter());
+      self.stems = B::from_bitvec(stems);
     }
+    Ok(())
   }
   pub fn shrink_if_possible(&mut self) {
     match self.shrink() {
@@ -327,43 +545,178 @@ impl K2Tree {
     }
   }
   pub fn shrink(&mut self) -> Result<()> {
-    if self.matrix_width <= self.k.pow(3) {
-      return Err(Error::CouldNotShrink(format!("Already at minimum size: {}", self.matrix_width)))
+    if self.tree_width() <= self.k.pow(3) {
+      return Err(Error::CouldNotShrink(format!("Already at minimum size: {}", self.tree_width())))
     }
-    else if self.stems[0..4] != bitvec![1,0,0,0] {
+    else if !self.stems.get(0)
+    || self.stem_ones(0, self.stem_len()) != 1 {
       return Err(Error::CouldNotShrink("Shrinking would lose information about the matrix".into()))
     }
-    self.matrix_width /= self.k;
-    self.max_slayers -= 1;
-    self.slayer_starts.remove(0);
-    for slayer_start in &mut self.slayer_starts {
-      *slayer_start -= 4;
-    }
-    /* Remove top layer stem */
-    for _ in 0..4 { self.stems.remove(0); }
+    unsafe { self.shrink_unchecked(); }
     Ok(())
   }
   pub unsafe fn shrink_unchecked(&mut self) {
-    self.matrix_width /= self.k;
+    /* Extents are clipped to the smaller square */
+    let stem_len = self.stem_len();
+    let tree_width = self.tree_width() / self.k;
+    self.matrix_width = std::cmp::min(self.matrix_width, tree_width);
+    self.matrix_height = std::cmp::min(self.matrix_height, tree_width);
     self.max_slayers -= 1;
     self.slayer_starts.remove(0);
     for slayer_start in &mut self.slayer_starts {
-      *slayer_start -= 4;
+      *slayer_start -= stem_len;
     }
     /* Remove top layer stem */
-    for _ in 0..4 { self.stems.remove(0); }
+    self.stems = B::from_bitvec(BitVec::from(&self.stems.as_bitslice()[stem_len..]));
+  }
+  pub fn resize(&mut self, width: usize, height: usize) -> Result<()> {
+    /* Changes the extents, growing or shrinking the stems to the
+    smallest square holding both. Fails if a 1 would be cut off */
+    if self.iter_range(width..self.matrix_width, 0..self.matrix_height).next().is_some()
+    || self.iter_range(0..self.matrix_width, height..self.matrix_height).next().is_some() {
+      return Err(Error::CouldNotShrink("Resizing would lose information about the matrix".into()))
+    }
+    if !can_cover(self.k, width, height) {
+      return Err(too_wide(width, height))
+    }
+    if self.leaves.is_empty() {
+      let encoding = self.leaf_encoding();
+      *self = K2Tree::with_extents(self.k, width, height)?.into_store();
+      self.set_leaf_encoding(encoding);
+      return Ok(())
+    }
+    let extent = std::cmp::max(width, height);
+    while self.tree_width() < extent {
+      self.grow()?;
+    }
+    while self.tree_width() / self.k >= extent
+    && self.shrink().is_ok() {}
+    self.matrix_width = width;
+    self.matrix_height = height;
+    Ok(())
+  }
+  /* Set Operations */
+  pub fn union(&self, other: &K2Tree<B>) -> Result<K2Tree> where B: Clone {
+    self.combine(other, SetOp::Union)
+  }
+  pub fn intersect(&self, other: &K2Tree<B>) -> Result<K2Tree> where B: Clone {
+    self.combine(other, SetOp::Intersection)
+  }
+  pub fn difference(&self, other: &K2Tree<B>) -> Result<K2Tree> where B: Clone {
+    self.combine(other, SetOp::Difference)
+  }
+  pub fn symmetric_difference(&self, other: &K2Tree<B>) -> Result<K2Tree> where B: Clone {
+    self.combine(other, SetOp::SymmetricDifference)
+  }
+  /* Matrix Operations */
+  pub fn multiply(&self, other: &K2Tree<B>) -> Result<K2Tree> where B: Clone {
+    /* The boolean matrix product, read as composing relations:
+    (x, z) is set when (x, y) is set in self and (y, z) in other */
+    if self.k != other.k {
+      return Err(Error::MismatchedK(self.k, other.k))
+    }
+    let grown_self = self.grown_to(other.tree_width())?;
+    let grown_other = other.grown_to(self.tree_width())?;
+    let a = grown_self.as_ref().unwrap_or(self);
+    let b = grown_other.as_ref().unwrap_or(other);
+    let mut coords: Vec<(usize, usize)> = Vec::new();
+    if !a.leaves.is_empty() && !b.leaves.is_empty() {
+      K2Tree::multiply_node(a, b, 0, 0, 0, [0, 0], a.tree_width()/a.k, &mut coords);
+    }
+    /* The product has self's columns and other's rows */
+    K2Tree::from_coordinates_with_extents(a.k, self.matrix_width, other.matrix_height, coords)
+  }
+  pub fn transpose(&self) -> K2Tree {
+    /* Mirrors the matrix along its diagonal by swapping the order of
+    children in every stem and leaf */
+    let mut tree = K2Tree {
+      matrix_width: self.matrix_height,
+      matrix_height: self.matrix_width,
+      max_slayers: self.max_slayers,
+      ..K2Tree::with_k(self.k)
+    };
+    let mut layers: Vec<BitVec> = vec![BitVec::new(); self.max_slayers];
+    let mut leaves = BitVec::new();
+    if !self.leaves.is_empty() {
+      self.transpose_node(0, 0, &mut layers, &mut leaves);
+    }
+    tree.set_layers(layers, leaves);
+    tree
   }
   /* To / From */
-  pub fn into_matrix(self) -> BitMatrix { unimplemented!() }
-  pub fn to_matrix(&self) -> BitMatrix { unimplemented!() }
-  pub fn from_matrix(m: BitMatrix) -> Result<Self> {
-    let mut tree = K2Tree::new();
-    for (x, column) in m.iter().enumerate() {
-      for y in one_positions(column).into_iter() {
-        tree.set(x, y, true)?;
-      }
+  pub fn into_matrix(self) -> BitMatrix {
+    self.to_matrix()
+  }
+  pub fn to_matrix(&self) -> BitMatrix {
+    /* Same layout from_matrix takes: m[x] is the column at x */
+    let mut m: BitMatrix = vec![bitvec![0; self.matrix_height]; self.matrix_width];
+    for [x, y] in self.iter_range(0..self.matrix_width, 0..self.matrix_height) {
+      m[x].set(y, true);
     }
-    Ok(tree)
+    m
+  }
+  pub fn into_store<C: BitStore>(self) -> K2Tree<C> {
+    /* The same tree with its stems and plain leaves moved to another store */
+    K2Tree {
+      matrix_width: self.matrix_width,
+      matrix_height: self.matrix_height,
+      k: self.k,
+      max_slayers: self.max_slayers,
+      slayer_starts: self.slayer_starts,
+      stems: C::from_bitvec(self.stems.to_bitvec()),
+      stem_to_leaf: self.stem_to_leaf,
+      leaves: match self.leaves {
+        LeafLayer::Plain(bits) => LeafLayer::Plain(C::from_bitvec(bits.to_bitvec())),
+        LeafLayer::Decoded(bits) => LeafLayer::Decoded(C::from_bitvec(bits.to_bitvec())),
+        LeafLayer::Vocabulary(vocabulary) => LeafLayer::Vocabulary(vocabulary),
+      },
+    }
+  }
+  /* Dense / Sparse Exports */
+  pub fn to_pbm(&self) -> Vec<u8> {
+    /* Raw (P4) portable bitmap, one row of pixels per y
+    with set cells in black */
+    let row_bytes = (self.matrix_width + 7) / 8;
+    let mut pbm = format!("P4\n{} {}\n", self.matrix_width, self.matrix_height).into_bytes();
+    let header_len = pbm.len();
+    pbm.resize(header_len + (row_bytes * self.matrix_height), 0);
+    for [x, y] in self.iter_range(0..self.matrix_width, 0..self.matrix_height) {
+      pbm[header_len + (y * row_bytes) + (x / 8)] |= 0x80 >> (x % 8);
+    }
+    pbm
+  }
+  pub fn to_matrix_market(&self) -> String {
+    /* Coordinate pattern format, entries are 1-indexed (row, column)
+    pairs, so (y+1, x+1) */
+    let cells: Vec<[usize; 2]> = self.iter_range(0..self.matrix_width, 0..self.matrix_height).collect();
+    let mut mm = String::from("%%MatrixMarket matrix coordinate pattern general\n");
+    mm.push_str(&format!("{} {} {}\n", self.matrix_height, self.matrix_width, cells.len()));
+    for [x, y] in cells {
+      mm.push_str(&format!("{} {}\n", y+1, x+1));
+    }
+    mm
+  }
+  pub fn to_csr(&self) -> (Vec<usize>, Vec<usize>) {
+    /* Compressed sparse rows: (row_offsets, column_indices) where row y's
+    columns are column_indices[row_offsets[y]..row_offsets[y+1]] */
+    let mut row_offsets = vec![0; self.matrix_height+1];
+    let cells: Vec<[usize; 2]> = self.iter_range(0..self.matrix_width, 0..self.matrix_height).collect();
+    for &[_, y] in &cells {
+      row_offsets[y+1] += 1;
+    }
+    for y in 0..self.matrix_height {
+      row_offsets[y+1] += row_offsets[y];
+    }
+    let mut next_in_row = row_offsets.clone();
+    let mut column_indices = vec![0; cells.len()];
+    for &[x, y] in &cells {
+      column_indices[next_in_row[y]] = x;
+      next_in_row[y] += 1;
+    }
+    for y in 0..self.matrix_height {
+      column_indices[row_offsets[y]..row_offsets[y+1]].sort_unstable();
+    }
+    (row_offsets, column_indices)
   }
   /* Serialization / Deserialization */
   pub fn to_json(&self) -> Result<String> {
@@ -372,505 +725,3395 @@ impl K2Tree {
   pub fn into_json(self) -> Result<String> {
     Ok(serde_json::to_string(&self)?)
   }
-  pub fn from_json(json: &str) -> Result<Self> {
-    Ok(serde_json::from_str::<Self>(json)?)
+  pub fn write_to(&self, writer: &mut impl std::io::Write) -> Result<()> {
+    self.write_with_leaf_encoding(writer, self.leaf_encoding())
+  }
+  pub fn write_with_leaf_encoding(&self, writer: &mut impl std::io::Write, encoding: LeafEncoding) -> Result<()> {
+    /* See BinaryHeader for the layout */
+    let stems = bits_to_bytes(self.stems.as_bitslice());
+    let (leaves, flags) = match (encoding, &self.leaves) {
+      (LeafEncoding::Plain, _) => (bits_to_bytes(&self.leaves.bits()), 0),
+      (LeafEncoding::Vocabulary, LeafLayer::Vocabulary(vocabulary)) => {
+        let mut leaves = Vec::new();
+        vocabulary.write_to(&mut leaves);
+        (leaves, BINARY_LEAF_VOCABULARY)
+      },
+      (LeafEncoding::Vocabulary, LeafLayer::Plain(bits))
+      | (LeafEncoding::Vocabulary, LeafLayer::Decoded(bits)) => {
+        let mut leaves = Vec::new();
+        LeafVocabulary::new(bits.as_bitslice(), self.stem_len()).write_to(&mut leaves);
+        (leaves, BINARY_LEAF_VOCABULARY)
+      },
+    };
+    let fields = [
+      self.k,
+      self.matrix_width,
+      self.matrix_height,
+      self.max_slayers,
+      self.slayer_starts.len(),
+      self.stems.len(),
+      self.leaves.len()
+    ];
+    write_binary(writer, flags, fields, &self.slayer_starts, &[stems, leaves])
   }
 }
 
-/* Iterators */
-#[allow(dead_code)] //Not needed yet within library yet, but users could want it
-pub struct StemBit {
-  value: bool,
-  layer: usize,
-  stem: usize,
-  bit: usize,
-}
-#[derive(Clone, Debug)]
-pub struct LeafBit {
-  pub value: bool,
-  pub x: usize,
-  pub y: usize,
-}
-pub struct Stems<'a> {
-  tree: &'a K2Tree,
-  pos: usize,
-  layer: usize,
-  stem: usize,
-  bit: usize,
+/* Views */
+/* What a K2TreeView needs besides the bytes written by K2Tree::write_to,
+checked and built once so any number of views of those bytes are free to make */
+#[derive(Debug, Clone)]
+pub struct K2TreeIndex {
+  header: BinaryHeader,
+  stem_ranks: RankIndex,
+  vocabulary: Option<LeafVocabulary>,
 }
-impl<'a> Iterator for Stems<'a> {
-  type Item = StemBit;
-  fn next(&mut self) -> Option<Self::Item> {
-    if self.pos >= self.tree.stems.len() {
-      return None
+impl K2TreeIndex {
+  pub fn new(bytes: &[u8]) -> Result<Self> {
+    let header = BinaryHeader::parse(bytes)?;
+    let stems = header.stems(bytes);
+    check_stem_layers(header.k, header.max_slayers, &header.slayer_starts, stems).map_err(invalid_binary)?;
+    let stem_ranks = RankIndex::new(stems);
+    if let Some(full) = header.full(bytes) {
+      if full.iter().zip(stems.iter()).any(|(full, stem)| full && stem) {
+        return Err(invalid_binary("a child can't be both a stem and a 1-region"))
+      }
     }
-    let value = self.tree.stems[self.pos];
-    let ret_v = Some(StemBit {
-      value: value,
-      layer: self.layer,
-      stem: self.stem,
-      bit: self.bit,
-    });
-    self.bit = (self.bit + 1) % 4;
-    if self.bit == 0 { self.stem = (self.stem + 1) % self.tree.layer_len(self.layer); }
-    if self.stem == 0 { self.layer += 1; }
-    ret_v
-  }
-}
-pub struct Leaves<'a> {
-  tree: &'a K2Tree,
-  pos: usize,
-}
-impl<'a> Iterator for Leaves<'a> {
-  type Item = LeafBit;
-  fn next(&mut self) -> Option<Self::Item> {
-    /* Need get_coords(bit_pos) function for leaf bits for this */
-    if self.pos == self.tree.leaves.len() { return None }
-    let [x, y] = self.tree.get_coords(self.pos);
-    let value = self.tree.leaves[self.pos];
-    self.pos += 1;
-    Some(LeafBit {
-      value: value,
-      x: x,
-      y: y,
+    let (vocabulary, leaves_len) = if header.leaf_vocabulary {
+      let leaf_bytes = &bytes[header.leaves_offset..header.end_offset];
+      let vocabulary = LeafVocabulary::parse(leaf_bytes, header.k * header.k, header.leaves_len)?;
+      let leaves_len = vocabulary.len();
+      (Some(vocabulary), leaves_len)
+    }
+    else {
+      (None, header.leaves_len)
+    };
+    /* Every 1 in the final stem layer points to a leaf, the stems
+    have been checked to hold a final layer */
+    let final_layer_start = header.slayer_starts[header.slayer_starts.len()-1];
+    let num_leaves = stem_ranks.rank(stems, stems.len()) - stem_ranks.rank(stems, final_layer_start);
+    if num_leaves * header.k * header.k != leaves_len {
+      return Err(Error::InvalidBinary(format!(
+        "{} leaf bits do not match the {} 1s in the final stem layer",
+        leaves_len,
+        num_leaves
+      )))
+    }
+    Ok(K2TreeIndex {
+      header: header,
+      stem_ranks: stem_ranks,
+      vocabulary: vocabulary,
     })
   }
 }
-pub struct IntoLeaves {
-  tree: K2Tree,
-  pos: usize,
+/* A read-only K2Tree over borrowed bits, either another K2Tree's (or
+K2Tree1's) or the bytes written by K2Tree::write_to, which it reads in place */
+#[derive(Debug)]
+pub struct K2TreeView<'a, B: BitStore = GrowableBits> {
+  matrix_width: usize,
+  matrix_height: usize,
+  k: usize,
+  max_slayers: usize,
+  slayer_starts: Cow<'a, [usize]>,
+  stems: &'a BitSlice,
+  full: Option<&'a BitSlice>, //1-regions, only viewing a K2Tree1 has them
+  leaves: LeafBits<'a>,
+  stem_ranks: StemRanks<'a, B>,
 }
-impl Iterator for IntoLeaves {
-  type Item = LeafBit;
-  fn next(&mut self) -> Option<Self::Item> {
-    /* Need get_coords(bit_pos) function for leaf bits for this */
-    if self.pos == self.tree.leaves.len() { return None }
-    let [x, y] = self.tree.get_coords(self.pos);
-    let value = self.tree.leaves[self.pos];
-    self.pos += 1;
-    Some(LeafBit {
-      value: value,
-      x: x,
-      y: y,
-    })
+impl<'a, B: BitStore> Clone for K2TreeView<'a, B> {
+  fn clone(&self) -> Self {
+    K2TreeView {
+      matrix_width: self.matrix_width,
+      matrix_height: self.matrix_height,
+      k: self.k,
+      max_slayers: self.max_slayers,
+      slayer_starts: self.slayer_starts.clone(),
+      stems: self.stems,
+      full: self.full,
+      leaves: self.leaves.clone(),
+      stem_ranks: self.stem_ranks.clone(),
+    }
   }
 }
-pub struct StemsRaw<'a> {
-  stems: &'a BitVec,
-  pos: usize,
-}
-impl<'a> Iterator for StemsRaw<'a> {
-  type Item = bool;
-  fn next(&mut self) -> Option<Self::Item> {
-    if self.pos >= self.stems.len() {
-      return None
+impl<'a> K2TreeView<'a> {
+  /* Creation */
+  pub fn new(bytes: &'a [u8]) -> Result<Self> {
+    /* Only the stem layer starts and rank index are built, the bits stay in bytes.
+    Every call checks and indexes the bytes again, with_index shares one index */
+    let K2TreeIndex {
+      header,
+      stem_ranks,
+      vocabulary,
+    } = K2TreeIndex::new(bytes)?;
+    let slayer_starts = Cow::Owned(header.slayer_starts.clone());
+    Ok(K2TreeView::from_parts(bytes, &header, slayer_starts, Cow::Owned(stem_ranks), vocabulary.map(Cow::Owned)))
+  }
+  pub fn with_index(bytes: &'a [u8], index: &'a K2TreeIndex) -> Result<Self> {
+    /* Borrows everything from index, which must have been built from these bytes */
+    if bytes.len() != index.header.end_offset + 8 {
+      return Err(invalid_binary("the index was built from different bytes"))
     }
-    self.pos += 1;
-    Some(self.stems[self.pos])
+    let slayer_starts = Cow::Borrowed(&index.header.slayer_starts[..]);
+    let vocabulary = index.vocabulary.as_ref().map(Cow::Borrowed);
+    Ok(K2TreeView::from_parts(bytes, &index.header, slayer_starts, Cow::Borrowed(&index.stem_ranks), vocabulary))
   }
 }
-pub struct LeavesRaw<'a> {
-  leaves: &'a BitVec,
-  pos: usize,
-}
-impl<'a> Iterator for LeavesRaw<'a> {
-  type Item = bool;
-  fn next(&mut self) -> Option<Self::Item> {
-    if self.pos >= self.leaves.len() {
-      return None
+impl<'a, B: BitStore> K2TreeView<'a, B> {
+  /* Information */
+  pub fn matrix_width(&self) -> usize {
+    self.matrix_width
+  }
+  pub fn matrix_height(&self) -> usize {
+    self.matrix_height
+  }
+  pub fn k(&self) -> usize {
+    self.k
+  }
+  /* Operation */
+  pub fn get(&self, x: usize, y: usize) -> Result<bool> {
+    if x >= self.matrix_width || y >= self.matrix_height {
+      return Err(Error::OutOfBounds([x, y], self.max_coords()))
     }
-    self.pos += 1;
-    Some(self.leaves[self.pos])
+    Ok(self.iter_range(x..x+1, y..y+1).next().is_some())
   }
-}
-
-/* Traits */
-impl core::fmt::Display for K2Tree {
-  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-    if self.leaves.len() == 0 { return write!(f, "[0000]") }
-    let mut s = String::new();
-    let mut i: usize = 1;
-    for layer_num in 0..self.slayer_starts.len() {
-      for bit_pos in self.layer_start(layer_num)..self.layer_start(layer_num+1) {
-        if self.stems[bit_pos] {
-          s.push('1');
-        }
-        else {
-          s.push('0');
-        }
-        if i == self.k*self.k
-        && (bit_pos - self.layer_start(layer_num)) < self.layer_len(layer_num)-1 {
-          s.push(',');
-          i = 1;
-        } 
+  pub fn get_row(&self, y: usize) -> Result<BitVec> {
+    if y >= self.matrix_height {
+      return Err(Error::OutOfBounds([0, y], self.max_coords()))
+    }
+    let mut ret_v = bitvec![0; self.matrix_width];
+    for x in self.predecessors(y) {
+      ret_v.set(x, true);
+    }
+    Ok(ret_v)
+  }
+  pub fn get_column(&self, x: usize) -> Result<BitVec> {
+    if x >= self.matrix_width {
+      return Err(Error::OutOfBounds([x, 0], self.max_coords()))
+    }
+    let mut ret_v = bitvec![0; self.matrix_height];
+    for y in self.successors(x) {
+      ret_v.set(y, true);
+    }
+    Ok(ret_v)
+  }
+  pub fn successors(&self, x: usize) -> Successors<B> {
+    self.as_view().into_successors(x)
+  }
+  pub fn predecessors(&self, y: usize) -> Predecessors<B> {
+    self.as_view().into_predecessors(y)
+  }
+  pub fn iter_range(&self, x_range: std::ops::Range<usize>, y_range: std::ops::Range<usize>) -> RangeIter<B> {
+    self.as_view().into_range_iter(x_range, y_range)
+  }
+  pub fn par_cells(&self) -> ParCells<B> {
+    self.as_view().into_par_cells()
+  }
+  pub fn cursor(&self) -> K2TreeCursor<B> {
+    self.as_view().into_cursor()
+  }
+  pub fn into_cursor(self) -> K2TreeCursor<'a, B> {
+    K2TreeCursor::new(self)
+  }
+  pub fn into_par_cells(self) -> ParCells<'a, B> {
+    let (width, height) = (self.matrix_width, self.matrix_height);
+    ParCells {
+      cells: self.into_range_iter(0..width, 0..height),
+    }
+  }
+  pub fn as_view(&self) -> K2TreeView<B> {
+    /* A view borrowing this one's index rather than copying it */
+    K2TreeView {
+      matrix_width: self.matrix_width,
+      matrix_height: self.matrix_height,
+      k: self.k,
+      max_slayers: self.max_slayers,
+      slayer_starts: Cow::Borrowed(&self.slayer_starts[..]),
+      stems: self.stems,
+      full: self.full,
+      leaves: self.leaves.reborrow(),
+      stem_ranks: self.stem_ranks.reborrow(),
+    }
+  }
+  /* To / From */
+  pub fn to_k2tree(&self) -> K2Tree {
+    if self.full.is_some() {
+      /* 1-regions have to be written out cell by cell */
+      let mut tree = K2Tree {
+        matrix_width: self.matrix_width,
+        matrix_height: self.matrix_height,
+        max_slayers: self.max_slayers,
+        ..K2Tree::with_k(self.k)
+      };
+      let (layers, leaves) = tree.layers_from_keys(
+        self.iter_range(0..self.matrix_width, 0..self.matrix_height)
+        .map(|[x, y]| tree.z_order_key(x, y))
+        .collect()
+      );
+      tree.set_layers(layers, leaves);
+      return tree
+    }
+    let stems = BitVec::from(self.stems);
+    let leaves = self.leaves.to_leaf_layer();
+    /* stem_to_leaf holds the position of every 1 in the final layer */
+    let final_layer_start = self.slayer_starts[self.slayer_starts.len()-1];
+    let stem_to_leaf = one_positions(&stems)
+      .into_iter()
+      .filter(|&pos| pos >= final_layer_start)
+      .map(|pos| pos - final_layer_start)
+      .collect();
+    K2Tree {
+      matrix_width: self.matrix_width,
+      matrix_height: self.matrix_height,
+      k: self.k,
+      max_slayers: self.max_slayers,
+      slayer_starts: self.slayer_starts.to_vec(),
+      stems: GrowableBits::from_bitvec(stems),
+      stem_to_leaf: stem_to_leaf,
+      leaves: leaves,
+    }
+  }
+}
+
+/* 1-Regions */
+/* A K2Tree that stores a submatrix of all 1s as a single bit instead of
+every stem and leaf beneath it, the "k2-tree with 1-regions". full runs
+alongside stems, a 1 there marks the child as entirely set, in which case
+its stem bit is 0 and it has no children of its own */
+#[derive(Debug, Clone)]
+pub struct K2Tree1 {
+  matrix_width: usize, //number of columns, x
+  matrix_height: usize, //number of rows, y
+  k: usize, //k^2 == number of submatrices in each stem/leaf
+  max_slayers: usize, //max stem layers
+  slayer_starts: Vec<usize>, //stem layer starts
+  stems: BitVec, //1 where the child is partly set
+  full: BitVec, //1 where the child is all 1s
+  leaves: BitVec,
+  stem_ranks: RankIndex, //rank/select over stems
+}
+impl K2Tree1 {
+  /* Creation */
+  pub fn new() -> Self {
+    K2Tree1::with_k(2)
+  }
+  pub fn with_k(k: usize) -> Self {
+    K2Tree1::from_empty(K2Tree::with_k(k))
+  }
+  pub fn with_extents(k: usize, width: usize, height: usize) -> Result<Self> {
+    Ok(K2Tree1::from_empty(K2Tree::with_extents(k, width, height)?))
+  }
+  /* Operation */
+  pub fn is_empty(&self) -> bool {
+    self.as_view().root_is_empty()
+  }
+  pub fn get(&self, x: usize, y: usize) -> Result<bool> {
+    self.as_view().get(x, y)
+  }
+  pub fn get_row(&self, y: usize) -> Result<BitVec> {
+    self.as_view().get_row(y)
+  }
+  pub fn get_column(&self, x: usize) -> Result<BitVec> {
+    self.as_view().get_column(x)
+  }
+  pub fn successors(&self, x: usize) -> Successors {
+    self.as_view().into_successors(x)
+  }
+  pub fn predecessors(&self, y: usize) -> Predecessors {
+    self.as_view().into_predecessors(y)
+  }
+  pub fn set(&mut self, x: usize, y: usize, state: bool) -> Result<()> {
+    /* Edits the bits in place as K2Tree::set does. A removal splits every
+    1-region on the way down to the cell into a stem of 1-regions (or a
+    leaf of 1s), an insert that fills a leaf or stem merges it into a
+    1-region in its parent */
+    if self.get(x, y)? == state { return Ok(()) }
+    if self.slayer_starts.len() < self.max_slayers {
+      /* An empty tree only has its root layer */
+      let stems_len = self.stems.len();
+      self.slayer_starts.resize(self.max_slayers, stems_len);
+    }
+    let stem_len = self.stem_len();
+    let last_layer = self.max_slayers-1;
+    let mut path: Vec<usize> = Vec::with_capacity(self.max_slayers); //bit on the way down in each layer
+    let mut stem_start = 0;
+    let mut sub_width = self.tree_width() / self.k;
+    for layer in 0..self.max_slayers {
+      let child_pos = (((y / sub_width) % self.k) * self.k) + ((x / sub_width) % self.k);
+      let bit_pos = stem_start + child_pos;
+      path.push(bit_pos);
+      if self.full[bit_pos] {
+        /* Only a removal reaches a 1-region */
+        self.full.set(bit_pos, false);
+        self.set_stem_bit(bit_pos, true);
+        self.insert_child(layer, bit_pos, true)?;
+      }
+      else if !self.stems[bit_pos] {
+        /* Only an insert reaches a 0 */
+        self.set_stem_bit(bit_pos, true);
+        self.insert_child(layer, bit_pos, false)?;
+      }
+      if layer < last_layer {
+        stem_start = self.as_view().child_stem(layer, stem_start, child_pos)
+          .map_err(|_| Error::TraverseError(x, y))?;
+        sub_width /= self.k;
+      }
+    }
+    let leaf_start = self.as_view().leaf_start(path[last_layer])
+      .map_err(|_| Error::TraverseError(x, y))?;
+    self.leaves.set(leaf_start + ((y % self.k) * self.k) + (x % self.k), state);
+    let leaf = &self.leaves[leaf_start..leaf_start+stem_len];
+    if (state && !leaf.all()) || (!state && leaf.any()) {
+      return Ok(())
+    }
+    /* The leaf is all 1s or all 0s, either way it goes, and then any
+    stem above it left all 1-regions or empty goes too */
+    remove_block(&mut self.leaves, leaf_start, stem_len)
+      .map_err(|_| Error::LeafRemovalError(leaf_start, stem_len))?;
+    self.set_stem_bit(path[last_layer], false);
+    self.full.set(path[last_layer], state);
+    let mut layer = last_layer;
+    while layer > 0 {
+      let stem_start = block_start(path[layer], stem_len);
+      let stem_full = &self.full[stem_start..stem_start+stem_len];
+      let stem_empty = !stem_full.any() && !self.stems[stem_start..stem_start+stem_len].any();
+      if !(state && stem_full.all()) && !(!state && stem_empty) { break }
+      self.remove_stem(layer, stem_start)?;
+      self.set_stem_bit(path[layer-1], false);
+      self.full.set(path[layer-1], state);
+      layer -= 1;
+    }
+    if !self.stems.any() && !self.full.any() {
+      self.slayer_starts = vec![0];
+    }
+    Ok(())
+  }
+  pub fn apply_batch<I: IntoIterator<Item=(usize, usize, bool)>>(&mut self, changes: I) -> Result<()> {
+    /* Applies every (x, y, state) in order to the tree's regions and
+    rebuilds the bits once, a removal from inside a 1-region splits it
+    into the regions around the cell. Nothing changes if any is out of bounds */
+    let changes: Vec<(usize, usize, bool)> = changes.into_iter().collect();
+    for &(x, y, _) in changes.iter() {
+      if x >= self.matrix_width || y >= self.matrix_height {
+        return Err(Error::OutOfBounds([x, y], self.as_view().max_coords()))
+      }
+    }
+    let mut regions = self.regions();
+    for (x, y, state) in changes.into_iter() {
+      let key = self.z_order_key(x, y);
+      match (state, self.region_holding(&regions, key)) {
+        (true, None) => { regions.insert(key, self.max_slayers + 1); },
+        (false, Some(_)) => self.split_region(&mut regions, key),
+        _ => {},
+      }
+    }
+    self.merge_regions(&mut regions);
+    self.set_regions(&regions);
+    Ok(())
+  }
+  /* Iteration */
+  pub fn stems(&self) -> Stems1 {
+    /* A child that is a 1-region reads as 0 here, as it has no stem */
+    Stems1 {
+      tree: &self,
+      pos: 0,
+      layer: 0,
+      stem: 0,
+      bit: 0,
+    }
+  }
+  pub fn leaves(&self) -> Leaves1 {
+    Leaves1 {
+      tree: self.as_view(),
+      pos: 0,
+    }
+  }
+  pub fn iter_range(&self, x_range: std::ops::Range<usize>, y_range: std::ops::Range<usize>) -> RangeIter {
+    self.as_view().into_range_iter(x_range, y_range)
+  }
+  pub fn par_cells(&self) -> ParCells {
+    self.as_view().into_par_cells()
+  }
+  pub fn cursor(&self) -> K2TreeCursor {
+    self.as_view().into_cursor()
+  }
+  pub fn as_view(&self) -> K2TreeView {
+    K2TreeView {
+      matrix_width: self.matrix_width,
+      matrix_height: self.matrix_height,
+      k: self.k,
+      max_slayers: self.max_slayers,
+      slayer_starts: Cow::Borrowed(&self.slayer_starts[..]),
+      stems: &self.stems[..],
+      full: Some(&self.full[..]),
+      leaves: LeafBits::Plain(&self.leaves[..]),
+      stem_ranks: StemRanks::Index(Cow::Borrowed(&self.stem_ranks)),
+    }
+  }
+  /* Information */
+  pub fn matrix_width(&self) -> usize {
+    self.matrix_width
+  }
+  pub fn matrix_height(&self) -> usize {
+    self.matrix_height
+  }
+  pub fn k(&self) -> usize {
+    self.k
+  }
+  pub fn heapsize(&self) -> usize {
+    let mut size: usize = std::mem::size_of_val(self);
+    size += std::mem::size_of::<usize>() * self.slayer_starts.len();
+    size += self.stems.len() / 8;
+    size += self.full.len() / 8;
+    size += self.leaves.len() / 8;
+    size += self.stem_ranks.heapsize();
+    size
+  }
+  /* Mutation */
+  pub fn grow(&mut self) -> Result<()> {
+    /* Both extents grow by a factor of k, every region
+    moves one layer further from the root */
+    if !stems_cover(self.k, self.max_slayers + 1, 0, 0) {
+      return Err(too_wide(self.matrix_width, self.matrix_height))
+    }
+    let regions = self.regions();
+    self.max_slayers += 1;
+    let tree_width = self.k.pow(self.max_slayers as u32 + 1);
+    self.matrix_width = grown_extent(self.matrix_width, self.k, tree_width);
+    self.matrix_height = grown_extent(self.matrix_height, self.k, tree_width);
+    let regions: Regions = regions.into_iter().map(|(start, depth)| (start, depth+1)).collect();
+    self.set_regions(&regions);
+    Ok(())
+  }
+  pub fn shrink_if_possible(&mut self) {
+    match self.shrink() {
+      _ => ()
+    }
+  }
+  pub fn shrink(&mut self) -> Result<()> {
+    /* Extents are clipped to the smaller square */
+    if self.tree_width() <= self.k.pow(3) {
+      return Err(Error::CouldNotShrink(format!("Already at minimum size: {}", self.tree_width())))
+    }
+    let tree_width = self.tree_width() / self.k;
+    if self.iter_range(tree_width..self.matrix_width, 0..self.matrix_height).next().is_some()
+    || self.iter_range(0..self.matrix_width, tree_width..self.matrix_height).next().is_some() {
+      return Err(Error::CouldNotShrink("Shrinking would lose information about the matrix".into()))
+    }
+    self.remove_top_layers(1);
+    self.matrix_width = std::cmp::min(self.matrix_width, tree_width);
+    self.matrix_height = std::cmp::min(self.matrix_height, tree_width);
+    Ok(())
+  }
+  pub fn resize(&mut self, width: usize, height: usize) -> Result<()> {
+    /* Changes the extents, growing or shrinking the stems to the
+    smallest square holding both. Fails if a 1 would be cut off */
+    if self.iter_range(width..self.matrix_width, 0..self.matrix_height).next().is_some()
+    || self.iter_range(0..self.matrix_width, height..self.matrix_height).next().is_some() {
+      return Err(Error::CouldNotShrink("Resizing would lose information about the matrix".into()))
+    }
+    if !can_cover(self.k, width, height) {
+      return Err(too_wide(width, height))
+    }
+    let extent = std::cmp::max(width, height);
+    while self.tree_width() < extent {
+      self.grow()?;
+    }
+    let mut max_slayers = self.max_slayers;
+    while max_slayers > 2 && self.k.pow(max_slayers as u32) >= extent {
+      max_slayers -= 1;
+    }
+    if max_slayers < self.max_slayers {
+      self.remove_top_layers(self.max_slayers - max_slayers);
+    }
+    self.matrix_width = width;
+    self.matrix_height = height;
+    Ok(())
+  }
+  /* Set Operations */
+  pub fn union(&self, other: &K2Tree1) -> Result<K2Tree1> {
+    self.combine(other, SetOp::Union)
+  }
+  pub fn intersect(&self, other: &K2Tree1) -> Result<K2Tree1> {
+    self.combine(other, SetOp::Intersection)
+  }
+  pub fn difference(&self, other: &K2Tree1) -> Result<K2Tree1> {
+    self.combine(other, SetOp::Difference)
+  }
+  pub fn symmetric_difference(&self, other: &K2Tree1) -> Result<K2Tree1> {
+    self.combine(other, SetOp::SymmetricDifference)
+  }
+  /* To / From */
+  pub fn from_matrix(m: BitMatrix) -> Result<Self> {
+    /* m[x] is the column at x, as in K2Tree::from_matrix */
+    let height = m.iter().fold(0, |height, column| std::cmp::max(height, column.len()));
+    let mut coords: Vec<(usize, usize)> = Vec::new();
+    for (x, column) in m.iter().enumerate() {
+      for y in one_positions(column).into_iter() {
+        coords.push((x, y));
+      }
+    }
+    K2Tree1::from_coordinates_with_extents(2, m.len(), height, coords)
+  }
+  pub fn to_matrix(&self) -> BitMatrix {
+    let mut m: BitMatrix = vec![bitvec![0; self.matrix_height]; self.matrix_width];
+    for [x, y] in self.iter_range(0..self.matrix_width, 0..self.matrix_height) {
+      m[x].set(y, true);
+    }
+    m
+  }
+  pub fn from_coordinates<I: IntoIterator<Item=(usize, usize)>>(width: usize, coords: I) -> Result<Self> {
+    K2Tree1::from_coordinates_with_k(width, 2, coords)
+  }
+  pub fn from_coordinates_with_k<I: IntoIterator<Item=(usize, usize)>>(width: usize, k: usize, coords: I) -> Result<Self> {
+    let mut tree_width = k.pow(3);
+    while tree_width < width {
+      tree_width = tree_width.checked_mul(k).ok_or_else(|| too_wide(width, width))?;
+    }
+    K2Tree1::from_coordinates_with_extents(k, tree_width, tree_width, coords)
+  }
+  pub fn from_coordinates_with_extents<I: IntoIterator<Item=(usize, usize)>>(k: usize, width: usize, height: usize, coords: I) -> Result<Self> {
+    let mut tree = K2Tree1::with_extents(k, width, height)?;
+    let mut regions = Regions::new();
+    for (x, y) in coords {
+      if x >= tree.matrix_width || y >= tree.matrix_height {
+        return Err(Error::OutOfBounds([x, y], tree.as_view().max_coords()))
+      }
+      regions.insert(tree.z_order_key(x, y), tree.max_slayers + 1);
+    }
+    tree.merge_regions(&mut regions);
+    tree.set_regions(&regions);
+    Ok(tree)
+  }
+  pub fn from_k2tree(tree: &K2Tree) -> Result<Self> {
+    let cells = tree.iter_range(0..tree.matrix_width, 0..tree.matrix_height).map(|[x, y]| (x, y));
+    K2Tree1::from_coordinates_with_extents(tree.k, tree.matrix_width, tree.matrix_height, cells)
+  }
+  pub fn to_k2tree(&self) -> K2Tree {
+    self.as_view().to_k2tree()
+  }
+  /* Serialization / Deserialization */
+  pub fn to_json(&self) -> Result<String> {
+    Ok(serde_json::to_string(self)?)
+  }
+  pub fn into_json(self) -> Result<String> {
+    Ok(serde_json::to_string(&self)?)
+  }
+  pub fn from_json(json: &str) -> Result<Self> {
+    Ok(serde_json::from_str::<Self>(json)?)
+  }
+  pub fn write_to(&self, writer: &mut impl std::io::Write) -> Result<()> {
+    /* The layout of K2Tree::write_to with the 1-regions after the stems */
+    let fields = [
+      self.k,
+      self.matrix_width,
+      self.matrix_height,
+      self.max_slayers,
+      self.slayer_starts.len(),
+      self.stems.len(),
+      self.leaves.len()
+    ];
+    let sections = [bits_to_bytes(&self.stems), bits_to_bytes(&self.full), bits_to_bytes(&self.leaves)];
+    write_binary(writer, BINARY_ONE_REGIONS, fields, &self.slayer_starts, &sections)
+  }
+  pub fn read_from(mut reader: impl std::io::Read) -> Result<Self> {
+    /* Reads what either K2Tree1::write_to or K2Tree::write_to wrote */
+    let mut buf: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    let view = K2TreeView::new(&buf)?;
+    let full = match view.full {
+      Some(full) => full,
+      None => return K2Tree1::from_k2tree(&view.to_k2tree()),
+    };
+    let leaves = match view.leaves {
+      LeafBits::Plain(leaves) => leaves,
+      LeafBits::Vocabulary(_) => return Err(invalid_binary("1-regions can't have a leaf vocabulary")),
+    };
+    Ok(K2Tree1 {
+      matrix_width: view.matrix_width,
+      matrix_height: view.matrix_height,
+      k: view.k,
+      max_slayers: view.max_slayers,
+      slayer_starts: view.slayer_starts.to_vec(),
+      stems: BitVec::from(view.stems),
+      full: BitVec::from(full),
+      leaves: BitVec::from(leaves),
+      stem_ranks: RankIndex::new(view.stems),
+    })
+  }
+}
+
+/* Cursors */
+/* A position in a K2Tree, at a stem or leaf covering a square submatrix,
+for writing traversals that the iterators don't cover. Children are
+numbered row by row from the top left, as in the stems */
+#[derive(Debug, Clone)]
+pub struct K2TreeCursor<'a, B: BitStore = GrowableBits> {
+  tree: K2TreeView<'a, B>,
+  path: Vec<CursorNode>, //from the root down to the current node
+}
+#[derive(Debug, Clone, Copy)]
+struct CursorNode {
+  layer: usize, //max_slayers for leaves
+  start: usize, //bit position in stems or leaves
+  range: Range,
+}
+impl<'a, B: BitStore> K2TreeCursor<'a, B> {
+  pub fn new(tree: K2TreeView<'a, B>) -> Self {
+    let tree_width = tree.k.pow(tree.max_slayers as u32 + 1);
+    K2TreeCursor {
+      tree: tree,
+      path: vec![CursorNode {
+        layer: 0,
+        start: 0,
+        range: [[0, tree_width-1], [0, tree_width-1]],
+      }],
+    }
+  }
+  /* Navigation */
+  pub fn root(&self) -> Self {
+    K2TreeCursor {
+      tree: self.tree.clone(),
+      path: vec![self.path[0]],
+    }
+  }
+  pub fn child(&self, i: usize) -> Option<Self> {
+    /* None for a leaf, a child that's all 0s (or all 1s in a K2Tree1)
+    or an i past the last child */
+    let node = self.node();
+    if self.is_leaf()
+    || i >= self.tree.stem_len()
+    || !self.tree.stems[node.start+i] {
+      return None
+    }
+    let start = if node.layer == self.tree.max_slayers-1 {
+      self.tree.leaf_start(node.start+i).ok()?
+    }
+    else {
+      self.tree.child_stem(node.layer, node.start, i).ok()?
+    };
+    let mut path = self.path.clone();
+    path.push(CursorNode {
+      layer: node.layer+1,
+      start: start,
+      range: to_subranges(node.range, self.tree.k)[i],
+    });
+    Some(K2TreeCursor {
+      tree: self.tree.clone(),
+      path: path,
+    })
+  }
+  pub fn parent(&self) -> Option<Self> {
+    if self.is_root() { return None }
+    Some(K2TreeCursor {
+      tree: self.tree.clone(),
+      path: self.path[..self.path.len()-1].to_vec(),
+    })
+  }
+  /* Information */
+  pub fn is_root(&self) -> bool {
+    self.path.len() == 1
+  }
+  pub fn is_leaf(&self) -> bool {
+    self.node().layer == self.tree.max_slayers
+  }
+  pub fn layer(&self) -> usize {
+    self.node().layer
+  }
+  pub fn num_children(&self) -> usize {
+    self.tree.stem_len()
+  }
+  pub fn range(&self) -> [std::ops::Range<usize>; 2] {
+    /* The x and y ranges covered, which can reach past the extents */
+    let range = self.node().range;
+    [range[0][0]..range[0][1]+1, range[1][0]..range[1][1]+1]
+  }
+  pub fn child_range(&self, i: usize) -> Option<[std::ops::Range<usize>; 2]> {
+    /* The ranges of child i, or in a leaf of cell i, None past the last */
+    if i >= self.tree.stem_len() { return None }
+    let range = to_subranges(self.node().range, self.tree.k)[i];
+    Some([range[0][0]..range[0][1]+1, range[1][0]..range[1][1]+1])
+  }
+  pub fn bit(&self, i: usize) -> bool {
+    /* Whether child i has any 1s, or in a leaf whether cell i is set */
+    let node = self.node();
+    if i >= self.tree.stem_len() { false }
+    else if self.is_leaf() { self.tree.leaves[node.start+i] }
+    else { self.tree.stems[node.start+i] || self.tree.is_full(node.start+i) }
+  }
+  pub fn is_full(&self, i: usize) -> bool {
+    /* Whether child i is a 1-region, only ever true in a K2Tree1 */
+    !self.is_leaf() && i < self.tree.stem_len() && self.tree.is_full(self.node().start+i)
+  }
+  pub fn tree(&self) -> &K2TreeView<'a, B> {
+    &self.tree
+  }
+}
+impl<'a, B: BitStore> K2TreeCursor<'a, B> {
+  fn node(&self) -> CursorNode {
+    self.path[self.path.len()-1]
+  }
+}
+
+/* Statistics */
+/* How a K2Tree is laid out and how well it compresses, sizes are in bits
+as stored, so after any leaf encoding and without the rank indexes */
+#[derive(Debug, Clone, PartialEq)]
+pub struct K2TreeStats {
+  pub stem_layers: usize,
+  pub bits_per_layer: Vec<usize>,
+  pub ones_per_layer: Vec<usize>,
+  pub leaves: usize,
+  pub total_bits: usize, //stems and leaves
+  pub cells_set: usize,
+  pub matrix_cells: usize, //matrix_width * matrix_height
+  pub bits_per_cell: f64, //0 with no cells set
+  pub dense_ratio: f64, //bits in the dense matrix over total_bits
+  pub coordinate_ratio: f64, //bits in a list of (usize, usize) over total_bits
+}
+impl K2TreeStats {
+  fn new(bits_per_layer: Vec<usize>,
+    ones_per_layer: Vec<usize>,
+    leaves: usize,
+    total_bits: usize,
+    cells_set: usize,
+    matrix_cells: usize) -> Self {
+    K2TreeStats {
+      stem_layers: bits_per_layer.len(),
+      bits_per_layer: bits_per_layer,
+      ones_per_layer: ones_per_layer,
+      leaves: leaves,
+      total_bits: total_bits,
+      cells_set: cells_set,
+      matrix_cells: matrix_cells,
+      bits_per_cell: if cells_set == 0 { 0.0 } else { total_bits as f64 / cells_set as f64 },
+      dense_ratio: matrix_cells as f64 / total_bits as f64,
+      coordinate_ratio: (cells_set * COORDINATE_BITS) as f64 / total_bits as f64,
+    }
+  }
+}
+/* Size of a cell in a coordinate list */
+pub const COORDINATE_BITS: usize = 2 * 8 * std::mem::size_of::<usize>();
+
+/* Iterators */
+#[derive(Clone, Debug)]
+pub struct StemBit {
+  pub value: bool,
+  pub layer: usize,
+  pub stem: usize,
+  pub bit: usize,
+}
+#[derive(Clone, Debug)]
+pub struct LeafBit {
+  pub value: bool,
+  pub x: usize,
+  pub y: usize,
+}
+pub struct Stems<'a, B: BitStore = GrowableBits> {
+  tree: &'a K2Tree<B>,
+  pos: usize,
+  layer: usize,
+  stem: usize,
+  bit: usize,
+}
+impl<'a, B: BitStore> Iterator for Stems<'a, B> {
+  type Item = StemBit;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos >= self.tree.stems.len() {
+      return None
+    }
+    let value = self.tree.stems.get(self.pos);
+    let ret_v = Some(StemBit {
+      value: value,
+      layer: self.layer,
+      stem: self.stem,
+      bit: self.bit,
+    });
+    let stem_len = self.tree.stem_len();
+    self.pos += 1;
+    self.bit = (self.bit + 1) % stem_len;
+    if self.bit == 0 {
+      self.stem = (self.stem + 1) % (self.tree.layer_len(self.layer) / stem_len);
+      if self.stem == 0 { self.layer += 1; }
+    }
+    ret_v
+  }
+}
+pub struct Leaves<'a, B: BitStore = GrowableBits> {
+  tree: &'a K2Tree<B>,
+  pos: usize,
+}
+impl<'a, B: BitStore> Iterator for Leaves<'a, B> {
+  type Item = LeafBit;
+  fn next(&mut self) -> Option<Self::Item> {
+    /* Need get_coords(bit_pos) function for leaf bits for this */
+    if self.pos == self.tree.leaves.len() { return None }
+    let [x, y] = self.tree.get_coords(self.pos);
+    let value = self.tree.leaves[self.pos];
+    self.pos += 1;
+    Some(LeafBit {
+      value: value,
+      x: x,
+      y: y,
+    })
+  }
+}
+pub struct IntoLeaves<B: BitStore = GrowableBits> {
+  tree: K2Tree<B>,
+  pos: usize,
+}
+impl<B: BitStore> Iterator for IntoLeaves<B> {
+  type Item = LeafBit;
+  fn next(&mut self) -> Option<Self::Item> {
+    /* Need get_coords(bit_pos) function for leaf bits for this */
+    if self.pos == self.tree.leaves.len() { return None }
+    let [x, y] = self.tree.get_coords(self.pos);
+    let value = self.tree.leaves[self.pos];
+    self.pos += 1;
+    Some(LeafBit {
+      value: value,
+      x: x,
+      y: y,
+    })
+  }
+}
+pub struct Stems1<'a> {
+  tree: &'a K2Tree1,
+  pos: usize,
+  layer: usize,
+  stem: usize,
+  bit: usize,
+}
+impl<'a> Iterator for Stems1<'a> {
+  type Item = StemBit;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos >= self.tree.stems.len() {
+      return None
+    }
+    let ret_v = Some(StemBit {
+      value: self.tree.stems[self.pos],
+      layer: self.layer,
+      stem: self.stem,
+      bit: self.bit,
+    });
+    let stem_len = self.tree.stem_len();
+    self.pos += 1;
+    self.bit = (self.bit + 1) % stem_len;
+    if self.bit == 0 {
+      self.stem = (self.stem + 1) % (self.tree.layer_len(self.layer) / stem_len);
+      if self.stem == 0 { self.layer += 1; }
+    }
+    ret_v
+  }
+}
+pub struct Leaves1<'a> {
+  tree: K2TreeView<'a>,
+  pos: usize,
+}
+impl<'a> Iterator for Leaves1<'a> {
+  type Item = LeafBit;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos == self.tree.leaves.len() { return None }
+    let [x, y] = self.tree.leaf_coords(self.pos);
+    let value = self.tree.leaves[self.pos];
+    self.pos += 1;
+    Some(LeafBit {
+      value: value,
+      x: x,
+      y: y,
+    })
+  }
+}
+enum RangeNode {
+  Stem(usize, usize, Range), //layer, stem_start, stem_range
+  Leaf(usize, Range, usize), //leaf_start, leaf_range, next bit to check
+  Full(Range, usize), //full_range, next bit to check once it's k by k
+}
+pub struct RangeIter<'a, B: BitStore = GrowableBits> {
+  tree: K2TreeView<'a, B>,
+  query: Range,
+  stack: Vec<RangeNode>,
+}
+impl<'a, B: BitStore> Iterator for RangeIter<'a, B> {
+  type Item = [usize; 2];
+  fn next(&mut self) -> Option<Self::Item> {
+    let stem_len = self.tree.stem_len();
+    while let Some(node) = self.stack.pop() {
+      let tree = &self.tree;
+      match node {
+        RangeNode::Stem(layer, stem_start, range) => {
+          self.push_children(layer, stem_start, range);
+        },
+        RangeNode::Leaf(leaf_start, range, mut bit) => {
+          while bit < stem_len {
+            let x = range[0][0] + (bit % tree.k);
+            let y = range[1][0] + (bit / tree.k);
+            let value = tree.leaves[leaf_start+bit];
+            bit += 1;
+            if value && within_range(&self.query, x, y) {
+              /* Come back for the rest of the leaf next time */
+              if bit < stem_len {
+                self.stack.push(RangeNode::Leaf(leaf_start, range, bit));
+              }
+              return Some([x, y])
+            }
+          }
+        },
+        RangeNode::Full(range, mut bit) => {
+          if range[0][1] - range[0][0] + 1 > tree.k {
+            /* Split until k by k so cells come out in the same order
+            as from leaves */
+            self.push_full_children(range);
+            continue
+          }
+          while bit < stem_len {
+            let x = range[0][0] + (bit % tree.k);
+            let y = range[1][0] + (bit / tree.k);
+            bit += 1;
+            if within_range(&self.query, x, y) {
+              if bit < stem_len {
+                self.stack.push(RangeNode::Full(range, bit));
+              }
+              return Some([x, y])
+            }
+          }
+        },
+      }
+    }
+    None
+  }
+}
+impl<'a, B: BitStore> RangeIter<'a, B> {
+  fn push_children(&mut self, layer: usize, stem_start: usize, range: Range) {
+    let tree = &self.tree;
+    let subranges = to_subranges(range, tree.k);
+    /* Push children backwards so they're visited in order */
+    for child_pos in (0..tree.stem_len()).rev() {
+      if !ranges_overlap(&subranges[child_pos], &self.query) {
+        continue
+      }
+      if !tree.stems[stem_start+child_pos] {
+        if tree.is_full(stem_start+child_pos) {
+          self.stack.push(RangeNode::Full(subranges[child_pos], 0));
+        }
+        continue
+      }
+      if layer == tree.max_slayers-1 {
+        if let Ok(leaf_start) = tree.leaf_start(stem_start+child_pos) {
+          self.stack.push(RangeNode::Leaf(leaf_start, subranges[child_pos], 0));
+        }
+      }
+      else if let Ok(child_stem) = tree.child_stem(layer, stem_start, child_pos) {
+        self.stack.push(RangeNode::Stem(layer+1, child_stem, subranges[child_pos]));
+      }
+    }
+  }
+  fn push_full_children(&mut self, range: Range) {
+    let subranges = to_subranges(range, self.tree.k);
+    for child_pos in (0..self.tree.stem_len()).rev() {
+      if ranges_overlap(&subranges[child_pos], &self.query) {
+        self.stack.push(RangeNode::Full(subranges[child_pos], 0));
+      }
+    }
+  }
+  fn split(&mut self) -> Option<Self> {
+    /* Hands the later half of the subtrees still to visit to a new
+    iterator. With a single subtree left its children are split instead,
+    so a fresh iterator is split by top-level subtree first */
+    while self.stack.len() == 1 {
+      match self.stack[0] {
+        RangeNode::Stem(layer, stem_start, range) => {
+          self.stack.pop();
+          self.push_children(layer, stem_start, range);
+        },
+        RangeNode::Full(range, 0) if range[0][1] - range[0][0] + 1 > self.tree.k => {
+          self.stack.pop();
+          self.push_full_children(range);
+        },
+        _ => return None,
+      }
+    }
+    if self.stack.len() < 2 { return None }
+    /* The stack is popped from the back, so later subtrees are at the front */
+    let later: Vec<RangeNode> = self.stack.drain(..self.stack.len()/2).collect();
+    Some(RangeIter {
+      tree: self.tree.clone(),
+      query: self.query,
+      stack: later,
+    })
+  }
+}
+/* Splittable iterator over every set cell, for scanning a tree on
+several threads. Each part decodes coordinates on the way down like
+RangeIter, and parts come out in the same order as iter_range's cells */
+pub struct ParCells<'a, B: BitStore = GrowableBits> {
+  cells: RangeIter<'a, B>,
+}
+impl<'a, B: BitStore> ParCells<'a, B> {
+  pub fn split(&mut self) -> Option<Self> {
+    self.cells.split().map(|cells| ParCells { cells: cells })
+  }
+  pub fn into_parts(self, max_parts: usize) -> Vec<Self> {
+    split_parts(self, max_parts, ParCells::split)
+  }
+  pub fn for_each<F: Fn([usize; 2]) + Sync>(self, f: F) {
+    run_parts(self.into_parts(num_cpus::get() * 4), |part| part.cells.for_each(&f));
+  }
+  pub fn map_collect<T: Send, F: Fn([usize; 2]) -> T + Sync>(self, f: F) -> Vec<T> {
+    /* Results are in the same order as iter_range's */
+    run_parts(self.into_parts(num_cpus::get() * 4), |part| part.cells.map(&f).collect::<Vec<T>>())
+      .into_iter()
+      .flatten()
+      .collect()
+  }
+  pub fn count(self) -> usize {
+    run_parts(self.into_parts(num_cpus::get() * 4), |part| part.cells.count())
+      .into_iter()
+      .sum()
+  }
+}
+impl<'a, B: BitStore> IntoIterator for ParCells<'a, B> {
+  type Item = [usize; 2];
+  type IntoIter = RangeIter<'a, B>;
+  fn into_iter(self) -> Self::IntoIter {
+    self.cells
+  }
+}
+pub struct Successors<'a, B: BitStore = GrowableBits> {
+  cells: RangeIter<'a, B>,
+}
+impl<'a, B: BitStore> Iterator for Successors<'a, B> {
+  type Item = usize;
+  fn next(&mut self) -> Option<Self::Item> {
+    self.cells.next().map(|[_, y]| y)
+  }
+}
+pub struct Predecessors<'a, B: BitStore = GrowableBits> {
+  cells: RangeIter<'a, B>,
+}
+impl<'a, B: BitStore> Iterator for Predecessors<'a, B> {
+  type Item = usize;
+  fn next(&mut self) -> Option<Self::Item> {
+    self.cells.next().map(|[x, _]| x)
+  }
+}
+pub struct StemsRaw<'a> {
+  stems: &'a BitSlice,
+  pos: usize,
+}
+impl<'a> Iterator for StemsRaw<'a> {
+  type Item = bool;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos >= self.stems.len() {
+      return None
+    }
+    self.pos += 1;
+    Some(self.stems[self.pos])
+  }
+}
+pub struct LeavesRaw<'a, B: BitStore = GrowableBits> {
+  leaves: &'a LeafLayer<B>,
+  pos: usize,
+}
+impl<'a, B: BitStore> Iterator for LeavesRaw<'a, B> {
+  type Item = bool;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos >= self.leaves.len() {
+      return None
+    }
+    self.pos += 1;
+    Some(self.leaves[self.pos])
+  }
+}
+
+/* Traits */
+impl<B: BitStore> core::fmt::Display for K2Tree<B> {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    if self.leaves.len() == 0 {
+      return write!(f, "[{}]", "0".repeat(self.stem_len()))
+    }
+    let mut s = String::new();
+    let mut i: usize = 1;
+    for layer_num in 0..self.slayer_starts.len() {
+      for bit_pos in self.layer_start(layer_num)..self.layer_start(layer_num+1) {
+        if self.stems.get(bit_pos) {
+          s.push('1');
+        }
+        else {
+          s.push('0');
+        }
+        if i == self.k*self.k
+        && (bit_pos - self.layer_start(layer_num)) < self.layer_len(layer_num)-1 {
+          s.push(',');
+          i = 1;
+        } 
+        else {
+          i += 1;
+        }
+      }
+      i = 1;
+      s.push_str("::");
+    }
+    i = 1;
+    for bit_pos in 0..self.leaves.len() {
+      if self.leaves[bit_pos] {
+        s.push('1');
+      }
+      else {
+        s.push('0');
+      }
+      if i == self.k*self.k
+      && bit_pos < self.leaves.len()-1 {
+        s.push(',');
+        i = 1;
+      } 
+      else {
+        i += 1;
+      }
+    }
+    write!(f, "[{}]", s)
+  }
+}
+impl<B: BitStore> PartialEq for K2Tree<B> {
+  fn eq(&self, other: &Self) -> bool {
+    self.k == other.k
+    && self.matrix_width == other.matrix_width
+    && self.matrix_height == other.matrix_height
+    && self.stems.as_bitslice() == other.stems.as_bitslice()
+    && self.leaves == other.leaves
+  }
+}
+impl<B: BitStore> Eq for K2Tree<B> {}
+impl Default for K2Tree {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+impl PartialEq for K2Tree1 {
+  fn eq(&self, other: &Self) -> bool {
+    self.k == other.k
+    && self.matrix_width == other.matrix_width
+    && self.matrix_height == other.matrix_height
+    && self.stems == other.stems
+    && self.full == other.full
+    && self.leaves == other.leaves
+  }
+}
+impl Eq for K2Tree1 {}
+impl Default for K2Tree1 {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+impl<B: BitStore> std::hash::Hash for K2Tree<B> {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+      self.k.hash(state);
+      self.matrix_width.hash(state);
+      self.matrix_height.hash(state);
+      self.stems.as_bitslice().hash(state);
+      for leaf in self.leaves.leaves(self.stem_len()) {
+        leaf.hash(state);
+      }
+  }
+}
+impl std::convert::TryFrom<BitMatrix> for K2Tree {
+  type Error = Error;
+  fn try_from(bit_matrix: BitMatrix) -> Result<Self> {
+    /* Implement error checking here */
+    Self::from_matrix(bit_matrix)
+  }
+}
+impl std::convert::TryFrom<Vec<Vec<bool>>> for K2Tree {
+  type Error = Error;
+  fn try_from(matrix: Vec<Vec<bool>>) -> Result<Self> {
+    Self::from_matrix(
+      matrix.into_iter().map(|v| {
+        let mut bv = BitVec::new();
+        for bit in v.into_iter() {
+          bv.push(bit);
+        }
+        bv
+      }).collect()
+    )
+  }
+}
+impl<B: BitStore> Serialize for K2Tree<B> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    let mut state = serializer.serialize_struct("K2Tree", 8)?;
+    state.serialize_field("matrixWidth", &self.matrix_width)?;
+    state.serialize_field("matrixHeight", &self.matrix_height)?;
+    state.serialize_field("k", &self.k)?;
+    state.serialize_field("maxStemLayers", &self.max_slayers)?;
+    state.serialize_field("stemLayerStarts", &self.slayer_starts)?;
+    state.serialize_field("stems", &self.stems.to_bitvec().into_vec() as &Vec<u8>)?;
+    state.serialize_field("stemToLeaf", &self.stem_to_leaf)?;
+    state.serialize_field("leaves", &LeafBytes(&self.leaves))?;
+    state.end()
+  }
+}
+impl<'de> Deserialize<'de> for K2Tree {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(field_identifier, rename_all = "camelCase")]
+    enum Field {
+      MatrixWidth,
+      MatrixHeight,
+      K,
+      MaxStemLayers,
+      StemLayerStarts,
+      Stems,
+      StemToLeaf,
+      Leaves
+    }
+    struct K2TreeVisitor;
+    impl<'de> Visitor<'de> for K2TreeVisitor {
+      type Value = K2Tree;
+      fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("struct K2Tree")
+      }
+      fn visit_map<V: MapAccess<'de>>(self, mut map: V) -> std::result::Result<K2Tree, V::Error> {
+        let mut m_width = None;
+        let mut m_height = None;
+        let mut k = None;
+        let mut max_slayers = None;
+        let mut slayer_starts = None;
+        let mut stems = None;
+        let mut stem_to_leaf = None;
+        let mut leaves = None;
+        while let Some(key) = map.next_key()? {
+          match key {
+            Field::MatrixWidth => {
+                if m_width.is_some() {
+                    return Err(de::Error::duplicate_field("matrix_width"));
+                }
+                m_width = Some(map.next_value()?);
+            }
+            Field::MatrixHeight => {
+              if m_height.is_some() {
+                  return Err(de::Error::duplicate_field("matrix_height"));
+              }
+              m_height = Some(map.next_value()?);
+            }
+            Field::K => {
+              if k.is_some() {
+                  return Err(de::Error::duplicate_field("k"));
+              }
+              k = Some(map.next_value()?);
+            }
+            Field::MaxStemLayers => {
+              if max_slayers.is_some() {
+                  return Err(de::Error::duplicate_field("max_stem_layer"));
+              }
+              max_slayers = Some(map.next_value()?);
+            }
+            Field::StemLayerStarts => {
+              if slayer_starts.is_some() {
+                  return Err(de::Error::duplicate_field("stem_layer_starts"));
+              }
+              slayer_starts = Some(map.next_value()?);
+            }
+            Field::Stems => {
+              if stems.is_some() {
+                return Err(de::Error::duplicate_field("stems"));
+              }
+              stems = Some(BitVec::from(map.next_value::<Vec<u8>>()?));
+            }
+            Field::StemToLeaf => {
+              if stem_to_leaf.is_some() {
+                return Err(de::Error::duplicate_field("stem_to_leaf"));
+              }
+              stem_to_leaf = Some(map.next_value()?);
+            }
+            Field::Leaves => {
+              if leaves.is_some() {
+                return Err(de::Error::duplicate_field("leaves"));
+              }
+              leaves = Some(BitVec::from(map.next_value::<Vec<u8>>()?));
+            }
+          }
+        }
+        let m_width = m_width.ok_or_else(|| de::Error::missing_field("matrixWidth"))?;
+        /* Trees serialized before rectangular matrices were square */
+        let m_height = m_height.unwrap_or(m_width);
+        let k: usize = k.ok_or_else(|| de::Error::missing_field("k"))?;
+        let max_slayers = max_slayers.ok_or_else(|| de::Error::missing_field("maxStemLayers"))?;
+        let slayer_starts: Vec<usize> = slayer_starts.ok_or_else(|| de::Error::missing_field("stemLayerStarts"))?;
+        let mut stems: BitVec = stems.ok_or_else(|| de::Error::missing_field("stems"))?;
+        let stem_to_leaf: Vec<usize> = stem_to_leaf.ok_or_else(|| de::Error::missing_field("stemToLeaf"))?;
+        let mut leaves: BitVec = leaves.ok_or_else(|| de::Error::missing_field("leaves"))?;
+        if k < 2 || !stems_cover(k, max_slayers, m_width, m_height) {
+          return Err(de::Error::custom("Invalid K2Tree: the stems do not cover the matrix"))
+        }
+        /* Bits are serialized as whole bytes, trim the padding back off.
+        Each layer holds one stem per 1 in the layer above it */
+        let stem_len = k * k;
+        let num_layers = slayer_starts.len();
+        if num_layers == 0
+        || slayer_starts.windows(2).any(|starts| starts[0] > starts[1])
+        || slayer_starts[num_layers-1] > stems.len() {
+          return Err(de::Error::custom("Invalid K2Tree: stem layer starts are not in ascending order within the stems"))
+        }
+        let stems_len = if num_layers == 1 {
+          Some(stem_len)
+        }
         else {
-          i += 1;
+          let last_start = slayer_starts[num_layers-1];
+          (ones_in_range(&stems, slayer_starts[num_layers-2], last_start))
+            .checked_mul(stem_len)
+            .and_then(|last_len| last_start.checked_add(last_len))
+        };
+        match stems_len {
+          Some(stems_len) if stems_len <= stems.len() => stems.truncate(stems_len),
+          _ => return Err(de::Error::custom("Invalid K2Tree: the stem layers run past the stems")),
+        }
+        if let Err(reason) = check_stem_layers(k, max_slayers, &slayer_starts, &stems) {
+          return Err(de::Error::custom(format!("Invalid K2Tree: {}", reason)))
+        }
+        /* stem_to_leaf holds the position of every 1 in the final layer */
+        let final_layer_start = slayer_starts[num_layers-1];
+        if !(final_layer_start..stems.len())
+          .filter(|&pos| stems[pos])
+          .map(|pos| pos - final_layer_start)
+          .eq(stem_to_leaf.iter().cloned()) {
+          return Err(de::Error::custom("Invalid K2Tree: stemToLeaf does not match the 1s in the final stem layer"))
+        }
+        match stem_to_leaf.len().checked_mul(stem_len) {
+          Some(leaves_len) if leaves_len <= leaves.len() => leaves.truncate(leaves_len),
+          _ => return Err(de::Error::custom("Invalid K2Tree: the leaves do not match the 1s in the final stem layer")),
+        }
+        Ok(K2Tree {
+          matrix_width: m_width,
+          matrix_height: m_height,
+          k: k,
+          max_slayers: max_slayers,
+          slayer_starts: slayer_starts,
+          stems: stems.into(),
+          stem_to_leaf: stem_to_leaf,
+          leaves: leaves.into(),
+        })
+      }
+    }
+    const FIELDS: &'static [&'static str] = &[
+      "matrix_width",
+      "matrix_height",
+      "k",
+      "max_stem_layers",
+      "stem_layer_starts",
+      "stems",
+      "stem_to_leaf",
+      "leaves"
+    ];
+    deserializer.deserialize_struct("K2Tree", FIELDS, K2TreeVisitor)
+  }
+}
+
+/* The JSON form of a K2Tree1, each bit vector packed into
+bytes as in write_to with its length alongside */
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct K2Tree1Json {
+  matrix_width: usize,
+  matrix_height: usize,
+  k: usize,
+  max_stem_layers: usize,
+  stem_layer_starts: Vec<usize>,
+  num_stems: usize,
+  stems: Vec<u8>,
+  full: Vec<u8>,
+  num_leaves: usize,
+  leaves: Vec<u8>,
+}
+impl Serialize for K2Tree1 {
+  fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    K2Tree1Json {
+      matrix_width: self.matrix_width,
+      matrix_height: self.matrix_height,
+      k: self.k,
+      max_stem_layers: self.max_slayers,
+      stem_layer_starts: self.slayer_starts.clone(),
+      num_stems: self.stems.len(),
+      stems: bits_to_bytes(&self.stems),
+      full: bits_to_bytes(&self.full),
+      num_leaves: self.leaves.len(),
+      leaves: bits_to_bytes(&self.leaves),
+    }.serialize(serializer)
+  }
+}
+impl<'de> Deserialize<'de> for K2Tree1 {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    /* Checked as K2TreeIndex checks the binary form */
+    let json = K2Tree1Json::deserialize(deserializer)?;
+    let invalid = |reason: &str| de::Error::custom(format!("Invalid K2Tree1: {}", reason));
+    if json.k < 2 || !stems_cover(json.k, json.max_stem_layers, json.matrix_width, json.matrix_height) {
+      return Err(invalid("the stems do not cover the matrix"))
+    }
+    if bytes_for_bits(json.num_stems) != json.stems.len()
+    || json.full.len() != json.stems.len()
+    || bytes_for_bits(json.num_leaves) != json.leaves.len() {
+      return Err(invalid("the number of bits does not match the bytes holding them"))
+    }
+    let mut stems = BitVec::from(json.stems);
+    let mut full = BitVec::from(json.full);
+    let mut leaves = BitVec::from(json.leaves);
+    stems.truncate(json.num_stems);
+    full.truncate(json.num_stems);
+    leaves.truncate(json.num_leaves);
+    check_stem_layers(json.k, json.max_stem_layers, &json.stem_layer_starts, &stems).map_err(invalid)?;
+    if full.iter().zip(stems.iter()).any(|(full, stem)| full && stem) {
+      return Err(invalid("a child can't be both a stem and a 1-region"))
+    }
+    let final_layer_start = json.stem_layer_starts[json.stem_layer_starts.len()-1];
+    if ones_in_range(&stems, final_layer_start, stems.len()) * json.k * json.k != leaves.len() {
+      return Err(invalid("the leaves do not match the 1s in the final stem layer"))
+    }
+    let stem_ranks = RankIndex::new(&stems);
+    Ok(K2Tree1 {
+      matrix_width: json.matrix_width,
+      matrix_height: json.matrix_height,
+      k: json.k,
+      max_slayers: json.max_stem_layers,
+      slayer_starts: json.stem_layer_starts,
+      stems: stems,
+      full: full,
+      leaves: leaves,
+      stem_ranks: stem_ranks,
+    })
+  }
+}
+
+/* Private */
+impl<'a> K2TreeView<'a> {
+  fn from_parts(bytes: &'a [u8],
+    header: &BinaryHeader,
+    slayer_starts: Cow<'a, [usize]>,
+    stem_ranks: Cow<'a, RankIndex>,
+    vocabulary: Option<Cow<'a, LeafVocabulary>>) -> Self {
+    let stems = header.stems(bytes);
+    let leaves = match vocabulary {
+      Some(vocabulary) => LeafBits::Vocabulary(vocabulary),
+      None => LeafBits::Plain(&BitSlice::from_slice(&bytes[header.leaves_offset..header.end_offset])[..header.leaves_len]),
+    };
+    K2TreeView {
+      matrix_width: header.matrix_width,
+      matrix_height: header.matrix_height,
+      k: header.k,
+      max_slayers: header.max_slayers,
+      slayer_starts: slayer_starts,
+      stems: stems,
+      full: header.full(bytes),
+      leaves: leaves,
+      stem_ranks: StemRanks::Index(stem_ranks),
+    }
+  }
+}
+impl<'a, B: BitStore> K2TreeView<'a, B> {
+  fn into_range_iter(self, x_range: std::ops::Range<usize>, y_range: std::ops::Range<usize>) -> RangeIter<'a, B> {
+    /* Only descend into subtrees that have a 1 somewhere and overlap
+    the query, everything else is skipped without being visited */
+    let x_end = std::cmp::min(x_range.end, self.matrix_width);
+    let y_end = std::cmp::min(y_range.end, self.matrix_height);
+    let mut stack = Vec::new();
+    if x_range.start < x_end
+    && y_range.start < y_end
+    && !self.root_is_empty() {
+      let tree_width = self.k.pow(self.max_slayers as u32 + 1);
+      stack.push(RangeNode::Stem(0, 0, [[0, tree_width-1], [0, tree_width-1]]));
+    }
+    RangeIter {
+      query: [[x_range.start, x_end.saturating_sub(1)], [y_range.start, y_end.saturating_sub(1)]],
+      tree: self,
+      stack: stack,
+    }
+  }
+  fn into_successors(self, x: usize) -> Successors<'a, B> {
+    let height = self.matrix_height;
+    Successors {
+      cells: self.into_range_iter(x..x.saturating_add(1), 0..height),
+    }
+  }
+  fn into_predecessors(self, y: usize) -> Predecessors<'a, B> {
+    let width = self.matrix_width;
+    Predecessors {
+      cells: self.into_range_iter(0..width, y..y.saturating_add(1)),
+    }
+  }
+  fn max_coords(&self) -> [usize; 2] {
+    [self.matrix_width.saturating_sub(1), self.matrix_height.saturating_sub(1)]
+  }
+  fn root_is_empty(&self) -> bool {
+    /* Nothing is set unless the root stem has a 1 or a 1-region */
+    let stem_len = self.stem_len();
+    !self.stems[..stem_len].iter().any(|bit| bit)
+    && !(0..stem_len).any(|bit_pos| self.is_full(bit_pos))
+  }
+  fn is_full(&self, stem_bitpos: usize) -> bool {
+    match self.full {
+      Some(full) => full[stem_bitpos],
+      None => false,
+    }
+  }
+  fn stem_len(&self) -> usize {
+    self.k * self.k
+  }
+  fn num_stems_before_child(&self, bit_pos: usize, layer: usize) -> usize {
+    let layer_start = self.layer_start(layer);
+    self.stem_ranks.rank(self.stems, bit_pos) - self.stem_ranks.rank(self.stems, layer_start)
+  }
+  fn layer_start(&self, l: usize) -> usize {
+    if l == self.slayer_starts.len() {
+      self.stems.len()
+    }
+    else {
+      self.slayer_starts[l]
+    }
+  }
+  fn leaf_start(&self, stem_bitpos: usize) -> std::result::Result<usize, ()> {
+    if !self.stems[stem_bitpos] { return Err(()) }
+    /* The nth 1 in the final stem layer points to the nth leaf */
+    let final_layer_start = self.slayer_starts[self.slayer_starts.len()-1];
+    Ok((self.stem_ranks.rank(self.stems, stem_bitpos)
+      - self.stem_ranks.rank(self.stems, final_layer_start))
+      * self.stem_len())
+  }
+  fn child_stem(&self, layer: usize, stem_start: usize, nth_child: usize) -> std::result::Result<usize, ()> {
+    if !self.stems[stem_start+nth_child]
+    || layer == self.max_slayers-1 {
+      /* If stem_bit is 0 or final stem layer, cannot have children */
+      return Err(())
+    }
+    Ok(self.layer_start(layer+1)
+    + (self.num_stems_before_child(stem_start+nth_child, layer) * self.stem_len()))
+  }
+  fn leaf_coords(&self, leaf_bit_pos: usize) -> [usize; 2] {
+    /* As K2Tree::get_coords, but without stem_to_leaf: the nth stem
+    (or leaf) below a layer is the child of the nth 1 in that layer,
+    so select finds each parent on the way up */
+    let stem_len = self.stem_len();
+    let mut nth = leaf_bit_pos / stem_len;
+    let mut offsets = Vec::with_capacity(self.max_slayers);
+    for layer in (0..self.slayer_starts.len()).rev() {
+      let layer_start = self.slayer_starts[layer];
+      let ones_before = self.stem_ranks.rank(self.stems, layer_start);
+      let parent_bit = match self.stem_ranks.select(self.stems, ones_before + nth) {
+        Some(parent_bit) => parent_bit,
+        None => unreachable!("every leaf and stem below the root has a parent"),
+      };
+      let stem_start = block_start(parent_bit, stem_len);
+      offsets.push(parent_bit - stem_start);
+      nth = (stem_start - layer_start) / stem_len;
+    }
+    offsets.reverse();
+    let tree_width = self.k.pow(self.max_slayers as u32 + 1);
+    let mut range = [[0, tree_width-1], [0, tree_width-1]];
+    for offset in offsets.into_iter() {
+      range = to_subranges(range, self.k)[offset];
+    }
+    let leaf_offset = leaf_bit_pos % stem_len;
+    [range[0][0] + (leaf_offset % self.k), range[1][0] + (leaf_offset / self.k)]
+  }
+}
+/* The 1-regions of a K2Tree1, the z-order key of the first cell in each
+mapped to the depth of its node, 1 being a child of the root and
+max_slayers+1 a single cell */
+type Regions = std::collections::BTreeMap<u128, usize>;
+impl K2Tree1 {
+  fn from_empty(tree: K2Tree) -> Self {
+    /* A K2Tree1 with the stems of an empty K2Tree, and so no 1-regions */
+    K2Tree1 {
+      matrix_width: tree.matrix_width,
+      matrix_height: tree.matrix_height,
+      k: tree.k,
+      max_slayers: tree.max_slayers,
+      slayer_starts: tree.slayer_starts,
+      full: bitvec![0; tree.stems.len()],
+      stems: tree.stems.bits,
+      leaves: BitVec::new(),
+      stem_ranks: tree.stems.ranks,
+    }
+  }
+  fn stem_len(&self) -> usize {
+    self.k * self.k
+  }
+  fn tree_width(&self) -> usize {
+    self.k.pow(self.max_slayers as u32 + 1)
+  }
+  fn set_stem_bit(&mut self, bit_pos: usize, state: bool) {
+    self.stem_ranks.set(&mut self.stems, bit_pos, state);
+  }
+  fn insert_child(&mut self, layer: usize, bit_pos: usize, full: bool) -> Result<()> {
+    /* Adds the child of the 1 at bit_pos in layer, a stem of 1-regions
+    (or leaf of 1s) if full, otherwise an empty one */
+    let stem_len = self.stem_len();
+    if layer == self.max_slayers-1 {
+      let leaf_start = self.as_view().leaf_start(bit_pos)
+        .map_err(|_| Error::LeafInsertionError(bit_pos, stem_len))?;
+      insert_block(&mut self.leaves, leaf_start, stem_len)
+        .map_err(|_| Error::LeafInsertionError(leaf_start, stem_len))?;
+      if full { self.leaves[leaf_start..leaf_start+stem_len].set_all(true); }
+      return Ok(())
+    }
+    let stem_start = block_start(bit_pos, stem_len);
+    let child_start = self.as_view().child_stem(layer, stem_start, bit_pos - stem_start)
+      .map_err(|_| Error::StemInsertionError(bit_pos, stem_len))?;
+    if insert_block(&mut self.stems, child_start, stem_len).is_err()
+    || insert_block(&mut self.full, child_start, stem_len).is_err() {
+      return Err(Error::StemInsertionError(child_start, stem_len))
+    }
+    if full { self.full[child_start..child_start+stem_len].set_all(true); }
+    for layer_start in &mut self.slayer_starts[layer+2..] {
+      *layer_start += stem_len;
+    }
+    self.stem_ranks.update_from(&self.stems, child_start);
+    Ok(())
+  }
+  fn remove_stem(&mut self, layer: usize, stem_start: usize) -> Result<()> {
+    /* Removes a stem with no children from layer */
+    let stem_len = self.stem_len();
+    if remove_block(&mut self.stems, stem_start, stem_len).is_err()
+    || remove_block(&mut self.full, stem_start, stem_len).is_err() {
+      return Err(Error::StemRemovalError(stem_start, stem_len))
+    }
+    for layer_start in &mut self.slayer_starts[layer+1..] {
+      *layer_start -= stem_len;
+    }
+    self.stem_ranks.update_from(&self.stems, stem_start);
+    Ok(())
+  }
+  fn layer_len(&self, l: usize) -> usize {
+    if l == self.slayer_starts.len()-1 {
+      return self.stems.len() - self.slayer_starts[l]
+    }
+    self.slayer_starts[l+1] - self.slayer_starts[l]
+  }
+  fn z_order_key(&self, x: usize, y: usize) -> u128 {
+    let base = self.stem_len() as u128;
+    let mut key: u128 = 0;
+    let mut sub_width = self.tree_width();
+    while sub_width > 1 {
+      sub_width /= self.k;
+      let child_pos = (((y / sub_width) % self.k) * self.k) + ((x / sub_width) % self.k);
+      key = (key * base) + child_pos as u128;
+    }
+    key
+  }
+  fn region_span(&self, depth: usize) -> u128 {
+    /* Number of cells covered by a region at depth */
+    (self.stem_len() as u128).pow((self.max_slayers + 1 - depth) as u32)
+  }
+  fn regions(&self) -> Regions {
+    let mut regions = Regions::new();
+    self.collect_regions(0, 0, 0, &mut regions);
+    regions
+  }
+  fn collect_regions(&self, layer: usize, stem_start: usize, prefix: u128, regions: &mut Regions) {
+    let view = self.as_view();
+    let base = self.stem_len() as u128;
+    for child_pos in 0..self.stem_len() {
+      let node = (prefix * base) + child_pos as u128;
+      let bit_pos = stem_start + child_pos;
+      if self.full[bit_pos] {
+        regions.insert(node * self.region_span(layer+1), layer+1);
+      }
+      else if !self.stems[bit_pos] {
+        continue
+      }
+      else if layer == self.max_slayers-1 {
+        if let Ok(leaf_start) = view.leaf_start(bit_pos) {
+          for leaf_bit in 0..self.stem_len() {
+            if self.leaves[leaf_start+leaf_bit] {
+              regions.insert((node * base) + leaf_bit as u128, self.max_slayers+1);
+            }
+          }
+        }
+      }
+      else if let Ok(child_stem) = view.child_stem(layer, stem_start, child_pos) {
+        self.collect_regions(layer+1, child_stem, node, regions);
+      }
+    }
+  }
+  fn merge_regions(&self, regions: &mut Regions) {
+    /* Replaces every k*k sibling regions with their parent, deepest
+    first so merges carry upwards. The root is never a region */
+    let base = self.stem_len() as u128;
+    for depth in (2..=self.max_slayers+1).rev() {
+      let span = self.region_span(depth);
+      let mut siblings = std::collections::BTreeMap::<u128, usize>::new();
+      for (&start, &region_depth) in regions.iter() {
+        if region_depth == depth {
+          *siblings.entry(start / (span * base)).or_insert(0) += 1;
+        }
+      }
+      for (parent, num_siblings) in siblings.into_iter() {
+        if num_siblings == self.stem_len() {
+          for child_pos in 0..base {
+            regions.remove(&(((parent * base) + child_pos) * span));
+          }
+          regions.insert(parent * span * base, depth-1);
+        }
+      }
+    }
+  }
+  fn region_holding(&self, regions: &Regions, key: u128) -> Option<(u128, usize)> {
+    match regions.range(..=key).next_back() {
+      Some((&start, &depth)) if key < start + self.region_span(depth) => Some((start, depth)),
+      _ => None,
+    }
+  }
+  fn split_region(&self, regions: &mut Regions, key: u128) {
+    /* Removes the cell at key from the region holding it, what's
+    left of the region becomes the siblings of the cell's ancestors */
+    let (start, depth) = match self.region_holding(regions, key) {
+      Some(region) => region,
+      None => return,
+    };
+    regions.remove(&start);
+    let base = self.stem_len() as u128;
+    for child_depth in depth+1..=self.max_slayers+1 {
+      let span = self.region_span(child_depth);
+      let on_path = key / span;
+      let first_sibling = (on_path / base) * base;
+      for node in first_sibling..first_sibling+base {
+        if node != on_path {
+          regions.insert(node * span, child_depth);
+        }
+      }
+    }
+  }
+  fn remove_top_layers(&mut self, removed: usize) {
+    /* Everything set lies in the first child of the top layers,
+    so the regions keep their keys and lose those layers */
+    let regions = self.regions();
+    self.max_slayers -= removed;
+    let mut shrunk = Regions::new();
+    for (start, depth) in regions.into_iter() {
+      if depth > removed {
+        shrunk.insert(start, depth - removed);
+      }
+      else {
+        /* Was the whole of the new matrix */
+        let span = self.region_span(1);
+        for child_pos in 0..self.stem_len() as u128 {
+          shrunk.insert(child_pos * span, 1);
+        }
+      }
+    }
+    self.set_regions(&shrunk);
+  }
+  fn combine(&self, other: &K2Tree1, op: SetOp) -> Result<K2Tree1> {
+    /* Works on both trees' regions rather than their cells, so a 1-region
+    is handled whole however many cells it covers. Growing a tree moves
+    every region one layer down without changing its key, so the shallower
+    tree's regions only need their depths lifting to line up */
+    if self.k != other.k {
+      return Err(Error::MismatchedK(self.k, other.k))
+    }
+    let mut tree = K2Tree1::from_empty(K2Tree::with_no_extents(self.k));
+    while tree.max_slayers < std::cmp::max(self.max_slayers, other.max_slayers) {
+      tree.grow()?;
+    }
+    tree.matrix_width = std::cmp::max(self.matrix_width, other.matrix_width);
+    tree.matrix_height = std::cmp::max(self.matrix_height, other.matrix_height);
+    let a = self.regions_at(tree.max_slayers);
+    let b = other.regions_at(tree.max_slayers);
+    let mut regions = match op {
+      SetOp::Union => tree.union_regions(&a, &b),
+      SetOp::Intersection => tree.intersect_regions(&a, &b),
+      SetOp::Difference => tree.subtract_regions(&a, &b),
+      SetOp::SymmetricDifference => {
+        let a_only = tree.subtract_regions(&a, &b);
+        let b_only = tree.subtract_regions(&b, &a);
+        tree.union_regions(&a_only, &b_only)
+      },
+    };
+    tree.merge_regions(&mut regions);
+    tree.set_regions(&regions);
+    Ok(tree)
+  }
+  fn regions_at(&self, max_slayers: usize) -> Regions {
+    let lift = max_slayers - self.max_slayers;
+    self.regions().into_iter().map(|(start, depth)| (start, depth + lift)).collect()
+  }
+  fn union_regions(&self, a: &Regions, b: &Regions) -> Regions {
+    /* Regions only ever nest or don't overlap, so in key order
+    any that starts before the last kept one ends lies inside it */
+    let mut all: Regions = a.clone();
+    for (&start, &depth) in b.iter() {
+      let kept = all.entry(start).or_insert(depth);
+      *kept = std::cmp::min(*kept, depth);
+    }
+    let mut regions = Regions::new();
+    let mut kept_end: u128 = 0;
+    for (start, depth) in all.into_iter() {
+      if regions.is_empty() || start >= kept_end {
+        kept_end = start + self.region_span(depth);
+        regions.insert(start, depth);
+      }
+    }
+    regions
+  }
+  fn intersect_regions(&self, a: &Regions, b: &Regions) -> Regions {
+    /* Of two overlapping regions one holds the other, which is their
+    intersection, and the larger may overlap more of the other side's */
+    let a: Vec<(u128, usize)> = a.iter().map(|(&start, &depth)| (start, depth)).collect();
+    let b: Vec<(u128, usize)> = b.iter().map(|(&start, &depth)| (start, depth)).collect();
+    let mut regions = Regions::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+      let a_end = a[i].0 + self.region_span(a[i].1);
+      let b_end = b[j].0 + self.region_span(b[j].1);
+      if a_end <= b[j].0 {
+        i += 1;
+      }
+      else if b_end <= a[i].0 {
+        j += 1;
+      }
+      else if a[i].1 >= b[j].1 {
+        regions.insert(a[i].0, a[i].1);
+        i += 1;
+      }
+      else {
+        regions.insert(b[j].0, b[j].1);
+        j += 1;
+      }
+    }
+    regions
+  }
+  fn subtract_regions(&self, a: &Regions, b: &Regions) -> Regions {
+    /* Every region of a less the regions of b inside it,
+    unless it lies inside one of b's */
+    let mut regions = Regions::new();
+    for (&start, &depth) in a.iter() {
+      if let Some((_, hole_depth)) = self.region_holding(b, start) {
+        if hole_depth <= depth { continue }
+      }
+      let end = start + self.region_span(depth);
+      let holes: Vec<(u128, usize)> = b.range(start..end).map(|(&start, &depth)| (start, depth)).collect();
+      self.carve_region(start, depth, &holes, &mut regions);
+    }
+    regions
+  }
+  fn carve_region(&self, start: u128, depth: usize, holes: &[(u128, usize)], regions: &mut Regions) {
+    /* Adds the region at start without holes, which all lie inside it,
+    splitting it into its children until each hole is a whole child */
+    if holes.is_empty() {
+      regions.insert(start, depth);
+      return
+    }
+    if holes.iter().any(|&(_, hole_depth)| hole_depth <= depth) { return }
+    let span = self.region_span(depth+1);
+    for child_pos in 0..self.stem_len() as u128 {
+      let child_start = start + (child_pos * span);
+      let child_holes: Vec<(u128, usize)> = holes.iter()
+        .cloned()
+        .filter(|&(hole_start, _)| hole_start >= child_start && hole_start < child_start + span)
+        .collect();
+      self.carve_region(child_start, depth+1, &child_holes, regions);
+    }
+  }
+  fn set_regions(&mut self, regions: &Regions) {
+    /* Replace the tree's contents with regions, which are already merged */
+    let regions: Vec<(u128, usize)> = regions.iter().map(|(&start, &depth)| (start, depth)).collect();
+    let mut layers: Vec<BitVec> = vec![BitVec::new(); self.max_slayers];
+    let mut full_layers: Vec<BitVec> = vec![BitVec::new(); self.max_slayers];
+    let mut leaves = BitVec::new();
+    if regions.is_empty() {
+      self.slayer_starts = vec![0];
+      self.stems = bitvec![0; self.stem_len()];
+      self.full = bitvec![0; self.stem_len()];
+    }
+    else {
+      self.build_node(0, &regions, &mut layers, &mut full_layers, &mut leaves);
+      let mut slayer_starts: Vec<usize> = Vec::new();
+      let mut stems = BitVec::new();
+      let mut full = BitVec::new();
+      for (layer, full_layer) in layers.into_iter().zip(full_layers.into_iter()) {
+        slayer_starts.push(stems.len());
+        stems.extend(layer.iter());
+        full.extend(full_layer.iter());
+      }
+      self.slayer_starts = slayer_starts;
+      self.stems = stems;
+      self.full = full;
+    }
+    self.leaves = leaves;
+    self.stem_ranks = RankIndex::new(&self.stems);
+  }
+  fn build_node(&self,
+    depth: usize,
+    regions: &[(u128, usize)],
+    layers: &mut Vec<BitVec>,
+    full_layers: &mut Vec<BitVec>,
+    leaves: &mut BitVec) {
+    /* Appends the node at depth holding regions to its layer and then its
+    children, which keeps every layer in order as in combine_node */
+    let stem_len = self.stem_len();
+    let base = stem_len as u128;
+    if depth == self.max_slayers {
+      let leaf_start = leaves.len();
+      leaves.resize(leaf_start + stem_len, false);
+      for &(key, _) in regions {
+        leaves.set(leaf_start + (key % base) as usize, true);
+      }
+      return
+    }
+    let child_span = self.region_span(depth+1);
+    let stem_start = layers[depth].len();
+    layers[depth].resize(stem_start + stem_len, false);
+    full_layers[depth].resize(stem_start + stem_len, false);
+    let mut i = 0;
+    while i < regions.len() {
+      let child_pos = ((regions[i].0 / child_span) % base) as usize;
+      let mut j = i + 1;
+      while j < regions.len()
+      && ((regions[j].0 / child_span) % base) as usize == child_pos {
+        j += 1;
+      }
+      if regions[i].1 == depth+1 {
+        full_layers[depth].set(stem_start + child_pos, true);
+      }
+      else {
+        layers[depth].set(stem_start + child_pos, true);
+        self.build_node(depth+1, &regions[i..j], layers, full_layers, leaves);
+      }
+      i = j;
+    }
+  }
+}
+pub(crate) type Range = [[usize; 2]; 2];
+/* Binary layout written by write_to, all integers little-endian:
+   0..4      magic b"RK2T"
+   4..6      version (u16), currently 2
+   6..8      flags (u16), BINARY_LEAF_VOCABULARY, BINARY_ONE_REGIONS or 0
+   8..16     k (u64)
+   16..24    matrix_width (u64)
+   24..32    matrix_height (u64)
+   32..40    max_slayers (u64)
+   40..48    number of stem layers, L (u64)
+   48..56    number of bits in stems (u64)
+   56..64    number of bits in leaves (u64)
+   64..      L stem layer starts (u64 each)
+   then      stems, leaves, each packed 8 bits per byte with the
+             first bit as the most significant and any padding 0,
+             or the stems and a LeafVocabulary if that flag is set,
+             or the stems, the 1-regions (as many bits as the stems)
+             and the leaves for a K2Tree1
+   last 8    FNV-1a 64 hash of every byte before it (u64)
+  Version 1 is the same without matrix_height, its trees are square */
+const BINARY_MAGIC: [u8; 4] = *b"RK2T";
+const BINARY_VERSION: u16 = 2;
+const BINARY_HEADER_LEN: usize = 64;
+const BINARY_LEAF_VOCABULARY: u16 = 1;
+const BINARY_ONE_REGIONS: u16 = 2;
+#[derive(Debug, Clone)]
+struct BinaryHeader {
+  k: usize,
+  matrix_width: usize,
+  matrix_height: usize,
+  max_slayers: usize,
+  slayer_starts: Vec<usize>,
+  stems_len: usize,
+  leaves_len: usize,
+  leaf_vocabulary: bool,
+  stems_offset: usize,
+  full_offset: Option<usize>, //only with 1-regions
+  leaves_offset: usize,
+  end_offset: usize,
+}
+impl BinaryHeader {
+  fn parse(bytes: &[u8]) -> Result<BinaryHeader> {
+    if bytes.len() < 8 {
+      return Err(invalid_binary("too short to hold a header"))
+    }
+    if bytes[0..4] != BINARY_MAGIC {
+      return Err(invalid_binary("missing magic number"))
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    /* Version 1 had no matrix_height, every later field sits 8 bytes earlier */
+    let (header_len, shift) = match version {
+      1 => (BINARY_HEADER_LEN - 8, 0),
+      BINARY_VERSION => (BINARY_HEADER_LEN, 8),
+      _ => return Err(Error::InvalidBinary(format!("unsupported version {}", version))),
+    };
+    if bytes.len() < header_len + 8 {
+      return Err(invalid_binary("too short to hold a header"))
+    }
+    let end_offset = bytes.len() - 8;
+    let mut checksum = [0u8; 8];
+    checksum.copy_from_slice(&bytes[end_offset..]);
+    if u64::from_le_bytes(checksum) != fnv1a(&bytes[..end_offset]) {
+      return Err(invalid_binary("checksum mismatch"))
+    }
+    let read_u64 = |offset: usize| -> usize {
+      let mut field = [0u8; 8];
+      field.copy_from_slice(&bytes[offset..offset+8]);
+      u64::from_le_bytes(field) as usize
+    };
+    let flags = u16::from_le_bytes([bytes[6], bytes[7]]);
+    if flags & !(BINARY_LEAF_VOCABULARY | BINARY_ONE_REGIONS) != 0
+    || flags == BINARY_LEAF_VOCABULARY | BINARY_ONE_REGIONS {
+      return Err(Error::InvalidBinary(format!("unknown flags {:#x}", flags)))
+    }
+    let leaf_vocabulary = flags & BINARY_LEAF_VOCABULARY != 0;
+    let one_regions = flags & BINARY_ONE_REGIONS != 0;
+    let k = read_u64(8);
+    let matrix_width = read_u64(16);
+    let matrix_height = if version == 1 { matrix_width } else { read_u64(24) };
+    let max_slayers = read_u64(24 + shift);
+    let num_slayers = read_u64(32 + shift);
+    let stems_len = read_u64(40 + shift);
+    let leaves_len = read_u64(48 + shift);
+    if k < 2 {
+      return Err(Error::InvalidBinary(format!("k must be at least 2, got {}", k)))
+    }
+    if !stems_cover(k, max_slayers, matrix_width, matrix_height) {
+      return Err(invalid_binary("the stems do not cover the matrix"))
+    }
+    /* Every field is untrusted, so an offset that overflows is as
+    invalid as one past the end of the input */
+    let section_lengths = || invalid_binary("section lengths do not match the size of the input");
+    let stems_offset = num_slayers.checked_mul(8)
+      .and_then(|len| len.checked_add(header_len))
+      .ok_or_else(section_lengths)?;
+    let stems_end = stems_offset.checked_add(bytes_for_bits(stems_len))
+      .ok_or_else(section_lengths)?;
+    let (full_offset, leaves_offset) = if one_regions {
+      (Some(stems_end), stems_end.checked_add(bytes_for_bits(stems_len)).ok_or_else(section_lengths)?)
+    }
+    else {
+      (None, stems_end)
+    };
+    /* A leaf vocabulary checks its own length when it's read */
+    if num_slayers == 0
+    || leaves_offset > end_offset
+    || (!leaf_vocabulary && leaves_offset.checked_add(bytes_for_bits(leaves_len)) != Some(end_offset)) {
+      return Err(section_lengths())
+    }
+    let slayer_starts: Vec<usize> = (0..num_slayers).map(|i| read_u64(header_len + (i * 8))).collect();
+    if !stem_layers_fit(k, max_slayers, &slayer_starts, stems_len) {
+      return Err(invalid_binary("stem layer starts are not whole stems in ascending order within the stems"))
+    }
+    Ok(BinaryHeader {
+      k: k,
+      matrix_width: matrix_width,
+      matrix_height: matrix_height,
+      max_slayers: max_slayers,
+      slayer_starts: slayer_starts,
+      stems_len: stems_len,
+      leaves_len: leaves_len,
+      leaf_vocabulary: leaf_vocabulary,
+      stems_offset: stems_offset,
+      full_offset: full_offset,
+      leaves_offset: leaves_offset,
+      end_offset: end_offset,
+    })
+  }
+  fn stems<'a>(&self, bytes: &'a [u8]) -> &'a BitSlice {
+    &BitSlice::from_slice(&bytes[self.stems_offset..])[..self.stems_len]
+  }
+  fn full<'a>(&self, bytes: &'a [u8]) -> Option<&'a BitSlice> {
+    self.full_offset.map(|full_offset| &BitSlice::from_slice(&bytes[full_offset..])[..self.stems_len])
+  }
+}
+pub(crate) fn stems_cover(k: usize, max_slayers: usize, matrix_width: usize, matrix_height: usize) -> bool {
+  /* With k at least 2, a usize can't address a tree of 64 or more stem layers */
+  let tree_width = if max_slayers < 64 { k.checked_pow(max_slayers as u32 + 1) } else { None };
+  match tree_width {
+    Some(tree_width) => tree_width >= std::cmp::max(matrix_width, matrix_height),
+    None => false,
+  }
+}
+fn stem_layers_fit(k: usize, max_slayers: usize, slayer_starts: &[usize], stems_len: usize) -> bool {
+  /* Stem layer starts are whole stems in ascending order within the stems,
+  and there's at least one layer but no more than max_slayers */
+  let stem_len = k * k;
+  !slayer_starts.is_empty()
+  && slayer_starts.len() <= max_slayers
+  && stems_len % stem_len == 0
+  && slayer_starts[0] == 0
+  && slayer_starts.windows(2).all(|starts| starts[0] <= starts[1])
+  && slayer_starts.iter().all(|&start| start <= stems_len && start % stem_len == 0)
+}
+fn check_stem_layers(k: usize, max_slayers: usize, slayer_starts: &[usize], stems: &BitSlice) -> std::result::Result<(), &'static str> {
+  /* The root layer is one stem and each later layer holds one stem per 1
+  in the layer above it. A tree with any stems has all max_slayers layers,
+  one without has only its root */
+  if !stem_layers_fit(k, max_slayers, slayer_starts, stems.len()) {
+    return Err("stem layer starts are not whole stems in ascending order within the stems")
+  }
+  let stem_len = k * k;
+  if slayer_starts.len() != max_slayers && stems[..std::cmp::min(stem_len, stems.len())].any() {
+    return Err("a tree with stems below its root has fewer than max_stem_layers layers")
+  }
+  let mut layer_len = stem_len;
+  for (i, &start) in slayer_starts.iter().enumerate() {
+    let end = slayer_starts.get(i+1).cloned().unwrap_or(stems.len());
+    if end - start != layer_len {
+      return Err("a stem layer's length does not match the 1s in the layer above it")
+    }
+    layer_len = match stems[start..end].count_ones().checked_mul(stem_len) {
+      Some(next_len) => next_len,
+      None => return Err("a stem layer's length does not match the 1s in the layer above it"),
+    };
+  }
+  Ok(())
+}
+fn write_binary(writer: &mut impl std::io::Write,
+  flags: u16,
+  fields: [usize; 7],
+  slayer_starts: &[usize],
+  sections: &[Vec<u8>]) -> Result<()> {
+  /* Header fields in the order BinaryHeader reads them, then the
+  stem layer starts, each section in turn and the checksum */
+  let sections_len = sections.iter().fold(0, |len, section| len + section.len());
+  let mut buf: Vec<u8> = Vec::with_capacity(
+    BINARY_HEADER_LEN + (slayer_starts.len() * 8) + sections_len + 8
+  );
+  buf.extend_from_slice(&BINARY_MAGIC);
+  buf.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+  buf.extend_from_slice(&flags.to_le_bytes());
+  for &field in fields.iter() {
+    buf.extend_from_slice(&(field as u64).to_le_bytes());
+  }
+  for &slayer_start in slayer_starts.iter() {
+    buf.extend_from_slice(&(slayer_start as u64).to_le_bytes());
+  }
+  for section in sections.iter() {
+    buf.extend_from_slice(section);
+  }
+  let checksum = fnv1a(&buf);
+  buf.extend_from_slice(&checksum.to_le_bytes());
+  writer.write_all(&buf)?;
+  Ok(())
+}
+#[derive(Clone, Copy)]
+enum SetOp {
+  Union,
+  Intersection,
+  Difference,
+  SymmetricDifference,
+}
+impl SetOp {
+  fn apply(self, a: bool, b: bool) -> bool {
+    match self {
+      SetOp::Union => a || b,
+      SetOp::Intersection => a && b,
+      SetOp::Difference => a && !b,
+      SetOp::SymmetricDifference => a != b,
+    }
+  }
+  fn may_produce(self, a_present: bool, b_present: bool) -> bool {
+    /* Whether combining two submatrices, each all 0s when absent,
+    could contain a 1 */
+    match self {
+      SetOp::Union | SetOp::SymmetricDifference => a_present || b_present,
+      SetOp::Intersection => a_present && b_present,
+      SetOp::Difference => a_present,
+    }
+  }
+}
+enum DescendResult {
+  Leaf(usize, Range), //leaf_start, leaf_range
+  Stem(usize, Range), //stem_start, stem_range
+}
+struct DescendEnv {
+  /* Allows for descend to be recursive without parameter hell */
+  x: usize,
+  y: usize,
+  slayer_max: usize,
+}
+impl<B: BitStore> K2Tree<B> {
+  fn stem_len(&self) -> usize {
+    self.k * self.k
+  }
+  fn tree_width(&self) -> usize {
+    /* Width of the square the stems cover, at least both extents */
+    self.k.pow(self.max_slayers as u32 + 1)
+  }
+  fn max_coords(&self) -> [usize; 2] {
+    self.as_view().max_coords()
+  }
+  fn offset_in_block(&self, r: &Range, x: usize, y: usize) -> usize {
+    /* Position of the child (or leaf bit) containing (x, y) in the block
+    covering r, children are ordered row by row from the top left */
+    let sub_width = (r[0][1] - r[0][0] + 1) / self.k;
+    (((y - r[1][0]) / sub_width) * self.k) + ((x - r[0][0]) / sub_width)
+  }
+  fn z_order_key(&self, x: usize, y: usize) -> u128 {
+    /* The child positions on the path from the root down to (x, y),
+    read as the digits of a base k*k number */
+    let base = self.stem_len() as u128;
+    let mut key: u128 = 0;
+    let mut sub_width = self.tree_width();
+    while sub_width > 1 {
+      sub_width /= self.k;
+      let child_pos = (((y / sub_width) % self.k) * self.k) + ((x / sub_width) % self.k);
+      key = (key * base) + child_pos as u128;
+    }
+    key
+  }
+  fn layers_from_keys(&self, mut keys: Vec<u128>) -> (Vec<BitVec>, BitVec) {
+    /* Stem layers and leaves holding the cells with the given z-order keys */
+    keys.sort_unstable();
+    keys.dedup();
+    /* Every node in a layer is a distinct prefix of the sorted keys and
+    they appear in the same order as the layer stores them, so each
+    layer can be written out with a single pass over the keys */
+    let stem_len = self.stem_len();
+    let base = stem_len as u128;
+    let digits = self.max_slayers + 1;
+    let mut layers: Vec<BitVec> = vec![BitVec::new(); self.max_slayers];
+    let mut leaves = BitVec::new();
+    for layer in 0..digits {
+      let node_div = if layer == 0 { None } else { Some(base.pow((digits - layer) as u32)) };
+      let child_div = base.pow((digits - layer - 1) as u32);
+      let bits = if layer < self.max_slayers {
+        &mut layers[layer]
+      }
+      else {
+        &mut leaves
+      };
+      let mut curr_node: Option<u128> = None;
+      let mut node_start = 0;
+      for &key in &keys {
+        let node = match node_div {
+          Some(div) => key / div,
+          None => 0,
+        };
+        if curr_node != Some(node) {
+          curr_node = Some(node);
+          node_start = bits.len();
+          bits.resize(node_start + stem_len, false);
         }
+        bits.set(node_start + ((key / child_div) % base) as usize, true);
       }
-      i = 1;
-      s.push_str("::");
     }
-    i = 1;
-    for bit_pos in 0..self.leaves.len() {
-      if self.leaves[bit_pos] {
-        s.push('1');
-      }
-      else {
-        s.push('0');
-      }
-      if i == self.k*self.k
-      && bit_pos < self.leaves.len()-1 {
-        s.push(',');
-        i = 1;
-      } 
-      else {
-        i += 1;
-      }
+    (layers, leaves)
+  }
+  fn leaf_parent(&self, bit_pos: usize) -> usize {
+    self.layer_start(self.max_slayers-1) + self.stem_to_leaf[bit_pos / self.stem_len()]
+  }
+  fn stem_start(&self, bit_pos: usize) -> usize {
+    block_start(bit_pos, self.stem_len())
+  }
+  fn get_coords(&self, leaf_bit_pos: usize) -> [usize; 2] {
+    /* Start at the leaf_bit and traverse our way up to the top of the tree,
+    keeping track of the path we took on our way up in terms of
+    bit-positions (offsets) in the stems. Then, traverse back down the same
+    path to find the coords of the leaf_bit. */
+    let parent_bit = self.leaf_parent(leaf_bit_pos);
+    let mut stem_start = self.stem_start(parent_bit);
+    let mut offset = parent_bit - stem_start;
+    let mut offsets = Vec::new();
+    offsets.push(offset);
+    for _ in 1..self.max_slayers {
+      let parent = self.parent(stem_start);
+      stem_start = parent.0;
+      offset = parent.1;
+      offsets.push(offset);
+    }
+    /* Reverse the offsets ready to traverse them back down the tree */
+    offsets.reverse();
+    let tree_width = self.tree_width();
+    let mut range = [[0, tree_width-1], [0, tree_width-1]];
+    for layer in 0..self.max_slayers {
+      range = to_subranges(range, self.k)[offsets[layer]];
+    }
+    let leaf_offset = leaf_bit_pos - self.stem_start(leaf_bit_pos);
+    [range[0][0] + (leaf_offset % self.k), range[1][0] + (leaf_offset / self.k)]
+  }
+  fn layer_from_range(&self, r: Range) -> usize {
+    let r_width = r[0][1]-r[0][0]+1;
+    log_k(self.tree_width(), self.k) - log_k(r_width, self.k)
+  }
+  fn matrix_bit(&self, x: usize, y: usize, m_width: usize) -> Result<DescendResult> {
+    let env = DescendEnv {
+      x: x,
+      y: y,
+      slayer_max: self.slayer_starts.len()-1,
+    };
+    self.descend(&env, 0, 0, [[0, m_width-1], [0, m_width-1]])
+  }
+  fn descend(&self, env: &DescendEnv, layer: usize, stem_pos: usize, range: Range) -> Result<DescendResult> {
+    if !within_range(&range, env.x, env.y) {
+      return Err(Error::TraverseError(env.x, env.y))
+    }
+    let child_pos = self.offset_in_block(&range, env.x, env.y);
+    let child_range = to_subranges(range, self.k)[child_pos];
+    if !self.stems.get(stem_pos+child_pos) { return Ok(DescendResult::Stem(stem_pos, range)) } //The bit exists within a range that has all zeros
+    else if layer == env.slayer_max {
+      let leaf_start = match self.leaf_start(stem_pos + child_pos) {
+        Ok(ls) => ls,
+        Err(_) => return Err(Error::TraverseError(env.x, env.y)),
+      };
+      Ok(DescendResult::Leaf(leaf_start, child_range))
+    }
+    else {
+      let child_stem = match self.child_stem(layer, stem_pos, child_pos) {
+        Ok(cs) => cs,
+        Err(_) => return Err(Error::TraverseError(env.x, env.y)),
+      };
+      self.descend(env,
+                   layer+1,
+                   child_stem,
+                   child_range)
+    }
+  }
+  fn layer_start(&self, l: usize) -> usize {
+    self.as_view().layer_start(l)
+  }
+  fn layer_len(&self, l: usize) -> usize {
+    if l == self.slayer_starts.len()-1 {
+      return self.stems.len() - self.slayer_starts[l]
+    }
+    self.slayer_starts[l+1] - self.slayer_starts[l]
+  }
+  fn leaf_start(&self, stem_bitpos: usize) -> std::result::Result<usize, ()> {
+    self.as_view().leaf_start(stem_bitpos)
+  }
+  fn child_stem(&self, layer: usize, stem_start: usize, nth_child: usize) -> std::result::Result<usize, ()> {
+    self.as_view().child_stem(layer, stem_start, nth_child)
+  }
+  fn parent(&self, stem_start: usize) -> (usize, usize) {
+    /* Returns (stem_start, bit_offset) */
+    if self.slayer_starts.len() > 1
+    && stem_start >= self.slayer_starts[1] {
+      /* If stem isn't in layer 0, look for parent.
+      The nth stem in a layer is the child of the nth 1 in the layer above */
+      let layer = match self.slayer_starts.binary_search(&stem_start) {
+        Ok(layer) => layer,
+        Err(next_layer) => next_layer - 1,
+      };
+      let nth_stem_in_layer = (stem_start - self.slayer_starts[layer]) / self.stem_len();
+      let ones_before_parent_layer = self.stems.rank(self.slayer_starts[layer-1]);
+      let parent_bit = self.stems
+        .select(ones_before_parent_layer + nth_stem_in_layer)
+        .unwrap_or(std::usize::MAX);
+      let parent_stem_start = self.stem_start(parent_bit);
+      (parent_stem_start, parent_bit - parent_stem_start)
+    }
+    else {
+      (std::usize::MAX, std::usize::MAX)
+    }
+  }
+  #[allow(dead_code)] //Used by unit tests
+  fn parent_stem(&self, stem_start: usize) -> usize {
+    self.parent(stem_start).0
+  }
+  #[allow(dead_code)] //Used by unit tests
+  fn parent_bit(&self, stem_start: usize) -> usize {
+    let (stem_start, bit_offset) = self.parent(stem_start);
+    stem_start + bit_offset
+  }
+  pub fn heapsize(&self) -> usize {
+    let mut size: usize = std::mem::size_of_val(self);
+    size += std::mem::size_of::<usize>() * self.slayer_starts.len();
+    size += self.stems.heapsize();
+    size += std::mem::size_of::<usize>() * self.stem_to_leaf.len();
+    size += self.leaves.heapsize();
+    size
+  }
+  pub fn stats(&self) -> K2TreeStats {
+    let stem_layers = if self.leaves.is_empty() { 0 } else { self.slayer_starts.len() };
+    let mut bits_per_layer = Vec::with_capacity(stem_layers);
+    let mut ones_per_layer = Vec::with_capacity(stem_layers);
+    for layer in 0..stem_layers {
+      let (begin, end) = (self.layer_start(layer), self.layer_start(layer+1));
+      bits_per_layer.push(end - begin);
+      ones_per_layer.push(self.stem_ones(begin, end));
+    }
+    K2TreeStats::new(
+      bits_per_layer,
+      ones_per_layer,
+      self.leaves.len() / self.stem_len(),
+      self.stems.len() + self.leaves.storage_bits(),
+      self.count_ones(),
+      self.matrix_width * self.matrix_height,
+    )
+  }
+  fn combine(&self, other: &K2Tree<B>, op: SetOp) -> Result<K2Tree> where B: Clone {
+    if self.k != other.k {
+      return Err(Error::MismatchedK(self.k, other.k))
+    }
+    /* Grow whichever tree is smaller so both cover the same matrix */
+    let grown_self = self.grown_to(other.tree_width())?;
+    let grown_other = other.grown_to(self.tree_width())?;
+    let a = grown_self.as_ref().unwrap_or(self);
+    let b = grown_other.as_ref().unwrap_or(other);
+    let mut result = K2Tree::with_k(self.k);
+    while result.tree_width() < a.tree_width() { result.grow()?; }
+    result.matrix_width = std::cmp::max(self.matrix_width, other.matrix_width);
+    result.matrix_height = std::cmp::max(self.matrix_height, other.matrix_height);
+    let mut layers: Vec<BitVec> = vec![BitVec::new(); a.max_slayers];
+    let mut leaves = BitVec::new();
+    let a_root = if a.leaves.is_empty() { None } else { Some(0) };
+    let b_root = if b.leaves.is_empty() { None } else { Some(0) };
+    K2Tree::combine_node(a, b, op, a_root, b_root, 0, &mut layers, &mut leaves);
+    result.set_layers(layers, leaves);
+    Ok(result)
+  }
+  fn combine_node(a: &K2Tree<B>,
+                  b: &K2Tree<B>,
+                  op: SetOp,
+                  a_node: Option<usize>,
+                  b_node: Option<usize>,
+                  layer: usize,
+                  layers: &mut Vec<BitVec>,
+                  leaves: &mut BitVec) -> bool {
+    /* Combines the nodes of a and b covering the same submatrix, where a
+    missing node is all 0s, and appends the result to its layer.
+    Returns whether the result has any 1s, all-0 results are left out.
+    Visiting nodes depth first still appends each layer's nodes in order. */
+    let stem_len = a.stem_len();
+    let mut block = bitvec![0; stem_len];
+    if layer == a.max_slayers {
+      for bit in 0..stem_len {
+        let a_bit = a_node.map_or(false, |leaf_start| a.leaves[leaf_start+bit]);
+        let b_bit = b_node.map_or(false, |leaf_start| b.leaves[leaf_start+bit]);
+        block.set(bit, op.apply(a_bit, b_bit));
+      }
+      if block.not_any() { return false }
+      leaves.extend(block.iter());
+      return true
+    }
+    for child_pos in 0..stem_len {
+      let a_child = a_node.and_then(|stem_start| a.child_node(layer, stem_start, child_pos));
+      let b_child = b_node.and_then(|stem_start| b.child_node(layer, stem_start, child_pos));
+      /* Skip children that can't contain a 1 in the result */
+      if !op.may_produce(a_child.is_some(), b_child.is_some()) { continue }
+      if K2Tree::combine_node(a, b, op, a_child, b_child, layer+1, layers, leaves) {
+        block.set(child_pos, true);
+      }
+    }
+    if block.not_any() { return false }
+    layers[layer].extend(block.iter());
+    true
+  }
+  fn multiply_node(a: &K2Tree<B>,
+                   b: &K2Tree<B>,
+                   a_node: usize,
+                   b_node: usize,
+                   layer: usize,
+                   [x_offset, z_offset]: [usize; 2],
+                   child_width: usize,
+                   coords: &mut Vec<(usize, usize)>) {
+    /* a_node covers the submatrix of a at columns x and rows y,
+    b_node covers the submatrix of b at columns y and rows z.
+    Only pairs of children that are both non-empty can meet */
+    let k = a.k;
+    if layer == a.max_slayers {
+      for y in 0..k {
+        for x in 0..k {
+          if !a.leaves[a_node+(y*k)+x] { continue }
+          for z in 0..k {
+            if b.leaves[b_node+(z*k)+y] {
+              coords.push((x_offset+x, z_offset+z));
+            }
+          }
+        }
+      }
+      return
+    }
+    for y in 0..k {
+      for x in 0..k {
+        let a_child = match a.child_node(layer, a_node, (y*k)+x) {
+          Some(a_child) => a_child,
+          None => continue,
+        };
+        for z in 0..k {
+          if let Some(b_child) = b.child_node(layer, b_node, (z*k)+y) {
+            K2Tree::multiply_node(a, b, a_child, b_child, layer+1,
+              [x_offset+(x*child_width), z_offset+(z*child_width)],
+              child_width/k,
+              coords);
+          }
+        }
+      }
+    }
+  }
+  fn transpose_node(&self, node: usize, layer: usize, layers: &mut Vec<BitVec>, leaves: &mut BitVec) {
+    /* Visiting children in their transposed order appends
+    each layer's nodes in the transposed level order */
+    let k = self.k;
+    if layer == self.max_slayers {
+      for y in 0..k {
+        for x in 0..k {
+          leaves.push(self.leaves[node+(x*k)+y]);
+        }
+      }
+      return
+    }
+    let mut block = bitvec![0; self.stem_len()];
+    for y in 0..k {
+      for x in 0..k {
+        if let Some(child) = self.child_node(layer, node, (x*k)+y) {
+          block.set((y*k)+x, true);
+          self.transpose_node(child, layer+1, layers, leaves);
+        }
+      }
+    }
+    layers[layer].extend(block.iter());
+  }
+  fn grown_to(&self, tree_width: usize) -> Result<Option<K2Tree<B>>> where B: Clone {
+    /* A copy of the tree with its stems grown to cover at least
+    tree_width, if they cover less */
+    if self.tree_width() >= tree_width { return Ok(None) }
+    let mut tree = self.clone();
+    while tree.tree_width() < tree_width { tree.grow()?; }
+    Ok(Some(tree))
+  }
+  fn count_node(&self, layer: usize, stem_start: usize, range: Range, query: &Range) -> usize {
+    let subranges = to_subranges(range, self.k);
+    let mut count = 0;
+    for child_pos in 0..self.stem_len() {
+      let bit_pos = stem_start + child_pos;
+      if !self.stems.get(bit_pos)
+      || !ranges_overlap(&subranges[child_pos], query) {
+        continue
+      }
+      if range_contains(query, &subranges[child_pos]) {
+        count += self.leaf_ones(self.subtree_leaves(layer, bit_pos));
+      }
+      else if layer == self.max_slayers-1 {
+        if let Ok(leaf_start) = self.leaf_start(bit_pos) {
+          let leaf_range = subranges[child_pos];
+          for leaf_bit in 0..self.stem_len() {
+            let x = leaf_range[0][0] + (leaf_bit % self.k);
+            let y = leaf_range[1][0] + (leaf_bit / self.k);
+            if self.leaves[leaf_start+leaf_bit] && within_range(query, x, y) {
+              count += 1;
+            }
+          }
+        }
+      }
+      else if let Ok(child_stem) = self.child_stem(layer, stem_start, child_pos) {
+        count += self.count_node(layer+1, child_stem, subranges[child_pos], query);
+      }
+    }
+    count
+  }
+  fn subtree_leaves(&self, layer: usize, bit_pos: usize) -> std::ops::Range<usize> {
+    /* The leaf bits below the 1 at bit_pos. Layers are in level order, so
+    the descendants of any run of bits in a layer are a run of stems in
+    the next, the ones after as many 1s as come before the run */
+    let stem_len = self.stem_len();
+    let (mut begin, mut end) = (bit_pos, bit_pos+1);
+    for curr_layer in layer..self.max_slayers {
+      let ones_before = self.stems.rank(self.layer_start(curr_layer));
+      let first_child = self.stems.rank(begin) - ones_before;
+      let last_child = self.stems.rank(end) - ones_before;
+      let child_start = if curr_layer == self.max_slayers-1 { 0 } else { self.layer_start(curr_layer+1) };
+      begin = child_start + (first_child * stem_len);
+      end = child_start + (last_child * stem_len);
+    }
+    begin..end
+  }
+  fn leaf_ones(&self, leaf_bits: std::ops::Range<usize>) -> usize {
+    /* leaf_bits covers whole leaves */
+    match &self.leaves {
+      LeafLayer::Plain(leaves) | LeafLayer::Decoded(leaves) => leaves.rank(leaf_bits.end) - leaves.rank(leaf_bits.start),
+      LeafLayer::Vocabulary(vocabulary) => vocabulary.count_ones(leaf_bits),
+    }
+  }
+  fn child_node(&self, layer: usize, stem_start: usize, child_pos: usize) -> Option<usize> {
+    /* The start of the child stem, or of the leaf if stem_start
+    is in the final stem layer */
+    if !self.stems.get(stem_start+child_pos) { None }
+    else if layer == self.max_slayers-1 { self.leaf_start(stem_start+child_pos).ok() }
+    else { self.child_stem(layer, stem_start, child_pos).ok() }
+  }
+  fn set_layers(&mut self, layers: Vec<BitVec>, leaves: BitVec) {
+    /* Replace the tree's contents with the given stem layers and leaves,
+    each already in level order */
+    if leaves.is_empty() {
+      self.slayer_starts = vec![0];
+      self.stems = B::from_bitvec(bitvec![0; self.stem_len()]);
+      self.stem_to_leaf = Vec::new();
+    }
+    else {
+      let mut slayer_starts: Vec<usize> = Vec::new();
+      let mut stems = BitVec::new();
+      for layer in layers.into_iter() {
+        slayer_starts.push(stems.len());
+        stems.extend(layer.iter());
+      }
+      let final_layer_start = slayer_starts[slayer_starts.len()-1];
+      self.stem_to_leaf = one_positions(&stems)
+        .into_iter()
+        .filter(|&pos| pos >= final_layer_start)
+        .map(|pos| pos - final_layer_start)
+        .collect();
+      self.slayer_starts = slayer_starts;
+      self.stems = B::from_bitvec(stems);
+    }
+    /* Whatever the encoding, the new leaves are encoded the same way */
+    let encoding = self.leaf_encoding();
+    self.leaves = LeafLayer::Plain(B::from_bitvec(leaves));
+    self.set_leaf_encoding(encoding);
+  }
+  fn set_stem_bit(&mut self, bit_pos: usize, state: bool) -> Result<()> {
+    if let Err(()) = self.stems.set(bit_pos, state) {
+      return Err(Error::ReadOnlyStore)
+    }
+    Ok(())
+  }
+  fn stem_ones(&self, begin: usize, end: usize) -> usize {
+    self.stems.rank(end) - self.stems.rank(begin)
+  }
+}
+
+/* Bit Stores */
+/* Where a K2Tree keeps its stems and plain leaves. A store answers rank
+and select itself, so it can keep whatever index suits how it's used,
+and refuses changes with Err(()) if it can't make them */
+pub trait BitStore: std::fmt::Debug + Send + Sync {
+  fn from_bitvec(bits: BitVec) -> Self where Self: Sized;
+  fn as_bitslice(&self) -> &BitSlice;
+  fn set(&mut self, bit_pos: usize, state: bool) -> std::result::Result<(), ()>;
+  fn insert_block(&mut self, block_start: usize, block_len: usize) -> std::result::Result<(), ()>;
+  fn remove_block(&mut self, block_start: usize, block_len: usize) -> std::result::Result<(), ()>;
+  /* Number of 1s in [0, bit_pos) */
+  fn rank(&self, bit_pos: usize) -> usize;
+  /* Position of the nth 1, counting from 0 */
+  fn select(&self, nth: usize) -> Option<usize>;
+  fn heapsize(&self) -> usize;
+  fn len(&self) -> usize {
+    self.as_bitslice().len()
+  }
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+  fn get(&self, bit_pos: usize) -> bool {
+    self.as_bitslice()[bit_pos]
+  }
+  fn to_bitvec(&self) -> BitVec {
+    BitVec::from(self.as_bitslice())
+  }
+}
+/* The default store, a BitVec with its rank index kept in step on every change */
+#[derive(Debug, Clone)]
+pub struct GrowableBits {
+  bits: BitVec,
+  ranks: RankIndex,
+}
+impl BitStore for GrowableBits {
+  fn from_bitvec(bits: BitVec) -> Self {
+    GrowableBits {
+      ranks: RankIndex::new(&bits),
+      bits: bits,
+    }
+  }
+  fn as_bitslice(&self) -> &BitSlice {
+    &self.bits[..]
+  }
+  fn set(&mut self, bit_pos: usize, state: bool) -> std::result::Result<(), ()> {
+    self.ranks.set(&mut self.bits, bit_pos, state);
+    Ok(())
+  }
+  fn insert_block(&mut self, block_start: usize, block_len: usize) -> std::result::Result<(), ()> {
+    insert_block(&mut self.bits, block_start, block_len)?;
+    self.ranks.update_from(&self.bits, block_start);
+    Ok(())
+  }
+  fn remove_block(&mut self, block_start: usize, block_len: usize) -> std::result::Result<(), ()> {
+    remove_block(&mut self.bits, block_start, block_len)?;
+    self.ranks.update_from(&self.bits, block_start);
+    Ok(())
+  }
+  fn rank(&self, bit_pos: usize) -> usize {
+    self.ranks.rank(&self.bits, bit_pos)
+  }
+  fn select(&self, nth: usize) -> Option<usize> {
+    self.ranks.select(&self.bits, nth)
+  }
+  fn heapsize(&self) -> usize {
+    self.bits.len() / 8 + self.ranks.heapsize()
+  }
+}
+impl PartialEq for GrowableBits {
+  fn eq(&self, other: &Self) -> bool {
+    self.bits == other.bits
+  }
+}
+impl Eq for GrowableBits {}
+impl PartialEq<BitVec> for GrowableBits {
+  fn eq(&self, other: &BitVec) -> bool {
+    self.bits == *other
+  }
+}
+impl From<BitVec> for GrowableBits {
+  fn from(bits: BitVec) -> Self {
+    GrowableBits::from_bitvec(bits)
+  }
+}
+/* A store for trees that are built once and only read after, which also
+samples where every SELECT_SAMPLE_RATE-th 1 is so that select starts
+from the right block rather than searching all of them. Only its bits are
+fixed: set refuses with ReadOnlyStore, but whatever rebuilds the whole tree
+(grow, shrink, resize, apply_batch, set operations...) builds a new store */
+const SELECT_SAMPLE_RATE: usize = 512;
+#[derive(Debug, Clone)]
+pub struct StaticBits {
+  bits: BitVec,
+  ranks: RankIndex,
+  select_samples: Vec<usize>, //block holding the (i*SELECT_SAMPLE_RATE)th 1
+}
+impl BitStore for StaticBits {
+  fn from_bitvec(bits: BitVec) -> Self {
+    let ranks = RankIndex::new(&bits);
+    let ones = ranks.rank(&bits, bits.len());
+    let mut select_samples = Vec::new();
+    for block in 0..ranks.block_ranks.len() {
+      let ones_to_end = ranks.block_ranks.get(block+1).cloned().unwrap_or(ones);
+      while select_samples.len() * SELECT_SAMPLE_RATE < ones_to_end {
+        select_samples.push(block);
+      }
+    }
+    StaticBits {
+      bits: bits,
+      ranks: ranks,
+      select_samples: select_samples,
+    }
+  }
+  fn as_bitslice(&self) -> &BitSlice {
+    &self.bits[..]
+  }
+  fn set(&mut self, _: usize, _: bool) -> std::result::Result<(), ()> {
+    Err(())
+  }
+  fn insert_block(&mut self, _: usize, _: usize) -> std::result::Result<(), ()> {
+    Err(())
+  }
+  fn remove_block(&mut self, _: usize, _: usize) -> std::result::Result<(), ()> {
+    Err(())
+  }
+  fn rank(&self, bit_pos: usize) -> usize {
+    self.ranks.rank(&self.bits, bit_pos)
+  }
+  fn select(&self, nth: usize) -> Option<usize> {
+    let first_block = match self.select_samples.get(nth / SELECT_SAMPLE_RATE) {
+      Some(&block) => block,
+      None => return None,
+    };
+    self.ranks.select_from(&self.bits, nth, first_block)
+  }
+  fn heapsize(&self) -> usize {
+    self.bits.len() / 8
+    + self.ranks.heapsize()
+    + std::mem::size_of::<usize>() * self.select_samples.len()
+  }
+}
+/* A store reading bits in place from a byte slice, copying them
+only if they're changed */
+#[derive(Debug, Clone)]
+pub struct ByteBits<'a> {
+  bits: Cow<'a, BitSlice>,
+  ranks: RankIndex,
+}
+impl<'a> ByteBits<'a> {
+  pub fn new(bytes: &'a [u8], len: usize) -> Result<Self> {
+    /* The first len bits of bytes */
+    if bytes.len() * 8 < len {
+      return Err(invalid_binary("fewer bytes than bits"))
+    }
+    Ok(ByteBits::from_bitslice(&BitSlice::from_slice(bytes)[..len]))
+  }
+  fn from_bitslice(bits: &'a BitSlice) -> Self {
+    ByteBits {
+      bits: Cow::Borrowed(bits),
+      ranks: RankIndex::new(bits),
+    }
+  }
+}
+impl<'a> BitStore for ByteBits<'a> {
+  fn from_bitvec(bits: BitVec) -> Self {
+    ByteBits {
+      ranks: RankIndex::new(&bits),
+      bits: Cow::Owned(bits),
+    }
+  }
+  fn as_bitslice(&self) -> &BitSlice {
+    &self.bits
+  }
+  fn set(&mut self, bit_pos: usize, state: bool) -> std::result::Result<(), ()> {
+    self.ranks.set(self.bits.to_mut(), bit_pos, state);
+    Ok(())
+  }
+  fn insert_block(&mut self, block_start: usize, block_len: usize) -> std::result::Result<(), ()> {
+    let bits = self.bits.to_mut();
+    insert_block(bits, block_start, block_len)?;
+    self.ranks.update_from(bits, block_start);
+    Ok(())
+  }
+  fn remove_block(&mut self, block_start: usize, block_len: usize) -> std::result::Result<(), ()> {
+    let bits = self.bits.to_mut();
+    remove_block(bits, block_start, block_len)?;
+    self.ranks.update_from(bits, block_start);
+    Ok(())
+  }
+  fn rank(&self, bit_pos: usize) -> usize {
+    self.ranks.rank(&self.bits, bit_pos)
+  }
+  fn select(&self, nth: usize) -> Option<usize> {
+    self.ranks.select(&self.bits, nth)
+  }
+  fn heapsize(&self) -> usize {
+    let bits = match &self.bits {
+      Cow::Borrowed(_) => 0,
+      Cow::Owned(bits) => bits.len() / 8,
+    };
+    bits + self.ranks.heapsize()
+  }
+}
+/* A K2TreeView's rank/select over its stems, its own index when read
+from bytes or a K2Tree1, the store's when borrowed from a K2Tree, whose
+type the view carries so that queries call it without dynamic dispatch */
+#[derive(Debug)]
+enum StemRanks<'a, B: BitStore> {
+  Index(Cow<'a, RankIndex>),
+  Store(&'a B),
+}
+impl<'a, B: BitStore> Clone for StemRanks<'a, B> {
+  fn clone(&self) -> Self {
+    match self {
+      StemRanks::Index(index) => StemRanks::Index(index.clone()),
+      StemRanks::Store(store) => StemRanks::Store(*store),
+    }
+  }
+}
+impl<'a, B: BitStore> StemRanks<'a, B> {
+  fn rank(&self, stems: &BitSlice, bit_pos: usize) -> usize {
+    match self {
+      StemRanks::Index(index) => index.rank(stems, bit_pos),
+      StemRanks::Store(store) => store.rank(bit_pos),
+    }
+  }
+  fn select(&self, stems: &BitSlice, nth: usize) -> Option<usize> {
+    match self {
+      StemRanks::Index(index) => index.select(stems, nth),
+      StemRanks::Store(store) => store.select(nth),
+    }
+  }
+  fn reborrow(&self) -> StemRanks<B> {
+    match self {
+      StemRanks::Index(index) => StemRanks::Index(Cow::Borrowed(&**index)),
+      StemRanks::Store(store) => StemRanks::Store(*store),
+    }
+  }
+}
+
+/* Rank / Select */
+const RANK_BLOCK_LEN: usize = 512;
+#[derive(Debug, Clone, Default)]
+struct RankIndex {
+  /* block_ranks[i] is the number of 1s in bits[0..i*RANK_BLOCK_LEN],
+  so rank only counts within a single block and select
+  binary searches to the right block before counting */
+  block_ranks: Vec<usize>,
+}
+impl RankIndex {
+  fn new(bits: &BitSlice) -> Self {
+    let mut index = RankIndex { block_ranks: Vec::new() };
+    index.update_from(bits, 0);
+    index
+  }
+  fn update_from(&mut self, bits: &BitSlice, bit_pos: usize) {
+    /* Blocks wholly before bit_pos are unaffected by a change at bit_pos,
+    recount every block from there on */
+    let first_block = bit_pos / RANK_BLOCK_LEN;
+    self.block_ranks.truncate(first_block + 1);
+    if self.block_ranks.is_empty() { self.block_ranks.push(0); }
+    let last_block = bits.len() / RANK_BLOCK_LEN;
+    for block in self.block_ranks.len()..=last_block {
+      let ones = bits[(block-1)*RANK_BLOCK_LEN..block*RANK_BLOCK_LEN].count_ones();
+      self.block_ranks.push(self.block_ranks[block-1] + ones);
+    }
+  }
+  fn set(&mut self, bits: &mut BitSlice, bit_pos: usize, state: bool) {
+    /* Setting a bit can't move any others, only the counts
+    of the blocks after it change and only by one */
+    if bits[bit_pos] == state { return }
+    bits.set(bit_pos, state);
+    for ones in self.block_ranks[bit_pos / RANK_BLOCK_LEN + 1..].iter_mut() {
+      if state { *ones += 1 } else { *ones -= 1 }
+    }
+  }
+  fn rank(&self, bits: &BitSlice, bit_pos: usize) -> usize {
+    /* Number of 1s in bits[0..bit_pos] */
+    let block = bit_pos / RANK_BLOCK_LEN;
+    self.block_ranks[block] + bits[block*RANK_BLOCK_LEN..bit_pos].count_ones()
+  }
+  fn select(&self, bits: &BitSlice, nth: usize) -> Option<usize> {
+    /* Position of the nth 1 in bits, counting from 0.
+    Find the last block with at most nth 1s before it, the nth 1 lies within it */
+    self.select_from(bits, nth, 0)
+  }
+  fn select_from(&self, bits: &BitSlice, nth: usize, first_block: usize) -> Option<usize> {
+    /* select, searching only the blocks from first_block on,
+    which has to have at most nth 1s before it */
+    let (mut block, mut high) = (first_block, self.block_ranks.len());
+    while high - block > 1 {
+      let mid = (block + high) / 2;
+      if self.block_ranks[mid] <= nth { block = mid; }
+      else { high = mid; }
+    }
+    let mut ones = self.block_ranks[block];
+    for bit_pos in block*RANK_BLOCK_LEN..bits.len() {
+      if bits[bit_pos] {
+        if ones == nth { return Some(bit_pos) }
+        ones += 1;
+      }
+    }
+    None
+  }
+  fn heapsize(&self) -> usize {
+    std::mem::size_of::<usize>() * self.block_ranks.len()
+  }
+}
+
+/* Leaf Vocabulary */
+/* How a K2Tree stores its leaves, Vocabulary keeps each distinct leaf
+once, most frequent first, and every leaf as a variable-length code
+pointing into them. Trees with few distinct leaves get a lot smaller,
+at the cost of a slower get and of re-encoding on every set */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeafEncoding {
+  Plain,
+  Vocabulary,
+}
+impl Default for LeafEncoding {
+  fn default() -> Self {
+    LeafEncoding::Plain
+  }
+}
+#[derive(Debug, Clone)]
+enum LeafLayer<B: BitStore = GrowableBits> {
+  Plain(B),
+  Vocabulary(LeafVocabulary),
+  Decoded(B), //a vocabulary's leaves after a change, encoded again when written
+}
+impl<B: BitStore> LeafLayer<B> {
+  fn encoding(&self) -> LeafEncoding {
+    match self {
+      LeafLayer::Plain(_) => LeafEncoding::Plain,
+      LeafLayer::Vocabulary(_) | LeafLayer::Decoded(_) => LeafEncoding::Vocabulary,
+    }
+  }
+  fn len(&self) -> usize {
+    match self {
+      LeafLayer::Plain(bits) | LeafLayer::Decoded(bits) => bits.len(),
+      LeafLayer::Vocabulary(vocabulary) => vocabulary.len(),
+    }
+  }
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+  fn bits(&self) -> Cow<BitSlice> {
+    match self {
+      LeafLayer::Plain(bits) | LeafLayer::Decoded(bits) => Cow::Borrowed(bits.as_bitslice()),
+      LeafLayer::Vocabulary(vocabulary) => Cow::Owned(vocabulary.to_bitvec()),
+    }
+  }
+  fn leaf(&self, nth: usize, stem_len: usize) -> &BitSlice {
+    match self {
+      LeafLayer::Plain(bits) | LeafLayer::Decoded(bits) => &bits.as_bitslice()[nth*stem_len..(nth+1)*stem_len],
+      LeafLayer::Vocabulary(vocabulary) => vocabulary.leaf(nth),
+    }
+  }
+  fn leaves(&self, stem_len: usize) -> impl Iterator<Item=&BitSlice> {
+    /* Every leaf in order, borrowed from the vocabulary's blocks rather than decoded */
+    (0..self.len() / stem_len).map(move |nth| self.leaf(nth, stem_len))
+  }
+  fn to_mut(&mut self) -> &mut B {
+    /* The plain bits, decoding the vocabulary first if there is one */
+    if let LeafLayer::Vocabulary(vocabulary) = self {
+      *self = LeafLayer::Decoded(B::from_bitvec(vocabulary.to_bitvec()));
+    }
+    match self {
+      LeafLayer::Plain(bits) | LeafLayer::Decoded(bits) => bits,
+      LeafLayer::Vocabulary(_) => unreachable!(),
+    }
+  }
+  fn modify<F: FnOnce(&mut B) -> std::result::Result<(), ()>>(&mut self, change: F) -> std::result::Result<(), ()> {
+    /* Makes a change to the plain bits, a vocabulary is only
+    swapped for its decoded bits once the store accepts the change */
+    match self {
+      LeafLayer::Plain(bits) | LeafLayer::Decoded(bits) => change(bits),
+      LeafLayer::Vocabulary(vocabulary) => {
+        let mut bits = B::from_bitvec(vocabulary.to_bitvec());
+        change(&mut bits)?;
+        *self = LeafLayer::Decoded(bits);
+        Ok(())
+      },
+    }
+  }
+  fn as_bits(&self) -> LeafBits {
+    match self {
+      LeafLayer::Plain(bits) | LeafLayer::Decoded(bits) => LeafBits::Plain(bits.as_bitslice()),
+      LeafLayer::Vocabulary(vocabulary) => LeafBits::Vocabulary(Cow::Borrowed(vocabulary)),
+    }
+  }
+  fn heapsize(&self) -> usize {
+    match self {
+      LeafLayer::Plain(bits) | LeafLayer::Decoded(bits) => bits.heapsize(),
+      LeafLayer::Vocabulary(vocabulary) => vocabulary.heapsize(),
     }
-    write!(f, "[{}]", s)
   }
-}
-impl PartialEq for K2Tree {
-  fn eq(&self, other: &Self) -> bool {
-    self.k == other.k
-    && self.matrix_width == other.matrix_width
-    && self.stems == other.stems
-    && self.leaves == other.leaves
+  fn storage_bits(&self) -> usize {
+    match self {
+      LeafLayer::Plain(bits) | LeafLayer::Decoded(bits) => bits.len(),
+      LeafLayer::Vocabulary(vocabulary) => {
+        vocabulary.blocks.len()
+        + vocabulary.codes.chunks.iter().zip(vocabulary.codes.more.iter())
+          .fold(0, |bits, (chunks, more)| bits + chunks.len() + more.len())
+      },
+    }
   }
 }
-impl Eq for K2Tree {}
-impl Default for K2Tree {
-  fn default() -> Self {
-    Self::new()
+impl<B: BitStore> std::ops::Index<usize> for LeafLayer<B> {
+  type Output = bool;
+  fn index(&self, bit_pos: usize) -> &bool {
+    let bit = match self {
+      LeafLayer::Plain(bits) | LeafLayer::Decoded(bits) => bits.get(bit_pos),
+      LeafLayer::Vocabulary(vocabulary) => vocabulary.get(bit_pos),
+    };
+    if bit { &true } else { &false }
   }
 }
-impl std::hash::Hash for K2Tree {
-  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-      self.k.hash(state);
-      self.matrix_width.hash(state);
-      self.stems.hash(state);
-      self.leaves.hash(state);
+impl<B: BitStore> PartialEq for LeafLayer<B> {
+  fn eq(&self, other: &Self) -> bool {
+    /* Leaf by leaf, so neither side's vocabulary is decoded */
+    match (self, other) {
+      (LeafLayer::Vocabulary(vocabulary), _) | (_, LeafLayer::Vocabulary(vocabulary)) => {
+        self.len() == other.len()
+        && self.leaves(vocabulary.stem_len).eq(other.leaves(vocabulary.stem_len))
+      },
+      _ => self.bits() == other.bits(),
+    }
   }
 }
-impl std::convert::TryFrom<BitMatrix> for K2Tree {
-  type Error = Error;
-  fn try_from(bit_matrix: BitMatrix) -> Result<Self> {
-    /* Implement error checking here */
-    Self::from_matrix(bit_matrix)
+impl<B: BitStore> PartialEq<BitVec> for LeafLayer<B> {
+  fn eq(&self, other: &BitVec) -> bool {
+    match self {
+      LeafLayer::Vocabulary(vocabulary) => {
+        self.len() == other.len()
+        && self.leaves(vocabulary.stem_len).eq(other.chunks(vocabulary.stem_len))
+      },
+      _ => *self.bits() == *other.as_bitslice(),
+    }
   }
 }
-impl std::convert::TryFrom<Vec<Vec<bool>>> for K2Tree {
-  type Error = Error;
-  fn try_from(matrix: Vec<Vec<bool>>) -> Result<Self> {
-    Self::from_matrix(
-      matrix.into_iter().map(|v| {
-        let mut bv = BitVec::new();
-        for bit in v.into_iter() {
-          bv.push(bit);
-        }
-        bv
-      }).collect()
-    )
+/* Serializes as the bytes of the plain leaves, a byte at a
+time rather than from a decoded copy of a vocabulary */
+struct LeafBytes<'a, B: BitStore>(&'a LeafLayer<B>);
+impl<'a, B: BitStore> Serialize for LeafBytes<'a, B> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    let leaves = self.0;
+    let num_bytes = bytes_for_bits(leaves.len());
+    let mut seq = serializer.serialize_seq(Some(num_bytes))?;
+    for byte_pos in 0..num_bytes {
+      let byte = (0..8)
+        .filter(|bit| (byte_pos * 8) + bit < leaves.len() && leaves[(byte_pos * 8) + bit])
+        .fold(0u8, |byte, bit| byte | (0x80 >> bit));
+      seq.serialize_element(&byte)?;
+    }
+    seq.end()
   }
 }
-impl Serialize for K2Tree {
-  fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
-    let mut state = serializer.serialize_struct("K2Tree", 7)?;
-    state.serialize_field("matrixWidth", &self.matrix_width)?;
-    state.serialize_field("k", &self.k)?;
-    state.serialize_field("maxStemLayers", &self.max_slayers)?;
-    state.serialize_field("stemLayerStarts", &self.slayer_starts)?;
-    state.serialize_field("stems", &self.stems.clone().into_vec() as &Vec<u8>)?;
-    state.serialize_field("stemToLeaf", &self.stem_to_leaf)?;
-    state.serialize_field("leaves", &self.leaves.clone().into_vec() as &Vec<u8>)?;
-    state.end()
+impl<B: BitStore> From<BitVec> for LeafLayer<B> {
+  fn from(bits: BitVec) -> Self {
+    LeafLayer::Plain(B::from_bitvec(bits))
   }
 }
-impl<'de> Deserialize<'de> for K2Tree {
-  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
-    #[derive(Deserialize)]
-    #[serde(field_identifier, rename_all = "camelCase")]
-    enum Field {
-      MatrixWidth,
-      K,
-      MaxStemLayers,
-      StemLayerStarts,
-      Stems,
-      StemToLeaf,
-      Leaves
+/* A K2TreeView's leaves, borrowed from a tree or read from bytes */
+#[derive(Debug, Clone)]
+enum LeafBits<'a> {
+  Plain(&'a BitSlice),
+  Vocabulary(Cow<'a, LeafVocabulary>),
+}
+impl<'a> LeafBits<'a> {
+  fn len(&self) -> usize {
+    match self {
+      LeafBits::Plain(bits) => bits.len(),
+      LeafBits::Vocabulary(vocabulary) => vocabulary.len(),
     }
-    struct K2TreeVisitor;
-    impl<'de> Visitor<'de> for K2TreeVisitor {
-      type Value = K2Tree;
-      fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        formatter.write_str("struct K2Tree")
-      }
-      fn visit_map<V: MapAccess<'de>>(self, mut map: V) -> std::result::Result<K2Tree, V::Error> {
-        let mut m_width = None;
-        let mut k = None;
-        let mut max_slayers = None;
-        let mut slayer_starts = None;
-        let mut stems = None;
-        let mut stem_to_leaf = None;
-        let mut leaves = None;
-        while let Some(key) = map.next_key()? {
-          match key {
-            Field::MatrixWidth => {
-                if m_width.is_some() {
-                    return Err(de::Error::duplicate_field("matrix_width"));
-                }
-                m_width = Some(map.next_value()?);
-            }
-            Field::K => {
-              if k.is_some() {
-                  return Err(de::Error::duplicate_field("k"));
-              }
-              k = Some(map.next_value()?);
-            }
-            Field::MaxStemLayers => {
-              if max_slayers.is_some() {
-                  return Err(de::Error::duplicate_field("max_stem_layer"));
-              }
-              max_slayers = Some(map.next_value()?);
-            }
-            Field::StemLayerStarts => {
-              if slayer_starts.is_some() {
-                  return Err(de::Error::duplicate_field("stem_layer_starts"));
-              }
-              slayer_starts = Some(map.next_value()?);
-            }
-            Field::Stems => {
-              if stems.is_some() {
-                return Err(de::Error::duplicate_field("stems"));
-              }
-              stems = Some(BitVec::from(map.next_value::<Vec<u8>>()?));
-            }
-            Field::StemToLeaf => {
-              if stem_to_leaf.is_some() {
-                return Err(de::Error::duplicate_field("stem_to_leaf"));
-              }
-              stem_to_leaf = Some(map.next_value()?);
-            }
-            Field::Leaves => {
-              if leaves.is_some() {
-                return Err(de::Error::duplicate_field("leaves"));
-              }
-              leaves = Some(BitVec::from(map.next_value::<Vec<u8>>()?));
-            }
-          }
-        }
-        let m_width = m_width.ok_or_else(|| de::Error::missing_field("matrixWidth"))?;
-        let k = k.ok_or_else(|| de::Error::missing_field("k"))?;
-        let max_slayers = max_slayers.ok_or_else(|| de::Error::missing_field("maxStemLayers"))?;
-        let slayer_starts = slayer_starts.ok_or_else(|| de::Error::missing_field("stemLayerStarts"))?;
-        let stems = stems.ok_or_else(|| de::Error::missing_field("stems"))?;
-        let stem_to_leaf = stem_to_leaf.ok_or_else(|| de::Error::missing_field("stemToLeaf"))?;
-        let leaves = leaves.ok_or_else(|| de::Error::missing_field("leaves"))?;
-        Ok(K2Tree {
-          matrix_width: m_width,
-          k: k,
-          max_slayers: max_slayers,
-          slayer_starts: slayer_starts,
-          stems: stems,
-          stem_to_leaf: stem_to_leaf,
-          leaves: leaves
-        })
-      }
+  }
+  fn reborrow(&self) -> LeafBits {
+    match self {
+      LeafBits::Plain(bits) => LeafBits::Plain(bits),
+      LeafBits::Vocabulary(vocabulary) => LeafBits::Vocabulary(Cow::Borrowed(&**vocabulary)),
+    }
+  }
+  fn to_leaf_layer(&self) -> LeafLayer {
+    match self {
+      LeafBits::Plain(bits) => LeafLayer::Plain(GrowableBits::from_bitvec(BitVec::from(*bits))),
+      LeafBits::Vocabulary(vocabulary) => LeafLayer::Vocabulary(vocabulary.clone().into_owned()),
     }
-    const FIELDS: &'static [&'static str] = &[
-      "matrix_width",
-      "k",
-      "max_stem_layers",
-      "stem_layer_starts",
-      "stems",
-      "stem_to_leaf",
-      "leaves"
-    ];
-    deserializer.deserialize_struct("K2Tree", FIELDS, K2TreeVisitor)
   }
 }
-
-/* Private */
-type Range = [[usize; 2]; 2];
-enum DescendResult {
-  Leaf(usize, Range), //leaf_start, leaf_range
-  Stem(usize, Range), //stem_start, stem_range
+impl<'a> std::ops::Index<usize> for LeafBits<'a> {
+  type Output = bool;
+  fn index(&self, bit_pos: usize) -> &bool {
+    let bit = match self {
+      LeafBits::Plain(bits) => bits[bit_pos],
+      LeafBits::Vocabulary(vocabulary) => vocabulary.get(bit_pos),
+    };
+    if bit { &true } else { &false }
+  }
 }
-struct DescendEnv {
-  /* Allows for descend to be recursive without parameter hell */
-  x: usize,
-  y: usize,
-  slayer_max: usize,
+#[derive(Debug, Clone)]
+struct LeafVocabulary {
+  stem_len: usize,
+  blocks: BitVec, //every distinct leaf, most frequent first
+  codes: Dac, //position in blocks of every leaf, in order
 }
-impl K2Tree {
-  fn leaf_parent(&self, bit_pos: usize) -> usize {
-    self.layer_start(self.max_slayers-1) + self.stem_to_leaf[bit_pos / 4]
-  }
-  fn stem_start(bit_pos: usize) -> usize {
-    bit_pos / 4 * 4
-  }
-  fn get_coords(&self, leaf_bit_pos: usize) -> [usize; 2] {
-    /* Start at the leaf_bit and traverse our way up to the top of the tree,
-    keeping track of the path we took on our way up in terms of
-    bit-positions (offsets) in the stems. Then, traverse back down the same
-    path to find the coords of the leaf_bit. */
-    let parent_bit = self.leaf_parent(leaf_bit_pos);
-    let mut stem_start = Self::stem_start(parent_bit);
-    let mut offset = parent_bit - stem_start;
-    let mut offsets = Vec::new();
-    offsets.push(offset);
-    for _ in 1..self.max_slayers {
-      let parent = self.parent(stem_start);
-      stem_start = parent.0;
-      offset = parent.1;
-      offsets.push(offset);
+impl LeafVocabulary {
+  fn new(leaves: &BitSlice, stem_len: usize) -> Self {
+    /* Ties in frequency go to the leaf seen first so the same
+    leaves always give the same vocabulary */
+    let mut counts: std::collections::HashMap<BitVec, (usize, usize)> = std::collections::HashMap::new();
+    for (nth, leaf) in leaves.chunks(stem_len).enumerate() {
+      counts.entry(BitVec::from(leaf)).or_insert((0, nth)).0 += 1;
     }
-    /* Reverse the offsets ready to traverse them back down the tree */
-    offsets.reverse();
-    let mut range = [[0, self.matrix_width-1], [0, self.matrix_width-1]];
-    for layer in 0..self.max_slayers {
-      range = to_4_subranges(range)[offsets[layer]];
+    let mut by_frequency: Vec<(BitVec, (usize, usize))> = counts.into_iter().collect();
+    by_frequency.sort_by_key(|&(_, (count, first))| (std::cmp::Reverse(count), first));
+    let mut positions: std::collections::HashMap<BitVec, usize> = std::collections::HashMap::new();
+    let mut blocks = BitVec::new();
+    for (position, (leaf, _)) in by_frequency.into_iter().enumerate() {
+      blocks.extend(leaf.iter());
+      positions.insert(leaf, position);
     }
-    let leaf_offset = leaf_bit_pos - (leaf_bit_pos/4*4);
-    match leaf_offset {
-      0 => [range[0][0], range[1][0]],
-      1 => [range[0][1], range[1][0]],
-      2 => [range[0][0], range[1][1]],
-      3 => [range[0][1], range[1][1]],
-      _ => [std::usize::MAX, std::usize::MAX],
+    let codes: Vec<usize> = leaves.chunks(stem_len).map(|leaf| positions[&BitVec::from(leaf)]).collect();
+    LeafVocabulary {
+      stem_len: stem_len,
+      blocks: blocks,
+      codes: Dac::new(&codes),
     }
   }
-  fn layer_from_range(&self, r: Range) -> usize {
-    let r_width = r[0][1]-r[0][0]+1;
-    ((self.matrix_width as f64).log(self.k as f64) as usize)
-    - ((r_width as f64).log(self.k as f64) as usize)
+  fn len(&self) -> usize {
+    self.codes.len() * self.stem_len
   }
-  fn matrix_bit(&self, x: usize, y: usize, m_width: usize) -> Result<DescendResult> {
-    let env = DescendEnv {
-      x: x,
-      y: y,
-      slayer_max: self.slayer_starts.len()-1,
-    };
-    self.descend(&env, 0, 0, [[0, m_width-1], [0, m_width-1]])
+  fn get(&self, bit_pos: usize) -> bool {
+    let block = self.codes.get(bit_pos / self.stem_len);
+    self.blocks[(block * self.stem_len) + (bit_pos % self.stem_len)]
   }
-  fn descend(&self, env: &DescendEnv, layer: usize, stem_pos: usize, range: Range) -> Result<DescendResult> {
-    let subranges = to_4_subranges(range);
-    for (child_pos, child) in self.stems[stem_pos..stem_pos+4].iter().enumerate() {
-      if within_range(&subranges[child_pos], env.x, env.y) {
-        if !child { return Ok(DescendResult::Stem(stem_pos, range)) } //The bit exists within a range that has all zeros
-        else if layer == env.slayer_max {
-          let leaf_start = match self.leaf_start(stem_pos + child_pos) {
-            Ok(ls) => ls,
-            Err(_) => return Err(Error::TraverseError(env.x, env.y)),
-          };
-          return Ok(DescendResult::Leaf(leaf_start, subranges[child_pos]))
-        }
-        else {
-          let child_stem = match self.child_stem(layer, stem_pos, child_pos) {
-            Ok(cs) => cs,
-            Err(_) => return Err(Error::TraverseError(env.x, env.y)),
-          };
-          return self.descend(env,
-                              layer+1,
-                              child_stem,
-                              subranges[child_pos])
-        }
-      }
+  fn leaf(&self, nth: usize) -> &BitSlice {
+    let block_start = self.codes.get(nth) * self.stem_len;
+    &self.blocks[block_start..block_start+self.stem_len]
+  }
+  fn count_ones(&self, leaf_bits: std::ops::Range<usize>) -> usize {
+    /* Has to visit each leaf in leaf_bits, which covers whole leaves */
+    (leaf_bits.start / self.stem_len..leaf_bits.end / self.stem_len).fold(0, |count, nth| {
+      let block_start = self.codes.get(nth) * self.stem_len;
+      count + self.blocks[block_start..block_start+self.stem_len].count_ones()
+    })
+  }
+  fn to_bitvec(&self) -> BitVec {
+    let mut bits = BitVec::with_capacity(self.len());
+    for nth in 0..self.codes.len() {
+      let block_start = self.codes.get(nth) * self.stem_len;
+      bits.extend(self.blocks[block_start..block_start+self.stem_len].iter());
     }
-    Err(Error::TraverseError(env.x, env.y)) //Should never return this
+    bits
   }
-  fn num_stems_before_child(&self, bit_pos: usize, layer: usize) -> usize {
-    let layer_start = self.layer_start(layer);
-    ones_in_range(&self.stems, layer_start, bit_pos)
+  fn heapsize(&self) -> usize {
+    (self.blocks.len() / 8) + self.codes.heapsize()
   }
-  fn layer_start(&self, l: usize) -> usize {
-    if l == self.slayer_starts.len() {
-      self.stems.len()
+  fn write_to(&self, buf: &mut Vec<u8>) {
+    /* Number of distinct leaves, chunk length, number of levels and the
+    values in each (u64 each), then the leaves followed by each level's
+    chunks and continuation bits, every section packed like the stems */
+    let num_blocks = self.blocks.len() / self.stem_len;
+    for &field in [num_blocks, self.codes.chunk_len, self.codes.chunks.len()].iter() {
+      buf.extend_from_slice(&(field as u64).to_le_bytes());
     }
-    else {
-      self.slayer_starts[l]
+    for more in self.codes.more.iter() {
+      buf.extend_from_slice(&(more.len() as u64).to_le_bytes());
     }
-  }
-  fn layer_len(&self, l: usize) -> usize {
-    if l == self.slayer_starts.len()-1 {
-      return self.stems.len() - self.slayer_starts[l]
+    buf.extend_from_slice(&bits_to_bytes(&self.blocks));
+    for (chunks, more) in self.codes.chunks.iter().zip(self.codes.more.iter()) {
+      buf.extend_from_slice(&bits_to_bytes(chunks));
+      buf.extend_from_slice(&bits_to_bytes(more));
     }
-    self.slayer_starts[l+1] - self.slayer_starts[l]
-  }
-  fn leaf_start(&self, stem_bitpos: usize) -> std::result::Result<usize, ()> {
-    if !self.stems[stem_bitpos] { return Err(()) }
-    Ok(self.stem_to_leaf
-            .iter()
-            .position(|&n| n == (stem_bitpos - self.slayer_starts[self.slayer_starts.len()-1]))
-            .unwrap()
-            * 4)
   }
-  fn child_stem(&self, layer: usize, stem_start: usize, nth_child: usize) -> std::result::Result<usize, ()> {
-    if !self.stems[stem_start+nth_child]
-    || layer == self.max_slayers-1 {
-      /* If stem_bit is 0 or final stem layer, cannot have children */
-      return Err(())
+  fn parse(bytes: &[u8], stem_len: usize, leaves_len: usize) -> Result<Self> {
+    let read_u64 = |offset: usize| -> Result<usize> {
+      if offset + 8 > bytes.len() {
+        return Err(invalid_binary("leaf vocabulary is cut short"))
+      }
+      let mut field = [0u8; 8];
+      field.copy_from_slice(&bytes[offset..offset+8]);
+      Ok(u64::from_le_bytes(field) as usize)
+    };
+    let read_bits = |offset: usize, len: usize| -> Result<BitVec> {
+      let end = offset.checked_add(bytes_for_bits(len)).filter(|&end| end <= bytes.len());
+      match end {
+        Some(end) => Ok(BitVec::from(&BitSlice::from_slice(&bytes[offset..end])[..len])),
+        None => Err(invalid_binary("leaf vocabulary is cut short")),
+      }
+    };
+    let num_blocks = read_u64(0)?;
+    let chunk_len = read_u64(8)?;
+    let num_levels = read_u64(16)?;
+    if chunk_len == 0 || chunk_len >= 64
+    || num_levels > (64 + chunk_len - 1) / chunk_len {
+      return Err(invalid_binary("leaf vocabulary codes are malformed"))
     }
-    Ok(self.layer_start(layer+1)
-    + (self.num_stems_before_child(stem_start+nth_child, layer) * 4))
-  }
-  fn parent(&self, stem_start: usize) -> (usize, usize) {
-    /* Returns (stem_start, bit_offset) */
-    if stem_start >= self.slayer_starts[1] {
-      /* If stem isn't in layer 0, look for parent */
-      let mut parent_layer_start = 0;
-      let mut curr_layer_start = 0;
-      for (i, layer_start) in (0..self.slayer_starts.len()).enumerate() {
-        if i == self.slayer_starts.len()-1 {
-          if stem_start >= self.slayer_starts[layer_start]
-          && stem_start < self.stems.len() {
-            parent_layer_start = self.slayer_starts[layer_start-1];
-            curr_layer_start = self.slayer_starts[layer_start];
-          }
-        }
-        else if stem_start >= self.slayer_starts[layer_start]
-        && stem_start < self.slayer_starts[layer_start+1] {
-          parent_layer_start = self.slayer_starts[layer_start-1];
-          curr_layer_start = self.slayer_starts[layer_start];
-        }
+    let mut offset = 24;
+    let mut level_lens = Vec::with_capacity(num_levels);
+    for _ in 0..num_levels {
+      level_lens.push(read_u64(offset)?);
+      offset += 8;
+    }
+    let blocks = read_bits(offset, num_blocks.saturating_mul(stem_len))?;
+    offset += (blocks.len() + 7) / 8;
+    let mut codes = Dac {
+      chunk_len: chunk_len,
+      chunks: Vec::new(),
+      more: Vec::new(),
+      more_ranks: Vec::new(),
+    };
+    /* Each level holds a value for every 1 in the level before */
+    let mut expected_len = leaves_len / stem_len;
+    for &level_len in level_lens.iter() {
+      if level_len != expected_len {
+        return Err(invalid_binary("leaf vocabulary codes are malformed"))
       }
-      let nth_stem_in_layer = (stem_start - curr_layer_start)/4;
-      let mut i = 0;
-      let mut bit_pos_in_parent_stem_layer = 0;
-      for bit in &self.stems[parent_layer_start..curr_layer_start] {
-        if bit {
-          if i == nth_stem_in_layer { break }
-          i += 1;
+      let chunks = read_bits(offset, level_len.saturating_mul(chunk_len))?;
+      offset += (chunks.len() + 7) / 8;
+      let more = read_bits(offset, level_len)?;
+      offset += (more.len() + 7) / 8;
+      expected_len = more.count_ones();
+      codes.chunks.push(chunks);
+      codes.more_ranks.push(RankIndex::new(&more));
+      codes.more.push(more);
+    }
+    if expected_len != 0
+    || offset != bytes.len()
+    || (level_lens.is_empty() && leaves_len != 0)
+    || leaves_len % stem_len != 0 {
+      return Err(invalid_binary("leaf vocabulary does not match the leaves"))
+    }
+    if (0..codes.len()).any(|nth| codes.get(nth) >= num_blocks) {
+      return Err(invalid_binary("leaf vocabulary code points past the vocabulary"))
+    }
+    Ok(LeafVocabulary {
+      stem_len: stem_len,
+      blocks: blocks,
+      codes: codes,
+    })
+  }
+}
+/* Directly addressable codes: values are cut into chunks of chunk_len bits,
+level l holding the lth chunk of every value that has one, so small values
+(the frequent leaves) take a single chunk and any value is read without
+decoding the ones before it */
+#[derive(Debug, Clone)]
+struct Dac {
+  chunk_len: usize,
+  chunks: Vec<BitVec>,
+  more: Vec<BitVec>, //more[l][i] is whether the ith value in level l has another chunk
+  more_ranks: Vec<RankIndex>,
+}
+impl Dac {
+  fn new(values: &[usize]) -> Self {
+    /* Use whichever chunk length takes the fewest bits */
+    let max_bits = values.iter().fold(1, |max_bits, &value| {
+      std::cmp::max(max_bits, (0usize.leading_zeros() - value.leading_zeros()) as usize)
+    });
+    let size_with = |chunk_len: usize| -> usize {
+      values.iter().fold(0, |size, &value| {
+        let value_bits = (0usize.leading_zeros() - value.leading_zeros()) as usize;
+        let num_chunks = std::cmp::max(1, (value_bits + chunk_len - 1) / chunk_len);
+        size + (num_chunks * (chunk_len + 1))
+      })
+    };
+    let chunk_len = (1..=max_bits).min_by_key(|&chunk_len| size_with(chunk_len)).unwrap_or(1);
+    let mut dac = Dac {
+      chunk_len: chunk_len,
+      chunks: Vec::new(),
+      more: Vec::new(),
+      more_ranks: Vec::new(),
+    };
+    let mut level_values: Vec<usize> = values.to_vec();
+    while !level_values.is_empty() {
+      let mut chunks = BitVec::with_capacity(level_values.len() * chunk_len);
+      let mut more = BitVec::with_capacity(level_values.len());
+      let mut next_values = Vec::new();
+      for &value in level_values.iter() {
+        for bit in 0..chunk_len {
+          chunks.push((value >> bit) & 1 == 1);
         }
-        bit_pos_in_parent_stem_layer += 1;
+        let rest = value >> chunk_len;
+        more.push(rest != 0);
+        if rest != 0 { next_values.push(rest); }
       }
-      (((bit_pos_in_parent_stem_layer / 4) * 4) + parent_layer_start,
-        bit_pos_in_parent_stem_layer % 4)
-    }
-    else {
-      (std::usize::MAX, std::usize::MAX)
+      dac.chunks.push(chunks);
+      dac.more_ranks.push(RankIndex::new(&more));
+      dac.more.push(more);
+      level_values = next_values;
     }
+    dac
   }
-  #[allow(dead_code)] //Used by unit tests
-  fn parent_stem(&self, stem_start: usize) -> usize {
-    self.parent(stem_start).0
+  fn len(&self) -> usize {
+    self.more.first().map_or(0, |more| more.len())
   }
-  #[allow(dead_code)] //Used by unit tests
-  fn parent_bit(&self, stem_start: usize) -> usize {
-    let (stem_start, bit_offset) = self.parent(stem_start);
-    stem_start + bit_offset
+  fn get(&self, nth: usize) -> usize {
+    let mut value = 0;
+    let mut pos = nth;
+    for level in 0..self.chunks.len() {
+      for bit in 0..self.chunk_len {
+        if self.chunks[level][(pos * self.chunk_len) + bit] {
+          value |= 1 << ((level * self.chunk_len) + bit);
+        }
+      }
+      if !self.more[level][pos] { break }
+      pos = self.more_ranks[level].rank(&self.more[level], pos);
+    }
+    value
   }
-  pub fn heapsize(&self) -> usize {
-    let mut size: usize = std::mem::size_of_val(self);
-    size += std::mem::size_of::<usize>() * self.slayer_starts.len();
-    size += self.stems.len() / 8;
-    size += std::mem::size_of::<usize>() * self.stem_to_leaf.len();
-    size += self.leaves.len() / 8;
+  fn heapsize(&self) -> usize {
+    let mut size = 0;
+    for level in 0..self.chunks.len() {
+      size += self.chunks[level].len() / 8;
+      size += self.more[level].len() / 8;
+      size += self.more_ranks[level].heapsize();
+    }
     size
   }
 }
 
 /* Utils */
+pub(crate) fn can_cover(k: usize, matrix_width: usize, matrix_height: usize) -> bool {
+  /* Whether a tree of some number of stem layers covers the matrix, with
+  k at least 2 no tree of 64 or more layers has a width a usize can count */
+  (0..64).any(|max_slayers| stems_cover(k, max_slayers, matrix_width, matrix_height))
+}
+fn too_wide(matrix_width: usize, matrix_height: usize) -> Error {
+  Error::CouldNotGrow(format!("a tree covering a {}x{} matrix would be wider than a usize can count", matrix_width, matrix_height))
+}
+pub(crate) fn invalid_binary(reason: &str) -> Error {
+  Error::InvalidBinary(reason.to_string())
+}
+pub(crate) fn bytes_for_bits(num_bits: usize) -> usize {
+  /* (num_bits + 7) / 8 without overflowing at usize::MAX */
+  num_bits / 8 + (num_bits % 8 != 0) as usize
+}
+pub(crate) fn bits_to_bytes(bits: &BitSlice) -> Vec<u8> {
+  /* Packed bytes with the bits past the end cleared */
+  let mut bytes = BitVec::from(bits).into_vec();
+  bytes.truncate((bits.len() + 7) / 8);
+  if bits.len() % 8 != 0 {
+    let last = bytes.len()-1;
+    bytes[last] &= 0xffu8 << (8 - (bits.len() % 8));
+  }
+  bytes
+}
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for &byte in bytes {
+    hash ^= byte as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash
+}
 fn block_start(bit_pos: usize, block_len: usize) -> usize {
   (bit_pos / block_len) * block_len
 }
@@ -894,17 +4137,87 @@ fn insert_block(bit_vec: &mut BitVec, block_start: usize, block_len: usize) -> s
     Ok(())
   }
 }
-fn to_4_subranges(r: Range) -> [Range; 4] {
-  [
-    [[r[0][0], r[0][0]+((r[0][1]-r[0][0])/2)],   [r[1][0], r[1][0]+((r[1][1]-r[1][0])/2)]], //Top left quadrant
-    [[r[0][0]+((r[0][1]-r[0][0])/2)+1, r[0][1]], [r[1][0], r[1][0]+((r[1][1]-r[1][0])/2)]], //Top right quadrant
-    [[r[0][0], r[0][0]+((r[0][1]-r[0][0])/2)],   [r[1][0]+((r[1][1]-r[1][0])/2)+1, r[1][1]]], //Bottom left quadrant
-    [[r[0][0]+((r[0][1]-r[0][0])/2)+1, r[0][1]], [r[1][0]+((r[1][1]-r[1][0])/2)+1, r[1][1]]]  //Bottom right quadrant
-  ]
+pub(crate) fn grown_extent(extent: usize, k: usize, tree_width: usize) -> usize {
+  /* An extent of 0 would stay 0 however many times it's multiplied,
+  so it takes the whole of the grown tree instead */
+  if extent == 0 { tree_width } else { extent * k }
+}
+pub(crate) fn log_k(n: usize, k: usize) -> usize {
+  /* Integer log, n is always a power of k */
+  let mut layers = 0;
+  let mut width = 1;
+  while width < n {
+    width *= k;
+    layers += 1;
+  }
+  layers
+}
+pub(crate) fn to_subranges(r: Range, k: usize) -> Vec<Range> {
+  /* Splits r into its k*k submatrices, ordered row by row
+  from the top left submatrix */
+  let sub_width = (r[0][1] - r[0][0] + 1) / k;
+  let mut subranges = Vec::with_capacity(k*k);
+  for row in 0..k {
+    for col in 0..k {
+      let x_start = r[0][0] + (col * sub_width);
+      let y_start = r[1][0] + (row * sub_width);
+      subranges.push([[x_start, x_start+sub_width-1], [y_start, y_start+sub_width-1]]);
+    }
+  }
+  subranges
 }
-fn within_range(r: &Range, x: usize, y: usize) -> bool {
+pub(crate) fn within_range(r: &Range, x: usize, y: usize) -> bool {
   x >= r[0][0] && x <= r[0][1] && y >= r[1][0] && y <= r[1][1]
 }
+pub(crate) fn split_parts<P, F: Fn(&mut P) -> Option<P>>(whole: P, max_parts: usize, split: F) -> Vec<P> {
+  /* Keeps splitting every part with split, which hands back the later
+  half, until there are max_parts or none of them can be split any further */
+  let mut parts = vec![whole];
+  while parts.len() < max_parts {
+    let num_parts = parts.len();
+    let mut split_parts = Vec::with_capacity(num_parts * 2);
+    for mut part in parts {
+      let later = if split_parts.len() + num_parts < max_parts { split(&mut part) } else { None };
+      split_parts.push(part);
+      if let Some(later) = later { split_parts.push(later); }
+    }
+    parts = split_parts;
+    if parts.len() == num_parts { break }
+  }
+  parts
+}
+pub(crate) fn run_parts<P: Send, T: Send, F: Fn(P) -> T + Sync>(parts: Vec<P>, f: F) -> Vec<T> {
+  /* Runs f over parts on up to one thread per core, each thread taking
+  the next part whenever it finishes one. Results are in the parts' order */
+  let num_parts = parts.len();
+  let queue = std::sync::Mutex::new(parts.into_iter().enumerate());
+  let results = std::sync::Mutex::new(Vec::with_capacity(num_parts));
+  std::thread::scope(|scope| {
+    for _ in 0..std::cmp::min(num_cpus::get(), num_parts) {
+      scope.spawn(|| loop {
+        let next = queue.lock().unwrap().next();
+        match next {
+          Some((i, part)) => {
+            let result = f(part);
+            results.lock().unwrap().push((i, result));
+          },
+          None => break,
+        }
+      });
+    }
+  });
+  let mut results = results.into_inner().unwrap();
+  results.sort_by_key(|&(i, _)| i);
+  results.into_iter().map(|(_, result)| result).collect()
+}
+fn range_contains(outer: &Range, inner: &Range) -> bool {
+  outer[0][0] <= inner[0][0] && inner[0][1] <= outer[0][1]
+  && outer[1][0] <= inner[1][0] && inner[1][1] <= outer[1][1]
+}
+pub(crate) fn ranges_overlap(a: &Range, b: &Range) -> bool {
+  a[0][0] <= b[0][1] && b[0][0] <= a[0][1]
+  && a[1][0] <= b[1][1] && b[1][0] <= a[1][1]
+}
 fn ones_in_range(bits: &BitVec, begin: usize, end: usize) -> usize {
   bits[begin..end].iter().fold(0, |total, bit| total + bit as usize)
 }
@@ -931,11 +4244,22 @@ mod unit_tests {
     let json = tree.to_json().unwrap();
     let from_json_tree = K2Tree::from_json(&json).unwrap();
     assert_eq!(tree, from_json_tree);
+    let empty = K2Tree::with_k(3);
+    assert_eq!(K2Tree::from_json(&empty.to_json().unwrap()).unwrap(), empty);
+    /* Layers that don't match the stems are rejected rather than panicking */
+    for starts in ["[]", "[4, 0]", "[0, 8]", "[0, 400]", "[0]"].iter() {
+      let bad_starts = json.replace("\"stemLayerStarts\":[0,4]", &format!("\"stemLayerStarts\":{}", starts));
+      assert_ne!(bad_starts, json);
+      assert!(K2Tree::from_json(&bad_starts).is_err());
+    }
+    let bad_leaves = json.replace("\"stemToLeaf\":[0,1,3,4,8]", "\"stemToLeaf\":[0,1,3,4,9]");
+    assert_ne!(bad_leaves, json);
+    assert!(K2Tree::from_json(&bad_leaves).is_err());
   }
   #[test]
   fn flood() {
     let mut tree = K2Tree::new();
-    for _ in 0..3 { tree.grow(); }
+    for _ in 0..3 { tree.grow().unwrap(); }
     dbg!(tree.matrix_width());
     let xs: Vec<usize> = vec![22, 22];
     let ys: Vec<usize> = vec![33, 22];
@@ -949,7 +4273,7 @@ mod unit_tests {
   fn flood_2() {
     let mut rng = rand::thread_rng();
     let mut tree = K2Tree::new();
-    for _ in 0..10 { tree.grow(); }
+    for _ in 0..10 { tree.grow().unwrap(); }
     dbg!(tree.matrix_width());
     let mut nums: Vec<[usize; 2]> = Vec::new();
     for _ in 0..500 {
@@ -965,22 +4289,24 @@ mod unit_tests {
     let mut tree = K2Tree::new();
     assert_eq!(tree, K2Tree {
       matrix_width: 8,
+      matrix_height: 8,
       k: 2,
       max_slayers: 2,
       slayer_starts: vec![0],
-      stems: bitvec![0,0,0,0],
+      stems: bitvec![0,0,0,0].into(),
       stem_to_leaf: Vec::new(),
-      leaves: BitVec::new(),
+      leaves: BitVec::new().into(),
     });
-    tree.grow();
+    tree.grow().unwrap();
     assert_eq!(tree, K2Tree {
       matrix_width: 16,
+      matrix_height: 16,
       k: 2,
       max_slayers: 3,
       slayer_starts: vec![0],
-      stems: bitvec![0,0,0,0],
+      stems: bitvec![0,0,0,0].into(),
       stem_to_leaf: Vec::new(),
-      leaves: BitVec::new(),
+      leaves: BitVec::new().into(),
     });
   }
   #[test]
@@ -988,25 +4314,38 @@ mod unit_tests {
     let mut tree = K2Tree::new();
     assert_eq!(tree, K2Tree {
       matrix_width: 8,
+      matrix_height: 8,
       k: 2,
       max_slayers: 2,
       slayer_starts: vec![0],
-      stems: bitvec![0,0,0,0],
+      stems: bitvec![0,0,0,0].into(),
       stem_to_leaf: Vec::new(),
-      leaves: BitVec::new(),
+      leaves: BitVec::new().into(),
     });
-    for _ in 0..4 { tree.grow(); }
+    for _ in 0..4 { tree.grow().unwrap(); }
     assert_eq!(tree, K2Tree {
       matrix_width: 128,
+      matrix_height: 128,
       k: 2,
       max_slayers: 6,
       slayer_starts: vec![0],
-      stems: bitvec![0,0,0,0],
+      stems: bitvec![0,0,0,0].into(),
       stem_to_leaf: Vec::new(),
-      leaves: BitVec::new(),
+      leaves: BitVec::new().into(),
     });
   }
   #[test]
+  fn grow_2() {
+    /* Empty extents take the whole grown tree rather than staying 0 */
+    let mut tree = K2Tree::with_extents(2, 0, 5).unwrap();
+    tree.grow().unwrap();
+    assert_eq!((tree.matrix_width(), tree.matrix_height()), (16, 10));
+    tree.set(15, 9, true).unwrap();
+    let mut tree = K2Tree1::with_extents(3, 0, 0).unwrap();
+    tree.grow().unwrap();
+    assert_eq!((tree.matrix_width(), tree.matrix_height()), (81, 81));
+  }
+  #[test]
   fn test_send() {
       fn assert_send<T: Send>() {}
       assert_send::<K2Tree>();
@@ -1030,12 +4369,13 @@ mod unit_tests {
     ];
     let tree = K2Tree {
       matrix_width: 8,
+      matrix_height: 8,
       k: 2,
       max_slayers: 2,
       slayer_starts: vec![0, 4],
-      stems:  bitvec![0,1,1,1, 1,1,0,1, 1,0,0,0, 1,0,0,0],
+      stems:  bitvec![0,1,1,1, 1,1,0,1, 1,0,0,0, 1,0,0,0].into(),
       stem_to_leaf: vec![0, 1, 3, 4, 8],
-      leaves: bitvec![0,1,1,0, 0,1,0,1, 1,1,0,0, 1,0,0,0, 0,1,1,0]
+      leaves: bitvec![0,1,1,0, 0,1,0,1, 1,1,0,0, 1,0,0,0, 0,1,1,0].into(),
     };
     assert_eq!(tree, K2Tree::from_matrix(m).unwrap());
   }
@@ -1045,7 +4385,7 @@ mod unit_tests {
     assert_eq!(vec![1,3,5,9], one_positions(&bv));
   }
   #[test]
-  fn to_4_subranges_0() {
+  fn to_subranges_0() {
     let ranges = [[[0, 7], [0, 7]], [[4, 7], [0, 3]], [[8, 15], [8, 15]]];
     let subranges = [
       [[[0, 3], [0, 3]], [[4, 7], [0, 3]], [[0, 3], [4, 7]], [[4, 7], [4, 7]]],
@@ -1053,7 +4393,7 @@ mod unit_tests {
       [[[8, 11], [8, 11]], [[12, 15], [8, 11]], [[8, 11], [12, 15]], [[12, 15], [12, 15]]]
     ];
     for i in 0..ranges.len() {
-      assert_eq!(to_4_subranges(ranges[i]), subranges[i]);
+      assert_eq!(to_subranges(ranges[i], 2), subranges[i].to_vec());
     }
   }
   #[test]
@@ -1158,4 +4498,1067 @@ mod unit_tests {
     let tree = K2Tree::test_tree();
     assert_eq!(tree.get_coords(12), [0, 4]);
   }
+  #[test]
+  fn with_k_0() {
+    let tree = K2Tree::with_k(4);
+    assert_eq!(tree, K2Tree {
+      matrix_width: 64,
+      matrix_height: 64,
+      k: 4,
+      max_slayers: 2,
+      slayer_starts: vec![0],
+      stems: bitvec![0; 16].into(),
+      stem_to_leaf: Vec::new(),
+      leaves: BitVec::new().into(),
+    });
+  }
+  #[test]
+  fn with_k_1() {
+    let mut tree = K2Tree::with_k(4);
+    tree.set(5, 2, true).unwrap();
+    tree.set(63, 63, true).unwrap();
+    assert_eq!(tree.slayer_starts, vec![0, 16]);
+    assert_eq!(tree.stems, bitvec![1,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,1,
+                                   0,1,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0,
+                                   0,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,1]);
+    assert_eq!(tree.stem_to_leaf, vec![1, 31]);
+    assert_eq!(tree.leaves, bitvec![0,0,0,0, 0,0,0,0, 0,1,0,0, 0,0,0,0,
+                                    0,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,1]);
+    assert_eq!(tree.get_coords(9), [5, 2]);
+    assert_eq!(tree.get_coords(31), [63, 63]);
+  }
+  #[test]
+  fn set_get_any_k() {
+    use std::collections::HashSet;
+    let mut rng = rand::thread_rng();
+    for &k in [2, 3, 4, 8].iter() {
+      let mut tree = K2Tree::with_k(k);
+      if k < 8 { tree.grow().unwrap(); }
+      let width = tree.matrix_width();
+      let mut reference: HashSet<[usize; 2]> = HashSet::new();
+      for _ in 0..200 {
+        let x: usize = rng.gen_range(0, width);
+        let y: usize = rng.gen_range(0, width);
+        let state = rng.gen_range(0, 3) > 0;
+        tree.set(x, y, state).unwrap();
+        if state { reference.insert([x, y]); }
+        else { reference.remove(&[x, y]); }
+      }
+      for &[x, y] in reference.iter() {
+        assert!(tree.get(x, y).unwrap());
+      }
+      let set_leaves: HashSet<[usize; 2]> = tree.leaves()
+        .filter(|leaf| leaf.value)
+        .map(|leaf| [leaf.x, leaf.y])
+        .collect();
+      assert_eq!(set_leaves, reference);
+      assert_eq!(K2Tree::from_json(&tree.to_json().unwrap()).unwrap(), tree);
+      for &[x, y] in reference.iter() {
+        tree.set(x, y, false).unwrap();
+      }
+      assert!(tree.is_empty());
+      assert_eq!(tree.stems, bitvec![0; k*k]);
+    }
+  }
+  #[test]
+  fn rank_select_0() {
+    let mut bits = BitVec::new();
+    for i in 0..2000 { bits.push(i % 3 == 0); }
+    let index = RankIndex::new(&bits);
+    assert_eq!(index.rank(&bits, 0), 0);
+    assert_eq!(index.rank(&bits, 1), 1);
+    assert_eq!(index.rank(&bits, 1000), 334);
+    assert_eq!(index.rank(&bits, 2000), 667);
+    assert_eq!(index.select(&bits, 0), Some(0));
+    assert_eq!(index.select(&bits, 200), Some(600));
+    assert_eq!(index.select(&bits, 666), Some(1998));
+    assert_eq!(index.select(&bits, 667), None);
+  }
+  #[test]
+  fn rank_set_0() {
+    let mut rng = rand::thread_rng();
+    let mut bits = BitVec::new();
+    for i in 0..3000 { bits.push(i % 5 == 0); }
+    let mut index = RankIndex::new(&bits);
+    for _ in 0..500 {
+      index.set(&mut bits, rng.gen_range(0, 3000), rng.gen());
+      assert_eq!(index.block_ranks, RankIndex::new(&bits).block_ranks);
+    }
+    /* Setting the last bit of a block only changes the blocks after it */
+    index.set(&mut bits, RANK_BLOCK_LEN - 1, true);
+    index.set(&mut bits, RANK_BLOCK_LEN - 1, true);
+    assert_eq!(index.block_ranks, RankIndex::new(&bits).block_ranks);
+    index.set(&mut bits, 2999, false);
+    assert_eq!(index.block_ranks, RankIndex::new(&bits).block_ranks);
+    assert_eq!(index.rank(&bits, 3000), bits.count_ones());
+  }
+  #[test]
+  fn ranks_kept_in_step() {
+    let mut rng = rand::thread_rng();
+    let mut tree = K2Tree::new();
+    for _ in 0..7 { tree.grow().unwrap(); }
+    for _ in 0..300 {
+      let x: usize = rng.gen_range(0, 1024);
+      let y: usize = rng.gen_range(0, 1024);
+      tree.set(x, y, rng.gen_range(0, 4) > 0).unwrap();
+    }
+    assert!(tree.stems.len() > RANK_BLOCK_LEN);
+    assert_eq!(tree.stems.ranks.block_ranks, RankIndex::new(&tree.stems.bits).block_ranks);
+    match &tree.leaves {
+      LeafLayer::Plain(leaves) => assert_eq!(leaves.ranks.block_ranks, RankIndex::new(&leaves.bits).block_ranks),
+      LeafLayer::Vocabulary(_) | LeafLayer::Decoded(_) => panic!("leaves should be plain"),
+    }
+    tree.grow().unwrap();
+    tree.shrink().unwrap();
+    assert_eq!(tree.stems.ranks.block_ranks, RankIndex::new(&tree.stems.bits).block_ranks);
+  }
+  #[test]
+  fn from_coordinates_0() {
+    let coords = vec![(0, 4), (4, 1), (4, 5), (5, 0), (5, 4), (6, 2), (7, 0), (7, 1), (7, 2)];
+    assert_eq!(K2Tree::from_coordinates(8, coords).unwrap(), K2Tree::test_tree());
+  }
+  #[test]
+  fn from_coordinates_matches_set() {
+    let mut rng = rand::thread_rng();
+    for &k in [2, 3, 4].iter() {
+      let mut coords: Vec<(usize, usize)> = Vec::new();
+      for _ in 0..300 {
+        coords.push((rng.gen_range(0, 200), rng.gen_range(0, 200)));
+      }
+      let mut set_tree = K2Tree::with_k(k);
+      while set_tree.matrix_width() < 200 { set_tree.grow().unwrap(); }
+      for &(x, y) in &coords {
+        set_tree.set(x, y, true).unwrap();
+      }
+      let built_tree = K2Tree::from_coordinates_with_k(200, k, coords).unwrap();
+      assert_eq!(built_tree, set_tree);
+      assert_eq!(built_tree.slayer_starts, set_tree.slayer_starts);
+      assert_eq!(built_tree.stem_to_leaf, set_tree.stem_to_leaf);
+      assert_eq!(built_tree.max_slayers, set_tree.max_slayers);
+    }
+    assert_eq!(K2Tree::from_coordinates(8, Vec::new()).unwrap(), K2Tree::new());
+    assert!(K2Tree::from_coordinates(8, vec![(8, 0)]).is_err());
+  }
+  #[test]
+  fn iter_range_0() {
+    let tree = K2Tree::test_tree();
+    let cells: Vec<[usize; 2]> = tree.iter_range(4..8, 0..3).collect();
+    assert_eq!(cells, vec![[5, 0], [4, 1], [7, 0], [7, 1], [6, 2], [7, 2]]);
+    assert_eq!(tree.iter_range(0..4, 0..4).count(), 0);
+    assert_eq!(tree.iter_range(0..100, 0..100).count(), 9);
+    assert_eq!(tree.iter_range(3..3, 0..8).count(), 0);
+  }
+  #[test]
+  fn iter_range_1() {
+    let mut rng = rand::thread_rng();
+    for &k in [2, 3].iter() {
+      let mut coords: Vec<(usize, usize)> = Vec::new();
+      for _ in 0..300 {
+        coords.push((rng.gen_range(0, 100), rng.gen_range(0, 100)));
+      }
+      let tree = K2Tree::from_coordinates_with_k(100, k, coords.clone()).unwrap();
+      for _ in 0..20 {
+        let (x0, y0) = (rng.gen_range(0, 100), rng.gen_range(0, 100));
+        let (x1, y1) = (rng.gen_range(x0, 101), rng.gen_range(y0, 101));
+        let mut expected: Vec<[usize; 2]> = coords.iter()
+          .filter(|&&(x, y)| x >= x0 && x < x1 && y >= y0 && y < y1)
+          .map(|&(x, y)| [x, y])
+          .collect();
+        expected.sort();
+        expected.dedup();
+        let mut found: Vec<[usize; 2]> = tree.iter_range(x0..x1, y0..y1).collect();
+        found.sort();
+        assert_eq!(found, expected);
+      }
+    }
+  }
+  #[test]
+  fn successors_predecessors_0() {
+    let tree = K2Tree::test_tree();
+    assert_eq!(tree.successors(7).collect::<Vec<usize>>(), vec![0, 1, 2]);
+    assert_eq!(tree.successors(4).collect::<Vec<usize>>(), vec![1, 5]);
+    assert_eq!(tree.successors(1).count(), 0);
+    assert_eq!(tree.predecessors(0).collect::<Vec<usize>>(), vec![5, 7]);
+    assert_eq!(tree.predecessors(4).collect::<Vec<usize>>(), vec![0, 5]);
+    assert_eq!(tree.predecessors(8).count(), 0);
+    assert_eq!(tree.successors(usize::MAX).count(), 0);
+    assert_eq!(tree.predecessors(usize::MAX).count(), 0);
+    assert_eq!(tree.get_column(7).unwrap(), bitvec![1,1,1,0,0,0,0,0]);
+    assert_eq!(tree.get_row(4).unwrap(), bitvec![1,0,0,0,0,1,0,0]);
+  }
+  #[test]
+  fn to_from_matrix_0() {
+    let tree = K2Tree::test_tree();
+    let m = tree.to_matrix();
+    assert_eq!(m[7], bitvec![1,1,1,0,0,0,0,0]);
+    assert_eq!(m[1], bitvec![0; 8]);
+    assert_eq!(K2Tree::from_matrix(m).unwrap(), tree);
+    let mut big_tree = K2Tree::new();
+    for _ in 0..3 { big_tree.grow().unwrap(); }
+    big_tree.set(40, 3, true).unwrap();
+    big_tree.set(2, 63, true).unwrap();
+    assert_eq!(K2Tree::from_matrix(big_tree.clone().into_matrix()).unwrap(), big_tree);
+  }
+  #[test]
+  fn to_pbm_0() {
+    let pbm = K2Tree::test_tree().to_pbm();
+    let mut expected = b"P4\n8 8\n".to_vec();
+    expected.extend_from_slice(&[0x05, 0x09, 0x03, 0x00, 0x84, 0x08, 0x00, 0x00]);
+    assert_eq!(pbm, expected);
+  }
+  #[test]
+  fn to_matrix_market_0() {
+    let mm = K2Tree::test_tree().to_matrix_market();
+    let mut lines = mm.lines();
+    assert_eq!(lines.next(), Some("%%MatrixMarket matrix coordinate pattern general"));
+    assert_eq!(lines.next(), Some("8 8 9"));
+    let mut entries: Vec<&str> = lines.collect();
+    entries.sort();
+    assert_eq!(entries, vec!["1 6", "1 8", "2 5", "2 8", "3 7", "3 8", "5 1", "5 6", "6 5"]);
+  }
+  #[test]
+  fn to_csr_0() {
+    let (row_offsets, column_indices) = K2Tree::test_tree().to_csr();
+    assert_eq!(row_offsets, vec![0, 2, 4, 6, 6, 8, 9, 9, 9]);
+    assert_eq!(column_indices, vec![5, 7, 4, 7, 6, 7, 0, 5, 4]);
+  }
+  #[test]
+  fn set_operations_0() {
+    let a = K2Tree::from_coordinates(8, vec![(0, 0), (1, 1), (5, 6), (7, 7)]).unwrap();
+    let b = K2Tree::from_coordinates(8, vec![(1, 1), (5, 6), (2, 3)]).unwrap();
+    let cells = |tree: K2Tree| -> Vec<[usize; 2]> {
+      let mut cells: Vec<[usize; 2]> = tree.iter_range(0..8, 0..8).collect();
+      cells.sort();
+      cells
+    };
+    assert_eq!(cells(a.union(&b).unwrap()), vec![[0, 0], [1, 1], [2, 3], [5, 6], [7, 7]]);
+    assert_eq!(cells(a.intersect(&b).unwrap()), vec![[1, 1], [5, 6]]);
+    assert_eq!(cells(a.difference(&b).unwrap()), vec![[0, 0], [7, 7]]);
+    assert_eq!(cells(a.symmetric_difference(&b).unwrap()), vec![[0, 0], [2, 3], [7, 7]]);
+    let c = K2Tree::from_coordinates(8, vec![(3, 3)]).unwrap();
+    assert_eq!(a.intersect(&c).unwrap(), K2Tree::new());
+    assert!(a.union(&K2Tree::with_k(4)).is_err());
+  }
+  #[test]
+  fn set_operations_1() {
+    use std::collections::HashSet;
+    let mut rng = rand::thread_rng();
+    for &k in [2, 3].iter() {
+      let mut a_coords: HashSet<(usize, usize)> = HashSet::new();
+      let mut b_coords: HashSet<(usize, usize)> = HashSet::new();
+      for _ in 0..200 {
+        a_coords.insert((rng.gen_range(0, 30), rng.gen_range(0, 30)));
+        b_coords.insert((rng.gen_range(0, 100), rng.gen_range(0, 100)));
+      }
+      let a = K2Tree::from_coordinates_with_k(30, k, a_coords.clone()).unwrap();
+      let b = K2Tree::from_coordinates_with_k(100, k, b_coords.clone()).unwrap();
+      let expected = |coords: HashSet<(usize, usize)>| -> K2Tree {
+        K2Tree::from_coordinates_with_k(100, k, coords).unwrap()
+      };
+      assert_eq!(a.union(&b).unwrap(), expected(a_coords.union(&b_coords).cloned().collect()));
+      assert_eq!(b.union(&a).unwrap(), expected(a_coords.union(&b_coords).cloned().collect()));
+      assert_eq!(a.intersect(&b).unwrap(), expected(a_coords.intersection(&b_coords).cloned().collect()));
+      assert_eq!(a.difference(&b).unwrap(), expected(a_coords.difference(&b_coords).cloned().collect()));
+      assert_eq!(b.difference(&a).unwrap(), expected(b_coords.difference(&a_coords).cloned().collect()));
+      assert_eq!(a.symmetric_difference(&b).unwrap(),
+        expected(a_coords.symmetric_difference(&b_coords).cloned().collect()));
+    }
+  }
+  #[test]
+  fn multiply_0() {
+    let a = K2Tree::from_coordinates(8, vec![(0, 1), (2, 1), (3, 4)]).unwrap();
+    let b = K2Tree::from_coordinates(16, vec![(1, 5), (1, 6), (4, 12), (7, 7)]).unwrap();
+    let product = a.multiply(&b).unwrap();
+    assert_eq!(product.matrix_width(), 8);
+    assert_eq!(product.matrix_height(), 16);
+    let mut cells: Vec<[usize; 2]> = product.iter_range(0..16, 0..16).collect();
+    cells.sort();
+    assert_eq!(cells, vec![[0, 5], [0, 6], [2, 5], [2, 6], [3, 12]]);
+    assert_eq!(b.multiply(&a).unwrap(), K2Tree::with_extents(2, 16, 8).unwrap());
+    assert!(a.multiply(&K2Tree::with_k(3)).is_err());
+  }
+  #[test]
+  fn multiply_1() {
+    use std::collections::HashSet;
+    let mut rng = rand::thread_rng();
+    for &k in [2, 3].iter() {
+      let mut a_coords: HashSet<(usize, usize)> = HashSet::new();
+      let mut b_coords: HashSet<(usize, usize)> = HashSet::new();
+      for _ in 0..150 {
+        a_coords.insert((rng.gen_range(0, 60), rng.gen_range(0, 60)));
+        b_coords.insert((rng.gen_range(0, 60), rng.gen_range(0, 60)));
+      }
+      let mut expected: HashSet<(usize, usize)> = HashSet::new();
+      for &(x, y) in a_coords.iter() {
+        for &(_, z) in b_coords.iter().filter(|&&(b_y, _)| b_y == y) {
+          expected.insert((x, z));
+        }
+      }
+      let a = K2Tree::from_coordinates_with_k(60, k, a_coords).unwrap();
+      let b = K2Tree::from_coordinates_with_k(60, k, b_coords).unwrap();
+      assert_eq!(a.multiply(&b).unwrap(), K2Tree::from_coordinates_with_k(60, k, expected).unwrap());
+    }
+  }
+  #[test]
+  fn transpose_0() {
+    let tree = K2Tree::test_tree();
+    let transposed = tree.transpose();
+    for x in 0..8 {
+      for y in 0..8 {
+        assert_eq!(transposed.get(x, y).unwrap(), tree.get(y, x).unwrap());
+      }
+    }
+    assert_eq!(transposed.transpose(), tree);
+    assert_eq!(K2Tree::new().transpose(), K2Tree::new());
+  }
+  #[test]
+  fn transpose_1() {
+    use std::collections::HashSet;
+    let mut rng = rand::thread_rng();
+    for &k in [2, 3, 4].iter() {
+      let mut coords: HashSet<(usize, usize)> = HashSet::new();
+      for _ in 0..300 {
+        coords.insert((rng.gen_range(0, 100), rng.gen_range(0, 100)));
+      }
+      let tree = K2Tree::from_coordinates_with_k(100, k, coords.clone()).unwrap();
+      let expected = K2Tree::from_coordinates_with_k(100, k, coords.into_iter().map(|(x, y)| (y, x))).unwrap();
+      assert_eq!(tree.transpose(), expected);
+    }
+  }
+  #[test]
+  fn write_read_binary_0() {
+    let tree = K2Tree::test_tree();
+    let mut buf: Vec<u8> = Vec::new();
+    tree.write_to(&mut buf).unwrap();
+    assert_eq!(&buf[0..4], b"RK2T");
+    assert_eq!(buf.len(), 64 + (2 * 8) + 2 + 3 + 8);
+    let read_tree = K2Tree::read_from(&buf[..]).unwrap();
+    assert_eq!(read_tree, tree);
+    assert_eq!(read_tree.stem_to_leaf, tree.stem_to_leaf);
+    assert_eq!(read_tree.get_row(4).unwrap(), tree.get_row(4).unwrap());
+    let mut empty_buf: Vec<u8> = Vec::new();
+    K2Tree::with_k(3).write_to(&mut empty_buf).unwrap();
+    assert_eq!(K2Tree::read_from(&empty_buf[..]).unwrap(), K2Tree::with_k(3));
+  }
+  #[test]
+  fn write_read_binary_1() {
+    let mut buf: Vec<u8> = Vec::new();
+    K2Tree::test_tree().write_to(&mut buf).unwrap();
+    let mut corrupted = buf.clone();
+    corrupted[60] ^= 1;
+    assert!(K2Tree::read_from(&corrupted[..]).is_err());
+    let mut bad_magic = buf.clone();
+    bad_magic[0] = b'X';
+    assert!(K2Tree::read_from(&bad_magic[..]).is_err());
+    assert!(K2Tree::read_from(&buf[..buf.len()-1]).is_err());
+    let mut rng = rand::thread_rng();
+    for &k in [2, 3, 4].iter() {
+      let coords: Vec<(usize, usize)> = (0..300)
+        .map(|_| (rng.gen_range(0, 200), rng.gen_range(0, 200)))
+        .collect();
+      let tree = K2Tree::from_coordinates_with_k(200, k, coords).unwrap();
+      let mut buf: Vec<u8> = Vec::new();
+      tree.write_to(&mut buf).unwrap();
+      assert_eq!(K2Tree::read_from(&buf[..]).unwrap(), tree);
+    }
+  }
+  #[test]
+  fn write_read_binary_2() {
+    /* Headers with a valid checksum but fields that overflow or
+    don't fit the stems are rejected rather than panicking */
+    let mut buf: Vec<u8> = Vec::new();
+    K2Tree::test_tree().write_to(&mut buf).unwrap();
+    let resealed = |offset: usize, value: u64| -> Vec<u8> {
+      let mut bytes = buf[..buf.len()-8].to_vec();
+      bytes[offset..offset+8].copy_from_slice(&value.to_le_bytes());
+      let checksum = fnv1a(&bytes);
+      bytes.extend_from_slice(&checksum.to_le_bytes());
+      bytes
+    };
+    assert!(K2Tree::read_from(&resealed(32, u64::MAX)[..]).is_err()); //max_slayers
+    assert!(K2Tree::read_from(&resealed(40, u64::MAX)[..]).is_err()); //num_slayers
+    assert!(K2Tree::read_from(&resealed(40, u64::MAX / 8 + 1)[..]).is_err());
+    assert!(K2Tree::read_from(&resealed(48, u64::MAX)[..]).is_err()); //stems_len
+    assert!(K2Tree::read_from(&resealed(56, u64::MAX)[..]).is_err()); //leaves_len
+    assert!(K2Tree::read_from(&resealed(64, 1)[..]).is_err()); //first slayer start
+    assert!(K2Tree::read_from(&resealed(72, 6)[..]).is_err()); //not a whole stem
+    assert!(K2Tree::read_from(&resealed(72, 64)[..]).is_err()); //past the stems
+    assert!(K2TreeView::new(&buf[..40]).is_err());
+    assert!(K2TreeView::new(&resealed(72, 4)[..]).is_ok());
+    assert!(K2Tree::read_from(&resealed(40, 1)[..]).is_err()); //fewer than max_slayers
+  }
+  #[test]
+  fn write_read_binary_3() {
+    /* Flipping any stem bit and resealing the checksum leaves
+    layers or leaves that don't match the 1s above them */
+    let tree = K2Tree::test_tree();
+    let mut buf: Vec<u8> = Vec::new();
+    tree.write_to(&mut buf).unwrap();
+    let stems_offset = 64 + (tree.slayer_starts.len() * 8);
+    for pos in 0..tree.stems.len() {
+      let mut bytes = buf[..buf.len()-8].to_vec();
+      bytes[stems_offset + pos / 8] ^= 1 << (pos % 8);
+      let checksum = fnv1a(&bytes);
+      bytes.extend_from_slice(&checksum.to_le_bytes());
+      assert!(K2Tree::read_from(&bytes[..]).is_err());
+      assert!(K2TreeView::new(&bytes).is_err());
+      assert!(K2TreeIndex::new(&bytes).is_err());
+    }
+  }
+  #[test]
+  fn view_with_index_0() {
+    let mut rng = rand::thread_rng();
+    let coords: Vec<(usize, usize)> = (0..200).map(|_| (rng.gen_range(0, 50), rng.gen_range(0, 70))).collect();
+    let tree = K2Tree::from_coordinates_with_extents(3, 50, 70, coords).unwrap();
+    let mut buf: Vec<u8> = Vec::new();
+    tree.write_to(&mut buf).unwrap();
+    let index = K2TreeIndex::new(&buf).unwrap();
+    for _ in 0..2 {
+      let view = K2TreeView::with_index(&buf, &index).unwrap();
+      assert_eq!(view.to_k2tree(), tree);
+    }
+    let mut other: Vec<u8> = Vec::new();
+    K2Tree::test_tree().write_to(&mut other).unwrap();
+    assert!(K2TreeView::with_index(&other, &index).is_err());
+    /* A header without any stem layers is rejected up front */
+    let mut no_layers = other[..other.len()-8].to_vec();
+    no_layers[40..48].copy_from_slice(&0u64.to_le_bytes());
+    let checksum = fnv1a(&no_layers);
+    no_layers.extend_from_slice(&checksum.to_le_bytes());
+    assert!(K2TreeIndex::new(&no_layers).is_err());
+  }
+  #[test]
+  fn view_0() {
+    let tree = K2Tree::test_tree();
+    let mut buf: Vec<u8> = Vec::new();
+    tree.write_to(&mut buf).unwrap();
+    let view = K2TreeView::new(&buf).unwrap();
+    assert_eq!(view.matrix_width(), 8);
+    for i in 0..8 {
+      assert_eq!(view.get_row(i).unwrap(), tree.get_row(i).unwrap());
+      assert_eq!(view.get_column(i).unwrap(), tree.get_column(i).unwrap());
+      for j in 0..8 {
+        assert_eq!(view.get(i, j).unwrap(), tree.get(i, j).unwrap());
+      }
+    }
+    assert!(view.get(8, 0).is_err());
+    assert_eq!(view.iter_range(2..6, 0..8).collect::<Vec<_>>(), tree.iter_range(2..6, 0..8).collect::<Vec<_>>());
+    assert_eq!(view.to_k2tree(), tree);
+    assert_eq!(tree.as_view().successors(4).collect::<Vec<_>>(), vec![1, 5]);
+    assert!(K2TreeView::new(&buf[1..]).is_err());
+  }
+  #[test]
+  fn view_1() {
+    let mut rng = rand::thread_rng();
+    for &k in [2, 3].iter() {
+      let coords: Vec<(usize, usize)> = (0..500)
+        .map(|_| (rng.gen_range(0, 300), rng.gen_range(0, 300)))
+        .collect();
+      let tree = K2Tree::from_coordinates_with_k(300, k, coords).unwrap();
+      let mut buf: Vec<u8> = Vec::new();
+      tree.write_to(&mut buf).unwrap();
+      let view = K2TreeView::new(&buf).unwrap();
+      let width = tree.matrix_width();
+      assert_eq!(view.iter_range(0..width, 0..width).collect::<Vec<_>>(),
+        tree.iter_range(0..width, 0..width).collect::<Vec<_>>());
+      for x in 0..20 {
+        assert_eq!(view.successors(x).collect::<Vec<_>>(), tree.successors(x).collect::<Vec<_>>());
+        assert_eq!(view.predecessors(x).collect::<Vec<_>>(), tree.predecessors(x).collect::<Vec<_>>());
+      }
+    }
+  }
+  #[test]
+  fn rectangular_0() {
+    let tree = K2Tree::from_coordinates_with_extents(2, 20, 3, vec![(0, 0), (19, 2), (7, 1)]).unwrap();
+    assert_eq!((tree.matrix_width(), tree.matrix_height()), (20, 3));
+    assert_eq!(tree.get(19, 2).unwrap(), true);
+    assert!(tree.get(0, 3).is_err());
+    assert!(tree.get(20, 0).is_err());
+    assert_eq!(tree.get_row(1).unwrap().len(), 20);
+    assert_eq!(tree.get_column(7).unwrap().len(), 3);
+    assert_eq!(tree.successors(19).collect::<Vec<_>>(), vec![2]);
+    assert_eq!(tree.to_csr(), (vec![0, 1, 2, 3], vec![0, 7, 19]));
+    assert!(tree.to_matrix_market().starts_with("%%MatrixMarket matrix coordinate pattern general\n3 20 3\n"));
+    let transposed = tree.transpose();
+    assert_eq!((transposed.matrix_width(), transposed.matrix_height()), (3, 20));
+    assert_eq!(transposed.get(2, 19).unwrap(), true);
+    assert_eq!(K2Tree::from_matrix(tree.to_matrix()).unwrap(), tree);
+    assert_eq!(K2Tree::from_json(&tree.to_json().unwrap()).unwrap(), tree);
+    let mut buf: Vec<u8> = Vec::new();
+    tree.write_to(&mut buf).unwrap();
+    assert_eq!(K2Tree::read_from(&buf[..]).unwrap(), tree);
+  }
+  #[test]
+  fn resize_0() {
+    let mut tree = K2Tree::with_extents(2, 0, 0).unwrap();
+    assert_eq!(tree.max_slayers, 2);
+    tree.resize(40, 3).unwrap();
+    tree.set(39, 2, true).unwrap();
+    assert_eq!(tree.max_slayers, 5);
+    assert!(tree.resize(39, 3).is_err());
+    tree.set(39, 2, false).unwrap();
+    tree.set(5, 1, true).unwrap();
+    tree.resize(6, 2).unwrap();
+    assert_eq!(tree.max_slayers, 2);
+    assert_eq!((tree.matrix_width(), tree.matrix_height()), (6, 2));
+    assert_eq!(tree.iter_range(0..6, 0..2).collect::<Vec<_>>(), vec![[5, 1]]);
+  }
+  #[test]
+  fn k2_tree1_0() {
+    /* An 8 by 8 block of 1s is a single region */
+    let cells: Vec<(usize, usize)> = (0..64).map(|i| (8 + (i % 8), i / 8)).collect();
+    let mut tree = K2Tree1::from_coordinates(16, cells.clone()).unwrap();
+    assert_eq!(tree.stems, bitvec![0,0,0,0]);
+    assert_eq!(tree.full, bitvec![0,1,0,0]);
+    assert!(tree.leaves.is_empty());
+    assert!(tree.get(9, 3).unwrap());
+    assert!(!tree.get(7, 3).unwrap());
+    let k2_tree = K2Tree::from_coordinates(16, cells).unwrap();
+    assert_eq!(tree.iter_range(0..16, 0..16).collect::<Vec<_>>(), k2_tree.iter_range(0..16, 0..16).collect::<Vec<_>>());
+    assert_eq!(tree.get_row(3).unwrap(), k2_tree.get_row(3).unwrap());
+    assert_eq!(tree.to_k2tree(), k2_tree);
+    /* Removing a cell splits the region, putting it back merges it again */
+    tree.set(9, 3, false).unwrap();
+    assert!(!tree.get(9, 3).unwrap());
+    assert_eq!(tree.iter_range(0..16, 0..16).count(), 63);
+    assert_eq!(tree.full, bitvec![0,0,0,0, 0,1,1,1, 1,1,0,1]);
+    tree.set(9, 3, true).unwrap();
+    assert_eq!(tree, K2Tree1::from_k2tree(&k2_tree).unwrap());
+    assert_eq!(tree.stems, bitvec![0,0,0,0]);
+  }
+  #[test]
+  fn k2_tree1_1() {
+    let mut rng = rand::thread_rng();
+    let mut tree = K2Tree1::with_extents(2, 20, 12).unwrap();
+    let mut set = std::collections::HashSet::new();
+    for _ in 0..600 {
+      let (x, y) = (rng.gen_range(0, 20), rng.gen_range(0, 12));
+      /* Mostly 1s so that regions form and get split */
+      let state = rng.gen_range(0, 4) != 0;
+      tree.set(x, y, state).unwrap();
+      if state { set.insert((x, y)); } else { set.remove(&(x, y)); }
+    }
+    let mut cells: Vec<(usize, usize)> = set.iter().cloned().collect();
+    cells.sort_by_key(|&(x, y)| (y, x));
+    assert_eq!(tree.iter_range(0..20, 0..12).count(), set.len());
+    for y in 0..12 {
+      let row: Vec<usize> = cells.iter().filter(|&&(_, cy)| cy == y).map(|&(x, _)| x).collect();
+      assert_eq!(tree.predecessors(y).collect::<Vec<_>>(), row);
+    }
+    let built = K2Tree1::from_coordinates_with_extents(2, 20, 12, cells.clone()).unwrap();
+    assert_eq!(tree, built);
+    assert_eq!(tree.slayer_starts, built.slayer_starts);
+    assert_eq!(tree.to_k2tree(), K2Tree::from_coordinates_with_extents(2, 20, 12, cells).unwrap());
+    assert!(tree.heapsize() > 0);
+  }
+  #[test]
+  fn k2_tree1_set_0() {
+    /* Each set leaves the bits from_coordinates would build,
+    filling the matrix and then emptying it again in another order */
+    let mut rng = rand::thread_rng();
+    for &k in [2, 3].iter() {
+      let width = k * k * k;
+      let mut tree = K2Tree1::with_extents(k, width, width).unwrap();
+      let mut cells: Vec<(usize, usize)> = (0..width*width).map(|i| (i % width, i / width)).collect();
+      let mut set: Vec<(usize, usize)> = Vec::new();
+      for round in 0..2 {
+        for i in (1..cells.len()).rev() {
+          cells.swap(i, rng.gen_range(0, i+1));
+        }
+        for &(x, y) in cells.iter() {
+          tree.set(x, y, round == 0).unwrap();
+          if round == 0 { set.push((x, y)); } else { set.retain(|&cell| cell != (x, y)); }
+          let built = K2Tree1::from_coordinates_with_extents(k, width, width, set.clone()).unwrap();
+          assert_eq!(tree, built);
+          assert_eq!(tree.slayer_starts, built.slayer_starts);
+          let mut buf: Vec<u8> = Vec::new();
+          tree.write_to(&mut buf).unwrap();
+          assert_eq!(K2Tree1::read_from(&buf[..]).unwrap(), tree);
+        }
+      }
+      assert_eq!(tree.slayer_starts, vec![0]);
+      assert_eq!(tree, K2Tree1::with_extents(k, width, width).unwrap());
+    }
+  }
+  #[test]
+  fn k2_tree1_resize_0() {
+    let mut tree = K2Tree1::with_extents(2, 8, 8).unwrap();
+    for x in 0..8 {
+      for y in 0..8 {
+        tree.set(x, y, true).unwrap();
+      }
+    }
+    assert_eq!(tree.full, bitvec![1,1,1,1]);
+    tree.grow().unwrap();
+    assert_eq!((tree.matrix_width(), tree.matrix_height()), (16, 16));
+    assert_eq!(tree.stems, bitvec![1,0,0,0, 0,0,0,0]);
+    assert_eq!(tree.full, bitvec![0,0,0,0, 1,1,1,1]);
+    assert!(tree.resize(7, 8).is_err());
+    tree.resize(8, 8).unwrap();
+    assert_eq!(tree.max_slayers, 2);
+    assert_eq!(tree.full, bitvec![1,1,1,1]);
+    tree.set(0, 0, false).unwrap();
+    assert_eq!(tree.iter_range(0..8, 0..8).count(), 63);
+    assert!(K2Tree1::new().is_empty());
+  }
+  #[test]
+  fn k2_tree1_apply_batch_0() {
+    let mut rng = rand::thread_rng();
+    let mut tree = K2Tree1::with_extents(2, 30, 20).unwrap();
+    let mut expected = tree.clone();
+    let changes: Vec<(usize, usize, bool)> = (0..800).map(|_| {
+      (rng.gen_range(0, 30), rng.gen_range(0, 20), rng.gen_range(0, 4) != 0)
+    }).collect();
+    for &(x, y, state) in &changes {
+      expected.set(x, y, state).unwrap();
+    }
+    tree.apply_batch(changes).unwrap();
+    assert_eq!(tree, expected);
+    assert_eq!(tree.full, expected.full);
+    /* The last change to a cell wins, even inside a 1-region */
+    let mut tree = K2Tree1::from_coordinates(8, (0..64).map(|i| (i % 8, i / 8))).unwrap();
+    tree.apply_batch(vec![(3, 3, false), (3, 3, true), (4, 4, true), (4, 4, false)]).unwrap();
+    assert!(tree.get(3, 3).unwrap());
+    assert!(!tree.get(4, 4).unwrap());
+    assert_eq!(tree.iter_range(0..8, 0..8).count(), 63);
+    /* Out of bounds changes nothing */
+    let before = tree.clone();
+    assert!(tree.apply_batch(vec![(0, 0, false), (8, 0, true)]).is_err());
+    assert_eq!(tree, before);
+  }
+  #[test]
+  fn k2_tree1_set_ops_0() {
+    let mut rng = rand::thread_rng();
+    /* Blocks of 1s so that both trees have regions to combine */
+    let mut blocks = |width: usize, height: usize| -> Vec<(usize, usize)> {
+      let mut cells = Vec::new();
+      for _ in 0..6 {
+        let (x0, y0, side) = (rng.gen_range(0, width-8), rng.gen_range(0, height-8), rng.gen_range(1, 9));
+        for x in x0..x0+side {
+          for y in y0..y0+side {
+            cells.push((x, y));
+          }
+        }
+      }
+      cells
+    };
+    let a = K2Tree1::from_coordinates_with_extents(2, 40, 20, blocks(40, 20)).unwrap();
+    let b = K2Tree1::from_coordinates_with_extents(2, 16, 30, blocks(16, 30)).unwrap();
+    let (a2, b2) = (a.to_k2tree(), b.to_k2tree());
+    for &(result, expected) in [
+      (&a.union(&b).unwrap(), &a2.union(&b2).unwrap()),
+      (&a.intersect(&b).unwrap(), &a2.intersect(&b2).unwrap()),
+      (&a.difference(&b).unwrap(), &a2.difference(&b2).unwrap()),
+      (&b.difference(&a).unwrap(), &b2.difference(&a2).unwrap()),
+      (&a.symmetric_difference(&b).unwrap(), &a2.symmetric_difference(&b2).unwrap()),
+    ].iter() {
+      assert_eq!(result, &K2Tree1::from_k2tree(expected).unwrap());
+    }
+    assert_eq!(a.union(&a).unwrap(), a);
+    assert!(a.difference(&a).unwrap().is_empty());
+    assert!(a.union(&K2Tree1::with_k(3)).is_err());
+  }
+  #[test]
+  fn k2_tree1_shrink_0() {
+    let mut tree = K2Tree1::from_coordinates(8, (0..64).map(|i| (i % 8, i / 8))).unwrap();
+    assert!(tree.shrink().is_err());
+    tree.grow().unwrap();
+    tree.grow().unwrap();
+    assert_eq!(tree.matrix_width(), 32);
+    tree.shrink().unwrap();
+    assert_eq!((tree.matrix_width(), tree.matrix_height()), (16, 16));
+    tree.set(8, 0, true).unwrap();
+    assert!(tree.shrink().is_err());
+    tree.set(8, 0, false).unwrap();
+    tree.shrink_if_possible();
+    assert_eq!(tree, K2Tree1::from_coordinates(8, (0..64).map(|i| (i % 8, i / 8))).unwrap());
+  }
+  #[test]
+  fn k2_tree1_iterators_0() {
+    let mut cells: Vec<(usize, usize)> = (0..64).map(|i| (8 + (i % 8), i / 8)).collect();
+    cells.extend(vec![(0, 9), (1, 9), (5, 14), (15, 15)]);
+    let tree = K2Tree1::from_coordinates(16, cells).unwrap();
+    let stems: Vec<(bool, usize, usize, usize)> = tree.stems().map(|bit| (bit.value, bit.layer, bit.stem, bit.bit)).collect();
+    assert_eq!(stems.len(), tree.stems.len());
+    assert_eq!(stems[..4], [(false, 0, 0, 0), (false, 0, 0, 1), (true, 0, 0, 2), (true, 0, 0, 3)]);
+    assert_eq!(stems[4], (true, 1, 0, 0));
+    assert_eq!(stems[11], (true, 1, 1, 3));
+    /* Every leaf bit is where its cell is */
+    let leaves: Vec<LeafBit> = tree.leaves().collect();
+    assert_eq!(leaves.len(), tree.leaves.len());
+    for leaf_bit in leaves.iter() {
+      assert_eq!(leaf_bit.value, tree.get(leaf_bit.x, leaf_bit.y).unwrap());
+    }
+    let mut set: Vec<(usize, usize)> = leaves.iter().filter(|bit| bit.value).map(|bit| (bit.x, bit.y)).collect();
+    set.sort();
+    assert_eq!(set, vec![(0, 9), (1, 9), (5, 14), (15, 15)]);
+    assert_eq!(K2Tree1::new().leaves().count(), 0);
+  }
+  #[test]
+  fn k2_tree1_serialization_0() {
+    let mut rng = rand::thread_rng();
+    let mut cells: Vec<(usize, usize)> = (0..64).map(|i| (8 + (i % 8), i / 8)).collect();
+    cells.extend((0..100).map(|_| (rng.gen_range(0, 30), rng.gen_range(0, 20))));
+    let tree = K2Tree1::from_coordinates_with_extents(2, 30, 20, cells).unwrap();
+    assert_eq!(K2Tree1::from_json(&tree.to_json().unwrap()).unwrap(), tree);
+    assert_eq!(K2Tree1::from_json(&K2Tree1::new().into_json().unwrap()).unwrap(), K2Tree1::new());
+    assert!(K2Tree1::from_json(&tree.to_json().unwrap().replace("\"k\":2", "\"k\":3")).is_err());
+    /* The binary form keeps the 1-regions, K2Tree reads them cell by cell */
+    let mut bytes: Vec<u8> = Vec::new();
+    tree.write_to(&mut bytes).unwrap();
+    assert_eq!(u16::from_le_bytes([bytes[6], bytes[7]]), BINARY_ONE_REGIONS);
+    let read = K2Tree1::read_from(&bytes[..]).unwrap();
+    assert_eq!(read, tree);
+    assert_eq!(read.full, tree.full);
+    assert_eq!(K2Tree::read_from(&bytes[..]).unwrap(), tree.to_k2tree());
+    assert_eq!(
+      K2TreeView::new(&bytes).unwrap().iter_range(0..30, 0..20).collect::<Vec<_>>(),
+      tree.iter_range(0..30, 0..20).collect::<Vec<_>>()
+    );
+    assert!(K2Tree::from_bytes(&bytes).is_err());
+    let mut k2_bytes: Vec<u8> = Vec::new();
+    tree.to_k2tree().write_to(&mut k2_bytes).unwrap();
+    assert_eq!(K2Tree1::read_from(&k2_bytes[..]).unwrap(), tree);
+    /* A child can't be both a stem and a 1-region */
+    let mut both = tree.clone();
+    let stem_pos = one_positions(&both.stems)[0];
+    both.full.set(stem_pos, true);
+    let mut both_bytes: Vec<u8> = Vec::new();
+    both.write_to(&mut both_bytes).unwrap();
+    assert!(K2Tree1::read_from(&both_bytes[..]).is_err());
+    assert!(K2Tree1::from_json(&both.to_json().unwrap()).is_err());
+    /* Matrices */
+    let m = tree.to_matrix();
+    assert_eq!((m.len(), m[0].len()), (30, 20));
+    assert_eq!(K2Tree1::from_matrix(m).unwrap(), tree);
+  }
+  #[test]
+  fn leaf_vocabulary_0() {
+    let plain = K2Tree::test_tree();
+    let mut tree = K2Tree::test_tree().with_leaf_encoding(LeafEncoding::Vocabulary);
+    assert_eq!(tree.leaf_encoding(), LeafEncoding::Vocabulary);
+    /* 0110 appears twice so comes first */
+    match &tree.leaves {
+      LeafLayer::Vocabulary(vocabulary) => {
+        assert_eq!(vocabulary.blocks, bitvec![0,1,1,0, 0,1,0,1, 1,1,0,0, 1,0,0,0]);
+        assert_eq!((0..5).map(|nth| vocabulary.codes.get(nth)).collect::<Vec<_>>(), vec![0, 1, 2, 3, 0]);
+      },
+      LeafLayer::Plain(_) | LeafLayer::Decoded(_) => panic!("leaves should be encoded"),
+    }
+    assert_eq!(tree, plain);
+    for x in 0..8 {
+      for y in 0..8 {
+        assert_eq!(tree.get(x, y).unwrap(), plain.get(x, y).unwrap());
+      }
+    }
+    let leaf_bits = |tree: &K2Tree| tree.leaves().map(|bit| (bit.value, bit.x, bit.y)).collect::<Vec<_>>();
+    assert_eq!(leaf_bits(&tree), leaf_bits(&plain));
+    assert_eq!(tree.get_row(4).unwrap(), plain.get_row(4).unwrap());
+    /* Changes keep the encoding */
+    tree.set(7, 7, true).unwrap();
+    tree.set(1, 0, false).unwrap();
+    assert_eq!(tree.leaf_encoding(), LeafEncoding::Vocabulary);
+    let mut expected = K2Tree::test_tree();
+    expected.set(7, 7, true).unwrap();
+    expected.set(1, 0, false).unwrap();
+    assert_eq!(tree, expected);
+    tree.set_leaf_encoding(LeafEncoding::Plain);
+    assert_eq!(tree.leaves, expected.leaves.bits().into_owned());
+    assert!(K2Tree::new().with_leaf_encoding(LeafEncoding::Vocabulary).is_empty());
+  }
+  #[test]
+  fn leaf_vocabulary_1() {
+    /* Values spanning several chunks */
+    let values: Vec<usize> = vec![0, 1, 2, 3, 300, 1, 0, 70000, 0, 0, 0, 0, 5];
+    let dac = Dac::new(&values);
+    assert_eq!(dac.len(), values.len());
+    assert!(dac.chunks.len() > 1);
+    assert_eq!((0..values.len()).map(|nth| dac.get(nth)).collect::<Vec<_>>(), values);
+    assert_eq!(Dac::new(&[]).len(), 0);
+  }
+  #[test]
+  fn leaf_vocabulary_2() {
+    use std::hash::{Hash, Hasher};
+    let hash = |tree: &K2Tree| {
+      let mut hasher = std::collections::hash_map::DefaultHasher::new();
+      tree.hash(&mut hasher);
+      hasher.finish()
+    };
+    let mut plain = K2Tree::test_tree();
+    let mut tree = K2Tree::test_tree().with_leaf_encoding(LeafEncoding::Vocabulary);
+    assert_eq!(hash(&tree), hash(&plain));
+    assert_eq!(tree.to_json().unwrap(), plain.to_json().unwrap());
+    /* A change decodes the leaves once and leaves them decoded */
+    tree.set(7, 7, true).unwrap();
+    plain.set(7, 7, true).unwrap();
+    assert!(matches!(tree.leaves, LeafLayer::Decoded(_)));
+    tree.set(1, 0, false).unwrap();
+    plain.set(1, 0, false).unwrap();
+    assert!(matches!(tree.leaves, LeafLayer::Decoded(_)));
+    assert_eq!(tree.leaf_encoding(), LeafEncoding::Vocabulary);
+    assert_eq!(tree, plain);
+    assert_eq!(hash(&tree), hash(&plain));
+    /* Writing encodes them, as does asking for the encoding again */
+    let mut written: Vec<u8> = Vec::new();
+    tree.write_to(&mut written).unwrap();
+    let mut encoded: Vec<u8> = Vec::new();
+    plain.write_with_leaf_encoding(&mut encoded, LeafEncoding::Vocabulary).unwrap();
+    assert_eq!(written, encoded);
+    tree.set_leaf_encoding(LeafEncoding::Vocabulary);
+    assert!(matches!(tree.leaves, LeafLayer::Vocabulary(_)));
+    assert_eq!(tree, plain);
+    tree.set_leaf_encoding(LeafEncoding::Plain);
+    assert!(matches!(tree.leaves, LeafLayer::Plain(_)));
+    assert_eq!(tree.to_json().unwrap(), plain.to_json().unwrap());
+  }
+  #[test]
+  fn write_read_binary_leaf_vocabulary_0() {
+    let mut rng = rand::thread_rng();
+    let coords: Vec<(usize, usize)> = (0..300).map(|_| (rng.gen_range(0, 64), rng.gen_range(0, 40))).collect();
+    let tree = K2Tree::from_coordinates_with_extents(2, 64, 40, coords).unwrap();
+    let mut bytes: Vec<u8> = Vec::new();
+    tree.write_with_leaf_encoding(&mut bytes, LeafEncoding::Vocabulary).unwrap();
+    assert_eq!(u16::from_le_bytes([bytes[6], bytes[7]]), BINARY_LEAF_VOCABULARY);
+    let read = K2Tree::read_from(&bytes[..]).unwrap();
+    assert_eq!(read.leaf_encoding(), LeafEncoding::Vocabulary);
+    assert_eq!(read, tree);
+    let view = K2TreeView::new(&bytes).unwrap();
+    assert_eq!(
+      view.iter_range(0..64, 0..40).collect::<Vec<_>>(),
+      tree.iter_range(0..64, 0..40).collect::<Vec<_>>()
+    );
+    /* Writing an encoded tree gives the same bytes */
+    let mut rewritten: Vec<u8> = Vec::new();
+    read.write_to(&mut rewritten).unwrap();
+    assert_eq!(rewritten, bytes);
+    /* A cut short vocabulary is caught even with a valid checksum */
+    let mut cut: Vec<u8> = bytes[..bytes.len()-9].to_vec();
+    let checksum = fnv1a(&cut);
+    cut.extend_from_slice(&checksum.to_le_bytes());
+    assert!(K2Tree::read_from(&cut[..]).is_err());
+  }
+  #[test]
+  fn apply_batch_0() {
+    let mut rng = rand::thread_rng();
+    let mut tree = K2Tree::test_tree();
+    tree.resize(30, 20).unwrap();
+    let mut expected = tree.clone();
+    let changes: Vec<(usize, usize, bool)> = (0..400).map(|_| {
+      (rng.gen_range(0, 30), rng.gen_range(0, 20), rng.gen_range(0, 3) != 0)
+    }).collect();
+    for &(x, y, state) in &changes {
+      expected.set(x, y, state).unwrap();
+    }
+    tree.apply_batch(changes).unwrap();
+    assert_eq!(tree, expected);
+    assert_eq!(tree.stem_to_leaf, expected.stem_to_leaf);
+    /* The last change to a cell wins */
+    tree.apply_batch(vec![(3, 3, true), (3, 3, false), (4, 4, false), (4, 4, true)]).unwrap();
+    assert!(!tree.get(3, 3).unwrap());
+    assert!(tree.get(4, 4).unwrap());
+    /* Out of bounds changes nothing */
+    let before = tree.clone();
+    assert!(tree.apply_batch(vec![(0, 0, true), (30, 0, true)]).is_err());
+    assert_eq!(tree, before);
+    /* Removing everything leaves an empty tree */
+    let cells: Vec<(usize, usize, bool)> = tree.iter_range(0..30, 0..20).map(|[x, y]| (x, y, false)).collect();
+    let mut tree = tree.with_leaf_encoding(LeafEncoding::Vocabulary);
+    tree.apply_batch(cells).unwrap();
+    assert!(tree.is_empty());
+    assert_eq!(tree.leaf_encoding(), LeafEncoding::Vocabulary);
+    assert_eq!(tree, K2Tree::with_extents(2, 30, 20).unwrap());
+  }
+  #[test]
+  fn count_ones_0() {
+    let tree = K2Tree::test_tree();
+    assert_eq!(tree.count_ones(), 9);
+    assert_eq!(tree.count_in_row(0).unwrap(), 2);
+    assert_eq!(tree.count_in_column(4).unwrap(), tree.successors(4).count());
+    assert_eq!(tree.count_in_range(4..8, 0..3), 6);
+    assert_eq!(tree.count_in_range(0..100, 0..100), 9);
+    assert_eq!(tree.count_in_range(3..3, 0..8), 0);
+    assert!(tree.count_in_row(8).is_err());
+    assert_eq!(K2Tree::new().count_ones(), 0);
+  }
+  #[test]
+  fn count_ones_1() {
+    let mut rng = rand::thread_rng();
+    let mut tree = K2Tree::with_extents(3, 50, 30).unwrap();
+    let mut set = std::collections::HashSet::new();
+    for _ in 0..500 {
+      let (x, y, state) = (rng.gen_range(0, 50), rng.gen_range(0, 30), rng.gen_range(0, 4) != 0);
+      tree.set(x, y, state).unwrap();
+      if state { set.insert((x, y)); } else { set.remove(&(x, y)); }
+      assert_eq!(tree.count_ones(), set.len());
+    }
+    let encoded = tree.clone().with_leaf_encoding(LeafEncoding::Vocabulary);
+    for _ in 0..50 {
+      let (x0, y0) = (rng.gen_range(0, 50), rng.gen_range(0, 30));
+      let (x1, y1) = (rng.gen_range(x0, 51), rng.gen_range(y0, 31));
+      let expected = set.iter().filter(|&&(x, y)| x >= x0 && x < x1 && y >= y0 && y < y1).count();
+      assert_eq!(tree.count_in_range(x0..x1, y0..y1), expected);
+      assert_eq!(encoded.count_in_range(x0..x1, y0..y1), expected);
+    }
+    for y in 0..30 {
+      assert_eq!(tree.count_in_row(y).unwrap(), tree.predecessors(y).count());
+    }
+    for x in 0..50 {
+      assert_eq!(encoded.count_in_column(x).unwrap(), tree.successors(x).count());
+    }
+  }
+  #[test]
+  fn stats_0() {
+    let tree = K2Tree::test_tree();
+    let stats = tree.stats();
+    assert_eq!(stats.stem_layers, 2);
+    assert_eq!(stats.bits_per_layer, vec![4, 12]);
+    assert_eq!(stats.ones_per_layer, vec![3, 5]);
+    assert_eq!(stats.leaves, 5);
+    assert_eq!(stats.total_bits, 16 + 20);
+    assert_eq!(stats.cells_set, 9);
+    assert_eq!(stats.bits_per_cell, 4.0);
+    assert_eq!(stats.dense_ratio, 64.0 / 36.0);
+    assert_eq!(stats.coordinate_ratio, (9 * COORDINATE_BITS) as f64 / 36.0);
+    let empty = K2Tree::new().stats();
+    assert_eq!((empty.stem_layers, empty.cells_set, empty.bits_per_cell), (0, 0, 0.0));
+    /* Repeated leaves take fewer bits with a vocabulary */
+    let coords: Vec<(usize, usize)> = (0..64).flat_map(|x| vec![(x, 0), (x, 63)]).collect();
+    let tree = K2Tree::from_coordinates(64, coords).unwrap();
+    let encoded = tree.clone().with_leaf_encoding(LeafEncoding::Vocabulary);
+    assert!(encoded.stats().total_bits < tree.stats().total_bits);
+    assert_eq!(encoded.stats().cells_set, 128);
+  }
+  #[test]
+  fn cursor_0() {
+    fn collect(cursor: &K2TreeCursor, cells: &mut Vec<[usize; 2]>) {
+      for i in 0..cursor.num_children() {
+        if !cursor.bit(i) { continue }
+        if cursor.is_leaf() {
+          let range = cursor.child_range(i).unwrap();
+          cells.push([range[0].start, range[1].start]);
+        }
+        else if let Some(child) = cursor.child(i) {
+          assert_eq!(Some(child.range()), cursor.child_range(i));
+          assert_eq!(child.layer(), cursor.layer()+1);
+          collect(&child, cells);
+        }
+      }
+    }
+    let tree = K2Tree::test_tree();
+    let root = tree.cursor();
+    assert!(root.is_root() && !root.is_leaf() && root.parent().is_none());
+    assert_eq!(root.range(), [0..8, 0..8]);
+    let mut cells = Vec::new();
+    collect(&root, &mut cells);
+    let mut expected: Vec<[usize; 2]> = tree.iter_range(0..8, 0..8).collect();
+    cells.sort();
+    expected.sort();
+    assert_eq!(cells, expected);
+    /* Walking down and back up again */
+    let mut cursor = root.clone();
+    while !cursor.is_leaf() {
+      let i = (0..cursor.num_children()).find(|&i| cursor.bit(i)).unwrap();
+      cursor = cursor.child(i).unwrap();
+    }
+    assert_eq!(cursor.layer(), 2);
+    assert!(cursor.child(0).is_none());
+    while let Some(parent) = cursor.parent() {
+      cursor = parent;
+    }
+    assert!(cursor.is_root());
+    assert!(root.child(8).is_none());
+    assert!(root.child_range(4).is_none());
+    assert!(!root.bit(8));
+    /* 1-regions are set but have nothing below them */
+    let cells: Vec<(usize, usize)> = (0..64).map(|i| (8 + (i % 8), i / 8)).collect();
+    let tree = K2Tree1::from_coordinates(16, cells).unwrap();
+    let root = tree.cursor();
+    assert!(root.bit(1) && root.is_full(1) && root.child(1).is_none());
+    assert!(!root.bit(0) && !root.is_full(0));
+  }
+  #[test]
+  fn par_cells_0() {
+    let mut rng = rand::thread_rng();
+    let coords: Vec<(usize, usize)> = (0..2000).map(|_| (rng.gen_range(0, 300), rng.gen_range(0, 200))).collect();
+    let tree = K2Tree::from_coordinates_with_extents(3, 300, 200, coords).unwrap();
+    let expected: Vec<[usize; 2]> = tree.iter_range(0..300, 0..200).collect();
+    /* Split by top-level subtree first, then below */
+    let mut cells = tree.par_cells();
+    let later = cells.split().unwrap();
+    assert_eq!(cells.into_iter().chain(later).collect::<Vec<_>>(), expected);
+    let parts = tree.par_cells().into_parts(30);
+    assert_eq!(parts.len(), 30);
+    assert_eq!(parts.into_iter().flatten().collect::<Vec<_>>(), expected);
+    assert_eq!(tree.par_cells().map_collect(|cell| cell), expected);
+    assert_eq!(tree.par_cells().count(), expected.len());
+    let sum = std::sync::atomic::AtomicUsize::new(0);
+    tree.par_cells().for_each(|[x, y]| { sum.fetch_add(x + y, std::sync::atomic::Ordering::Relaxed); });
+    assert_eq!(sum.into_inner(), expected.iter().fold(0, |sum, [x, y]| sum + x + y));
+    /* Nothing to split in an empty tree */
+    assert!(K2Tree::new().par_cells().split().is_none());
+    assert_eq!(K2Tree::new().par_cells().count(), 0);
+    /* 1-regions are split too */
+    let cells: Vec<(usize, usize)> = (0..64).map(|i| (8 + (i % 8), i / 8)).collect();
+    let tree = K2Tree1::from_coordinates(16, cells).unwrap();
+    let parts = tree.par_cells().into_parts(4);
+    assert_eq!(parts.len(), 4);
+    assert_eq!(parts.into_iter().flatten().collect::<Vec<_>>(), tree.iter_range(0..16, 0..16).collect::<Vec<_>>());
+  }
+  #[test]
+  fn bit_stores_0() {
+    let mut rng = rand::thread_rng();
+    let coords: Vec<(usize, usize)> = (0..3000).map(|_| (rng.gen_range(0, 500), rng.gen_range(0, 400))).collect();
+    let tree = K2Tree::from_coordinates_with_extents(2, 500, 400, coords).unwrap();
+    let cells: Vec<[usize; 2]> = tree.iter_range(0..500, 0..400).collect();
+    let mut bytes = Vec::new();
+    tree.write_to(&mut bytes).unwrap();
+    /* Every store answers the same */
+    let mut static_tree: K2Tree<StaticBits> = tree.clone().into_store();
+    let mut byte_tree = K2Tree::from_bytes(&bytes).unwrap();
+    assert_eq!(static_tree.iter_range(0..500, 0..400).collect::<Vec<_>>(), cells);
+    assert_eq!(byte_tree.iter_range(0..500, 0..400).collect::<Vec<_>>(), cells);
+    assert_eq!(static_tree.count_ones(), cells.len());
+    assert_eq!(byte_tree.count_in_range(100..300, 50..250), tree.count_in_range(100..300, 50..250));
+    let [x, y] = cells[cells.len() / 2];
+    assert!(static_tree.get(x, y).unwrap() && byte_tree.get(x, y).unwrap());
+    /* Static stores refuse changes and are left as they were */
+    match static_tree.set(x, y, false) {
+      Err(Error::ReadOnlyStore) => {},
+      other => panic!("expected ReadOnlyStore, got {:?}", other),
+    }
+    assert_eq!(static_tree.clone().into_store::<GrowableBits>(), tree);
+    /* and keep their leaf encoding */
+    let mut encoded_tree = tree.clone();
+    encoded_tree.set_leaf_encoding(LeafEncoding::Vocabulary);
+    let mut encoded_tree: K2Tree<StaticBits> = encoded_tree.into_store();
+    assert!(matches!(encoded_tree.leaves, LeafLayer::Vocabulary(_)));
+    assert!(matches!(encoded_tree.set(x, y, false), Err(Error::ReadOnlyStore)));
+    assert!(matches!(encoded_tree.leaves, LeafLayer::Vocabulary(_)));
+    assert_eq!(encoded_tree.leaf_encoding(), LeafEncoding::Vocabulary);
+    assert!(encoded_tree.get(x, y).unwrap());
+    /* but can still be rebuilt whole */
+    static_tree.apply_batch(vec![(x, y, false)]).unwrap();
+    assert!(!static_tree.get(x, y).unwrap());
+    let static_cells: RangeIter<StaticBits> = static_tree.iter_range(0..500, 0..400);
+    assert_eq!(static_cells.count(), cells.len()-1);
+    /* Byte stores copy the bits on the first change */
+    byte_tree.set(x, y, false).unwrap();
+    assert!(!byte_tree.get(x, y).unwrap());
+    assert_eq!(byte_tree.count_ones(), cells.len()-1);
+    assert!(K2Tree::from_bytes(&bytes[..bytes.len()-1]).is_err());
+    /* Rank and select agree */
+    let bits: BitVec = (0..5000).map(|_| rng.gen_range(0, 3) == 0).collect();
+    let growable = GrowableBits::from_bitvec(bits.clone());
+    let fixed = StaticBits::from_bitvec(bits.clone());
+    let borrowed = ByteBits::new(bits.as_slice(), bits.len()).unwrap();
+    for bit_pos in (0..=bits.len()).step_by(37) {
+      assert_eq!(fixed.rank(bit_pos), growable.rank(bit_pos));
+      assert_eq!(borrowed.rank(bit_pos), growable.rank(bit_pos));
+    }
+    for nth in 0..=growable.rank(bits.len()) {
+      assert_eq!(fixed.select(nth), growable.select(nth));
+      assert_eq!(borrowed.select(nth), growable.select(nth));
+    }
+    assert!(ByteBits::new(&[0u8; 2], 17).is_err());
+  }
 }
\ No newline at end of file
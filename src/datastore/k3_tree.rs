@@ -0,0 +1,800 @@
+use {
+  bitvec::{prelude::bitvec, slice::BitSlice, vec::BitVec},
+  serde::{Serialize, Deserialize, Serializer, Deserializer, de},
+  crate::{
+    errors::K2TreeError as Error,
+    datastore::k2_tree::{
+      K2Tree, BitStore, GrowableBits,
+      invalid_binary, bytes_for_bits, bits_to_bytes, fnv1a, log_k, grown_extent, stems_cover,
+    },
+  }
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/* A k3-tree, the K2Tree of a cube rather than a matrix. Each stem splits
+its cube into k*k*k sub-cubes and has a bit per sub-cube saying whether it
+holds any 1s, so the stems of a layer are k*k*k bits each and laid out as a
+K2Tree's are: level order, children found by rank. A Graph's triples fit in
+one cube with x the subject, y the predicate and z the object, so a predicate
+with few triples costs only the bits on their paths rather than a tree of
+its own */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct K3Tree {
+  width: usize, //x
+  height: usize, //y
+  depth: usize, //z
+  k: usize,
+  max_slayers: usize,
+  slayer_starts: Vec<usize>, //every stem layer, including empty ones
+  stems: GrowableBits,
+  leaves: GrowableBits,
+}
+
+/* Public */
+impl K3Tree {
+  /* Creation */
+  pub fn new() -> Self {
+    K3Tree::with_k(2)
+  }
+  pub fn with_k(k: usize) -> Self {
+    assert!(k >= 2, "K3Tree k must be at least 2, got {}", k);
+    let side = k.pow(3);
+    let max_slayers = log_k(side, k) - 1;
+    let stem_len = k.pow(3);
+    let mut slayer_starts = vec![stem_len; max_slayers];
+    slayer_starts[0] = 0;
+    K3Tree {
+      width: side,
+      height: side,
+      depth: side,
+      k: k,
+      max_slayers: max_slayers,
+      slayer_starts: slayer_starts,
+      stems: bitvec![0; stem_len].into(),
+      leaves: BitVec::new().into(),
+    }
+  }
+  pub fn with_extents(k: usize, width: usize, height: usize, depth: usize) -> Self {
+    let mut tree = K3Tree::with_k(k);
+    while tree.tree_width() < std::cmp::max(width, std::cmp::max(height, depth)) {
+      tree.grow();
+    }
+    tree.width = width;
+    tree.height = height;
+    tree.depth = depth;
+    tree
+  }
+  pub fn from_cells<I: IntoIterator<Item=[usize; 3]>>(k: usize, width: usize, height: usize, depth: usize, cells: I) -> Result<Self> {
+    /* Builds the same tree as calling set(x, y, z, true)
+    for each [x, y, z], a layer at a time */
+    let mut tree = K3Tree::with_extents(k, width, height, depth);
+    let mut all_cells: Vec<[usize; 3]> = Vec::new();
+    for cell in cells {
+      tree.check_bounds(cell)?;
+      all_cells.push(cell);
+    }
+    all_cells.sort();
+    all_cells.dedup();
+    let stem_len = tree.stem_len();
+    let mut nodes = vec![(tree.root_cube(), all_cells)];
+    let mut layers: Vec<BitVec> = Vec::new();
+    for layer in 0..=tree.max_slayers {
+      let mut bits = BitVec::new();
+      let mut next_nodes = Vec::new();
+      for (cube, cells) in nodes.into_iter() {
+        let subcubes = to_subcubes(cube, k);
+        let mut children: Vec<Vec<[usize; 3]>> = vec![Vec::new(); stem_len];
+        for cell in cells.into_iter() {
+          children[child_pos(cube, k, cell)].push(cell);
+        }
+        for (child_pos, child_cells) in children.into_iter().enumerate() {
+          bits.push(!child_cells.is_empty());
+          if layer < tree.max_slayers && !child_cells.is_empty() {
+            next_nodes.push((subcubes[child_pos], child_cells));
+          }
+        }
+      }
+      layers.push(bits);
+      nodes = next_nodes;
+    }
+    let leaves = layers.pop().unwrap();
+    let mut stems = BitVec::new();
+    for (layer, bits) in layers.into_iter().enumerate() {
+      tree.slayer_starts[layer] = stems.len();
+      stems.extend(bits.iter());
+    }
+    tree.stems = stems.into();
+    tree.leaves = leaves.into();
+    Ok(tree)
+  }
+  /* Information */
+  pub fn width(&self) -> usize {
+    self.width
+  }
+  pub fn height(&self) -> usize {
+    self.height
+  }
+  pub fn depth(&self) -> usize {
+    self.depth
+  }
+  pub fn k(&self) -> usize {
+    self.k
+  }
+  pub fn is_empty(&self) -> bool {
+    self.leaves.is_empty()
+  }
+  pub fn count_ones(&self) -> usize {
+    self.leaves.rank(self.leaves.len())
+  }
+  pub fn storage_bits(&self) -> usize {
+    self.stems.len() + self.leaves.len()
+  }
+  pub fn heapsize(&self) -> usize {
+    std::mem::size_of::<usize>() * self.slayer_starts.len()
+    + self.stems.heapsize()
+    + self.leaves.heapsize()
+  }
+  /* Operation */
+  pub fn get(&self, x: usize, y: usize, z: usize) -> Result<bool> {
+    let cell = [x, y, z];
+    self.check_bounds(cell)?;
+    let mut node_start = 0;
+    let mut cube = self.root_cube();
+    for layer in 0..=self.max_slayers {
+      let child_pos = child_pos(cube, self.k, cell);
+      let bit_pos = node_start + child_pos;
+      if !self.layer_bits(layer).get(bit_pos) { return Ok(false) }
+      if layer < self.max_slayers {
+        node_start = self.child_start(layer, bit_pos);
+        cube = to_subcubes(cube, self.k)[child_pos];
+      }
+    }
+    Ok(true)
+  }
+  pub fn set(&mut self, x: usize, y: usize, z: usize, state: bool) -> Result<()> {
+    let cell = [x, y, z];
+    self.check_bounds(cell)?;
+    if state { self.insert(cell) }
+    else { self.remove(cell) }
+  }
+  /* Iteration */
+  pub fn iter(&self) -> Cells {
+    self.iter_range(0..self.width, 0..self.height, 0..self.depth)
+  }
+  pub fn iter_range(&self,
+    x_range: std::ops::Range<usize>,
+    y_range: std::ops::Range<usize>,
+    z_range: std::ops::Range<usize>) -> Cells {
+    /* Every [x, y, z] set within the ranges, in order of z, then y, then x
+    within each sub-cube */
+    let ends = [
+      std::cmp::min(x_range.end, self.width),
+      std::cmp::min(y_range.end, self.height),
+      std::cmp::min(z_range.end, self.depth),
+    ];
+    let starts = [x_range.start, y_range.start, z_range.start];
+    let mut stack = Vec::new();
+    if (0..3).all(|axis| starts[axis] < ends[axis]) {
+      stack.push(CellNode::Stem(0, 0, self.root_cube()));
+    }
+    Cells {
+      tree: self,
+      query: [
+        [starts[0], ends[0].saturating_sub(1)],
+        [starts[1], ends[1].saturating_sub(1)],
+        [starts[2], ends[2].saturating_sub(1)],
+      ],
+      stack: stack,
+    }
+  }
+  pub fn plane(&self, y: usize) -> Result<K2Tree> {
+    /* The K2Tree of the cells with the given y, x as its columns and
+    z as its rows. In a Graph, the slice of predicate y */
+    if y >= self.height {
+      return Err(Error::CellOutOfBounds([0, y, 0], self.max_coords()))
+    }
+    K2Tree::from_coordinates_with_extents(
+      self.k,
+      self.width,
+      self.depth,
+      self.iter_range(0..self.width, y..y+1, 0..self.depth).map(|[x, _, z]| (x, z))
+    )
+  }
+  /* Resizing */
+  pub fn grow(&mut self) {
+    /* Every extent grows by a factor of k (or to the new tree
+    width if it was 0), the old root
+    becomes the new root's first child */
+    let stem_len = self.stem_len();
+    let mut stems = bitvec![0; stem_len];
+    let mut slayer_starts = vec![0];
+    if self.is_empty() {
+      slayer_starts.extend(vec![stem_len; self.max_slayers]);
+    }
+    else {
+      stems.set(0, true);
+      stems.extend(self.stems.as_bitslice().iter());
+      slayer_starts.extend(self.slayer_starts.iter().map(|&slayer_start| slayer_start + stem_len));
+    }
+    self.max_slayers += 1;
+    self.width = grown_extent(self.width, self.k, self.tree_width());
+    self.height = grown_extent(self.height, self.k, self.tree_width());
+    self.depth = grown_extent(self.depth, self.k, self.tree_width());
+    self.slayer_starts = slayer_starts;
+    self.stems = stems.into();
+  }
+  pub fn resize(&mut self, width: usize, height: usize, depth: usize) -> Result<()> {
+    /* Grows the stems to cover the new extents if needed, but
+    never removes layers. Any cells outside the new extents is an error */
+    if self.iter_range(width..self.width, 0..self.height, 0..self.depth).next().is_some()
+    || self.iter_range(0..self.width, height..self.height, 0..self.depth).next().is_some()
+    || self.iter_range(0..self.width, 0..self.height, depth..self.depth).next().is_some() {
+      return Err(Error::CouldNotShrink("there are cells set outside of the new extents".into()))
+    }
+    while self.tree_width() < std::cmp::max(width, std::cmp::max(height, depth)) {
+      self.grow();
+    }
+    self.width = width;
+    self.height = height;
+    self.depth = depth;
+    Ok(())
+  }
+  /* Serialization / Deserialization */
+  pub fn write_to(&self, writer: &mut impl std::io::Write) -> Result<()> {
+    /* See BINARY_MAGIC for the layout */
+    let stems = bits_to_bytes(self.stems.as_bitslice());
+    let leaves = bits_to_bytes(self.leaves.as_bitslice());
+    let mut buf: Vec<u8> = Vec::with_capacity(
+      BINARY_HEADER_LEN + (self.slayer_starts.len() * 8) + stems.len() + leaves.len() + 8
+    );
+    buf.extend_from_slice(&BINARY_MAGIC);
+    buf.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes());
+    for &field in [
+      self.k,
+      self.width,
+      self.height,
+      self.depth,
+      self.max_slayers,
+      self.stems.len(),
+      self.leaves.len()
+    ].iter() {
+      buf.extend_from_slice(&(field as u64).to_le_bytes());
+    }
+    for &slayer_start in self.slayer_starts.iter() {
+      buf.extend_from_slice(&(slayer_start as u64).to_le_bytes());
+    }
+    buf.extend_from_slice(&stems);
+    buf.extend_from_slice(&leaves);
+    let checksum = fnv1a(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    writer.write_all(&buf)?;
+    Ok(())
+  }
+  pub fn read_from(mut reader: impl std::io::Read) -> Result<Self> {
+    let mut bytes: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    if bytes.len() < BINARY_HEADER_LEN + 8 {
+      return Err(invalid_binary("too short to hold a header"))
+    }
+    if bytes[0..4] != BINARY_MAGIC {
+      return Err(invalid_binary("missing magic number"))
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != BINARY_VERSION {
+      return Err(Error::InvalidBinary(format!("unsupported version {}", version)))
+    }
+    let end_offset = bytes.len() - 8;
+    let mut checksum = [0u8; 8];
+    checksum.copy_from_slice(&bytes[end_offset..]);
+    if u64::from_le_bytes(checksum) != fnv1a(&bytes[..end_offset]) {
+      return Err(invalid_binary("checksum mismatch"))
+    }
+    let read_u64 = |offset: usize| -> usize {
+      let mut field = [0u8; 8];
+      field.copy_from_slice(&bytes[offset..offset+8]);
+      u64::from_le_bytes(field) as usize
+    };
+    let k = read_u64(8);
+    let width = read_u64(16);
+    let height = read_u64(24);
+    let depth = read_u64(32);
+    let max_slayers = read_u64(40);
+    let stems_len = read_u64(48);
+    let leaves_len = read_u64(56);
+    if k < 2 {
+      return Err(Error::InvalidBinary(format!("k must be at least 2, got {}", k)))
+    }
+    if max_slayers == 0 || !stems_cover(k, max_slayers, width, std::cmp::max(height, depth)) {
+      return Err(invalid_binary("the stems do not cover the cube"))
+    }
+    /* Every field is untrusted, so an offset that overflows is as
+    invalid as one past the end of the input */
+    let section_lengths = || invalid_binary("section lengths do not match the size of the input");
+    let stems_offset = max_slayers.checked_mul(8)
+      .and_then(|len| len.checked_add(BINARY_HEADER_LEN))
+      .ok_or_else(section_lengths)?;
+    let leaves_offset = stems_offset.checked_add(bytes_for_bits(stems_len))
+      .ok_or_else(section_lengths)?;
+    if leaves_offset > end_offset
+    || leaves_offset.checked_add(bytes_for_bits(leaves_len)) != Some(end_offset) {
+      return Err(section_lengths())
+    }
+    let slayer_starts: Vec<usize> = (0..max_slayers).map(|i| read_u64(BINARY_HEADER_LEN + (i * 8))).collect();
+    let stems = BitVec::from(&BitSlice::from_slice(&bytes[stems_offset..leaves_offset])[..stems_len]);
+    let leaves = BitVec::from(&BitSlice::from_slice(&bytes[leaves_offset..end_offset])[..leaves_len]);
+    check_layers(k, max_slayers, &slayer_starts, &stems, leaves_len).map_err(invalid_binary)?;
+    Ok(K3Tree {
+      width: width,
+      height: height,
+      depth: depth,
+      k: k,
+      max_slayers: max_slayers,
+      slayer_starts: slayer_starts,
+      stems: stems.into(),
+      leaves: leaves.into(),
+    })
+  }
+  pub fn to_json(&self) -> Result<String> {
+    Ok(serde_json::to_string(self)?)
+  }
+  pub fn into_json(self) -> Result<String> {
+    Ok(serde_json::to_string(&self)?)
+  }
+  pub fn from_json(json: &str) -> Result<Self> {
+    Ok(serde_json::from_str::<Self>(json)?)
+  }
+}
+
+/* Iterators */
+enum CellNode {
+  Stem(usize, usize, Cube), //layer, node_start, node_cube
+  Cell([usize; 3]),
+}
+pub struct Cells<'a> {
+  tree: &'a K3Tree,
+  query: Cube,
+  stack: Vec<CellNode>,
+}
+impl<'a> Iterator for Cells<'a> {
+  type Item = [usize; 3];
+  fn next(&mut self) -> Option<Self::Item> {
+    while let Some(node) = self.stack.pop() {
+      match node {
+        CellNode::Stem(layer, node_start, cube) => {
+          self.push_children(layer, node_start, cube);
+        },
+        CellNode::Cell(cell) => return Some(cell),
+      }
+    }
+    None
+  }
+}
+impl<'a> Cells<'a> {
+  pub fn split(&mut self) -> Option<Self> {
+    /* Hands the later half of the subtrees still to visit to a new
+    iterator, as K2Tree's cells are split */
+    while self.stack.len() == 1 {
+      match self.stack.pop() {
+        Some(CellNode::Stem(layer, node_start, cube)) => {
+          self.push_children(layer, node_start, cube);
+        },
+        Some(cell) => {
+          self.stack.push(cell);
+          return None
+        },
+        None => return None,
+      }
+    }
+    if self.stack.len() < 2 { return None }
+    /* The stack is popped from the back, so later subtrees are at the front */
+    let later: Vec<CellNode> = self.stack.drain(..self.stack.len()/2).collect();
+    Some(Cells {
+      tree: self.tree,
+      query: self.query,
+      stack: later,
+    })
+  }
+  fn push_children(&mut self, layer: usize, node_start: usize, cube: Cube) {
+    let tree = self.tree;
+    let bits = tree.layer_bits(layer);
+    let subcubes = to_subcubes(cube, tree.k);
+    /* Push children backwards so they're visited in order */
+    for child_pos in (0..tree.stem_len()).rev() {
+      let bit_pos = node_start + child_pos;
+      if !bits.get(bit_pos)
+      || !cubes_overlap(&subcubes[child_pos], &self.query) {
+        continue
+      }
+      if layer == tree.max_slayers {
+        let subcube = subcubes[child_pos];
+        self.stack.push(CellNode::Cell([subcube[0][0], subcube[1][0], subcube[2][0]]));
+      }
+      else {
+        let child_start = tree.child_start(layer, bit_pos);
+        self.stack.push(CellNode::Stem(layer+1, child_start, subcubes[child_pos]));
+      }
+    }
+  }
+}
+
+/* Binary layout written by write_to, all integers little-endian:
+   0..4      magic b"RK3T"
+   4..6      version (u16), currently 1
+   6..8      flags (u16), always 0
+   8..16     k (u64)
+   16..24    width (u64)
+   24..32    height (u64)
+   32..40    depth (u64)
+   40..48    max_slayers, L (u64)
+   48..56    number of bits in stems (u64)
+   56..64    number of bits in leaves (u64)
+   64..      L stem layer starts (u64 each)
+   then      stems, leaves, each packed as in a K2Tree's binary form
+   last 8    FNV-1a 64 hash of every byte before it (u64) */
+const BINARY_MAGIC: [u8; 4] = *b"RK3T";
+const BINARY_VERSION: u16 = 1;
+const BINARY_HEADER_LEN: usize = 64;
+
+/* The JSON form of a K3Tree, each bit vector packed into
+bytes as in write_to with its length alongside */
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct K3TreeJson {
+  width: usize,
+  height: usize,
+  depth: usize,
+  k: usize,
+  max_stem_layers: usize,
+  stem_layer_starts: Vec<usize>,
+  num_stems: usize,
+  stems: Vec<u8>,
+  num_leaves: usize,
+  leaves: Vec<u8>,
+}
+impl Serialize for K3Tree {
+  fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    K3TreeJson {
+      width: self.width,
+      height: self.height,
+      depth: self.depth,
+      k: self.k,
+      max_stem_layers: self.max_slayers,
+      stem_layer_starts: self.slayer_starts.clone(),
+      num_stems: self.stems.len(),
+      stems: bits_to_bytes(self.stems.as_bitslice()),
+      num_leaves: self.leaves.len(),
+      leaves: bits_to_bytes(self.leaves.as_bitslice()),
+    }.serialize(serializer)
+  }
+}
+impl<'de> Deserialize<'de> for K3Tree {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    /* Checked as read_from checks the binary form */
+    let json = K3TreeJson::deserialize(deserializer)?;
+    let invalid = |reason: &str| de::Error::custom(format!("Invalid K3Tree: {}", reason));
+    if json.k < 2
+    || json.max_stem_layers == 0
+    || !stems_cover(json.k, json.max_stem_layers, json.width, std::cmp::max(json.height, json.depth)) {
+      return Err(invalid("the stems do not cover the cube"))
+    }
+    if bytes_for_bits(json.num_stems) != json.stems.len()
+    || bytes_for_bits(json.num_leaves) != json.leaves.len() {
+      return Err(invalid("the number of bits does not match the bytes holding them"))
+    }
+    let mut stems = BitVec::from(json.stems);
+    let mut leaves = BitVec::from(json.leaves);
+    stems.truncate(json.num_stems);
+    leaves.truncate(json.num_leaves);
+    check_layers(json.k, json.max_stem_layers, &json.stem_layer_starts, &stems, leaves.len()).map_err(invalid)?;
+    Ok(K3Tree {
+      width: json.width,
+      height: json.height,
+      depth: json.depth,
+      k: json.k,
+      max_slayers: json.max_stem_layers,
+      slayer_starts: json.stem_layer_starts,
+      stems: stems.into(),
+      leaves: leaves.into(),
+    })
+  }
+}
+
+/* Private */
+type Cube = [[usize; 2]; 3];
+impl K3Tree {
+  fn stem_len(&self) -> usize {
+    self.k.pow(3)
+  }
+  fn tree_width(&self) -> usize {
+    self.k.pow(self.max_slayers as u32 + 1)
+  }
+  fn root_cube(&self) -> Cube {
+    let tree_width = self.tree_width();
+    [[0, tree_width-1], [0, tree_width-1], [0, tree_width-1]]
+  }
+  fn max_coords(&self) -> [usize; 3] {
+    [self.width.saturating_sub(1), self.height.saturating_sub(1), self.depth.saturating_sub(1)]
+  }
+  fn check_bounds(&self, cell: [usize; 3]) -> Result<()> {
+    if cell[0] >= self.width
+    || cell[1] >= self.height
+    || cell[2] >= self.depth {
+      return Err(Error::CellOutOfBounds(cell, self.max_coords()))
+    }
+    Ok(())
+  }
+  fn layer_bits(&self, layer: usize) -> &GrowableBits {
+    if layer == self.max_slayers { &self.leaves } else { &self.stems }
+  }
+  fn layer_bits_mut(&mut self, layer: usize) -> &mut GrowableBits {
+    if layer == self.max_slayers { &mut self.leaves } else { &mut self.stems }
+  }
+  fn layer_start(&self, layer: usize) -> usize {
+    /* Leaves are in their own store, so start at 0 */
+    if layer == self.max_slayers { 0 } else { self.slayer_starts[layer] }
+  }
+  fn shift_layers(&mut self, first_layer: usize, by: isize) {
+    for slayer_start in self.slayer_starts.iter_mut().skip(first_layer) {
+      *slayer_start = (*slayer_start as isize + by) as usize;
+    }
+  }
+  fn child_start(&self, layer: usize, bit_pos: usize) -> usize {
+    /* Every 1 in a layer before bit_pos has a node in the next */
+    let ones_before = self.stems.rank(bit_pos) - self.stems.rank(self.layer_start(layer));
+    self.layer_start(layer+1) + (ones_before * self.stem_len())
+  }
+  fn insert(&mut self, cell: [usize; 3]) -> Result<()> {
+    let stem_len = self.stem_len();
+    let mut node_start = 0;
+    let mut cube = self.root_cube();
+    for layer in 0..self.max_slayers {
+      let child_pos = child_pos(cube, self.k, cell);
+      let bit_pos = node_start + child_pos;
+      let child_start = self.child_start(layer, bit_pos);
+      if !self.stems.get(bit_pos) {
+        /* Create the child, all 0s */
+        if let Err(()) = self.stems.set(bit_pos, true) {
+          return Err(Error::TraverseError(cell[0], cell[1]))
+        }
+        if let Err(()) = self.layer_bits_mut(layer+1).insert_block(child_start, stem_len) {
+          return Err(if layer+1 == self.max_slayers {
+            Error::LeafInsertionError(child_start, stem_len)
+          }
+          else {
+            Error::StemInsertionError(child_start, stem_len)
+          })
+        }
+        if layer+1 < self.max_slayers {
+          self.shift_layers(layer+2, stem_len as isize);
+        }
+      }
+      node_start = child_start;
+      cube = to_subcubes(cube, self.k)[child_pos];
+    }
+    let bit_pos = node_start + child_pos(cube, self.k, cell);
+    if let Err(()) = self.leaves.set(bit_pos, true) {
+      return Err(Error::TraverseError(cell[0], cell[1]))
+    }
+    Ok(())
+  }
+  fn remove(&mut self, cell: [usize; 3]) -> Result<()> {
+    let stem_len = self.stem_len();
+    /* The start of every node from the root to the leaf and the bit for cell in each */
+    let mut path: Vec<(usize, usize)> = Vec::with_capacity(self.max_slayers+1);
+    let mut node_start = 0;
+    let mut cube = self.root_cube();
+    for layer in 0..=self.max_slayers {
+      let child_pos = child_pos(cube, self.k, cell);
+      let bit_pos = node_start + child_pos;
+      if !self.layer_bits(layer).get(bit_pos) { return Ok(()) }
+      path.push((node_start, bit_pos));
+      if layer < self.max_slayers {
+        node_start = self.child_start(layer, bit_pos);
+        cube = to_subcubes(cube, self.k)[child_pos];
+      }
+    }
+    let _ = self.leaves.set(path[self.max_slayers].1, false);
+    /* Remove each node left with no 1s and clear its bit in the parent,
+    up to the root, which is always kept */
+    for layer in (1..=self.max_slayers).rev() {
+      let node_start = path[layer].0;
+      let bits = self.layer_bits(layer);
+      if (node_start..node_start+stem_len).any(|bit_pos| bits.get(bit_pos)) {
+        break
+      }
+      if let Err(()) = self.layer_bits_mut(layer).remove_block(node_start, stem_len) {
+        return Err(if layer == self.max_slayers {
+          Error::LeafRemovalError(node_start, stem_len)
+        }
+        else {
+          Error::StemRemovalError(node_start, stem_len)
+        })
+      }
+      if layer < self.max_slayers {
+        self.shift_layers(layer+1, -(stem_len as isize));
+      }
+      let _ = self.stems.set(path[layer-1].1, false);
+    }
+    Ok(())
+  }
+}
+impl Default for K3Tree {
+  fn default() -> Self {
+    K3Tree::new()
+  }
+}
+
+/* Utils */
+fn to_subcubes(cube: Cube, k: usize) -> Vec<Cube> {
+  /* Splits cube into its k*k*k sub-cubes, ordered by z,
+  then y, then x from the sub-cube at the origin */
+  let sub_width = (cube[0][1] - cube[0][0] + 1) / k;
+  let mut subcubes = Vec::with_capacity(k*k*k);
+  for z in 0..k {
+    for y in 0..k {
+      for x in 0..k {
+        let x_start = cube[0][0] + (x * sub_width);
+        let y_start = cube[1][0] + (y * sub_width);
+        let z_start = cube[2][0] + (z * sub_width);
+        subcubes.push([
+          [x_start, x_start+sub_width-1],
+          [y_start, y_start+sub_width-1],
+          [z_start, z_start+sub_width-1],
+        ]);
+      }
+    }
+  }
+  subcubes
+}
+fn child_pos(cube: Cube, k: usize, cell: [usize; 3]) -> usize {
+  /* Which of cube's k*k*k sub-cubes holds cell */
+  let sub_width = (cube[0][1] - cube[0][0] + 1) / k;
+  let x = (cell[0] - cube[0][0]) / sub_width;
+  let y = (cell[1] - cube[1][0]) / sub_width;
+  let z = (cell[2] - cube[2][0]) / sub_width;
+  (((z * k) + y) * k) + x
+}
+fn cubes_overlap(a: &Cube, b: &Cube) -> bool {
+  (0..3).all(|axis| a[axis][0] <= b[axis][1] && b[axis][0] <= a[axis][1])
+}
+fn check_layers(k: usize, max_slayers: usize, slayer_starts: &[usize], stems: &BitSlice, leaves_len: usize) -> std::result::Result<(), &'static str> {
+  /* The root is a single stem and every 1 has a child of k*k*k bits in
+  the next layer, so each layer's length follows from the one before it
+  and the leaves' from the last stem layer */
+  if slayer_starts.len() != max_slayers
+  || slayer_starts[0] != 0
+  || slayer_starts.windows(2).any(|starts| starts[0] > starts[1])
+  || slayer_starts[max_slayers-1] > stems.len() {
+    return Err("stem layer starts are not in ascending order within the stems")
+  }
+  let stem_len = k.checked_pow(3).ok_or("a stem is too long to address")?;
+  let mut layer_len = Some(stem_len);
+  for layer in 0..max_slayers {
+    let layer_end = if layer+1 < max_slayers { slayer_starts[layer+1] } else { stems.len() };
+    let layer_bits = &stems[slayer_starts[layer]..layer_end];
+    if layer_len != Some(layer_bits.len()) {
+      return Err("a stem layer does not have a stem for each 1 in the layer before it")
+    }
+    layer_len = layer_bits.count_ones().checked_mul(stem_len);
+  }
+  if layer_len != Some(leaves_len) {
+    return Err("the leaves do not match the 1s in the last stem layer")
+  }
+  Ok(())
+}
+
+/* Unit Tests */
+#[cfg(test)]
+mod unit_tests {
+  use super::*;
+  use rand::Rng;
+  fn sorted<I: Iterator<Item=[usize; 3]>>(cells: I) -> Vec<[usize; 3]> {
+    let mut cells: Vec<[usize; 3]> = cells.collect();
+    cells.sort();
+    cells
+  }
+  #[test]
+  fn k3_tree_0() {
+    let mut tree = K3Tree::with_extents(2, 8, 8, 8);
+    tree.set(1, 0, 0, true).unwrap();
+    tree.set(7, 6, 7, true).unwrap();
+    /* A bit per sub-cube, x fastest, then a node for each 1 in level order */
+    assert_eq!(tree.stems.as_bitslice(), &bitvec![1,0,0,0,0,0,0,1, 1,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,1][..]);
+    assert_eq!(tree.leaves.as_bitslice(), &bitvec![0,1,0,0,0,0,0,0, 0,0,0,0,0,1,0,0][..]);
+    assert!(tree.get(1, 0, 0).unwrap() && tree.get(7, 6, 7).unwrap());
+    assert!(!tree.get(0, 1, 0).unwrap());
+    assert_eq!(tree.iter().collect::<Vec<_>>(), vec![[1, 0, 0], [7, 6, 7]]);
+    assert_eq!(tree.iter_range(4..8, 0..8, 0..8).collect::<Vec<_>>(), vec![[7, 6, 7]]);
+    assert_eq!(tree, K3Tree::from_cells(2, 8, 8, 8, vec![[7, 6, 7], [1, 0, 0]]).unwrap());
+    assert_eq!(tree.plane(6).unwrap().get(7, 7).unwrap(), true);
+    /* Removing every cell leaves just the root */
+    tree.set(1, 0, 0, false).unwrap();
+    tree.set(7, 6, 7, false).unwrap();
+    assert!(tree.is_empty());
+    assert_eq!(tree.stems.as_bitslice(), &bitvec![0; 8][..]);
+    assert!(tree.set(8, 0, 0, true).is_err());
+  }
+  #[test]
+  fn k3_tree_1() {
+    let mut rng = rand::thread_rng();
+    let mut tree = K3Tree::with_extents(3, 40, 5, 30);
+    let mut set = std::collections::BTreeSet::new();
+    for _ in 0..800 {
+      let cell = [rng.gen_range(0, 40), rng.gen_range(0, 5), rng.gen_range(0, 30)];
+      let state = rng.gen_range(0, 3) != 0;
+      tree.set(cell[0], cell[1], cell[2], state).unwrap();
+      if state { set.insert(cell); } else { set.remove(&cell); }
+    }
+    assert_eq!(sorted(tree.iter()), sorted(set.iter().cloned()));
+    assert_eq!(tree.count_ones(), set.len());
+    assert_eq!(tree, K3Tree::from_cells(3, 40, 5, 30, set.iter().cloned()).unwrap());
+    for y in 0..5 {
+      let cells: Vec<(usize, usize)> = set.iter()
+        .filter(|cell| cell[1] == y)
+        .map(|&[x, _, z]| (x, z))
+        .collect();
+      assert_eq!(tree.plane(y).unwrap(), K2Tree::from_coordinates_with_extents(3, 40, 30, cells).unwrap());
+    }
+    assert_eq!(sorted(tree.iter_range(0..40, 0..5, 12..13)), sorted(set.iter().cloned().filter(|cell| cell[2] == 12)));
+    /* Growing keeps every cell */
+    tree.resize(100, 100, 100).unwrap();
+    assert_eq!(sorted(tree.iter()), sorted(set.iter().cloned()));
+    tree.set(99, 99, 99, true).unwrap();
+    assert!(tree.resize(50, 50, 50).is_err());
+    /* Binary round trip */
+    let mut bytes = Vec::new();
+    tree.write_to(&mut bytes).unwrap();
+    assert_eq!(K3Tree::read_from(&bytes[..]).unwrap(), tree);
+    bytes[70] ^= 1;
+    assert!(K3Tree::read_from(&bytes[..]).is_err());
+    /* Growing an empty cube gives it the whole tree */
+    let mut tree = K3Tree::with_extents(2, 0, 3, 0);
+    tree.grow();
+    assert_eq!((tree.width(), tree.height(), tree.depth()), (16, 6, 16));
+  }
+  #[test]
+  fn k3_tree_split_0() {
+    let mut rng = rand::thread_rng();
+    let cells: Vec<[usize; 3]> = (0..500).map(|_| [rng.gen_range(0, 64), rng.gen_range(0, 8), rng.gen_range(0, 64)]).collect();
+    let tree = K3Tree::from_cells(2, 64, 8, 64, cells).unwrap();
+    let expected: Vec<[usize; 3]> = tree.iter().collect();
+    let mut cells = tree.iter();
+    let later = cells.split().unwrap();
+    assert_eq!(cells.chain(later).collect::<Vec<_>>(), expected);
+    assert!(K3Tree::new().iter().split().is_none());
+  }
+  #[test]
+  fn k3_tree_serialization_0() {
+    let tree = K3Tree::from_cells(2, 16, 5, 12, vec![[1, 0, 2], [15, 4, 11], [9, 3, 0]]).unwrap();
+    assert_eq!(K3Tree::from_json(&tree.to_json().unwrap()).unwrap(), tree);
+    assert_eq!(K3Tree::from_json(&K3Tree::new().into_json().unwrap()).unwrap(), K3Tree::new());
+    let mut bytes = Vec::new();
+    tree.write_to(&mut bytes).unwrap();
+    /* Rewrites a header field and the checksum, so only the field is wrong */
+    let with_field = |offset: usize, value: u64| -> Vec<u8> {
+      let mut bytes = bytes.clone();
+      bytes[offset..offset+8].copy_from_slice(&value.to_le_bytes());
+      let end_offset = bytes.len() - 8;
+      let checksum = fnv1a(&bytes[..end_offset]);
+      bytes[end_offset..].copy_from_slice(&checksum.to_le_bytes());
+      bytes
+    };
+    /* Overflowing lengths and offsets */
+    assert!(K3Tree::read_from(&with_field(40, u64::MAX)[..]).is_err());
+    assert!(K3Tree::read_from(&with_field(48, u64::MAX)[..]).is_err());
+    assert!(K3Tree::read_from(&with_field(56, u64::MAX - 7)[..]).is_err());
+    /* A second stem layer that doesn't start after the root */
+    assert!(K3Tree::read_from(&with_field(72, 0)[..]).is_err());
+    /* A 1 without a child */
+    let json = tree.to_json().unwrap();
+    let mut extra_one = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+    extra_one["stems"][0] = (extra_one["stems"][0].as_u64().unwrap() ^ 0b10).into();
+    assert!(K3Tree::from_json(&extra_one.to_string()).is_err());
+    let mut short_leaves = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+    short_leaves["numLeaves"] = 8.into();
+    short_leaves["leaves"] = vec![0u8].into();
+    assert!(K3Tree::from_json(&short_leaves.to_string()).is_err());
+  }
+}
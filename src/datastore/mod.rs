@@ -1,4 +1,7 @@
 #![allow(non_snake_case)]
 
 pub mod graph;
-pub mod k2_tree;
\ No newline at end of file
+pub mod k2_tree;
+pub mod k2_graph;
+pub mod ik2_tree;
+pub mod k3_tree;
\ No newline at end of file
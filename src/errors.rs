@@ -10,6 +10,7 @@ pub enum GraphError {
   Io(Source<std::io::Error>),
   Serde(Source<serde_json::Error>),
   DeadK2Tree(String),
+  InvalidK(usize),
   K2Tree(Source<K2TreeError>),
   Parser(Source<ParserError>),
 }
@@ -36,6 +37,7 @@ impl std::fmt::Display for GraphError {
       Io(e) => write!(f, "{}", *e),
       Serde(e) => write!(f, "{}", *e),
       DeadK2Tree(reason) => write!(f, "Graph's K2Tree is invalid and considered dead because {}, meaning that the Graph's integrity is most likely compromised.", reason),
+      InvalidK(k) => write!(f, "A Graph's k must be at least 2, got {}", k),
       K2Tree(e) => write!(f, "{}", *e),
       Parser(e) => write!(f, "{}", *e),
     }
@@ -72,12 +74,23 @@ pub enum K2TreeError {
   LeafInsertionError(usize, usize),
   LeafRemovalError(usize, usize),
   CouldNotShrink(String),
+  CouldNotGrow(String),
+  MismatchedK(usize, usize),
+  Io(Source<std::io::Error>),
+  InvalidBinary(String),
+  ReadOnlyStore,
+  PredicateOutOfBounds(usize, usize),
+  PredicateInUse(usize),
+  CellOutOfBounds([usize; 3], [usize; 3]),
+  InvalidEdgeList(usize, String),
+  NodeOutOfBounds(usize, usize),
 }
 impl std::error::Error for K2TreeError {
   fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
     use K2TreeError::*;
     match self {
       Serde(e) => Some(&*e),
+      Io(e) => Some(&*e),
       _ => None,
     }
   }
@@ -94,6 +107,16 @@ impl std::fmt::Display for K2TreeError {
       LeafInsertionError(pos, leaf_len) => write!(f, "Could not insert leaf of length {} to BitVec at offset {}", leaf_len, pos),
       LeafRemovalError(pos, leaf_len) => write!(f, "Could not remove leaf of length {} to BitVec at offset {}", leaf_len, pos),
       CouldNotShrink(reason) => write!(f, "Could not shrink the matrix a K2Tree represents: {}", reason),
+      CouldNotGrow(reason) => write!(f, "Could not grow the matrix a K2Tree represents: {}", reason),
+      MismatchedK(k, other_k) => write!(f, "Attempt to combine a K2Tree with k={} and a K2Tree with k={}, both must share the same k", k, other_k),
+      Io(e) => write!(f, "{}", *e),
+      InvalidBinary(reason) => write!(f, "Could not read a K2Tree from its binary form: {}", reason),
+      ReadOnlyStore => write!(f, "Attempted to change a K2Tree in place whose bits are in a read-only store"),
+      PredicateOutOfBounds(predicate, num_predicates) => write!(f, "Attempt to access predicate {} in an IK2Tree with {} predicates", predicate, num_predicates),
+      PredicateInUse(predicate) => write!(f, "Could not remove predicate {} from an IK2Tree as it still has cells set", predicate),
+      CellOutOfBounds([x, y, z], [max_x, max_y, max_z]) => write!(f, "Attempt to access a cell at coordinates ({}, {}, {}) which are not in the range of the cube represented by the K3Tree: ({}, {}, {})", x, y, z, max_x, max_y, max_z),
+      InvalidEdgeList(line, reason) => write!(f, "Could not read a K2Graph from an edge list, line {}: {}", line, reason),
+      NodeOutOfBounds(node, k) => write!(f, "Attempt to add node {} to a K2Graph with k={}, whose tree can't be wide enough to hold it", node, k),
     }
   }
 }
@@ -102,6 +125,11 @@ impl From<serde_json::Error> for K2TreeError {
     K2TreeError::Serde(Box::new(err))
   }
 }
+impl From<std::io::Error> for K2TreeError {
+  fn from(err: std::io::Error) -> K2TreeError {
+    K2TreeError::Io(Box::new(err))
+  }
+}
 
 #[derive(Debug)]
 pub enum ParserError {
@@ -7,7 +7,17 @@ pub mod rdf;
 pub mod errors;
 
 pub use datastore::graph::Graph as Graph;
+pub use datastore::graph::MappedGraph as MappedGraph;
+pub use datastore::graph::GraphStorage as GraphStorage;
+pub use datastore::graph::IdOrder as IdOrder;
+pub use datastore::graph::ReorderReport as ReorderReport;
+pub use datastore::graph::VacuumReport as VacuumReport;
+pub use datastore::graph::GraphStats as GraphStats;
 pub use datastore::k2_tree::K2Tree as K2Tree;
+pub use datastore::k2_tree::K2Tree1 as K2Tree1;
+pub use datastore::k2_graph::K2Graph as K2Graph;
+pub use datastore::ik2_tree::IK2Tree as IK2Tree;
+pub use datastore::k3_tree::K3Tree as K3Tree;
 pub use rdf::query::Sparql as SparqlQuery;
 
 /* Common Definitions */